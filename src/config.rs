@@ -0,0 +1,220 @@
+//! Settings read from `config.toml` in the user's config directory,
+//! e.g. `~/.config/music-manager/config.toml`. Every setting has a default,
+//! so the file and any of its sections can be left out.
+
+use std::path::{Path, PathBuf};
+
+use eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
+
+use crate::download::profile::Profile;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub cover: CoverConfig,
+    pub download: DownloadConfig,
+}
+
+/// How thumbnails are turned into cover pictures
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoverConfig {
+    /// Covers larger than this many pixels on a side are scaled down
+    pub max_size: u32,
+    /// Quality of JPEG covers, from 1 to 100
+    pub jpeg_quality: u8,
+}
+
+impl Default for CoverConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 600,
+            jpeg_quality: 90,
+        }
+    }
+}
+
+/// How songs are downloaded and how the download queue is worked through
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloadConfig {
+    /// Number of jobs worked on at the same time
+    pub workers: usize,
+    pub backend: Backend,
+    /// Transcoding profile used unless `download --profile` chooses another
+    pub profile: Profile,
+    /// Path of the youtube-dl or yt-dlp executable, looked up in `PATH` by default
+    pub binary: Option<PathBuf>,
+    /// Directory the local backend imports music files from
+    pub directory: Option<PathBuf>,
+    /// How many times a failed download or conversion is tried again
+    pub retries: u32,
+    /// Seconds to wait before the first retry, doubled for each one after it
+    pub retry_delay: u64,
+    /// Seconds a download may take before it is stopped and counts as failed
+    pub download_timeout: u64,
+    /// Seconds a conversion may take before it is stopped and counts as failed
+    pub convert_timeout: u64,
+    /// Where jobs keep their files until they are moved into the library,
+    /// `.staging` in the music directory by default
+    pub staging_dir: Option<PathBuf>,
+    /// Whether downloaded songs are tagged with their ReplayGain track gain
+    pub replaygain: bool,
+    /// Whether silence at the start and end of downloaded songs is cut off
+    pub trim_silence: bool,
+    /// Audio quieter than this many dB below full scale counts as silence
+    pub silence_threshold: f64,
+    /// Seconds silence must last to be cut off, so short pauses stay
+    pub min_silence: f64,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            workers: 2,
+            backend: Backend::YoutubeDl,
+            profile: Profile::Flac,
+            binary: None,
+            directory: None,
+            retries: 2,
+            retry_delay: 5,
+            download_timeout: 600,
+            convert_timeout: 600,
+            staging_dir: None,
+            replaygain: false,
+            trim_silence: false,
+            silence_threshold: -50.0,
+            min_silence: 2.0,
+        }
+    }
+}
+
+impl DownloadConfig {
+    /// The staging directory for a library in `music_dir`. The default is hidden, so `scan`
+    /// doesn't take unfinished downloads for songs.
+    pub fn staging_dir(&self, music_dir: &Path) -> PathBuf {
+        self.staging_dir
+            .clone()
+            .unwrap_or_else(|| music_dir.join(".staging"))
+    }
+}
+
+/// What songs are downloaded with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    YoutubeDl,
+    YtDlp,
+    /// Music files in `download.directory`
+    Local,
+}
+
+impl Config {
+    pub fn path() -> Result<PathBuf> {
+        let project_dirs = directories_next::ProjectDirs::from("", "", "music-manager")
+            .ok_or_else(|| eyre!("directories_next failed to initialize"))?;
+        Ok(project_dirs.config_dir().join("config.toml"))
+    }
+
+    /// Loads the config file, using the defaults if it doesn't exist
+    pub fn load() -> Result<Config> {
+        let path = Config::path()?;
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let contents = std::fs::read_to_string(&path)
+            .wrap_err_with(|| format!("Can't read {}", path.display()))?;
+        Config::parse(&contents).wrap_err_with(|| format!("Invalid config {}", path.display()))
+    }
+
+    fn parse(contents: &str) -> Result<Config> {
+        let config: Config = toml::from_str(contents)?;
+        if config.cover.max_size == 0 {
+            return Err(eyre!("cover.max_size must be more than 0"));
+        }
+        if !(1..=100).contains(&config.cover.jpeg_quality) {
+            return Err(eyre!("cover.jpeg_quality must be between 1 and 100"));
+        }
+        if config.download.workers == 0 {
+            return Err(eyre!("download.workers must be more than 0"));
+        }
+        if config.download.backend == Backend::Local && config.download.directory.is_none() {
+            return Err(eyre!(
+                "download.directory must be set to use the local backend"
+            ));
+        }
+        if config.download.download_timeout == 0 || config.download.convert_timeout == 0 {
+            return Err(eyre!(
+                "download.download_timeout and download.convert_timeout must be more than 0"
+            ));
+        }
+        if config.download.silence_threshold >= 0.0 {
+            return Err(eyre!("download.silence_threshold must be below 0 dB"));
+        }
+        if config.download.min_silence <= 0.0 {
+            return Err(eyre!("download.min_silence must be more than 0"));
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.cover.max_size, 600);
+        assert_eq!(config.cover.jpeg_quality, 90);
+        assert_eq!(config.download.workers, 2);
+
+        let config = Config::parse("[cover]\nmax_size = 1000\n").unwrap();
+        assert_eq!(config.cover.max_size, 1000);
+        assert_eq!(config.cover.jpeg_quality, 90);
+
+        assert!(Config::parse("[cover]\nmax_size = 0\n").is_err());
+        assert!(Config::parse("[cover]\njpeg_quality = 101\n").is_err());
+        assert!(Config::parse("[cover]\nmaxsize = 1000\n").is_err());
+        assert!(Config::parse("[covers]\n").is_err());
+
+        let config = Config::parse("[download]\nworkers = 4\n").unwrap();
+        assert_eq!(config.download.workers, 4);
+        assert!(Config::parse("[download]\nworkers = 0\n").is_err());
+        assert_eq!(config.download.backend, Backend::YoutubeDl);
+        assert_eq!(config.download.profile, Profile::Flac);
+        let config = Config::parse("[download]\nprofile = \"mp3-v0\"\n").unwrap();
+        assert_eq!(config.download.profile, Profile::Mp3V0);
+        assert!(Config::parse("[download]\nprofile = \"wav\"\n").is_err());
+
+        let config =
+            Config::parse("[download]\nbackend = \"yt-dlp\"\nbinary = \"/opt/yt-dlp\"\n").unwrap();
+        assert_eq!(config.download.backend, Backend::YtDlp);
+        assert_eq!(config.download.binary, Some(PathBuf::from("/opt/yt-dlp")));
+        assert!(Config::parse("[download]\nbackend = \"local\"\n").is_err());
+        assert!(Config::parse("[download]\nbackend = \"youtube\"\n").is_err());
+
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.download.retries, 2);
+        assert_eq!(
+            config.download.staging_dir(Path::new("/music")),
+            PathBuf::from("/music/.staging")
+        );
+        let config =
+            Config::parse("[download]\nretries = 0\nstaging_dir = \"/tmp/staging\"\n").unwrap();
+        assert_eq!(config.download.retries, 0);
+        assert_eq!(
+            config.download.staging_dir(Path::new("/music")),
+            PathBuf::from("/tmp/staging")
+        );
+        assert!(Config::parse("[download]\ndownload_timeout = 0\n").is_err());
+
+        let config = Config::parse("[download]\ntrim_silence = true\nmin_silence = 0.5\n").unwrap();
+        assert!(config.download.trim_silence);
+        assert_eq!(config.download.silence_threshold, -50.0);
+        assert_eq!(config.download.min_silence, 0.5);
+        assert!(Config::parse("[download]\nsilence_threshold = 3\n").is_err());
+        assert!(Config::parse("[download]\nmin_silence = 0\n").is_err());
+    }
+}
@@ -0,0 +1,280 @@
+//! Turns YouTube thumbnails into cover pictures: letterboxing is trimmed, the image is
+//! cropped to a square and scaled down, then encoded as whichever of JPEG and PNG is smaller
+
+use eyre::{eyre, Result};
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder},
+    imageops::FilterType,
+    ColorType, DynamicImage, ImageFormat, RgbImage,
+};
+use log::{debug, warn};
+
+use crate::{
+    config::CoverConfig,
+    data::tag::{Picture, PictureType},
+};
+
+/// Channels at or below this value count as black when looking for letterboxing
+const BLACK_THRESHOLD: u8 = 24;
+/// Share of pixels in a row or column which may be brighter, for noise and logos
+const BRIGHT_TOLERANCE: f32 = 0.02;
+
+/// Downloads a thumbnail and turns it into a front cover
+pub async fn fetch_cover(url: &str, config: &CoverConfig) -> Result<Picture> {
+    let jpeg_url = jpeg_thumbnail_url(url);
+    let data = match fetch(&jpeg_url).await {
+        Ok(data) => data,
+        Err(e) if jpeg_url != url => {
+            debug!("Falling back to {} as {} failed: {}", url, jpeg_url, e);
+            fetch(url).await?
+        }
+        Err(e) => return Err(e),
+    };
+    process(&data, config)
+}
+
+async fn fetch(url: &str) -> Result<Vec<u8>> {
+    let response = reqwest::get(url).await?.error_for_status()?;
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// YouTube serves every thumbnail as both WebP and JPEG. The `image` WebP decoder only reads
+/// the luma channel, which made covers black and white, so the JPEG is preferred.
+pub fn jpeg_thumbnail_url(url: &str) -> String {
+    if !url.contains("ytimg.com/vi_webp/") {
+        return url.to_string();
+    }
+    let url = url.replacen("/vi_webp/", "/vi/", 1);
+    match url.split_once('?') {
+        Some((path, query)) => format!("{}?{}", path.replace(".webp", ".jpg"), query),
+        None => url.replace(".webp", ".jpg"),
+    }
+}
+
+/// Processes an image into a square front cover no larger than the configured size
+pub fn process(data: &[u8], config: &CoverConfig) -> Result<Picture> {
+    let format = image::guess_format(data)?;
+    if format == ImageFormat::WebP {
+        warn!("WebP thumbnails are decoded without color");
+    }
+    let image = image::load_from_memory_with_format(data, format)?.to_rgb8();
+
+    let image = trim_letterbox(&image);
+    let image = crop_square(&image);
+    let image = if image.width() > config.max_size {
+        DynamicImage::ImageRgb8(image)
+            .resize_exact(config.max_size, config.max_size, FilterType::Lanczos3)
+            .to_rgb8()
+    } else {
+        image
+    };
+
+    let (mime_type, data) = encode_smallest(&image, config.jpeg_quality)?;
+    let mut picture = Picture::new();
+    picture.picture_type = PictureType::CoverFront;
+    picture.mime_type = mime_type.to_string();
+    picture.width = image.width();
+    picture.height = image.height();
+    picture.depth = ColorType::Rgb8.bits_per_pixel() as u32;
+    picture.data = data;
+    Ok(picture)
+}
+
+fn is_black_line(pixels: impl Iterator<Item = [u8; 3]>) -> bool {
+    let mut total = 0;
+    let mut bright = 0;
+    for pixel in pixels {
+        total += 1;
+        if pixel.iter().any(|channel| *channel > BLACK_THRESHOLD) {
+            bright += 1;
+        }
+    }
+    total > 0 && bright as f32 <= total as f32 * BRIGHT_TOLERANCE
+}
+
+/// Removes black bars from every side. Images which are black all over are left as they are.
+fn trim_letterbox(image: &RgbImage) -> RgbImage {
+    let (width, height) = image.dimensions();
+    let row = |y: u32, left: u32, right: u32| {
+        is_black_line((left..right).map(move |x| image.get_pixel(x, y).0))
+    };
+    let column = |x: u32, top: u32, bottom: u32| {
+        is_black_line((top..bottom).map(move |y| image.get_pixel(x, y).0))
+    };
+
+    let mut top = 0;
+    while top < height && row(top, 0, width) {
+        top += 1;
+    }
+    if top == height {
+        return image.clone();
+    }
+    let mut bottom = height;
+    while bottom > top && row(bottom - 1, 0, width) {
+        bottom -= 1;
+    }
+    let mut left = 0;
+    while left < width && column(left, top, bottom) {
+        left += 1;
+    }
+    let mut right = width;
+    while right > left && column(right - 1, top, bottom) {
+        right -= 1;
+    }
+
+    if (top, bottom, left, right) != (0, height, 0, width) {
+        debug!(
+            "Trimmed letterboxing to {}x{} at ({}, {})",
+            right - left,
+            bottom - top,
+            left,
+            top
+        );
+    }
+    image::imageops::crop_imm(image, left, top, right - left, bottom - top).to_image()
+}
+
+/// Crops the middle square of the image
+fn crop_square(image: &RgbImage) -> RgbImage {
+    let (width, height) = image.dimensions();
+    let side = width.min(height);
+    image::imageops::crop_imm(image, (width - side) / 2, (height - side) / 2, side, side).to_image()
+}
+
+fn encode_smallest(image: &RgbImage, jpeg_quality: u8) -> Result<(&'static str, Vec<u8>)> {
+    let mut jpeg = vec![];
+    JpegEncoder::new_with_quality(&mut jpeg, jpeg_quality).encode(
+        image.as_raw(),
+        image.width(),
+        image.height(),
+        ColorType::Rgb8,
+    )?;
+    let mut png = vec![];
+    PngEncoder::new(&mut png).encode(
+        image.as_raw(),
+        image.width(),
+        image.height(),
+        ColorType::Rgb8,
+    )?;
+
+    match (jpeg.is_empty(), png.len() < jpeg.len()) {
+        (true, _) => Err(eyre!("Encoding the cover failed")),
+        (false, true) => Ok(("image/png", png)),
+        (false, false) => Ok(("image/jpeg", jpeg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    fn encode_png(image: &RgbImage) -> Vec<u8> {
+        let mut data = vec![];
+        DynamicImage::ImageRgb8(image.clone())
+            .write_to(&mut data, image::ImageOutputFormat::Png)
+            .unwrap();
+        data
+    }
+
+    /// A colorful 16:9 frame inside a 4:3 thumbnail, like YouTube's hqdefault.jpg
+    fn letterboxed_thumbnail() -> RgbImage {
+        RgbImage::from_fn(480, 360, |x, y| {
+            if !(45..315).contains(&y) {
+                // Bars are not perfectly black after JPEG compression
+                Rgb([(x % 7) as u8, 3, 5])
+            } else {
+                Rgb([(x * 255 / 480) as u8, 40, ((y - 45) * 255 / 270) as u8])
+            }
+        })
+    }
+
+    fn decode(picture: &Picture) -> RgbImage {
+        image::load_from_memory(&picture.data).unwrap().to_rgb8()
+    }
+
+    #[test]
+    fn trims_and_crops_thumbnails() {
+        let config = CoverConfig::default();
+        let picture = process(&encode_png(&letterboxed_thumbnail()), &config).unwrap();
+        assert_eq!(
+            (picture.width, picture.height, picture.depth),
+            (270, 270, 24)
+        );
+        assert_eq!(picture.picture_type, PictureType::CoverFront);
+
+        // The middle of the frame is kept, in color
+        let cover = decode(&picture);
+        assert_eq!(cover.dimensions(), (270, 270));
+        for (x, y) in [(0, 0), (269, 0), (0, 269), (269, 269), (135, 135)] {
+            let Rgb([red, green, _]) = *cover.get_pixel(x, y);
+            assert!(red > 40 && red < 210, "{:?}", cover.get_pixel(x, y));
+            assert!(green > 20 && green < 60, "{:?}", cover.get_pixel(x, y));
+        }
+    }
+
+    #[test]
+    fn scales_down_large_covers() {
+        let config = CoverConfig {
+            max_size: 100,
+            ..Default::default()
+        };
+        let image = RgbImage::from_fn(400, 300, |x, y| Rgb([x as u8, y as u8, 200]));
+        let picture = process(&encode_png(&image), &config).unwrap();
+        assert_eq!((picture.width, picture.height), (100, 100));
+        assert_eq!(decode(&picture).dimensions(), (100, 100));
+
+        // Small covers are not scaled up
+        let image = RgbImage::from_fn(50, 80, |x, y| Rgb([x as u8, y as u8, 200]));
+        let picture = process(&encode_png(&image), &config).unwrap();
+        assert_eq!((picture.width, picture.height), (50, 50));
+    }
+
+    #[test]
+    fn picks_the_smaller_format() {
+        let config = CoverConfig::default();
+        let flat = RgbImage::from_pixel(300, 300, Rgb([200, 30, 30]));
+        assert_eq!(
+            process(&encode_png(&flat), &config).unwrap().mime_type,
+            "image/png"
+        );
+
+        // A photo-like gradient with random noise
+        let mut seed: u32 = 1;
+        let noisy = RgbImage::from_fn(300, 300, |x, y| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let noise = (seed >> 16) % 32;
+            Rgb([
+                (x / 2 + noise) as u8,
+                (y / 2 + noise) as u8,
+                (noise * 4) as u8,
+            ])
+        });
+        assert_eq!(
+            process(&encode_png(&noisy), &config).unwrap().mime_type,
+            "image/jpeg"
+        );
+    }
+
+    #[test]
+    fn keeps_black_images() {
+        let black = RgbImage::from_pixel(160, 90, Rgb([0, 0, 0]));
+        let picture = process(&encode_png(&black), &CoverConfig::default()).unwrap();
+        assert_eq!((picture.width, picture.height), (90, 90));
+    }
+
+    #[test]
+    fn prefers_jpeg_thumbnails() {
+        assert_eq!(
+            jpeg_thumbnail_url("https://i.ytimg.com/vi_webp/x8VYWazR5mE/maxresdefault.webp"),
+            "https://i.ytimg.com/vi/x8VYWazR5mE/maxresdefault.jpg"
+        );
+        assert_eq!(
+            jpeg_thumbnail_url("https://i.ytimg.com/vi_webp/x8VYWazR5mE/hqdefault.webp?v=1"),
+            "https://i.ytimg.com/vi/x8VYWazR5mE/hqdefault.jpg?v=1"
+        );
+        let jpeg = "https://i.ytimg.com/vi/x8VYWazR5mE/hqdefault.jpg?sqp=-oaymw";
+        assert_eq!(jpeg_thumbnail_url(jpeg), jpeg);
+    }
+}
@@ -1,10 +1,29 @@
 use std::path::{Path, PathBuf};
 
 use eyre::{eyre, Result};
-use rusqlite::{params, Connection};
+use rusqlite::{params, params_from_iter, Connection, Params, Transaction};
 use time::OffsetDateTime;
 
-use super::song::Song;
+use super::{
+    job::{DownloadJob, JobState, Track},
+    migrations,
+    provenance::Provenance,
+    query::Query,
+    replaygain::ReplayGain,
+    song::Song,
+    trim::Trim,
+};
+use crate::download::profile::Profile;
+
+/// The state of a song's file when it was last scanned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileState {
+    pub size: u64,
+    /// Modification time in nanoseconds since the Unix epoch
+    pub modified: i64,
+    /// Hex encoded SHA-256 of the file contents
+    pub hash: String,
+}
 
 pub struct Database {
     connection: Connection,
@@ -12,30 +31,18 @@ pub struct Database {
 }
 
 impl Database {
+    /// Opens the database at the given path, creating it if needed and upgrading its schema
+    /// to the latest version. Fails if the database was created by a newer version.
     pub fn open_from_path<P>(path: P) -> Result<Database>
     where
         P: AsRef<Path>,
     {
-        let conn = Connection::open(&path)?;
+        let mut conn = Connection::open(&path)?;
 
-        // Create the table if it doesn't exist
-        conn.execute(
-            "
-            CREATE TABLE IF NOT EXISTS songs (
-                id                  INTEGER UNIQUE PRIMARY KEY,
-                song_path           nTEXT NOT NULL,
-                song_filename       nTEXT NOT NULL,
-                song_title          nTEXT,
-                song_artist         nTEXT,
-                song_album          nTEXT,
-                song_genre          nTEXT,
-                song_youtube_id     nTEXT,
-                song_thumbnail_url  nTEXT,
-                date_added          DATETIME
-            )
-            ",
-            [],
-        )?;
+        // Bring the schema up to date
+        migrations::migrate(&mut conn)?;
+        // Enabled after migrating, as migrations may need to rebuild referenced tables
+        conn.pragma_update(None, "foreign_keys", true)?;
 
         let database = Database {
             connection: conn,
@@ -47,54 +54,24 @@ impl Database {
 
     /// Returns all records in the database
     pub fn query_all_song_data(&self) -> Result<Vec<Song>> {
-        let mut stmt = self.connection.prepare(
-            "
-            SELECT id, song_path, song_filename, song_title, song_artist, song_album, song_genre,
-                song_youtube_id, song_thumbnail_url, date_added FROM songs
-            ",
-        )?;
-        let song_iter = stmt.query_map([], |row| {
-            Ok(Song::from_database(
-                row.get(0).ok(),
-                row.get(1)?,
-                row.get(2)?,
-                row.get(3).ok(),
-                row.get(4).ok(),
-                row.get(5).ok(),
-                row.get(6).ok(),
-                row.get(7).ok(),
-                row.get(8).ok(),
-            )
-            .unwrap())
-        })?;
-        let song_vec = song_iter.map(|song| song.unwrap()).collect::<Vec<Song>>();
+        let song_vec = self.all_songs()?;
         if song_vec.is_empty() {
             return Err(eyre!("No results were found. Is the database empty?"));
         }
         Ok(song_vec)
     }
 
+    /// Returns all records in the database. Unlike `query_all_song_data`, an empty database
+    /// is not an error.
+    pub fn all_songs(&self) -> Result<Vec<Song>> {
+        self.query_songs("ORDER BY songs.id", [])
+    }
+
     /// Find records by its name. Song title is the exact title of the song.
     /// If an undeterminate search is required, use `search_song`
     #[allow(dead_code)]
     pub fn query_song_by_name(&self, song_title: &str) -> Result<Vec<Song>> {
-        let query = format!("SELECT * from songs WHERE song_title = '{}'", song_title);
-        let mut stmt = self.connection.prepare(&query)?;
-        let song_iter = stmt.query_map([], |row| {
-            Ok(Song::from_database(
-                row.get(0).ok(),
-                row.get(1)?,
-                row.get(2)?,
-                row.get(3).ok(),
-                row.get(4).ok(),
-                row.get(5).ok(),
-                row.get(6).ok(),
-                row.get(7).ok(),
-                row.get(8).ok(),
-            )
-            .unwrap())
-        })?;
-        let song_vec = song_iter.map(|song| song.unwrap()).collect::<Vec<Song>>();
+        let song_vec = self.query_songs("WHERE songs.song_title = ?1", params![song_title])?;
 
         if song_vec.is_empty() {
             return Err(eyre!("No results were found. Is the database empty?"));
@@ -105,23 +82,7 @@ impl Database {
 
     /// Find a record by its ID
     pub fn query_song_by_id(&self, song_id: usize) -> Result<Vec<Song>> {
-        let query = format!("SELECT * from songs WHERE id = '{}'", song_id);
-        let mut stmt = self.connection.prepare(&query)?;
-        let song_iter = stmt.query_map([], |row| {
-            Ok(Song::from_database(
-                row.get(0).ok(),
-                row.get(1)?,
-                row.get(2)?,
-                row.get(3).ok(),
-                row.get(4).ok(),
-                row.get(5).ok(),
-                row.get(6).ok(),
-                row.get(7).ok(),
-                row.get(8).ok(),
-            )
-            .unwrap())
-        })?;
-        let song_vec = song_iter.map(|song| song.unwrap()).collect::<Vec<Song>>();
+        let song_vec = self.query_songs("WHERE songs.id = ?1", params![song_id])?;
 
         if song_vec.is_empty() {
             return Err(eyre!("No results were found. Is the database empty?"));
@@ -130,112 +91,247 @@ impl Database {
         Ok(song_vec)
     }
 
-    /// Insert a record into the database
-    pub fn insert_song(&self, song: &Song) -> Result<()> {
-        let mut artist_string = String::new();
-        for artist in song.artists.as_ref().unwrap_or(&vec!["None".to_string()]) {
-            artist_string.push_str(artist);
-            if artist == "None" {
-                continue;
-            }
-            artist_string.push(':');
+    /// Find the record of a file. Returns None if the file is not in the database
+    pub fn query_song_by_path(&self, path: &Path) -> Result<Option<Song>> {
+        let path = path
+            .to_str()
+            .ok_or_else(|| eyre!("Can't convert path to str"))?;
+        let mut song_vec = self.query_songs("WHERE songs.song_path = ?1", params![path])?;
+        Ok(if song_vec.is_empty() {
+            None
+        } else {
+            Some(song_vec.remove(0))
+        })
+    }
+
+    /// Find the records of songs downloaded from a YouTube video
+    pub fn query_songs_by_youtube_id(&self, youtube_id: &str) -> Result<Vec<Song>> {
+        self.query_songs(
+            "WHERE songs.song_youtube_id = ?1 ORDER BY songs.id",
+            params![youtube_id],
+        )
+    }
+
+    /// Returns the state of the song's file when it was last scanned
+    pub fn file_state(&self, song_id: usize) -> Result<Option<FileState>> {
+        let state = self.connection.query_row(
+            "SELECT file_size, file_modified, file_hash FROM songs WHERE id = ?1",
+            params![song_id],
+            |row| {
+                Ok(match (row.get(0)?, row.get(1)?, row.get(2)?) {
+                    (Some(size), Some(modified), Some(hash)) => Some(FileState {
+                        size,
+                        modified,
+                        hash,
+                    }),
+                    _ => None,
+                })
+            },
+        )?;
+        Ok(state)
+    }
+
+    /// Records the state of the song's file
+    pub fn set_file_state(&self, song_id: usize, state: &FileState) -> Result<()> {
+        self.connection.execute(
+            "UPDATE songs SET file_size = ?2, file_modified = ?3, file_hash = ?4 WHERE id = ?1",
+            params![song_id, state.size, state.modified, state.hash],
+        )?;
+        Ok(())
+    }
+
+    /// Returns every song the artist has worked on
+    pub fn songs_by_artist(&self, artist: &str) -> Result<Vec<Song>> {
+        let song_vec = self.query_songs(
+            "WHERE songs.id IN (
+                SELECT song_artists.song_id FROM song_artists
+                JOIN artists ON artists.id = song_artists.artist_id
+                WHERE artists.name = ?1
+            )
+            ORDER BY songs.id",
+            params![artist],
+        )?;
+
+        if song_vec.is_empty() {
+            return Err(eyre!("No songs by {} were found.", artist));
         }
 
+        Ok(song_vec)
+    }
+
+    /// Returns the names of albums which contain at least one song by the artist
+    pub fn albums_by_artist(&self, artist: &str) -> Result<Vec<String>> {
+        let mut stmt = self.connection.prepare(
+            "
+            SELECT DISTINCT albums.title FROM albums
+            JOIN songs ON songs.album_id = albums.id
+            JOIN song_artists ON song_artists.song_id = songs.id
+            JOIN artists ON artists.id = song_artists.artist_id
+            WHERE artists.name = ?1
+            ORDER BY albums.title
+            ",
+        )?;
+        let albums = stmt
+            .query_map(params![artist], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(albums)
+    }
+
+    /// Returns the names of all artists in the database
+    pub fn list_artists(&self) -> Result<Vec<String>> {
+        let mut stmt = self
+            .connection
+            .prepare("SELECT name FROM artists ORDER BY name")?;
+        let artists = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(artists)
+    }
+
+    /// Insert a record into the database. Returns the ID of the new record.
+    /// The date the song was added defaults to now.
+    pub fn insert_song(&self, song: &Song) -> Result<usize> {
+        let tx = self.connection.unchecked_transaction()?;
+
         let sql = "
             INSERT INTO songs (
                 song_path,
-                song_filename, 
+                song_filename,
                 song_title,
-                song_artist,
-                song_album,
-                song_genre,
+                album_id,
                 song_youtube_id,
                 song_thumbnail_url,
-                date_added
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                date_added,
+                source_codec,
+                source_bitrate,
+                source_url,
+                downloader_version,
+                converted_at,
+                release_date,
+                track_number,
+                replaygain_track_gain,
+                replaygain_track_peak,
+                replaygain_album_gain,
+                replaygain_album_peak,
+                original_duration,
+                trimmed_start,
+                trimmed_end
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+                ?18, ?19, ?20, ?21)
         ";
-        self.connection.execute(
+        let provenance = &song.provenance;
+        let replaygain = &song.replaygain;
+        tx.execute(
             sql,
             params![
                 song.file_path.to_str(),
                 song.file_name,
                 song.title,
-                artist_string,
-                song.album,
-                song.genre,
+                album_id(&tx, song)?,
                 song.youtube_id,
                 song.thumbnail_url,
-                OffsetDateTime::now_utc()
+                song.date_added.unwrap_or_else(OffsetDateTime::now_utc),
+                provenance.source_codec,
+                provenance.source_bitrate,
+                provenance.source_url,
+                provenance.downloader_version,
+                provenance.converted_at,
+                song.release_date,
+                song.track_number,
+                replaygain.track_gain,
+                replaygain.track_peak,
+                replaygain.album_gain,
+                replaygain.album_peak,
+                song.trim.original_duration,
+                song.trim.start,
+                song.trim.end
             ],
         )?;
-        Ok(())
+        let id = tx.last_insert_rowid();
+        set_song_artists_and_genres(&tx, id, song)?;
+
+        tx.commit()?;
+        Ok(id as usize)
     }
 
     /// Updates an existing record in the database. Note that id and timestamp can never be changed
     pub fn update_song(&self, song: &Song) -> Result<()> {
-        let mut artist_string = String::new();
-        for artist in song.artists.as_ref().unwrap_or(&vec!["None".to_string()]) {
-            artist_string.push_str(artist);
-            if artist == "None" {
-                continue;
-            }
-            artist_string.push(':');
-        }
+        let id = song
+            .id
+            .ok_or_else(|| eyre!("Can't update a song that is not in the database"))?;
+        let tx = self.connection.unchecked_transaction()?;
 
         let sql = "
             UPDATE songs SET
-                song_path = ?9,
-                song_filename = ?2, 
+                song_path = ?7,
+                song_filename = ?2,
                 song_title = ?3,
-                song_artist = ?4,
-                song_album = ?5,
-                song_genre = ?6,
-                song_youtube_id = ?7,
-                song_thumbnail_url = ?8
+                album_id = ?4,
+                song_youtube_id = ?5,
+                song_thumbnail_url = ?6,
+                source_codec = ?8,
+                source_bitrate = ?9,
+                source_url = ?10,
+                downloader_version = ?11,
+                converted_at = ?12,
+                release_date = ?13,
+                track_number = ?14,
+                replaygain_track_gain = ?15,
+                replaygain_track_peak = ?16,
+                replaygain_album_gain = ?17,
+                replaygain_album_peak = ?18,
+                original_duration = ?19,
+                trimmed_start = ?20,
+                trimmed_end = ?21
             WHERE id = ?1
         ";
-        self.connection.execute(
+        let provenance = &song.provenance;
+        let replaygain = &song.replaygain;
+        tx.execute(
             sql,
             params![
-                song.id,
+                id,
                 song.file_name,
                 song.title,
-                artist_string,
-                song.album,
-                song.genre,
+                album_id(&tx, song)?,
                 song.youtube_id,
                 song.thumbnail_url,
-                song.file_path.to_str()
+                song.file_path.to_str(),
+                provenance.source_codec,
+                provenance.source_bitrate,
+                provenance.source_url,
+                provenance.downloader_version,
+                provenance.converted_at,
+                song.release_date,
+                song.track_number,
+                replaygain.track_gain,
+                replaygain.track_peak,
+                replaygain.album_gain,
+                replaygain.album_peak,
+                song.trim.original_duration,
+                song.trim.start,
+                song.trim.end
             ],
         )?;
+        set_song_artists_and_genres(&tx, id as i64, song)?;
+        remove_unused_names(&tx)?;
+
+        tx.commit()?;
         Ok(())
     }
 
-    /// Returns songs that contains a string in its metadata
+    /// Returns songs matching a search, best matches first.
+    ///
+    /// Words match the start of any word in the title, artists, album or genre. Words in double
+    /// quotes match as a phrase and words prefixed by a field name, such as `artist:yoasobi` or
+    /// `album:"the book"`, only match that field. See `fts_query` for the details.
     pub fn search_song(&self, search_term: &str) -> Result<Vec<Song>> {
-        let query = format!(
-            "SELECT * from songs
-                WHERE song_title LIKE '%{}%' 
-                OR song_artist LIKE '%{}%' 
-                OR song_album LIKE '%{}%'",
-            search_term, search_term, search_term
-        );
-        let mut stmt = self.connection.prepare(&query)?;
-        let song_iter = stmt.query_map([], |row| {
-            Ok(Song::from_database(
-                row.get(0).ok(),
-                row.get(1)?,
-                row.get(2)?,
-                row.get(3).ok(),
-                row.get(4).ok(),
-                row.get(5).ok(),
-                row.get(6).ok(),
-                row.get(7).ok(),
-                row.get(8).ok(),
-            )
-            .unwrap())
-        })?;
-        let song_vec = song_iter.map(|song| song.unwrap()).collect::<Vec<Song>>();
+        let song_vec = self.query_songs(
+            "JOIN songs_fts ON songs_fts.rowid = songs.id
+            WHERE songs_fts MATCH ?1
+            ORDER BY bm25(songs_fts, 4.0, 2.0, 1.0, 0.5), songs.id",
+            params![fts_query(search_term)?],
+        )?;
 
         if song_vec.is_empty() {
             return Err(eyre!("No results were found."));
@@ -243,19 +339,595 @@ impl Database {
 
         Ok(song_vec)
     }
+
+    /// Returns songs matching a query written in the search query language
+    pub fn query(&self, query: &Query) -> Result<Vec<Song>> {
+        let (clause, params) = query.to_sql();
+        let song_vec = self.query_songs(&clause, params_from_iter(params))?;
+
+        if song_vec.is_empty() {
+            return Err(eyre!("No results were found."));
+        }
+
+        Ok(song_vec)
+    }
+
     pub fn remove_song(&self, id: usize) -> Result<()> {
-        let query = format!("DELETE from songs WHERE id = {}", id);
-        self.connection.execute(&query, [])?;
+        let tx = self.connection.unchecked_transaction()?;
+        tx.execute("DELETE from songs WHERE id = ?1", params![id])?;
+        remove_unused_names(&tx)?;
+        tx.commit()?;
 
         Ok(())
     }
 
+    /// Saves a playlist of songs in the given order, replacing any playlist of the same name.
+    /// Returns the ID of the playlist.
+    pub fn save_playlist(
+        &self,
+        name: &str,
+        youtube_id: Option<&str>,
+        song_ids: &[usize],
+    ) -> Result<usize> {
+        let tx = self.connection.unchecked_transaction()?;
+        tx.execute("DELETE FROM playlists WHERE name = ?1", params![name])?;
+        tx.execute(
+            "INSERT INTO playlists (name, youtube_id, created_at) VALUES (?1, ?2, ?3)",
+            params![name, youtube_id, OffsetDateTime::now_utc()],
+        )?;
+        let id = tx.last_insert_rowid();
+        for (position, song_id) in song_ids.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO playlist_songs (playlist_id, song_id, position) VALUES (?1, ?2, ?3)",
+                params![id, song_id, position],
+            )?;
+        }
+        tx.commit()?;
+        Ok(id as usize)
+    }
+
+    /// Returns the songs of a playlist in order
+    pub fn playlist_songs(&self, name: &str) -> Result<Vec<Song>> {
+        let exists = self.connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM playlists WHERE name = ?1)",
+            params![name],
+            |row| row.get::<_, bool>(0),
+        )?;
+        if !exists {
+            return Err(eyre!("There is no playlist named {}", name));
+        }
+
+        self.query_songs(
+            "JOIN playlist_songs ON playlist_songs.song_id = songs.id
+            JOIN playlists ON playlists.id = playlist_songs.playlist_id
+            WHERE playlists.name = ?1
+            ORDER BY playlist_songs.position",
+            params![name],
+        )
+    }
+
+    /// Returns the names of all playlists in the database
+    pub fn list_playlists(&self) -> Result<Vec<String>> {
+        let mut stmt = self
+            .connection
+            .prepare("SELECT name FROM playlists ORDER BY name")?;
+        let playlists = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(playlists)
+    }
+
+    /// Adds a job to the download queue with its tracks. Returns the ID of the new job.
+    pub fn insert_download_job(&self, job: &DownloadJob) -> Result<usize> {
+        let now = OffsetDateTime::now_utc();
+        let tx = self.connection.unchecked_transaction()?;
+        tx.execute(
+            "
+            INSERT INTO download_jobs (youtube_id, file_path, profile, title, artists, album,
+                thumbnail_url, source_bitrate, release_date, track_number, genre, state, error,
+                created_at, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?14)
+            ",
+            params![
+                job.youtube_id,
+                job.file_path.to_str(),
+                job.profile.to_string(),
+                job.title,
+                job.artists.as_ref().map(|artists| artists.join("\n")),
+                job.album,
+                job.thumbnail_url,
+                job.source_bitrate,
+                job.release_date,
+                job.track_number,
+                job.genre,
+                job.state.to_string(),
+                job.error,
+                job.created_at.unwrap_or(now)
+            ],
+        )?;
+        let id = tx.last_insert_rowid();
+        for track in &job.tracks {
+            tx.execute(
+                "
+                INSERT INTO download_job_tracks (job_id, number, title, artists, start_time,
+                    end_time, file_path)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                ",
+                params![
+                    id,
+                    track.number,
+                    track.title,
+                    track.artists.as_ref().map(|artists| artists.join("\n")),
+                    track.start,
+                    track.end,
+                    track.file_path.to_str()
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(id as usize)
+    }
+
+    /// Returns every job in the download queue, oldest first
+    pub fn download_jobs(&self) -> Result<Vec<DownloadJob>> {
+        self.query_download_jobs("ORDER BY id", [])
+    }
+
+    /// Returns the oldest job which still has work to do, leaving out the given jobs
+    pub fn next_pending_download_job(&self, skip: &[usize]) -> Result<Option<DownloadJob>> {
+        let clause = format!(
+            "WHERE state NOT IN ('done', 'failed') AND id NOT IN ({}) ORDER BY id LIMIT 1",
+            vec!["?"; skip.len()].join(", ")
+        );
+        let jobs = self.query_download_jobs(&clause, params_from_iter(skip))?;
+        Ok(jobs.into_iter().next())
+    }
+
+    /// Moves a job to another state. The error is recorded for failed jobs and cleared otherwise.
+    pub fn set_download_job_state(
+        &self,
+        id: usize,
+        state: JobState,
+        error: Option<&str>,
+    ) -> Result<()> {
+        self.connection.execute(
+            "UPDATE download_jobs SET state = ?2, error = ?3, updated_at = ?4 WHERE id = ?1",
+            params![id, state.to_string(), error, OffsetDateTime::now_utc()],
+        )?;
+        Ok(())
+    }
+
+    /// Records the codec a job downloaded and when it converted it
+    pub fn set_download_job_source(
+        &self,
+        id: usize,
+        source_codec: Option<&str>,
+        converted_at: OffsetDateTime,
+    ) -> Result<()> {
+        self.connection.execute(
+            "UPDATE download_jobs SET source_codec = ?2, converted_at = ?3 WHERE id = ?1",
+            params![id, source_codec, converted_at],
+        )?;
+        Ok(())
+    }
+
+    /// Records the song a job added to the library
+    pub fn set_download_job_song(&self, id: usize, song_id: usize) -> Result<()> {
+        self.connection.execute(
+            "UPDATE download_jobs SET song_id = ?2 WHERE id = ?1",
+            params![id, song_id],
+        )?;
+        Ok(())
+    }
+
+    /// Records the song a track of a job added to the library
+    pub fn set_download_job_track_song(
+        &self,
+        id: usize,
+        number: u32,
+        song_id: usize,
+    ) -> Result<()> {
+        self.connection.execute(
+            "UPDATE download_job_tracks SET song_id = ?3 WHERE job_id = ?1 AND number = ?2",
+            params![id, number, song_id],
+        )?;
+        Ok(())
+    }
+
+    /// Puts failed jobs back in the queue. Returns how many there were.
+    pub fn retry_failed_download_jobs(&self) -> Result<usize> {
+        let count = self.connection.execute(
+            "UPDATE download_jobs SET state = 'queued', error = NULL, updated_at = ?1
+            WHERE state = 'failed'",
+            params![OffsetDateTime::now_utc()],
+        )?;
+        Ok(count)
+    }
+
+    /// Removes the jobs in the given states from the queue. Returns how many there were.
+    pub fn remove_download_jobs(&self, states: &[JobState]) -> Result<usize> {
+        let mut count = 0;
+        for state in states {
+            count += self.connection.execute(
+                "DELETE FROM download_jobs WHERE state = ?1",
+                params![state.to_string()],
+            )?;
+        }
+        Ok(count)
+    }
+
+    fn query_download_jobs<P>(&self, clause: &str, params: P) -> Result<Vec<DownloadJob>>
+    where
+        P: Params,
+    {
+        let query = format!(
+            "
+            SELECT id, youtube_id, file_path, title, artists, album, thumbnail_url, state,
+                error, song_id, created_at, updated_at, profile, source_codec, source_bitrate,
+                converted_at, release_date, track_number, genre
+            FROM download_jobs
+            {}
+            ",
+            clause
+        );
+        let mut stmt = self.connection.prepare(&query)?;
+        let rows = stmt
+            .query_map(params, |row| {
+                Ok((
+                    DownloadJob {
+                        id: Some(row.get::<_, i64>(0)? as usize),
+                        youtube_id: row.get(1)?,
+                        file_path: PathBuf::from(row.get::<_, String>(2)?),
+                        profile: Profile::default(),
+                        title: row.get(3)?,
+                        artists: row
+                            .get::<_, Option<String>>(4)?
+                            .map(|artists| artists.split('\n').map(str::to_string).collect()),
+                        album: row.get(5)?,
+                        thumbnail_url: row.get(6)?,
+                        release_date: row.get(16)?,
+                        track_number: row.get(17)?,
+                        genre: row.get(18)?,
+                        tracks: vec![],
+                        source_codec: row.get(13)?,
+                        source_bitrate: row.get(14)?,
+                        converted_at: row.get(15)?,
+                        state: JobState::Queued,
+                        error: row.get(8)?,
+                        song_id: row.get::<_, Option<i64>>(9)?.map(|id| id as usize),
+                        created_at: row.get(10)?,
+                        updated_at: row.get(11)?,
+                    },
+                    row.get::<_, String>(7)?,
+                    row.get::<_, String>(12)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        rows.into_iter()
+            .map(|(mut job, state, profile)| {
+                job.state = state
+                    .parse()
+                    .map_err(|_| eyre!("Download job {:?} has unknown state {}", job.id, state))?;
+                job.profile = profile.parse().map_err(|_| {
+                    eyre!("Download job {:?} has unknown profile {}", job.id, profile)
+                })?;
+                job.tracks = self.download_job_tracks(job.id.unwrap_or_default())?;
+                Ok(job)
+            })
+            .collect()
+    }
+
+    fn download_job_tracks(&self, job_id: usize) -> Result<Vec<Track>> {
+        let mut stmt = self.connection.prepare(
+            "SELECT number, title, artists, start_time, end_time, file_path
+            FROM download_job_tracks WHERE job_id = ?1 ORDER BY number",
+        )?;
+        let tracks = stmt
+            .query_map(params![job_id], |row| {
+                Ok(Track {
+                    number: row.get(0)?,
+                    title: row.get(1)?,
+                    artists: row
+                        .get::<_, Option<String>>(2)?
+                        .map(|artists| artists.split('\n').map(str::to_string).collect()),
+                    start: row.get(3)?,
+                    end: row.get(4)?,
+                    file_path: PathBuf::from(row.get::<_, String>(5)?),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(tracks)
+    }
+
+    /// Runs a song query. `clause` is appended after the FROM clause and may refer to the
+    /// `songs` and `albums` tables.
+    fn query_songs<P>(&self, clause: &str, params: P) -> Result<Vec<Song>>
+    where
+        P: Params,
+    {
+        let query = format!(
+            "
+            SELECT songs.id, songs.song_path, songs.song_filename, songs.song_title,
+                albums.title, songs.song_youtube_id, songs.song_thumbnail_url, songs.date_added,
+                songs.source_codec, songs.source_bitrate, songs.source_url,
+                songs.downloader_version, songs.converted_at, songs.release_date,
+                songs.track_number, songs.replaygain_track_gain, songs.replaygain_track_peak,
+                songs.replaygain_album_gain, songs.replaygain_album_peak,
+                songs.original_duration, songs.trimmed_start, songs.trimmed_end,
+                album_artists.name
+            FROM songs
+            LEFT JOIN albums ON albums.id = songs.album_id
+            LEFT JOIN artists AS album_artists ON album_artists.id = albums.artist_id
+            {}
+            ",
+            clause
+        );
+        let mut stmt = self.connection.prepare(&query)?;
+        let rows = stmt
+            .query_map(params, |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                    row.get::<_, Option<String>>(6)?,
+                    // Tolerate dates written in formats rusqlite no longer reads
+                    row.get::<_, Option<OffsetDateTime>>(7).ok().flatten(),
+                    Provenance {
+                        source_codec: row.get(8)?,
+                        source_bitrate: row.get(9)?,
+                        source_url: row.get(10)?,
+                        downloader_version: row.get(11)?,
+                        converted_at: row.get(12)?,
+                    },
+                    row.get::<_, Option<String>>(13)?,
+                    row.get::<_, Option<u32>>(14)?,
+                    ReplayGain {
+                        track_gain: row.get(15)?,
+                        track_peak: row.get(16)?,
+                        album_gain: row.get(17)?,
+                        album_peak: row.get(18)?,
+                    },
+                    Trim {
+                        original_duration: row.get(19)?,
+                        start: row.get(20)?,
+                        end: row.get(21)?,
+                    },
+                    row.get::<_, Option<String>>(22)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut song_vec = vec![];
+        for (
+            id,
+            path,
+            file_name,
+            title,
+            album,
+            youtube_id,
+            thumbnail_url,
+            date_added,
+            provenance,
+            release_date,
+            track_number,
+            replaygain,
+            trim,
+            album_artist,
+        ) in rows
+        {
+            let artists = self.names_of_song(
+                "SELECT artists.name FROM song_artists
+                JOIN artists ON artists.id = song_artists.artist_id
+                WHERE song_artists.song_id = ?1 ORDER BY song_artists.position",
+                id,
+            )?;
+            // Albums of the first artist need no album artist
+            let first_artist = artists.as_ref().and_then(|artists| artists.first());
+            let album_artist = album_artist.filter(|artist| Some(artist) != first_artist);
+            let genres = self.names_of_song(
+                "SELECT genres.name FROM song_genres
+                JOIN genres ON genres.id = song_genres.genre_id
+                WHERE song_genres.song_id = ?1 ORDER BY song_genres.position",
+                id,
+            )?;
+
+            let mut song = Song::from_database(
+                Some(id as usize),
+                path,
+                file_name,
+                title,
+                artists,
+                album,
+                genres.and_then(|genres| genres.into_iter().next()),
+                youtube_id,
+                thumbnail_url,
+            )?;
+            song.date_added = date_added;
+            song.provenance = provenance;
+            song.release_date = release_date;
+            song.track_number = track_number;
+            song.replaygain = replaygain;
+            song.trim = trim;
+            song.album_artist = album_artist;
+            song_vec.push(song);
+        }
+
+        Ok(song_vec)
+    }
+
+    /// Runs a query returning names related to a song. Returns None if there are none.
+    fn names_of_song(&self, query: &str, song_id: i64) -> Result<Option<Vec<String>>> {
+        let mut stmt = self.connection.prepare_cached(query)?;
+        let names = stmt
+            .query_map(params![song_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(if names.is_empty() { None } else { Some(names) })
+    }
+
     #[allow(dead_code)]
     pub fn database_path(&self) -> PathBuf {
         self.path.to_path_buf()
     }
 }
 
+/// Columns of the full-text search index which can be searched individually
+const FTS_FIELDS: &[&str] = &["title", "artist", "album", "genre"];
+
+/// Quotes text as an FTS5 phrase, optionally matching the last word as a prefix
+pub fn fts_phrase(text: &str, prefix: bool) -> String {
+    let mut phrase = format!("\"{}\"", text.replace('"', "\"\""));
+    if prefix {
+        phrase.push('*');
+    }
+    phrase
+}
+
+/// Turns a search typed by the user into an FTS5 query.
+///
+/// Every term is quoted so user input can't inject FTS5 operators. Bare words become prefix
+/// queries, double-quoted text becomes a phrase query and a trailing `*` after a phrase makes
+/// its last word a prefix. A term may be limited to a column by prefixing it with `field:`.
+/// All terms have to match.
+pub fn fts_query(search_term: &str) -> Result<String> {
+    let mut terms = vec![];
+    let mut chars = search_term.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+
+        // Read an optional field name, e.g. `artist:`
+        let mut word = String::new();
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '"') {
+            word.push(c);
+        }
+        let mut field = None;
+        if let Some((name, rest)) = word.split_once(':') {
+            let name = name.to_lowercase();
+            if FTS_FIELDS.contains(&name.as_str()) {
+                field = Some(name);
+                word = rest.to_string();
+            }
+        }
+
+        let (text, prefix) = if word.is_empty() && chars.next_if_eq(&'"').is_some() {
+            let mut phrase = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => phrase.push(c),
+                    None => return Err(eyre!("Unterminated quote in search: {}", search_term)),
+                }
+            }
+            (phrase, chars.next_if_eq(&'*').is_some())
+        } else if let Some(word) = word.strip_suffix('*') {
+            (word.to_string(), true)
+        } else {
+            (word, true)
+        };
+
+        if text.trim().is_empty() {
+            continue;
+        }
+        let term = fts_phrase(&text, prefix);
+        match field {
+            Some(field) => terms.push(format!("{} : {}", field, term)),
+            None => terms.push(term),
+        }
+    }
+
+    if terms.is_empty() {
+        return Err(eyre!("Search is empty"));
+    }
+    Ok(terms.join(" AND "))
+}
+
+/// Returns the ID of the named row in a lookup table, inserting it if needed
+fn lookup_id(tx: &Transaction, table: &str, column: &str, name: &str) -> Result<i64> {
+    tx.execute(
+        &format!("INSERT OR IGNORE INTO {} ({}) VALUES (?1)", table, column),
+        params![name],
+    )?;
+    let id = tx.query_row(
+        &format!("SELECT id FROM {} WHERE {} = ?1", table, column),
+        params![name],
+        |row| row.get(0),
+    )?;
+    Ok(id)
+}
+
+/// Returns the ID of the song's album, inserting it if needed. Albums are told apart by their
+/// title and `Song::album_artist_or_artist`.
+fn album_id(tx: &Transaction, song: &Song) -> Result<Option<i64>> {
+    let title = match &song.album {
+        Some(title) => title,
+        None => return Ok(None),
+    };
+    let artist_id = song
+        .album_artist_or_artist()
+        .map(|artist| lookup_id(tx, "artists", "name", artist))
+        .transpose()?;
+    tx.execute(
+        "INSERT OR IGNORE INTO albums (title, artist_id) VALUES (?1, ?2)",
+        params![title, artist_id],
+    )?;
+    let id = tx.query_row(
+        "SELECT id FROM albums WHERE title = ?1 AND artist_id IS ?2",
+        params![title, artist_id],
+        |row| row.get(0),
+    )?;
+    Ok(Some(id))
+}
+
+/// Replaces the artists and genres linked to a song
+fn set_song_artists_and_genres(tx: &Transaction, song_id: i64, song: &Song) -> Result<()> {
+    tx.execute(
+        "DELETE FROM song_artists WHERE song_id = ?1",
+        params![song_id],
+    )?;
+    for (position, artist) in song.artists.iter().flatten().enumerate() {
+        let artist_id = lookup_id(tx, "artists", "name", artist)?;
+        tx.execute(
+            "INSERT OR IGNORE INTO song_artists (song_id, artist_id, position) VALUES (?1, ?2, ?3)",
+            params![song_id, artist_id, position],
+        )?;
+    }
+
+    tx.execute(
+        "DELETE FROM song_genres WHERE song_id = ?1",
+        params![song_id],
+    )?;
+    if let Some(genre) = &song.genre {
+        let genre_id = lookup_id(tx, "genres", "name", genre)?;
+        tx.execute(
+            "INSERT INTO song_genres (song_id, genre_id, position) VALUES (?1, ?2, 0)",
+            params![song_id, genre_id],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Removes artists, albums and genres which no longer belong to any song. Artists of albums
+/// are kept with their album.
+fn remove_unused_names(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "
+        DELETE FROM albums WHERE id NOT IN (SELECT album_id FROM songs WHERE album_id IS NOT NULL);
+        DELETE FROM artists WHERE id NOT IN (SELECT artist_id FROM song_artists)
+            AND id NOT IN (SELECT artist_id FROM albums WHERE artist_id IS NOT NULL);
+        DELETE FROM genres WHERE id NOT IN (SELECT genre_id FROM song_genres);
+        ",
+    )?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     // TODO: Write tests for database actions
@@ -283,13 +955,32 @@ mod tests {
             id: None,
             file_path: "test.flac".into(),
             file_name: "test".into(),
-            tag: metaflac::Tag::new(),
             title: Some("test".to_string()),
             artists: Some(vec!["testing_art".to_string()]),
             album: None,
             genre: None,
             youtube_id: None,
             thumbnail_url: None,
+            release_date: Some("2019-12-15".to_string()),
+            track_number: Some(1),
+            provenance: Provenance {
+                source_codec: Some("Opus".to_string()),
+                source_bitrate: Some(135),
+                source_url: Some("https://youtu.be/x8VYWazR5mE".to_string()),
+                downloader_version: Some("youtube-dl 2021.12.17".to_string()),
+                converted_at: Some(OffsetDateTime::from_unix_timestamp(1638352800).unwrap()),
+            },
+            replaygain: ReplayGain {
+                track_gain: Some(-6.48),
+                track_peak: Some(0.988553),
+                album_gain: None,
+                album_peak: None,
+            },
+            trim: Trim {
+                original_duration: Some(180.0),
+                start: Some(12.5),
+                end: Some(0.0),
+            },
             ..Default::default()
         };
 
@@ -315,7 +1006,6 @@ mod tests {
             id: None,
             file_path: "test.flac".into(),
             file_name: "test".into(),
-            tag: metaflac::Tag::new(),
             title: Some("test".to_string()),
             artists: Some(vec!["testing_art".to_string()]),
             album: None,
@@ -333,14 +1023,366 @@ mod tests {
             .unwrap();
 
         let mut new_song = database.query_song_by_name("test").unwrap();
-        let mut new_song = new_song.get_mut(0).unwrap();
+        let new_song = new_song.get_mut(0).unwrap();
 
         new_song.title = Some("test2".to_string());
         database.update_song(new_song).unwrap();
 
         let updated_title = database.query_song_by_name("test2").unwrap();
+        assert_eq!(updated_title.len(), 1);
 
         dbg!(&original_song);
         dbg!(&new_song);
     }
+
+    #[test]
+    fn query_songs_by_artist() {
+        let path = Path::new("/tmp/database_artists.sqlite");
+        if path.exists() {
+            std::fs::remove_file(path).unwrap();
+        }
+        let database = Database::open_from_path(path).unwrap();
+
+        database
+            .insert_song(&Song {
+                file_path: "a.flac".into(),
+                file_name: "a.flac".into(),
+                title: Some("Song A".to_string()),
+                artists: Some(vec![
+                    "YOASOBI".to_string(),
+                    "Artist: With Colon".to_string(),
+                ]),
+                album: Some("THE BOOK".to_string()),
+                genre: Some("J-Pop".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        database
+            .insert_song(&Song {
+                file_path: "b.flac".into(),
+                file_name: "b.flac".into(),
+                title: Some("Song B".to_string()),
+                artists: Some(vec!["YOASOBI".to_string()]),
+                album: Some("Single".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let songs = database.songs_by_artist("YOASOBI").unwrap();
+        assert_eq!(songs.len(), 2);
+        assert_eq!(
+            songs[0].artists,
+            Some(vec![
+                "YOASOBI".to_string(),
+                "Artist: With Colon".to_string()
+            ])
+        );
+        assert_eq!(songs[0].genre.as_deref(), Some("J-Pop"));
+        assert_eq!(
+            database
+                .songs_by_artist("Artist: With Colon")
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(database.songs_by_artist("Nobody").is_err());
+
+        assert_eq!(
+            database.albums_by_artist("YOASOBI").unwrap(),
+            vec!["Single", "THE BOOK"]
+        );
+        assert_eq!(
+            database.list_artists().unwrap(),
+            vec!["Artist: With Colon", "YOASOBI"]
+        );
+
+        // Artists without songs are removed along with the song
+        database.remove_song(songs[0].id.unwrap()).unwrap();
+        assert_eq!(database.list_artists().unwrap(), vec!["YOASOBI"]);
+        assert_eq!(
+            database.albums_by_artist("YOASOBI").unwrap(),
+            vec!["Single"]
+        );
+    }
+
+    #[test]
+    fn build_fts_query() {
+        assert_eq!(fts_query("yoru").unwrap(), "\"yoru\"*");
+        assert_eq!(fts_query("  yoru  ni ").unwrap(), "\"yoru\"* AND \"ni\"*");
+        assert_eq!(fts_query("\"the book\"").unwrap(), "\"the book\"");
+        assert_eq!(fts_query("\"the bo\"*").unwrap(), "\"the bo\"*");
+        assert_eq!(
+            fts_query("Artist:yoasobi album:\"the book\"").unwrap(),
+            "artist : \"yoasobi\"* AND album : \"the book\""
+        );
+        assert_eq!(fts_query("mood:happy").unwrap(), "\"mood:happy\"*");
+        assert_eq!(fts_query("a\"b c\"").unwrap(), "\"a\"* AND \"b c\"");
+        assert_eq!(fts_query("OR NOT").unwrap(), "\"OR\"* AND \"NOT\"*");
+        assert!(fts_query("\"unterminated").is_err());
+        assert!(fts_query("   ").is_err());
+    }
+
+    #[test]
+    fn full_text_search() {
+        let path = Path::new("/tmp/database_fts.sqlite");
+        if path.exists() {
+            std::fs::remove_file(path).unwrap();
+        }
+        let database = Database::open_from_path(path).unwrap();
+
+        for (title, artist, album) in [
+            ("Yoru ni Kakeru", "YOASOBI", "THE BOOK"),
+            ("Racing into the Night", "YOASOBI", "E-SIDE"),
+            ("Night Dancer", "imase", "Night Dancer"),
+        ] {
+            database
+                .insert_song(&Song {
+                    file_path: format!("{}.flac", title).into(),
+                    file_name: format!("{}.flac", title),
+                    title: Some(title.to_string()),
+                    artists: Some(vec![artist.to_string()]),
+                    album: Some(album.to_string()),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        let titles = |search: &str| {
+            database
+                .search_song(search)
+                .map(|songs| {
+                    songs
+                        .into_iter()
+                        .map(|song| song.title.unwrap())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        };
+
+        // Prefix matching
+        assert_eq!(titles("kake"), vec!["Yoru ni Kakeru"]);
+        // Phrase queries
+        assert_eq!(titles("\"into the night\""), vec!["Racing into the Night"]);
+        assert!(titles("\"night into\"").is_empty());
+        // Per-field matching
+        assert_eq!(titles("album:book"), vec!["Yoru ni Kakeru"]);
+        assert_eq!(titles("artist:yoasobi").len(), 2);
+        // Matches in the title rank above matches elsewhere
+        assert_eq!(
+            titles("night"),
+            vec!["Night Dancer", "Racing into the Night"]
+        );
+        // FTS5 syntax in the search is treated as text
+        assert!(titles("yoasobi OR imase").is_empty());
+
+        // The index follows changes to songs and their artists
+        let mut song = database
+            .query_song_by_name("Night Dancer")
+            .unwrap()
+            .remove(0);
+        song.artists = Some(vec!["Someone Else".to_string()]);
+        database.update_song(&song).unwrap();
+        assert_eq!(titles("artist:someone"), vec!["Night Dancer"]);
+        assert!(titles("imase").is_empty());
+        database.remove_song(song.id.unwrap()).unwrap();
+        assert!(titles("dancer").is_empty());
+    }
+
+    #[test]
+    fn query_language() {
+        let path = Path::new("/tmp/database_query.sqlite");
+        if path.exists() {
+            std::fs::remove_file(path).unwrap();
+        }
+        let database = Database::open_from_path(path).unwrap();
+
+        for (title, artist, album, genre, codec) in [
+            ("Yoru ni Kakeru", "YOASOBI", "Unknown", "Pop", Some("Opus")),
+            (
+                "Racing into the Night",
+                "YOASOBI",
+                "E-SIDE",
+                "Rock",
+                Some("FLAC"),
+            ),
+            ("Night Dancer", "imase", "Unknown", "Pop", None),
+            ("100% Night", "Someone", "Unknown", "Rock", Some("MP3")),
+        ] {
+            database
+                .insert_song(&Song {
+                    file_path: format!("{}.flac", title).into(),
+                    file_name: format!("{}.flac", title),
+                    title: Some(title.to_string()),
+                    artists: Some(vec![artist.to_string()]),
+                    album: Some(album.to_string()),
+                    genre: Some(genre.to_string()),
+                    provenance: Provenance {
+                        source_codec: codec.map(str::to_string),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        let titles = |query: &str| {
+            database
+                .query(&Query::parse(query).unwrap())
+                .map(|songs| {
+                    songs
+                        .into_iter()
+                        .map(|song| song.title.unwrap())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        };
+
+        assert_eq!(
+            titles("artist:\"Yoasobi\" album:unknown -genre:rock"),
+            vec!["Yoru ni Kakeru"]
+        );
+        assert_eq!(titles("night -artist:yoasobi").len(), 2);
+        assert_eq!(titles("title:100%"), vec!["100% Night"]);
+        assert_eq!(titles("title:0_"), Vec::<String>::new());
+        assert_eq!(titles("added:>2000-01-01").len(), 4);
+        assert_eq!(titles("added:<2000-01-01").len(), 0);
+        assert_eq!(titles("origin:lossy"), vec!["Yoru ni Kakeru", "100% Night"]);
+        assert_eq!(titles("origin:lossless"), vec!["Racing into the Night"]);
+        assert_eq!(titles("-origin:lossy").len(), 2);
+        assert_eq!(titles("-\"into the\" night artist:yoasobi").len(), 0);
+    }
+
+    #[test]
+    fn download_job_queue() {
+        let path = Path::new("/tmp/database_download_jobs.sqlite");
+        if path.exists() {
+            std::fs::remove_file(path).unwrap();
+        }
+        let database = Database::open_from_path(path).unwrap();
+
+        let mut job = DownloadJob::new("x8VYWazR5mE", "/music/Yoru ni Kakeru.flac".into());
+        job.title = Some("Yoru ni Kakeru".to_string());
+        job.artists = Some(vec!["YOASOBI".to_string(), "Ayase".to_string()]);
+        let first = database.insert_download_job(&job).unwrap();
+        let mut split = DownloadJob::new("dQw4w9WgXcQ", "/music/b.mp3".into());
+        split.tracks = vec![Track {
+            number: 1,
+            title: "Intro".to_string(),
+            artists: Some(vec!["Ayase".to_string()]),
+            start: 0,
+            end: Some(252_500),
+            file_path: "/music/B/01 - Intro.mp3".into(),
+        }];
+        let second = database.insert_download_job(&split).unwrap();
+
+        let jobs = database.download_jobs().unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert!(jobs[0].tracks.is_empty());
+        assert_eq!(jobs[1].tracks, split.tracks);
+        assert_eq!(jobs[0].id, Some(first));
+        assert_eq!(jobs[0].artists, job.artists);
+        assert_eq!(jobs[0].state, JobState::Queued);
+        assert!(jobs[0].created_at.is_some());
+        assert_eq!(jobs[0].profile, Profile::Flac);
+        assert_eq!(jobs[1].profile, Profile::Mp3V0);
+
+        let next = |skip: &[usize]| {
+            database
+                .next_pending_download_job(skip)
+                .unwrap()
+                .and_then(|job| job.id)
+        };
+        assert_eq!(next(&[]), Some(first));
+        assert_eq!(next(&[first]), Some(second));
+        assert_eq!(next(&[first, second]), None);
+        assert_eq!(
+            database
+                .next_pending_download_job(&[first])
+                .unwrap()
+                .unwrap()
+                .tracks,
+            split.tracks
+        );
+
+        database
+            .set_download_job_state(first, JobState::Failed, Some("youtube-dl failed"))
+            .unwrap();
+        database
+            .set_download_job_state(second, JobState::Done, None)
+            .unwrap();
+        assert_eq!(next(&[]), None);
+        assert_eq!(
+            database.download_jobs().unwrap()[0].error.as_deref(),
+            Some("youtube-dl failed")
+        );
+
+        let converted_at = OffsetDateTime::from_unix_timestamp(1638352800).unwrap();
+        database
+            .set_download_job_source(second, Some("Opus"), converted_at)
+            .unwrap();
+        let jobs = database.download_jobs().unwrap();
+        assert_eq!(jobs[1].source_codec.as_deref(), Some("Opus"));
+        assert_eq!(jobs[1].converted_at, Some(converted_at));
+
+        assert_eq!(database.retry_failed_download_jobs().unwrap(), 1);
+        assert_eq!(next(&[]), Some(first));
+        assert_eq!(database.download_jobs().unwrap()[0].error, None);
+
+        assert_eq!(database.remove_download_jobs(&[JobState::Done]).unwrap(), 1);
+        assert_eq!(database.download_jobs().unwrap().len(), 1);
+        let tracks: i64 = database
+            .connection
+            .query_row("SELECT COUNT(*) FROM download_job_tracks", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(tracks, 0);
+    }
+
+    #[test]
+    fn save_playlists() {
+        let path = Path::new("/tmp/database_playlists.sqlite");
+        if path.exists() {
+            std::fs::remove_file(path).unwrap();
+        }
+        let database = Database::open_from_path(path).unwrap();
+        let ids = ["A", "B", "C"]
+            .iter()
+            .map(|title| {
+                database
+                    .insert_song(&Song {
+                        file_path: format!("/music/{}.flac", title).into(),
+                        title: Some(title.to_string()),
+                        ..Default::default()
+                    })
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let titles = |name: &str| {
+            database
+                .playlist_songs(name)
+                .unwrap()
+                .into_iter()
+                .map(|song| song.title.unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        database
+            .save_playlist("Mix", Some("PL123"), &[ids[2], ids[0], ids[2]])
+            .unwrap();
+        assert_eq!(titles("Mix"), vec!["C", "A", "C"]);
+
+        // Saving again replaces the playlist
+        database.save_playlist("Mix", None, &[ids[1]]).unwrap();
+        assert_eq!(titles("Mix"), vec!["B"]);
+        database.save_playlist("Empty", None, &[]).unwrap();
+        assert_eq!(titles("Empty"), Vec::<String>::new());
+        assert_eq!(database.list_playlists().unwrap(), vec!["Empty", "Mix"]);
+        assert!(database.playlist_songs("Missing").is_err());
+
+        // Removed songs leave their playlists
+        database.remove_song(ids[1]).unwrap();
+        assert_eq!(titles("Mix"), Vec::<String>::new());
+    }
 }
@@ -0,0 +1,178 @@
+//! Jobs in the download queue. Each job downloads one video, converts it with its transcoding
+//! profile, tags it and adds it to the library. Jobs are stored in the database so an
+//! interrupted queue can resume.
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use strum_macros::{Display, EnumString, EnumVariantNames};
+use time::OffsetDateTime;
+
+use crate::download::profile::Profile;
+
+/// The stages a job goes through, in order. A job ends up either done or failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, EnumVariantNames, Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum JobState {
+    Queued,
+    Downloading,
+    Converting,
+    /// Cutting silence off the converted files, if `download.trim_silence` is on
+    Trimming,
+    Tagging,
+    Done,
+    Failed,
+}
+
+/// A song cut out of a video holding several, such as a whole album
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    /// Position on the album, from 1
+    pub number: u32,
+    pub title: String,
+    /// Artists of the track, if they differ from the job's
+    pub artists: Option<Vec<String>>,
+    /// Where the track starts in the video, in milliseconds
+    pub start: u64,
+    /// Where the track ends, or None if it lasts until the end of the video
+    pub end: Option<u64>,
+    /// Where the track ends up
+    pub file_path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadJob {
+    pub id: Option<usize>,
+    pub youtube_id: String,
+    /// Where the converted file ends up, or the directory of the tracks if the video is split
+    pub file_path: PathBuf,
+    /// What the download is converted to. The extension of `file_path` matches it.
+    pub profile: Profile,
+    pub title: Option<String>,
+    pub artists: Option<Vec<String>>,
+    pub album: Option<String>,
+    /// The DATE tag, a year or YYYY-MM-DD
+    pub release_date: Option<String>,
+    pub track_number: Option<u32>,
+    pub genre: Option<String>,
+    /// Songs the video is split into, all on the job's album. Jobs without tracks make one
+    /// song.
+    pub tracks: Vec<Track>,
+    pub thumbnail_url: Option<String>,
+    /// Codec of the downloaded audio, found once it is downloaded
+    pub source_codec: Option<String>,
+    /// Bitrate of the video's audio in kbit/s, if the backend tells
+    pub source_bitrate: Option<u32>,
+    pub converted_at: Option<OffsetDateTime>,
+    pub state: JobState,
+    /// Why the job failed
+    pub error: Option<String>,
+    /// The song the job added to the library
+    pub song_id: Option<usize>,
+    pub created_at: Option<OffsetDateTime>,
+    pub updated_at: Option<OffsetDateTime>,
+}
+
+impl DownloadJob {
+    pub fn new(youtube_id: &str, file_path: PathBuf) -> Self {
+        Self {
+            id: None,
+            youtube_id: youtube_id.to_string(),
+            profile: Profile::from_path(&file_path).unwrap_or_default(),
+            file_path,
+            title: None,
+            artists: None,
+            album: None,
+            release_date: None,
+            track_number: None,
+            genre: None,
+            tracks: vec![],
+            thumbnail_url: None,
+            source_codec: None,
+            source_bitrate: None,
+            converted_at: None,
+            state: JobState::Queued,
+            error: None,
+            song_id: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    /// Directory the job works in under the staging directory `staging`. Nothing of the job
+    /// is in the library until its converted and tagged file is moved to `file_path`.
+    pub fn staging_dir(&self, staging: &Path) -> PathBuf {
+        match self.id {
+            Some(id) => staging.join(format!("job-{}", id)),
+            None => staging.join(self.youtube_id.replace(['/', '\\'], "_")),
+        }
+    }
+
+    /// Path of the audio once it is downloaded completely. youtube-dl downloads Opus, other
+    /// backends may save other formats under this name. Downloads are written under another
+    /// name and renamed once they succeed, so a file here is never a partial download.
+    pub fn download_path(&self, staging: &Path) -> PathBuf {
+        self.staging_dir(staging).join("download.opus")
+    }
+
+    /// Path of the converted file until it is tagged and moved into the library. Like the
+    /// download, it is only there once the conversion succeeded.
+    pub fn converted_path(&self, staging: &Path) -> PathBuf {
+        self.staging_dir(staging)
+            .join(format!("converted.{}", self.profile.extension()))
+    }
+
+    /// Path of a track once it is cut out of the download
+    pub fn track_path(&self, staging: &Path, track: &Track) -> PathBuf {
+        self.staging_dir(staging).join(format!(
+            "track-{:02}.{}",
+            track.number,
+            self.profile.extension()
+        ))
+    }
+
+    /// The files the job makes in its staging directory, each with where it goes in the
+    /// library: the converted file, or one file per track
+    pub fn outputs(&self, staging: &Path) -> Vec<(PathBuf, PathBuf)> {
+        match self.tracks.is_empty() {
+            true => vec![(self.converted_path(staging), self.file_path.clone())],
+            false => self
+                .tracks
+                .iter()
+                .map(|track| (self.track_path(staging, track), track.file_path.clone()))
+                .collect(),
+        }
+    }
+
+    /// Where the job's files go in the library: `file_path`, or the path of every track
+    pub fn destinations(&self) -> Vec<PathBuf> {
+        match self.tracks.is_empty() {
+            true => vec![self.file_path.clone()],
+            false => self
+                .tracks
+                .iter()
+                .map(|track| track.file_path.clone())
+                .collect(),
+        }
+    }
+}
+
+impl Display for DownloadJob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match (&self.title, &self.album) {
+            (_, Some(album)) if !self.tracks.is_empty() => album.clone(),
+            (Some(title), _) => title.clone(),
+            _ => self
+                .file_path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| self.youtube_id.clone()),
+        };
+        match self.id {
+            Some(id) => write!(f, "#{} {}", id, name),
+            None => write!(f, "{}", name),
+        }
+    }
+}
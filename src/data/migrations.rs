@@ -0,0 +1,721 @@
+//! Versioned schema migrations for the songs database
+//!
+//! The schema version of a database is stored in `PRAGMA user_version`. Each entry in
+//! `MIGRATIONS` upgrades the schema by exactly one version, so a database at version `n`
+//! has had the first `n` migrations applied. Databases created before versioning was
+//! introduced report version 0.
+//!
+//! Never edit a migration that has been released. Add a new one to the end of the list instead.
+
+use eyre::{eyre, Result};
+use log::info;
+use rusqlite::{Connection, Transaction};
+
+pub struct Migration {
+    pub description: &'static str,
+    pub up: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+/// All migrations, in the order they are applied
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "Create songs table",
+        up: create_songs_table,
+    },
+    Migration {
+        description: "Move artists, albums and genres into their own tables",
+        up: normalize_artists_albums_genres,
+    },
+    Migration {
+        description: "Add full-text search index",
+        up: create_songs_fts,
+    },
+    Migration {
+        description: "Track file size, modification time and hash",
+        up: add_file_state,
+    },
+    Migration {
+        description: "Add download queue",
+        up: create_download_jobs,
+    },
+    Migration {
+        description: "Add playlists",
+        up: create_playlists,
+    },
+    Migration {
+        description: "Record the transcoding profile of download jobs",
+        up: add_download_job_profile,
+    },
+    Migration {
+        description: "Record where the audio of songs came from",
+        up: add_provenance,
+    },
+    Migration {
+        description: "Add release dates, track numbers and the genres of download jobs",
+        up: add_release_details,
+    },
+    Migration {
+        description: "Add ReplayGain values of songs",
+        up: add_replaygain,
+    },
+    Migration {
+        description: "Add tracks of download jobs splitting a video",
+        up: create_download_job_tracks,
+    },
+    Migration {
+        description: "Record silence trimmed from songs",
+        up: add_trim,
+    },
+    Migration {
+        description: "Tell apart albums of the same title by their artist",
+        up: key_albums_by_artist,
+    },
+];
+
+/// The schema version this build of music-manager expects
+pub fn latest_version() -> usize {
+    MIGRATIONS.len()
+}
+
+/// Returns the schema version stored in the database
+pub fn schema_version(conn: &Connection) -> Result<usize> {
+    let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    Ok(version as usize)
+}
+
+/// Upgrades the database to the latest schema version
+pub fn migrate(conn: &mut Connection) -> Result<()> {
+    migrate_to(conn, latest_version())
+}
+
+/// Upgrades the database to the given schema version.
+/// Every migration runs in its own transaction, so a failed migration leaves the
+/// database at the last version that was successfully applied.
+pub fn migrate_to(conn: &mut Connection, target: usize) -> Result<()> {
+    let version = schema_version(conn)?;
+    if version > latest_version() {
+        return Err(eyre!(
+            "Database schema version {} is newer than the latest supported version {}. \
+            Please upgrade music-manager.",
+            version,
+            latest_version()
+        ));
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().take(target).skip(version) {
+        let new_version = index + 1;
+        info!(
+            "Migrating database to version {}: {}",
+            new_version, migration.description
+        );
+        let tx = conn.transaction()?;
+        (migration.up)(&tx).map_err(|e| {
+            eyre!(
+                "Migration to version {} ({}) failed: {}",
+                new_version,
+                migration.description,
+                e
+            )
+        })?;
+        tx.pragma_update(None, "user_version", new_version as i64)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Version 1: the original songs table. Unversioned databases already have it,
+/// hence the `IF NOT EXISTS`.
+fn create_songs_table(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS songs (
+            id                  INTEGER UNIQUE PRIMARY KEY,
+            song_path           nTEXT NOT NULL,
+            song_filename       nTEXT NOT NULL,
+            song_title          nTEXT,
+            song_artist         nTEXT,
+            song_album          nTEXT,
+            song_genre          nTEXT,
+            song_youtube_id     nTEXT,
+            song_thumbnail_url  nTEXT,
+            date_added          DATETIME
+        );
+        ",
+    )
+}
+
+/// Version 2: artists, albums and genres get their own tables. Artists and genres are linked
+/// to songs through join tables, which keep the order they were entered in. The old
+/// `song_artist` column stored artists joined by colons, with "None" for no artist.
+fn normalize_artists_albums_genres(tx: &Transaction) -> rusqlite::Result<()> {
+    let old_rows = {
+        let mut stmt = tx.prepare("SELECT id, song_artist, song_album, song_genre FROM songs")?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows
+    };
+
+    tx.execute_batch(
+        "
+        CREATE TABLE artists (
+            id      INTEGER PRIMARY KEY,
+            name    TEXT NOT NULL UNIQUE
+        );
+        CREATE TABLE albums (
+            id      INTEGER PRIMARY KEY,
+            title   TEXT NOT NULL UNIQUE
+        );
+        CREATE TABLE genres (
+            id      INTEGER PRIMARY KEY,
+            name    TEXT NOT NULL UNIQUE
+        );
+
+        CREATE TABLE songs_new (
+            id                  INTEGER PRIMARY KEY,
+            song_path           TEXT NOT NULL,
+            song_filename       TEXT NOT NULL,
+            song_title          TEXT,
+            album_id            INTEGER REFERENCES albums(id) ON DELETE SET NULL,
+            song_youtube_id     TEXT,
+            song_thumbnail_url  TEXT,
+            date_added          DATETIME
+        );
+        INSERT INTO songs_new (id, song_path, song_filename, song_title, song_youtube_id,
+            song_thumbnail_url, date_added)
+        SELECT id, song_path, song_filename, song_title, song_youtube_id, song_thumbnail_url,
+            date_added
+        FROM songs;
+        DROP TABLE songs;
+        ALTER TABLE songs_new RENAME TO songs;
+
+        CREATE TABLE song_artists (
+            song_id     INTEGER NOT NULL REFERENCES songs(id) ON DELETE CASCADE,
+            artist_id   INTEGER NOT NULL REFERENCES artists(id) ON DELETE CASCADE,
+            position    INTEGER NOT NULL,
+            PRIMARY KEY (song_id, artist_id)
+        );
+        CREATE TABLE song_genres (
+            song_id     INTEGER NOT NULL REFERENCES songs(id) ON DELETE CASCADE,
+            genre_id    INTEGER NOT NULL REFERENCES genres(id) ON DELETE CASCADE,
+            position    INTEGER NOT NULL,
+            PRIMARY KEY (song_id, genre_id)
+        );
+        CREATE INDEX song_artists_artist ON song_artists(artist_id);
+        CREATE INDEX song_genres_genre ON song_genres(genre_id);
+        CREATE INDEX songs_album ON songs(album_id);
+        ",
+    )?;
+
+    for (song_id, artists, album, genre) in old_rows {
+        let artists = artists.unwrap_or_default();
+        let artists = artists
+            .split(':')
+            .filter(|artist| !artist.is_empty() && *artist != "None");
+        for (position, artist) in artists.enumerate() {
+            tx.execute("INSERT OR IGNORE INTO artists (name) VALUES (?1)", [artist])?;
+            tx.execute(
+                "INSERT OR IGNORE INTO song_artists (song_id, artist_id, position)
+                SELECT ?1, id, ?2 FROM artists WHERE name = ?3",
+                rusqlite::params![song_id, position, artist],
+            )?;
+        }
+
+        if let Some(album) = album {
+            tx.execute("INSERT OR IGNORE INTO albums (title) VALUES (?1)", [&album])?;
+            tx.execute(
+                "UPDATE songs SET album_id = (SELECT id FROM albums WHERE title = ?1)
+                WHERE id = ?2",
+                rusqlite::params![album, song_id],
+            )?;
+        }
+
+        if let Some(genre) = genre {
+            tx.execute("INSERT OR IGNORE INTO genres (name) VALUES (?1)", [&genre])?;
+            tx.execute(
+                "INSERT INTO song_genres (song_id, genre_id, position)
+                SELECT ?1, id, 0 FROM genres WHERE name = ?2",
+                rusqlite::params![song_id, genre],
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Version 3: an FTS5 index over song metadata. Rows are keyed by song ID and rebuilt from
+/// the `songs_fts_source` view by triggers whenever a song or anything linked to it changes.
+fn create_songs_fts(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE VIRTUAL TABLE songs_fts USING fts5(
+            title, artist, album, genre,
+            tokenize = 'unicode61 remove_diacritics 2'
+        );
+
+        CREATE VIEW songs_fts_source AS
+        SELECT
+            songs.id AS id,
+            songs.song_title AS title,
+            (SELECT group_concat(name, ' ') FROM (
+                SELECT artists.name AS name FROM song_artists
+                JOIN artists ON artists.id = song_artists.artist_id
+                WHERE song_artists.song_id = songs.id
+                ORDER BY song_artists.position
+            )) AS artist,
+            albums.title AS album,
+            (SELECT group_concat(name, ' ') FROM (
+                SELECT genres.name AS name FROM song_genres
+                JOIN genres ON genres.id = song_genres.genre_id
+                WHERE song_genres.song_id = songs.id
+                ORDER BY song_genres.position
+            )) AS genre
+        FROM songs
+        LEFT JOIN albums ON albums.id = songs.album_id;
+
+        INSERT INTO songs_fts (rowid, title, artist, album, genre)
+        SELECT id, title, artist, album, genre FROM songs_fts_source;
+
+        CREATE TRIGGER songs_fts_song_insert AFTER INSERT ON songs BEGIN
+            INSERT INTO songs_fts (rowid, title, artist, album, genre)
+            SELECT id, title, artist, album, genre FROM songs_fts_source WHERE id = new.id;
+        END;
+        CREATE TRIGGER songs_fts_song_update AFTER UPDATE ON songs BEGIN
+            DELETE FROM songs_fts WHERE rowid = old.id;
+            INSERT INTO songs_fts (rowid, title, artist, album, genre)
+            SELECT id, title, artist, album, genre FROM songs_fts_source WHERE id = new.id;
+        END;
+        CREATE TRIGGER songs_fts_song_delete AFTER DELETE ON songs BEGIN
+            DELETE FROM songs_fts WHERE rowid = old.id;
+        END;
+
+        CREATE TRIGGER songs_fts_artist_link AFTER INSERT ON song_artists BEGIN
+            DELETE FROM songs_fts WHERE rowid = new.song_id;
+            INSERT INTO songs_fts (rowid, title, artist, album, genre)
+            SELECT id, title, artist, album, genre FROM songs_fts_source WHERE id = new.song_id;
+        END;
+        CREATE TRIGGER songs_fts_artist_unlink AFTER DELETE ON song_artists BEGIN
+            DELETE FROM songs_fts WHERE rowid = old.song_id;
+            INSERT INTO songs_fts (rowid, title, artist, album, genre)
+            SELECT id, title, artist, album, genre FROM songs_fts_source WHERE id = old.song_id;
+        END;
+        CREATE TRIGGER songs_fts_genre_link AFTER INSERT ON song_genres BEGIN
+            DELETE FROM songs_fts WHERE rowid = new.song_id;
+            INSERT INTO songs_fts (rowid, title, artist, album, genre)
+            SELECT id, title, artist, album, genre FROM songs_fts_source WHERE id = new.song_id;
+        END;
+        CREATE TRIGGER songs_fts_genre_unlink AFTER DELETE ON song_genres BEGIN
+            DELETE FROM songs_fts WHERE rowid = old.song_id;
+            INSERT INTO songs_fts (rowid, title, artist, album, genre)
+            SELECT id, title, artist, album, genre FROM songs_fts_source WHERE id = old.song_id;
+        END;
+
+        CREATE TRIGGER songs_fts_artist_rename AFTER UPDATE ON artists BEGIN
+            DELETE FROM songs_fts WHERE rowid IN
+                (SELECT song_id FROM song_artists WHERE artist_id = new.id);
+            INSERT INTO songs_fts (rowid, title, artist, album, genre)
+            SELECT id, title, artist, album, genre FROM songs_fts_source WHERE id IN
+                (SELECT song_id FROM song_artists WHERE artist_id = new.id);
+        END;
+        CREATE TRIGGER songs_fts_album_rename AFTER UPDATE ON albums BEGIN
+            DELETE FROM songs_fts WHERE rowid IN (SELECT id FROM songs WHERE album_id = new.id);
+            INSERT INTO songs_fts (rowid, title, artist, album, genre)
+            SELECT id, title, artist, album, genre FROM songs_fts_source WHERE id IN
+                (SELECT id FROM songs WHERE album_id = new.id);
+        END;
+        CREATE TRIGGER songs_fts_genre_rename AFTER UPDATE ON genres BEGIN
+            DELETE FROM songs_fts WHERE rowid IN
+                (SELECT song_id FROM song_genres WHERE genre_id = new.id);
+            INSERT INTO songs_fts (rowid, title, artist, album, genre)
+            SELECT id, title, artist, album, genre FROM songs_fts_source WHERE id IN
+                (SELECT song_id FROM song_genres WHERE genre_id = new.id);
+        END;
+        ",
+    )
+}
+
+/// Version 4: the size, modification time and content hash of each file when it was last
+/// scanned, so `scan` can tell which files changed
+fn add_file_state(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        ALTER TABLE songs ADD COLUMN file_size INTEGER;
+        ALTER TABLE songs ADD COLUMN file_modified INTEGER;
+        ALTER TABLE songs ADD COLUMN file_hash TEXT;
+        CREATE INDEX songs_path ON songs(song_path);
+        ",
+    )
+}
+
+/// Version 5: the download queue. Artists are joined by newlines.
+fn create_download_jobs(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE download_jobs (
+            id              INTEGER PRIMARY KEY,
+            youtube_id      TEXT NOT NULL,
+            file_path       TEXT NOT NULL,
+            title           TEXT,
+            artists         TEXT,
+            album           TEXT,
+            thumbnail_url   TEXT,
+            state           TEXT NOT NULL DEFAULT 'queued',
+            error           TEXT,
+            song_id         INTEGER REFERENCES songs(id) ON DELETE SET NULL,
+            created_at      DATETIME NOT NULL,
+            updated_at      DATETIME NOT NULL
+        );
+        CREATE INDEX download_jobs_state ON download_jobs(state);
+        ",
+    )
+}
+
+/// Version 6: playlists. A song may appear more than once, so entries are keyed by position.
+fn create_playlists(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE playlists (
+            id          INTEGER PRIMARY KEY,
+            name        TEXT NOT NULL UNIQUE,
+            youtube_id  TEXT,
+            created_at  DATETIME NOT NULL
+        );
+        CREATE TABLE playlist_songs (
+            playlist_id INTEGER NOT NULL REFERENCES playlists(id) ON DELETE CASCADE,
+            song_id     INTEGER NOT NULL REFERENCES songs(id) ON DELETE CASCADE,
+            position    INTEGER NOT NULL,
+            PRIMARY KEY (playlist_id, position)
+        );
+        CREATE INDEX playlist_songs_song ON playlist_songs(song_id);
+        ",
+    )
+}
+
+/// Version 7: the transcoding profile of each download job. Earlier jobs all made FLAC files.
+fn add_download_job_profile(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE download_jobs ADD COLUMN profile TEXT NOT NULL DEFAULT 'flac';")
+}
+
+/// Version 8: the provenance of songs, and what jobs learn of it before their song exists.
+/// Bitrates are in kbit/s.
+fn add_provenance(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        ALTER TABLE songs ADD COLUMN source_codec TEXT;
+        ALTER TABLE songs ADD COLUMN source_bitrate INTEGER;
+        ALTER TABLE songs ADD COLUMN source_url TEXT;
+        ALTER TABLE songs ADD COLUMN downloader_version TEXT;
+        ALTER TABLE songs ADD COLUMN converted_at DATETIME;
+        ALTER TABLE download_jobs ADD COLUMN source_codec TEXT;
+        ALTER TABLE download_jobs ADD COLUMN source_bitrate INTEGER;
+        ALTER TABLE download_jobs ADD COLUMN converted_at DATETIME;
+        ",
+    )
+}
+
+/// Version 9: release dates and track numbers of songs, and the tags download jobs learn from
+/// youtube-dl. Dates are kept as written in the DATE tag, a year or YYYY-MM-DD.
+fn add_release_details(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        ALTER TABLE songs ADD COLUMN release_date TEXT;
+        ALTER TABLE songs ADD COLUMN track_number INTEGER;
+        ALTER TABLE download_jobs ADD COLUMN release_date TEXT;
+        ALTER TABLE download_jobs ADD COLUMN track_number INTEGER;
+        ALTER TABLE download_jobs ADD COLUMN genre TEXT;
+        ",
+    )
+}
+
+/// Version 10: ReplayGain gains in dB and peaks as a fraction of full scale
+fn add_replaygain(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        ALTER TABLE songs ADD COLUMN replaygain_track_gain REAL;
+        ALTER TABLE songs ADD COLUMN replaygain_track_peak REAL;
+        ALTER TABLE songs ADD COLUMN replaygain_album_gain REAL;
+        ALTER TABLE songs ADD COLUMN replaygain_album_peak REAL;
+        ",
+    )
+}
+
+/// Version 11: the tracks a download job splits its video into. Times are in milliseconds.
+fn create_download_job_tracks(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE download_job_tracks (
+            job_id      INTEGER NOT NULL REFERENCES download_jobs(id) ON DELETE CASCADE,
+            number      INTEGER NOT NULL,
+            title       TEXT NOT NULL,
+            artists     TEXT,
+            start_time  INTEGER NOT NULL,
+            end_time    INTEGER,
+            file_path   TEXT NOT NULL,
+            song_id     INTEGER REFERENCES songs(id) ON DELETE SET NULL,
+            PRIMARY KEY (job_id, number)
+        );
+        ",
+    )
+}
+
+/// Version 12: the length of trimmed songs before trimming and what was cut, in seconds
+fn add_trim(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        ALTER TABLE songs ADD COLUMN original_duration REAL;
+        ALTER TABLE songs ADD COLUMN trimmed_start REAL;
+        ALTER TABLE songs ADD COLUMN trimmed_end REAL;
+        ",
+    )
+}
+
+/// Version 13: albums belong to an artist, so albums of the same title by different artists
+/// are rows of their own. Existing albums are split by the first artist of their songs. Songs
+/// let go of their albums before the old table is dropped, as the foreign key would otherwise
+/// update them, and their search rows, in the middle of the drop. The search trigger on albums
+/// is dropped with the old table and made again.
+fn key_albums_by_artist(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TEMP TABLE album_keys AS
+        SELECT songs.id AS song_id, albums.title AS title,
+            (SELECT artist_id FROM song_artists WHERE song_artists.song_id = songs.id
+                ORDER BY position LIMIT 1) AS artist_id
+        FROM songs
+        JOIN albums ON albums.id = songs.album_id;
+
+        UPDATE songs SET album_id = NULL WHERE album_id IS NOT NULL;
+        DROP TABLE albums;
+        CREATE TABLE albums (
+            id          INTEGER PRIMARY KEY,
+            title       TEXT NOT NULL,
+            artist_id   INTEGER REFERENCES artists(id) ON DELETE SET NULL
+        );
+        CREATE UNIQUE INDEX albums_title_artist ON albums(title, IFNULL(artist_id, 0));
+
+        CREATE TRIGGER songs_fts_album_rename AFTER UPDATE ON albums BEGIN
+            DELETE FROM songs_fts WHERE rowid IN (SELECT id FROM songs WHERE album_id = new.id);
+            INSERT INTO songs_fts (rowid, title, artist, album, genre)
+            SELECT id, title, artist, album, genre FROM songs_fts_source WHERE id IN
+                (SELECT id FROM songs WHERE album_id = new.id);
+        END;
+
+        INSERT OR IGNORE INTO albums (title, artist_id)
+        SELECT title, artist_id FROM album_keys ORDER BY song_id;
+        UPDATE songs SET album_id = (
+            SELECT albums.id FROM album_keys
+            JOIN albums ON albums.title = album_keys.title
+                AND albums.artist_id IS album_keys.artist_id
+            WHERE album_keys.song_id = songs.id
+        );
+        DROP TABLE album_keys;
+        ",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::data::database::Database;
+
+    /// The schema used before migrations were introduced
+    const LEGACY_SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS songs (
+            id                  INTEGER UNIQUE PRIMARY KEY,
+            song_path           nTEXT NOT NULL,
+            song_filename       nTEXT NOT NULL,
+            song_title          nTEXT,
+            song_artist         nTEXT,
+            song_album          nTEXT,
+            song_genre          nTEXT,
+            song_youtube_id     nTEXT,
+            song_thumbnail_url  nTEXT,
+            date_added          DATETIME
+        )
+    ";
+
+    fn fresh_path(name: &str) -> PathBuf {
+        let path = Path::new("/tmp").join(name);
+        if path.exists() {
+            std::fs::remove_file(&path).unwrap();
+        }
+        path
+    }
+
+    /// Creates a database at the given schema version holding one song,
+    /// using the SQL that was valid for that version
+    fn create_fixture(path: &Path, version: usize) {
+        let mut conn = Connection::open(path).unwrap();
+        match version {
+            0 => conn.execute_batch(LEGACY_SCHEMA).unwrap(),
+            _ => migrate_to(&mut conn, version).unwrap(),
+        }
+        assert_eq!(schema_version(&conn).unwrap(), version);
+
+        match version {
+            0 | 1 => {
+                conn.execute(
+                    "INSERT INTO songs (song_path, song_filename, song_title, song_artist,
+                        song_album, song_youtube_id, date_added)
+                    VALUES ('/music/fixture.flac', 'fixture.flac', 'Fixture', 'Fixture Artist:',
+                        'Fixture Album', 'dQw4w9WgXcQ', '2021-12-01 10:00:00.0Z')",
+                    [],
+                )
+                .unwrap();
+            }
+            2..=12 => {
+                conn.execute_batch(
+                    "INSERT INTO albums (id, title) VALUES (1, 'Fixture Album');
+                    INSERT INTO artists (id, name) VALUES (1, 'Fixture Artist');
+                    INSERT INTO songs (id, song_path, song_filename, song_title, album_id,
+                        song_youtube_id, date_added)
+                    VALUES (1, '/music/fixture.flac', 'fixture.flac', 'Fixture', 1,
+                        'dQw4w9WgXcQ', '2021-12-01 10:00:00.0Z');
+                    INSERT INTO song_artists (song_id, artist_id, position) VALUES (1, 1, 0);",
+                )
+                .unwrap();
+            }
+            13 => {
+                conn.execute_batch(
+                    "INSERT INTO artists (id, name) VALUES (1, 'Fixture Artist');
+                    INSERT INTO albums (id, title, artist_id) VALUES (1, 'Fixture Album', 1);
+                    INSERT INTO songs (id, song_path, song_filename, song_title, album_id,
+                        song_youtube_id, date_added)
+                    VALUES (1, '/music/fixture.flac', 'fixture.flac', 'Fixture', 1,
+                        'dQw4w9WgXcQ', '2021-12-01 10:00:00.0Z');
+                    INSERT INTO song_artists (song_id, artist_id, position) VALUES (1, 1, 0);",
+                )
+                .unwrap();
+            }
+            _ => unreachable!("No fixture for schema version {}", version),
+        }
+    }
+
+    #[test]
+    fn migrate_fixtures_from_every_version() {
+        for version in 0..=latest_version() {
+            let path = fresh_path(&format!("migration_fixture_v{}.sqlite", version));
+            create_fixture(&path, version);
+
+            let database = Database::open_from_path(&path).unwrap();
+            let conn = Connection::open(&path).unwrap();
+            assert_eq!(schema_version(&conn).unwrap(), latest_version());
+
+            let songs = database.query_all_song_data().unwrap();
+            assert_eq!(songs.len(), 1, "schema version {}", version);
+            let song = songs.first().unwrap();
+            assert_eq!(song.title.as_deref(), Some("Fixture"));
+            assert_eq!(song.artists, Some(vec!["Fixture Artist".to_string()]));
+            assert_eq!(song.album.as_deref(), Some("Fixture Album"));
+            assert_eq!(song.youtube_id.as_deref(), Some("dQw4w9WgXcQ"));
+            assert_eq!(song.file_path, Path::new("/music/fixture.flac"));
+            assert_eq!(database.search_song("fixture artist").unwrap().len(), 1);
+        }
+    }
+
+    #[test]
+    fn split_legacy_artists() {
+        let path = fresh_path("migration_legacy_artists.sqlite");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(LEGACY_SCHEMA).unwrap();
+            conn.execute_batch(
+                "INSERT INTO songs (song_path, song_filename, song_title, song_artist, song_genre)
+                VALUES ('/music/a.flac', 'a.flac', 'A', 'First:Second:', 'Pop');
+                INSERT INTO songs (song_path, song_filename, song_title, song_artist)
+                VALUES ('/music/b.flac', 'b.flac', 'B', 'None');
+                INSERT INTO songs (song_path, song_filename, song_title, song_artist)
+                VALUES ('/music/c.flac', 'c.flac', 'C', 'Second:');",
+            )
+            .unwrap();
+        }
+
+        let database = Database::open_from_path(&path).unwrap();
+        let songs = database.query_all_song_data().unwrap();
+        assert_eq!(
+            songs[0].artists,
+            Some(vec!["First".to_string(), "Second".to_string()])
+        );
+        assert_eq!(songs[0].genre.as_deref(), Some("Pop"));
+        assert_eq!(songs[1].artists, None);
+        assert_eq!(songs[2].artists, Some(vec!["Second".to_string()]));
+        assert_eq!(database.list_artists().unwrap(), vec!["First", "Second"]);
+    }
+
+    #[test]
+    fn split_albums_by_artist() {
+        let path = fresh_path("migration_albums_by_artist.sqlite");
+        {
+            let mut conn = Connection::open(&path).unwrap();
+            migrate_to(&mut conn, 12).unwrap();
+            conn.execute_batch(
+                "INSERT INTO albums (id, title) VALUES (1, 'Greatest Hits');
+                INSERT INTO artists (id, name) VALUES (1, 'Queen'), (2, 'ABBA');
+                INSERT INTO songs (id, song_path, song_filename, song_title, album_id)
+                VALUES (1, '/music/a.flac', 'a.flac', 'Bohemian Rhapsody', 1),
+                    (2, '/music/b.flac', 'b.flac', 'Dancing Queen', 1),
+                    (3, '/music/c.flac', 'c.flac', 'Killer Queen', 1);
+                INSERT INTO song_artists (song_id, artist_id, position)
+                VALUES (1, 1, 0), (2, 2, 0), (3, 1, 0);",
+            )
+            .unwrap();
+        }
+
+        let database = Database::open_from_path(&path).unwrap();
+        let conn = Connection::open(&path).unwrap();
+        let albums = conn
+            .prepare("SELECT album_id FROM songs ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<i64>>>()
+            .unwrap();
+        assert_eq!(albums[0], albums[2]);
+        assert_ne!(albums[0], albums[1]);
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM albums", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+
+        assert_eq!(
+            database.albums_by_artist("ABBA").unwrap(),
+            vec!["Greatest Hits"]
+        );
+        let songs = database.search_song("album:greatest").unwrap();
+        assert_eq!(songs.len(), 3);
+        assert!(songs
+            .iter()
+            .all(|song| song.album.as_deref() == Some("Greatest Hits")));
+    }
+
+    #[test]
+    fn migrate_is_idempotent() {
+        let path = fresh_path("migration_idempotent.sqlite");
+        let mut conn = Connection::open(&path).unwrap();
+        migrate(&mut conn).unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn refuse_newer_database() {
+        let path = fresh_path("migration_newer.sqlite");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.pragma_update(None, "user_version", latest_version() as i64 + 1)
+                .unwrap();
+        }
+
+        assert!(Database::open_from_path(&path).is_err());
+    }
+}
@@ -1,3 +1,10 @@
 //! This module deals with persistent data store on the filesystem
 pub mod database;
+pub mod job;
+pub mod migrations;
+pub mod provenance;
+pub mod query;
+pub mod replaygain;
 pub mod song;
+pub mod tag;
+pub mod trim;
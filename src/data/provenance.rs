@@ -0,0 +1,144 @@
+//! Where the audio of a song came from. Downloaded songs are usually transcoded from lossy
+//! audio, such as the Opus YouTube serves, whatever format they are saved in. The provenance
+//! is kept in the database and in the file's tags, so `scan` can recover it.
+
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use super::tag::AudioTag;
+
+/// Codecs which don't lose anything, named as `AudioFormat` displays them or as ffprobe
+/// reports them. Any other codec counts as lossy.
+const LOSSLESS_CODECS: &[&str] = &["FLAC", "ALAC", "PCM", "WAV", "WavPack", "APE"];
+
+const SOURCE_CODEC: &str = "SOURCE_CODEC";
+const SOURCE_BITRATE: &str = "SOURCE_BITRATE";
+const SOURCE_URL: &str = "SOURCE_URL";
+const DOWNLOADER_VERSION: &str = "DOWNLOADER_VERSION";
+const CONVERSION_TIME: &str = "CONVERSION_TIME";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Provenance {
+    /// Codec of the downloaded audio, such as Opus
+    pub source_codec: Option<String>,
+    /// Bitrate of the downloaded audio in kbit/s
+    pub source_bitrate: Option<u32>,
+    pub source_url: Option<String>,
+    /// Name and version of the program the audio was downloaded with
+    pub downloader_version: Option<String>,
+    /// When the downloaded audio was converted to the file's format
+    pub converted_at: Option<OffsetDateTime>,
+}
+
+/// Whether audio in this codec has been through lossy compression
+pub fn is_lossy_codec(codec: &str) -> bool {
+    !LOSSLESS_CODECS
+        .iter()
+        .any(|lossless| lossless.eq_ignore_ascii_case(codec))
+}
+
+/// The SQL condition matching songs whose source codec is lossy
+pub fn lossy_origin_condition() -> String {
+    let lossless = LOSSLESS_CODECS
+        .iter()
+        .map(|codec| format!("'{}'", codec.to_lowercase()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("lower(songs.source_codec) NOT IN ({})", lossless)
+}
+
+impl Provenance {
+    pub fn is_empty(&self) -> bool {
+        *self == Provenance::default()
+    }
+
+    /// Whether the song was made from lossy audio. Songs of unknown origin are not.
+    pub fn is_lossy(&self) -> bool {
+        self.source_codec.as_deref().is_some_and(is_lossy_codec)
+    }
+
+    pub fn read(tag: &dyn AudioTag) -> Self {
+        let field = |key: &str| tag.get(key).and_then(|values| values.into_iter().next());
+        Self {
+            source_codec: field(SOURCE_CODEC),
+            source_bitrate: field(SOURCE_BITRATE).and_then(|bitrate| bitrate.parse().ok()),
+            source_url: field(SOURCE_URL),
+            downloader_version: field(DOWNLOADER_VERSION),
+            converted_at: field(CONVERSION_TIME)
+                .and_then(|time| OffsetDateTime::parse(&time, &Rfc3339).ok()),
+        }
+    }
+
+    /// Writes the known fields to the tag, leaving the others as they are
+    pub fn write(&self, tag: &mut dyn AudioTag) {
+        let fields = [
+            (SOURCE_CODEC, self.source_codec.clone()),
+            (
+                SOURCE_BITRATE,
+                self.source_bitrate.map(|bitrate| bitrate.to_string()),
+            ),
+            (SOURCE_URL, self.source_url.clone()),
+            (DOWNLOADER_VERSION, self.downloader_version.clone()),
+            (
+                CONVERSION_TIME,
+                self.converted_at
+                    .and_then(|time| time.format(&Rfc3339).ok()),
+            ),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                tag.set(key, vec![value]);
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Provenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source_codec.as_deref().unwrap_or("Unknown"))?;
+        if let Some(bitrate) = self.source_bitrate {
+            write!(f, " {} kbit/s", bitrate)?;
+        }
+        if let Some(url) = &self.source_url {
+            write!(f, " from {}", url)?;
+        }
+        if let Some(version) = &self.downloader_version {
+            write!(f, " with {}", version)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lossy_codecs() {
+        assert!(is_lossy_codec("Opus"));
+        assert!(is_lossy_codec("mp3"));
+        assert!(!is_lossy_codec("flac"));
+        assert!(!Provenance::default().is_lossy());
+    }
+
+    #[test]
+    fn tag_round_trip() {
+        let provenance = Provenance {
+            source_codec: Some("Opus".to_string()),
+            source_bitrate: Some(160),
+            source_url: Some("https://youtu.be/x8VYWazR5mE".to_string()),
+            downloader_version: Some("yt-dlp 2021.12.01".to_string()),
+            converted_at: Some(OffsetDateTime::from_unix_timestamp(1638352800).unwrap()),
+        };
+        let mut tag = metaflac::Tag::new();
+        provenance.write(&mut tag);
+        assert_eq!(
+            tag.get_vorbis("CONVERSION_TIME").unwrap().next(),
+            Some("2021-12-01T10:00:00Z")
+        );
+        assert_eq!(Provenance::read(&tag), provenance);
+        assert_eq!(
+            provenance.to_string(),
+            "Opus 160 kbit/s from https://youtu.be/x8VYWazR5mE with yt-dlp 2021.12.01"
+        );
+    }
+}
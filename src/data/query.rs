@@ -0,0 +1,544 @@
+//! The query language used by the `search` subcommand
+//!
+//! A query is a list of terms separated by whitespace, all of which have to match:
+//!
+//! ```text
+//! artist:"Yoasobi" album:unknown added:>2021-12-01 -genre:pop night
+//! ```
+//!
+//! - `night` or `"into the night"` matches words or phrases anywhere through the full-text index
+//! - `field:value` matches songs whose field contains the value, ignoring case. Fields are
+//!   `title`, `artist`, `album`, `genre` and `path`. `youtube:` matches a YouTube ID exactly
+//! - `added:2021-12-01` matches songs added on that day (UTC). The date can be prefixed by
+//!   `>`, `>=`, `<` or `<=`
+//! - `origin:lossy` matches songs made from lossy audio, such as downloads transcoded to FLAC,
+//!   and `origin:lossless` songs made from lossless audio. Songs of unknown origin match neither
+//! - A term prefixed by `-` matches songs the term does not match
+//!
+//! Values containing whitespace are written in double quotes.
+
+use std::fmt::Display;
+
+use rusqlite::types::Value;
+use time::{Date, Month};
+
+use super::{database::fts_phrase, provenance::lossy_origin_condition};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    pub terms: Vec<Term>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Term {
+    pub negated: bool,
+    pub filter: Filter,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    /// Free text matched through the full-text index. Phrases are matched exactly, words by prefix
+    Text { text: String, phrase: bool },
+    /// Matches a metadata field
+    Field(Field, String),
+    /// Compares the date the song was added to the library
+    Added(Comparison, Date),
+    /// Matches songs made from lossy audio when true, or from lossless audio when false
+    Origin { lossy: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Title,
+    Artist,
+    Album,
+    Genre,
+    Path,
+    YoutubeId,
+}
+
+impl Field {
+    const NAMES: &'static [(&'static str, Field)] = &[
+        ("title", Field::Title),
+        ("artist", Field::Artist),
+        ("album", Field::Album),
+        ("genre", Field::Genre),
+        ("path", Field::Path),
+        ("youtube", Field::YoutubeId),
+    ];
+
+    fn from_name(name: &str) -> Option<Field> {
+        let name = name.to_lowercase();
+        Field::NAMES
+            .iter()
+            .find(|(field_name, _)| *field_name == name)
+            .map(|(_, field)| *field)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Comparison {
+    fn operator(&self) -> &'static str {
+        match self {
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Equal => "=",
+            Comparison::GreaterOrEqual => ">=",
+            Comparison::Greater => ">",
+        }
+    }
+}
+
+/// An error in a query, with the character position it was found at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (at column {})", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Query {
+    pub fn parse(input: &str) -> Result<Query, ParseError> {
+        Parser {
+            chars: input.chars().collect(),
+            position: 0,
+        }
+        .parse()
+    }
+
+    /// Compiles the query into a clause for `Database::query_songs`, along with its parameters.
+    /// Every value is passed as a parameter, never formatted into the SQL.
+    pub fn to_sql(&self) -> (String, Vec<Value>) {
+        let mut params = vec![];
+        let mut clause = String::new();
+
+        // Words and phrases which have to match are searched in one go, so results can be
+        // ordered by relevance
+        let text_match = self
+            .terms
+            .iter()
+            .filter(|term| !term.negated)
+            .filter_map(|term| term.filter.fts_phrase())
+            .collect::<Vec<String>>();
+        let ranked = !text_match.is_empty();
+        if ranked {
+            clause.push_str(
+                "JOIN (
+                    SELECT rowid AS fts_id, bm25(songs_fts, 4.0, 2.0, 1.0, 0.5) AS fts_rank
+                    FROM songs_fts WHERE songs_fts MATCH ?
+                ) AS fts ON fts.fts_id = songs.id\n",
+            );
+            params.push(Value::Text(text_match.join(" AND ")));
+        }
+
+        let mut conditions = vec![];
+        for term in &self.terms {
+            if !term.negated && matches!(term.filter, Filter::Text { .. }) {
+                continue;
+            }
+            let condition = term.filter.condition(&mut params);
+            // NULL fields never match, and so always match when negated
+            conditions.push(match term.negated {
+                true => format!("NOT COALESCE(({}), 0)", condition),
+                false => format!("COALESCE(({}), 0)", condition),
+            });
+        }
+        if !conditions.is_empty() {
+            clause.push_str("WHERE ");
+            clause.push_str(&conditions.join("\nAND "));
+            clause.push('\n');
+        }
+
+        clause.push_str(match ranked {
+            true => "ORDER BY fts.fts_rank, songs.id",
+            false => "ORDER BY songs.id",
+        });
+
+        (clause, params)
+    }
+}
+
+impl Filter {
+    fn fts_phrase(&self) -> Option<String> {
+        match self {
+            Filter::Text { text, phrase } => Some(fts_phrase(text, !phrase)),
+            _ => None,
+        }
+    }
+
+    /// Returns the SQL condition for the filter, pushing its parameters
+    fn condition(&self, params: &mut Vec<Value>) -> String {
+        match self {
+            Filter::Text { .. } => {
+                params.push(Value::Text(self.fts_phrase().unwrap()));
+                "songs.id IN (SELECT rowid FROM songs_fts WHERE songs_fts MATCH ?)".to_string()
+            }
+            Filter::Field(Field::YoutubeId, value) => {
+                params.push(Value::Text(value.clone()));
+                "songs.song_youtube_id = ?".to_string()
+            }
+            Filter::Field(field, value) => {
+                params.push(Value::Text(like_pattern(value)));
+                match field {
+                    Field::Title => "songs.song_title LIKE ? ESCAPE '\\'",
+                    Field::Album => "albums.title LIKE ? ESCAPE '\\'",
+                    Field::Path => "songs.song_path LIKE ? ESCAPE '\\'",
+                    Field::Artist => {
+                        "EXISTS (
+                            SELECT 1 FROM song_artists
+                            JOIN artists ON artists.id = song_artists.artist_id
+                            WHERE song_artists.song_id = songs.id
+                                AND artists.name LIKE ? ESCAPE '\\'
+                        )"
+                    }
+                    Field::Genre => {
+                        "EXISTS (
+                            SELECT 1 FROM song_genres
+                            JOIN genres ON genres.id = song_genres.genre_id
+                            WHERE song_genres.song_id = songs.id
+                                AND genres.name LIKE ? ESCAPE '\\'
+                        )"
+                    }
+                    Field::YoutubeId => unreachable!(),
+                }
+                .to_string()
+            }
+            Filter::Added(comparison, date) => {
+                params.push(Value::Text(format!(
+                    "{:04}-{:02}-{:02}",
+                    date.year(),
+                    date.month() as u8,
+                    date.day()
+                )));
+                // Dates are stored as text starting with YYYY-MM-DD
+                format!(
+                    "substr(songs.date_added, 1, 10) {} ?",
+                    comparison.operator()
+                )
+            }
+            Filter::Origin { lossy: true } => lossy_origin_condition(),
+            Filter::Origin { lossy: false } => format!("NOT ({})", lossy_origin_condition()),
+        }
+    }
+}
+
+/// A LIKE pattern matching text containing the value
+fn like_pattern(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn parse(mut self) -> Result<Query, ParseError> {
+        let mut terms = vec![];
+        loop {
+            while self.peek().is_some_and(char::is_whitespace) {
+                self.position += 1;
+            }
+            if self.peek().is_none() {
+                break;
+            }
+            terms.push(self.term()?);
+        }
+        Ok(Query { terms })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn error<T>(&self, position: usize, message: String) -> Result<T, ParseError> {
+        Err(ParseError { position, message })
+    }
+
+    fn term(&mut self) -> Result<Term, ParseError> {
+        let negated = self.peek() == Some('-');
+        if negated {
+            self.position += 1;
+        }
+
+        let start = self.position;
+        if self.peek() == Some('"') {
+            let text = self.quoted()?;
+            return Ok(Term {
+                negated,
+                filter: Filter::Text { text, phrase: true },
+            });
+        }
+
+        let word = self.word();
+        if word.is_empty() {
+            return self.error(start, "Expected a search term".to_string());
+        }
+
+        let (name, value) = match word.split_once(':') {
+            Some((name, value)) => (name, value),
+            None => {
+                return Ok(Term {
+                    negated,
+                    filter: Filter::Text {
+                        text: word,
+                        phrase: false,
+                    },
+                })
+            }
+        };
+        let value_start = start + name.chars().count() + 1;
+
+        let filter = if name.eq_ignore_ascii_case("added") {
+            let (comparison, date) = self.date(value, value_start)?;
+            Filter::Added(comparison, date)
+        } else if name.eq_ignore_ascii_case("origin") {
+            match value.to_lowercase().as_str() {
+                "lossy" => Filter::Origin { lossy: true },
+                "lossless" => Filter::Origin { lossy: false },
+                _ => {
+                    return self.error(
+                        value_start,
+                        format!("Expected lossy or lossless, found '{}'", value),
+                    )
+                }
+            }
+        } else if let Some(field) = Field::from_name(name) {
+            let value = if value.is_empty() && self.peek() == Some('"') {
+                self.quoted()?
+            } else {
+                value.to_string()
+            };
+            if value.is_empty() {
+                return self.error(value_start, format!("Expected a value for {}:", name));
+            }
+            Filter::Field(field, value)
+        } else {
+            let fields = Field::NAMES
+                .iter()
+                .map(|(name, _)| *name)
+                .chain(["added", "origin"])
+                .collect::<Vec<_>>()
+                .join(", ");
+            return self.error(
+                start,
+                format!("Unknown field '{}'. Known fields are: {}", name, fields),
+            );
+        };
+
+        Ok(Term { negated, filter })
+    }
+
+    /// Reads until whitespace or a double quote
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || c == '"' {
+                break;
+            }
+            word.push(c);
+            self.position += 1;
+        }
+        word
+    }
+
+    /// Reads text in double quotes, starting at the opening quote
+    fn quoted(&mut self) -> Result<String, ParseError> {
+        let start = self.position;
+        self.position += 1;
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.position += 1;
+                    return Ok(text);
+                }
+                Some(c) => {
+                    text.push(c);
+                    self.position += 1;
+                }
+                None => return self.error(start, "Unterminated quote".to_string()),
+            }
+        }
+    }
+
+    fn date(&self, value: &str, start: usize) -> Result<(Comparison, Date), ParseError> {
+        let (comparison, date) = [
+            (">=", Comparison::GreaterOrEqual),
+            ("<=", Comparison::LessOrEqual),
+            (">", Comparison::Greater),
+            ("<", Comparison::Less),
+            ("=", Comparison::Equal),
+        ]
+        .iter()
+        .find_map(|(operator, comparison)| {
+            value.strip_prefix(operator).map(|date| (*comparison, date))
+        })
+        .unwrap_or((Comparison::Equal, value));
+
+        let invalid = || ParseError {
+            position: start,
+            message: format!("Expected a date like 2021-12-01, found '{}'", value),
+        };
+        let parts = date
+            .split('-')
+            .map(|part| part.parse::<u16>().map_err(|_| invalid()))
+            .collect::<Result<Vec<u16>, ParseError>>()?;
+        if parts.len() != 3 {
+            return Err(invalid());
+        }
+        let month = Month::try_from(parts[1] as u8).map_err(|_| invalid())?;
+        let date = Date::from_calendar_date(parts[0] as i32, month, parts[2] as u8)
+            .map_err(|_| invalid())?;
+
+        Ok((comparison, date))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str, phrase: bool) -> Filter {
+        Filter::Text {
+            text: text.to_string(),
+            phrase,
+        }
+    }
+
+    fn term(filter: Filter) -> Term {
+        Term {
+            negated: false,
+            filter,
+        }
+    }
+
+    #[test]
+    fn parse_terms() {
+        let query =
+            Query::parse("artist:\"Yoasobi\" album:unknown added:>2021-12-01 -genre:pop night")
+                .unwrap();
+        assert_eq!(
+            query.terms,
+            vec![
+                term(Filter::Field(Field::Artist, "Yoasobi".to_string())),
+                term(Filter::Field(Field::Album, "unknown".to_string())),
+                term(Filter::Added(
+                    Comparison::Greater,
+                    Date::from_calendar_date(2021, Month::December, 1).unwrap()
+                )),
+                Term {
+                    negated: true,
+                    filter: Filter::Field(Field::Genre, "pop".to_string()),
+                },
+                term(text("night", false)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_phrases_and_operators() {
+        let query =
+            Query::parse("\"into the night\" -\"live\" TITLE:\"a b\" added:<=2022-01-31").unwrap();
+        assert_eq!(
+            query.terms,
+            vec![
+                term(text("into the night", true)),
+                Term {
+                    negated: true,
+                    filter: text("live", true),
+                },
+                term(Filter::Field(Field::Title, "a b".to_string())),
+                term(Filter::Added(
+                    Comparison::LessOrEqual,
+                    Date::from_calendar_date(2022, Month::January, 31).unwrap()
+                )),
+            ]
+        );
+        assert_eq!(
+            Query::parse("origin:Lossy -origin:lossless").unwrap().terms,
+            vec![
+                term(Filter::Origin { lossy: true }),
+                Term {
+                    negated: true,
+                    filter: Filter::Origin { lossy: false },
+                },
+            ]
+        );
+        assert_eq!(Query::parse("   ").unwrap().terms, vec![]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = Query::parse("night mood:happy").unwrap_err();
+        assert_eq!(error.position, 6);
+        assert!(error.message.starts_with("Unknown field 'mood'"));
+
+        let error = Query::parse("title:\"open").unwrap_err();
+        assert_eq!(error.position, 6);
+        assert_eq!(error.message, "Unterminated quote");
+
+        let error = Query::parse("added:>2021-13-01").unwrap_err();
+        assert_eq!(error.position, 6);
+
+        assert_eq!(Query::parse("album:").unwrap_err().position, 6);
+        assert_eq!(Query::parse("x origin:mp3").unwrap_err().position, 9);
+        assert_eq!(Query::parse("a - b").unwrap_err().position, 3);
+    }
+
+    #[test]
+    fn generate_sql() {
+        let (sql, params) = Query::parse("artist:yoasobi added:>2021-12-01 -genre:pop")
+            .unwrap()
+            .to_sql();
+        assert!(!sql.contains("songs_fts"));
+        assert!(sql.contains("artists.name LIKE ? ESCAPE"));
+        assert!(sql.contains("NOT COALESCE((EXISTS"));
+        assert!(sql.contains("substr(songs.date_added, 1, 10) > ?"));
+        assert!(sql.ends_with("ORDER BY songs.id"));
+        assert_eq!(
+            params,
+            vec![
+                Value::Text("%yoasobi%".to_string()),
+                Value::Text("2021-12-01".to_string()),
+                Value::Text("%pop%".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn generate_ranked_sql() {
+        let (sql, params) = Query::parse("night \"the book\" -live title:100%")
+            .unwrap()
+            .to_sql();
+        assert!(sql.starts_with("JOIN (\n"));
+        assert!(sql.ends_with("ORDER BY fts.fts_rank, songs.id"));
+        assert_eq!(
+            params,
+            vec![
+                Value::Text("\"night\"* AND \"the book\"".to_string()),
+                Value::Text("\"live\"*".to_string()),
+                Value::Text("%100\\%%".to_string()),
+            ]
+        );
+    }
+}
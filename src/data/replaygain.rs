@@ -0,0 +1,151 @@
+//! ReplayGain values of songs, which tell players how much to turn a song up or down so songs
+//! play equally loud. Gains are relative to ReplayGain 2.0's reference of -18 LUFS. They are
+//! kept in the database and in the file's REPLAYGAIN_* tags, which players read.
+
+use super::tag::AudioTag;
+
+/// Loudness songs are brought to, in LUFS
+pub const REFERENCE_LOUDNESS: f64 = -18.0;
+
+const TRACK_GAIN: &str = "REPLAYGAIN_TRACK_GAIN";
+const TRACK_PEAK: &str = "REPLAYGAIN_TRACK_PEAK";
+const ALBUM_GAIN: &str = "REPLAYGAIN_ALBUM_GAIN";
+const ALBUM_PEAK: &str = "REPLAYGAIN_ALBUM_PEAK";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayGain {
+    /// Gain bringing the song to the reference loudness, in dB
+    pub track_gain: Option<f64>,
+    /// Highest sample of the song, where 1 is full scale
+    pub track_peak: Option<f64>,
+    /// Gain bringing the song's album to the reference loudness, in dB
+    pub album_gain: Option<f64>,
+    /// Highest sample of any song of the album
+    pub album_peak: Option<f64>,
+}
+
+/// Rounds a gain to the two decimals tags have, as other taggers write them. Measured values
+/// are rounded before they are kept, so the tags and the database agree.
+pub fn round_gain(gain: f64) -> f64 {
+    (gain * 100.0).round() / 100.0
+}
+
+/// Rounds a peak to the six decimals tags have
+pub fn round_peak(peak: f64) -> f64 {
+    (peak * 1_000_000.0).round() / 1_000_000.0
+}
+
+impl ReplayGain {
+    pub fn is_empty(&self) -> bool {
+        *self == ReplayGain::default()
+    }
+
+    pub fn read(tag: &dyn AudioTag) -> Self {
+        // Gains are written like "-6.48 dB"
+        let field = |key: &str| {
+            tag.get(key)
+                .and_then(|values| values.into_iter().next())
+                .and_then(|value| {
+                    value
+                        .trim()
+                        .trim_end_matches("dB")
+                        .trim()
+                        .parse::<f64>()
+                        .ok()
+                })
+        };
+        Self {
+            track_gain: field(TRACK_GAIN),
+            track_peak: field(TRACK_PEAK),
+            album_gain: field(ALBUM_GAIN),
+            album_peak: field(ALBUM_PEAK),
+        }
+    }
+
+    /// Writes the values to the tag. Unknown values are removed, so the tag never keeps an
+    /// album gain from before the song's album changed.
+    pub fn write(&self, tag: &mut dyn AudioTag) {
+        let fields = [
+            (
+                TRACK_GAIN,
+                self.track_gain.map(|gain| format!("{:.2} dB", gain)),
+            ),
+            (
+                TRACK_PEAK,
+                self.track_peak.map(|peak| format!("{:.6}", peak)),
+            ),
+            (
+                ALBUM_GAIN,
+                self.album_gain.map(|gain| format!("{:.2} dB", gain)),
+            ),
+            (
+                ALBUM_PEAK,
+                self.album_peak.map(|peak| format!("{:.6}", peak)),
+            ),
+        ];
+        for (key, value) in fields {
+            match value {
+                Some(value) => tag.set(key, vec![value]),
+                None => tag.remove(key),
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ReplayGain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        if let Some(gain) = self.track_gain {
+            parts.push(format!("track {:+.2} dB", gain));
+        }
+        if let Some(peak) = self.track_peak {
+            parts.push(format!("peak {:.6}", peak));
+        }
+        if let Some(gain) = self.album_gain {
+            parts.push(format!("album {:+.2} dB", gain));
+        }
+        if let Some(peak) = self.album_peak {
+            parts.push(format!("album peak {:.6}", peak));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tag_round_trip() {
+        let replaygain = ReplayGain {
+            track_gain: Some(-6.48),
+            track_peak: Some(0.988553),
+            album_gain: Some(-5.9),
+            album_peak: Some(1.0),
+        };
+        let mut tag = metaflac::Tag::new();
+        replaygain.write(&mut tag);
+        assert_eq!(
+            tag.get_vorbis("REPLAYGAIN_ALBUM_GAIN").unwrap().next(),
+            Some("-5.90 dB")
+        );
+        assert_eq!(ReplayGain::read(&tag), replaygain);
+        assert_eq!(
+            replaygain.to_string(),
+            "track -6.48 dB, peak 0.988553, album -5.90 dB, album peak 1.000000"
+        );
+
+        // Songs which lost their album lose its gain
+        let track_only = ReplayGain {
+            album_gain: None,
+            album_peak: None,
+            ..replaygain
+        };
+        track_only.write(&mut tag);
+        assert_eq!(
+            tag.get_vorbis("REPLAYGAIN_ALBUM_GAIN").map(|v| v.count()),
+            None
+        );
+        assert_eq!(ReplayGain::read(&tag), track_only);
+    }
+}
@@ -6,30 +6,51 @@ use std::{
 
 use debug_stub_derive::DebugStub;
 use log::{debug, error, info, warn};
-use metaflac::Tag;
+use time::OffsetDateTime;
 use tui_c::widgets::ListState;
 
 use eyre::Result;
 
-#[derive(Debug)]
+use super::{
+    provenance::Provenance,
+    replaygain::ReplayGain,
+    tag::{self, BoxedTag, Picture, PictureType},
+    trim::Trim,
+};
+
+#[derive(Debug, Clone)]
 pub enum MetadataSource {
     File,
     Database,
 }
 
-#[derive(DebugStub)]
+#[derive(DebugStub, Clone)]
 pub struct Song {
     pub id: Option<usize>,
     pub file_path: PathBuf,
     pub file_name: String,
-    #[debug_stub = "metaflac::Tag"]
-    pub tag: Tag,
+    #[debug_stub = "AudioTag"]
+    pub tag: BoxedTag,
     pub title: Option<String>,
     pub artists: Option<Vec<String>>,
     pub album: Option<String>,
+    /// The ALBUMARTIST tag, if it names someone other than the first artist
+    pub album_artist: Option<String>,
     pub genre: Option<String>,
     pub youtube_id: Option<String>,
     pub thumbnail_url: Option<String>,
+    /// The DATE tag, a year or YYYY-MM-DD
+    pub release_date: Option<String>,
+    pub track_number: Option<u32>,
+    /// When the song was added to the database
+    pub date_added: Option<OffsetDateTime>,
+    /// Where the audio came from
+    pub provenance: Provenance,
+    pub replaygain: ReplayGain,
+    /// Silence cut off the song when it was downloaded
+    pub trim: Trim,
+    /// Pictures embedded in the file
+    pub pictures: Vec<Picture>,
 
     pub items: Vec<String>,
     pub state: ListState,
@@ -39,7 +60,7 @@ pub struct Song {
 
 impl Song {
     pub fn read_music_file(path: &Path) -> Result<Self> {
-        let tag = Tag::read_from_path(path)?;
+        let tag = tag::read_from_path(path)?;
         let mut song = Self {
             file_path: path.to_path_buf(),
             file_name: path.file_name().unwrap().to_str().unwrap().to_owned(),
@@ -59,31 +80,21 @@ impl Song {
         path: String,
         file_name: String,
         title: Option<String>,
-        artists: Option<String>,
+        artists: Option<Vec<String>>,
         album: Option<String>,
         genre: Option<String>,
         youtube_id: Option<String>,
         thumbnail_url: Option<String>,
     ) -> Result<Self> {
-        // Decode artists into vec
-        let artists = if let Some(artists) = artists {
-            let mut artists_vec = vec![];
-            for artist in artists.split(':') {
-                if artist.is_empty() {
-                    continue;
-                }
-                artists_vec.push(artist.to_string());
-            }
-            Some(artists_vec)
-        } else {
-            None
-        };
-
-        let mut tag = Tag::new();
-        tag.set_vorbis("TITLE", vec![title.clone().unwrap()]);
-        tag.set_vorbis("ARTIST", artists.clone().unwrap());
+        let mut tag: BoxedTag = Box::new(metaflac::Tag::new());
+        if let Some(title) = title.clone() {
+            tag.set("TITLE", vec![title]);
+        }
+        if let Some(artists) = artists.clone() {
+            tag.set("ARTIST", artists);
+        }
         if let Some(album) = album.clone() {
-            tag.set_vorbis("ALBUM", vec![album]);
+            tag.set("ALBUM", vec![album]);
         }
 
         let file_path = Path::new(&path);
@@ -112,33 +123,68 @@ impl Song {
         self.init_title();
         self.init_artist();
         self.init_album();
+        self.init_genre();
+        self.init_release();
+        self.init_pictures();
+        self.provenance = Provenance::read(self.tag.as_ref());
+        self.replaygain = ReplayGain::read(self.tag.as_ref());
+        self.trim = Trim::read(self.tag.as_ref());
         self.initialized = true;
     }
 
     fn init_title(&mut self) {
         self.title = self
             .tag
-            .get_vorbis("TITLE")
-            .map(|mut title| title.next().unwrap().to_owned());
+            .get("TITLE")
+            .and_then(|title| title.into_iter().next());
     }
 
     fn init_artist(&mut self) {
-        self.artists = self
-            .tag
-            .get_vorbis("ARTIST")
-            .map(|artists| artists.map(|e| e.to_owned()).collect::<Vec<String>>());
+        self.artists = self.tag.get("ARTIST");
     }
     /// I want only one album, okay?
     /// If i have to change this in the future, so be it.
     fn init_album(&mut self) {
         self.album = self
             .tag
-            .get_vorbis("ALBUM")
-            .map(|mut album| album.next().unwrap().to_owned());
+            .get("ALBUM")
+            .and_then(|album| album.into_iter().next());
+        let first_artist = self.artists.as_ref().and_then(|artists| artists.first());
+        self.album_artist = self
+            .tag
+            .get("ALBUMARTIST")
+            .and_then(|artists| artists.into_iter().next())
+            .filter(|artist| Some(artist) != first_artist);
     }
 
-    fn _init_picture(&mut self) {
-        todo!()
+    /// Whose album the song is on: the album artist, or else the first artist. Albums of the
+    /// same title by different artists are told apart by it.
+    pub fn album_artist_or_artist(&self) -> Option<&str> {
+        self.album_artist
+            .as_deref()
+            .or_else(|| self.artists.as_ref()?.first().map(String::as_str))
+    }
+
+    fn init_genre(&mut self) {
+        self.genre = self
+            .tag
+            .get("GENRE")
+            .and_then(|genre| genre.into_iter().next());
+    }
+
+    fn init_release(&mut self) {
+        let first = |key: &str| {
+            self.tag
+                .get(key)
+                .and_then(|values| values.into_iter().next())
+        };
+        self.release_date = first("DATE");
+        self.track_number =
+            first("TRACKNUMBER").and_then(|number| tag::parse_track_number(&number));
+    }
+
+    fn init_pictures(&mut self) {
+        self.pictures = self.tag.pictures();
     }
 
     pub fn is_initialized(&self) -> bool {
@@ -146,7 +192,7 @@ impl Song {
     }
 
     /// Guarantees display to be in a specific order
-    /// Filename, song title, song artists, song album
+    /// Filename, song title, song artists, song album, pictures
     fn populate_list_items(&mut self) {
         debug!("Populating list items");
         let mut file_name_string = String::from("File name: ");
@@ -167,7 +213,25 @@ impl Song {
         let mut album_string = String::from("Album: ");
         album_string.push_str(&self.album.clone().unwrap_or_else(|| "None".to_string()));
 
-        let items = vec![file_name_string, title_string, artist_string, album_string];
+        let mut pictures_string = String::from("Pictures: ");
+        if self.pictures.is_empty() {
+            pictures_string.push_str("None");
+        } else {
+            let pictures = self
+                .pictures
+                .iter()
+                .map(tag::picture_summary)
+                .collect::<Vec<_>>();
+            pictures_string.push_str(&pictures.join("; "));
+        }
+
+        let items = vec![
+            file_name_string,
+            title_string,
+            artist_string,
+            album_string,
+            pictures_string,
+        ];
 
         self.items = items;
     }
@@ -195,6 +259,11 @@ impl Song {
                 self.edit_album(new_value);
                 self.populate_list_items()
             }
+            // Set the front cover from an image file, or remove every picture if empty
+            4 => {
+                self.edit_pictures(new_value);
+                self.populate_list_items()
+            }
             // Error codes
             10 => {
                 warn!(target: "song_edit", "Unable to get index value for MetadataListWidget. 'Tis a bug");
@@ -205,11 +274,19 @@ impl Song {
 
     // TODO: Rework metadata editing to commit changes to database
 
-    /// Edits the name of the file
+    /// Edits the name of the file, keeping its extension
     /// Note: file_name edits are applied immediately
     fn edit_filename(&mut self, mut new_file_name: String) {
-        if !new_file_name.contains("flac") {
-            new_file_name.push_str(".flac")
+        if let Some(extension) = self.file_path.extension().and_then(|e| e.to_str()) {
+            let new_extension = Path::new(&new_file_name)
+                .extension()
+                .and_then(|e| e.to_str());
+            if !new_extension
+                .is_some_and(|new_extension| new_extension.eq_ignore_ascii_case(extension))
+            {
+                new_file_name.push('.');
+                new_file_name.push_str(extension);
+            }
         }
         let mut new_file_path = self.file_path.clone();
         new_file_path.set_file_name(&new_file_name);
@@ -219,13 +296,13 @@ impl Song {
                 self.file_name = new_file_name;
                 self.file_path = new_file_path;
                 info!(target: "song_edit", "Set filename to: {}", self.file_name);
-                match Tag::read_from_path(&self.file_path) {
+                match tag::read_from_path(&self.file_path) {
                     Ok(tag) => {
                         self.tag = tag;
                         self.init();
                     }
                     Err(e) => {
-                        error!("Error reading FLAC tags: {}", e);
+                        error!("Error reading tags: {}", e);
                     }
                 };
             }
@@ -237,8 +314,7 @@ impl Song {
 
     fn edit_title(&mut self, new_value: String) {
         self.title = Some(new_value);
-        self.tag
-            .set_vorbis("TITLE", vec![self.title.as_ref().unwrap()]);
+        self.tag.set("TITLE", vec![self.title.clone().unwrap()]);
     }
 
     fn edit_artist(&mut self, new_value: String) {
@@ -249,18 +325,108 @@ impl Song {
             }
             artists.push(artist.to_string());
         }
-        self.tag.set_vorbis("ARTIST", artists.clone());
+        self.tag.set("ARTIST", artists.clone());
         self.artists = Some(artists);
     }
 
     fn edit_album(&mut self, new_album_value: String) {
-        self.tag.set_vorbis("ALBUM", vec![new_album_value.clone()]);
+        self.tag.set("ALBUM", vec![new_album_value.clone()]);
         self.album = Some(new_album_value);
     }
 
-    fn _edit_picture(&mut self, _new_value: &[u8]) {
-        // TODO: Implement setting a picture
-        todo!()
+    fn edit_pictures(&mut self, image_path: String) {
+        if image_path.trim().is_empty() {
+            self.remove_pictures(None);
+            info!(target: "song_edit", "Removed all pictures");
+            return;
+        }
+
+        let picture = std::fs::read(image_path.trim())
+            .map_err(eyre::Report::from)
+            .and_then(|data| tag::picture_from_image(PictureType::CoverFront, data))
+            .and_then(|picture| self.set_picture(picture));
+        match picture {
+            Ok(_) => info!(target: "song_edit", "Set front cover to: {}", image_path),
+            Err(e) => error!("Can't set the front cover: {}", e),
+        }
+    }
+
+    /// Adds a picture, replacing any picture of the same type
+    pub fn set_picture(&mut self, picture: Picture) -> Result<()> {
+        self.tag.set_picture(picture)?;
+        self.init_pictures();
+        self.populate_list_items();
+        Ok(())
+    }
+
+    /// Removes pictures of a type, or every picture if no type is given
+    pub fn remove_pictures(&mut self, picture_type: Option<PictureType>) {
+        self.tag.remove_pictures(picture_type);
+        self.init_pictures();
+        self.populate_list_items();
+    }
+
+    /// Writes the pictures to image files in the directory, named after the song file and
+    /// the picture type. Returns the paths written.
+    pub fn extract_pictures(
+        &self,
+        dir: &Path,
+        picture_type: Option<PictureType>,
+    ) -> Result<Vec<PathBuf>> {
+        let stem = self
+            .file_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("picture");
+        let mut paths: Vec<PathBuf> = vec![];
+        for picture in &self.pictures {
+            if picture_type.is_some_and(|picture_type| picture_type != picture.picture_type) {
+                continue;
+            }
+            let name = format!(
+                "{} - {}",
+                stem,
+                tag::picture_type_name(picture.picture_type)
+            );
+            let extension = tag::picture_extension(&picture.mime_type);
+            let mut path = dir.join(format!("{}.{}", name, extension));
+            // MP4 files can hold several pictures without a type
+            let mut count = 1;
+            while paths.contains(&path) {
+                count += 1;
+                path = dir.join(format!("{} {}.{}", name, count, extension));
+            }
+            std::fs::write(&path, &picture.data)?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+
+    /// Copies the title, artists, album and genre into the tag, removing those which are unset.
+    /// The album artist is written when there is one, and left as it is otherwise.
+    pub fn sync_tag(&mut self) {
+        if let Some(album_artist) = &self.album_artist {
+            self.tag.set("ALBUMARTIST", vec![album_artist.clone()]);
+        }
+        let fields = [
+            ("TITLE", self.title.clone().map(|title| vec![title])),
+            ("ARTIST", self.artists.clone()),
+            ("ALBUM", self.album.clone().map(|album| vec![album])),
+            ("GENRE", self.genre.clone().map(|genre| vec![genre])),
+        ];
+        for (key, values) in fields {
+            match values {
+                Some(values) => self.tag.set(key, values),
+                None => self.tag.remove(key),
+            }
+        }
+        self.populate_list_items();
+    }
+
+    /// Sets the ReplayGain values of the song and its tag
+    pub fn set_replaygain(&mut self, replaygain: ReplayGain) {
+        replaygain.write(self.tag.as_mut());
+        self.replaygain = replaygain;
     }
 
     pub fn write_tag_changes(&mut self) -> Result<()> {
@@ -275,11 +441,17 @@ impl Song {
         song_left.title == song_right.title
             && song_left.artists == song_right.artists
             && song_left.album == song_right.album
+            && song_left.album_artist == song_right.album_artist
             && song_left.genre == song_right.genre
+            && song_left.release_date == song_right.release_date
+            && song_left.track_number == song_right.track_number
             && song_left.youtube_id == song_right.youtube_id
             && song_left.thumbnail_url == song_right.thumbnail_url
             && song_left.file_name == song_right.file_name
             && song_left.file_path == song_right.file_path
+            && song_left.provenance == song_right.provenance
+            && song_left.replaygain == song_right.replaygain
+            && song_left.trim == song_right.trim
     }
 
     /// Select the next item.
@@ -326,16 +498,24 @@ impl Default for Song {
             id: None,
             file_path: Default::default(),
             file_name: Default::default(),
-            tag: Default::default(),
+            tag: Box::new(metaflac::Tag::new()),
             title: Default::default(),
             artists: Default::default(),
             album: Default::default(),
+            album_artist: None,
             items: vec![],
             state: ListState::default(),
             initialized: false,
             genre: Default::default(),
             youtube_id: Default::default(),
             thumbnail_url: Default::default(),
+            release_date: None,
+            track_number: None,
+            date_added: None,
+            provenance: Provenance::default(),
+            replaygain: ReplayGain::default(),
+            trim: Trim::default(),
+            pictures: vec![],
             metadata_source: MetadataSource::File,
         }
     }
@@ -346,10 +526,10 @@ impl Display for Song {
         write!(
             f,
             "{} - {} [ID: {}, Album: {}]",
-            self.title.clone().unwrap(),
-            self.artists.clone().unwrap().join(":"),
-            self.id.unwrap(),
-            self.album.clone().unwrap()
+            self.title.as_deref().unwrap_or("None"),
+            self.artists.clone().unwrap_or_default().join(", "),
+            self.id.unwrap_or_default(),
+            self.album.as_deref().unwrap_or("None")
         )
     }
 }
@@ -0,0 +1,53 @@
+//! FLAC files keep their metadata in a Vorbis comment block
+
+use std::path::Path;
+
+use eyre::Result;
+use metaflac::{block::BlockType, Block};
+
+use super::{AudioFormat, AudioTag, Picture, PictureType};
+
+impl AudioTag for metaflac::Tag {
+    fn get(&self, key: &str) -> Option<Vec<String>> {
+        self.get_vorbis(key)
+            .map(|values| values.map(str::to_string).collect())
+    }
+
+    fn set(&mut self, key: &str, values: Vec<String>) {
+        self.set_vorbis(key, values);
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.remove_vorbis(key);
+    }
+
+    fn pictures(&self) -> Vec<Picture> {
+        metaflac::Tag::pictures(self).cloned().collect()
+    }
+
+    fn set_picture(&mut self, picture: Picture) -> Result<()> {
+        self.remove_picture_type(picture.picture_type);
+        self.push_block(Block::Picture(picture));
+        Ok(())
+    }
+
+    fn remove_pictures(&mut self, picture_type: Option<PictureType>) {
+        match picture_type {
+            Some(picture_type) => self.remove_picture_type(picture_type),
+            None => self.remove_blocks(BlockType::Picture),
+        }
+    }
+
+    fn write_to_path(&mut self, path: &Path) -> Result<()> {
+        metaflac::Tag::write_to_path(self, path)?;
+        Ok(())
+    }
+
+    fn format(&self) -> AudioFormat {
+        AudioFormat::Flac
+    }
+
+    fn box_clone(&self) -> Box<dyn AudioTag> {
+        Box::new(self.clone())
+    }
+}
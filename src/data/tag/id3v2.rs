@@ -0,0 +1,164 @@
+//! MP3 files keep their metadata in an ID3v2 tag. Multiple values are separated by NUL
+//! characters, as in ID3v2.4.
+
+use std::path::Path;
+
+use eyre::Result;
+use id3::{frame::ExtendedText, ErrorKind, TagLike, Version};
+
+use super::{
+    picture_type_from_number, picture_type_number, with_dimensions, AudioFormat, AudioTag, Picture,
+    PictureType,
+};
+
+/// Frames of the fields with a frame of their own. Other fields are stored in TXXX frames.
+const FRAMES: &[(&str, &str)] = &[
+    ("TITLE", "TIT2"),
+    ("ARTIST", "TPE1"),
+    ("ALBUM", "TALB"),
+    ("ALBUMARTIST", "TPE2"),
+    ("GENRE", "TCON"),
+    ("DATE", "TDRC"),
+    ("TRACKNUMBER", "TRCK"),
+    ("DISCNUMBER", "TPOS"),
+];
+
+fn frame_id(key: &str) -> Option<&'static str> {
+    FRAMES
+        .iter()
+        .find(|(field, _)| field.eq_ignore_ascii_case(key))
+        .map(|(_, frame)| *frame)
+}
+
+/// Reads the tag, or starts an empty one if the file has none
+pub fn read_from_path(path: &Path) -> Result<id3::Tag> {
+    match id3::Tag::read_from_path(path) {
+        Ok(tag) => Ok(tag),
+        Err(e) if matches!(e.kind, ErrorKind::NoTag) => Ok(id3::Tag::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl AudioTag for id3::Tag {
+    fn get(&self, key: &str) -> Option<Vec<String>> {
+        let values = match frame_id(key) {
+            Some(frame) => self.text_values_for_frame_id(frame)?,
+            None => self
+                .extended_texts()
+                .find(|text| text.description.eq_ignore_ascii_case(key))?
+                .value
+                .split('\0')
+                .collect(),
+        };
+        Some(values.into_iter().map(str::to_string).collect())
+    }
+
+    fn set(&mut self, key: &str, values: Vec<String>) {
+        match frame_id(key) {
+            Some(frame) => self.set_text_values(frame, values),
+            None => {
+                AudioTag::remove(self, key);
+                self.add_frame(ExtendedText {
+                    description: key.to_string(),
+                    value: values.join("\0"),
+                });
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        match frame_id(key) {
+            Some(frame) => {
+                TagLike::remove(self, frame);
+            }
+            None => {
+                let descriptions = self
+                    .extended_texts()
+                    .filter(|text| text.description.eq_ignore_ascii_case(key))
+                    .map(|text| text.description.clone())
+                    .collect::<Vec<_>>();
+                for description in descriptions {
+                    self.remove_extended_text(Some(&description), None);
+                }
+            }
+        }
+    }
+
+    fn pictures(&self) -> Vec<Picture> {
+        id3::Tag::pictures(self)
+            .map(|frame| {
+                let mut picture = Picture::new();
+                picture.picture_type = picture_type_from_number(frame.picture_type.into());
+                picture.mime_type = frame.mime_type.clone();
+                picture.description = frame.description.clone();
+                picture.data = frame.data.clone();
+                with_dimensions(picture)
+            })
+            .collect()
+    }
+
+    fn set_picture(&mut self, picture: Picture) -> Result<()> {
+        let picture_type = id3_picture_type(picture.picture_type);
+        self.remove_picture_by_type(picture_type);
+        self.add_frame(id3::frame::Picture {
+            mime_type: picture.mime_type,
+            picture_type,
+            description: picture.description,
+            data: picture.data,
+        });
+        Ok(())
+    }
+
+    fn remove_pictures(&mut self, picture_type: Option<PictureType>) {
+        match picture_type {
+            Some(picture_type) => self.remove_picture_by_type(id3_picture_type(picture_type)),
+            None => self.remove_all_pictures(),
+        }
+    }
+
+    fn write_to_path(&mut self, path: &Path) -> Result<()> {
+        // ID3v2.2 can't be written, so those tags are upgraded
+        let version = match self.version() {
+            Version::Id3v23 => Version::Id3v23,
+            _ => Version::Id3v24,
+        };
+        id3::Tag::write_to_path(self, path, version)?;
+        Ok(())
+    }
+
+    fn format(&self) -> AudioFormat {
+        AudioFormat::Mp3
+    }
+
+    fn box_clone(&self) -> Box<dyn AudioTag> {
+        Box::new(self.clone())
+    }
+}
+
+fn id3_picture_type(picture_type: PictureType) -> id3::frame::PictureType {
+    use id3::frame::PictureType as Id3PictureType;
+    // Both follow the numbering of the ID3v2 APIC frame
+    [
+        Id3PictureType::Other,
+        Id3PictureType::Icon,
+        Id3PictureType::OtherIcon,
+        Id3PictureType::CoverFront,
+        Id3PictureType::CoverBack,
+        Id3PictureType::Leaflet,
+        Id3PictureType::Media,
+        Id3PictureType::LeadArtist,
+        Id3PictureType::Artist,
+        Id3PictureType::Conductor,
+        Id3PictureType::Band,
+        Id3PictureType::Composer,
+        Id3PictureType::Lyricist,
+        Id3PictureType::RecordingLocation,
+        Id3PictureType::DuringRecording,
+        Id3PictureType::DuringPerformance,
+        Id3PictureType::ScreenCapture,
+        Id3PictureType::BrightFish,
+        Id3PictureType::Illustration,
+        Id3PictureType::BandLogo,
+        Id3PictureType::PublisherLogo,
+    ][picture_type_number(picture_type) as usize]
+}
@@ -0,0 +1,509 @@
+//! Reads and writes the metadata of music files regardless of their format.
+//!
+//! Fields are named after Vorbis comments (TITLE, ARTIST, ALBUM, GENRE, ...). Each format maps
+//! them onto its own frames or atoms, and stores names it doesn't know as custom text fields.
+mod flac;
+mod id3v2;
+mod mp4;
+mod ogg;
+
+use std::{
+    fs::File,
+    io::{Cursor, Read},
+    path::Path,
+};
+
+use eyre::{eyre, Result, WrapErr};
+use image::GenericImageView;
+use strum_macros::Display;
+
+pub use metaflac::block::{Picture, PictureType};
+
+/// Extensions of the files considered music
+pub const EXTENSIONS: &[&str] = &["flac", "mp3", "ogg", "oga", "opus", "m4a", "mp4"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum AudioFormat {
+    #[strum(serialize = "FLAC")]
+    Flac,
+    #[strum(serialize = "MP3")]
+    Mp3,
+    #[strum(serialize = "Ogg Vorbis")]
+    Vorbis,
+    #[strum(serialize = "Opus")]
+    Opus,
+    #[strum(serialize = "M4A")]
+    M4a,
+}
+
+impl AudioFormat {
+    /// Identifies the format from the first bytes of the file
+    pub fn detect(path: &Path) -> Result<AudioFormat> {
+        let mut header = [0; 64];
+        let mut file = File::open(path)?;
+        let mut read = 0;
+        while read < header.len() {
+            match file.read(&mut header[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        AudioFormat::from_header(&header[..read])
+            .ok_or_else(|| eyre!("{} is not a supported music file", path.display()))
+    }
+
+    fn from_header(header: &[u8]) -> Option<AudioFormat> {
+        if header.starts_with(b"fLaC") {
+            Some(AudioFormat::Flac)
+        } else if header.starts_with(b"ID3")
+            // MPEG audio frame sync
+            || (header.len() >= 2 && header[0] == 0xFF && header[1] & 0xE0 == 0xE0)
+        {
+            Some(AudioFormat::Mp3)
+        } else if header.len() >= 8 && &header[4..8] == b"ftyp" {
+            Some(AudioFormat::M4a)
+        } else if header.starts_with(b"OggS") {
+            // The first packet starts after the 27 byte page header and the segment table
+            let segments = *header.get(26)? as usize;
+            let packet = header.get(27 + segments..)?;
+            if packet.starts_with(b"\x01vorbis") {
+                Some(AudioFormat::Vorbis)
+            } else if packet.starts_with(b"OpusHead") {
+                Some(AudioFormat::Opus)
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+
+    /// The usual extension of files in this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Flac => "flac",
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Vorbis => "ogg",
+            AudioFormat::Opus => "opus",
+            AudioFormat::M4a => "m4a",
+        }
+    }
+}
+
+/// Names of the picture types, in the order of their number in FLAC and ID3v2
+pub const PICTURE_TYPES: &[(&str, PictureType)] = &[
+    ("other", PictureType::Other),
+    ("icon", PictureType::Icon),
+    ("other-icon", PictureType::OtherIcon),
+    ("front", PictureType::CoverFront),
+    ("back", PictureType::CoverBack),
+    ("leaflet", PictureType::Leaflet),
+    ("media", PictureType::Media),
+    ("lead-artist", PictureType::LeadArtist),
+    ("artist", PictureType::Artist),
+    ("conductor", PictureType::Conductor),
+    ("band", PictureType::Band),
+    ("composer", PictureType::Composer),
+    ("lyricist", PictureType::Lyricist),
+    ("recording-location", PictureType::RecordingLocation),
+    ("during-recording", PictureType::DuringRecording),
+    ("during-performance", PictureType::DuringPerformance),
+    ("screen-capture", PictureType::ScreenCapture),
+    ("bright-fish", PictureType::BrightFish),
+    ("illustration", PictureType::Illustration),
+    ("band-logo", PictureType::BandLogo),
+    ("publisher-logo", PictureType::PublisherLogo),
+];
+
+pub fn picture_type_name(picture_type: PictureType) -> &'static str {
+    PICTURE_TYPES[picture_type_number(picture_type) as usize].0
+}
+
+pub fn picture_type_from_name(name: &str) -> Option<PictureType> {
+    PICTURE_TYPES
+        .iter()
+        .find(|(type_name, _)| type_name.eq_ignore_ascii_case(name))
+        .map(|(_, picture_type)| *picture_type)
+}
+
+fn picture_type_number(picture_type: PictureType) -> u8 {
+    PICTURE_TYPES
+        .iter()
+        .position(|(_, known_type)| *known_type == picture_type)
+        .unwrap_or_default() as u8
+}
+
+/// Unknown numbers are read as [`PictureType::Other`]
+fn picture_type_from_number(number: u8) -> PictureType {
+    PICTURE_TYPES
+        .get(number as usize)
+        .map_or(PictureType::Other, |(_, picture_type)| *picture_type)
+}
+
+/// Creates a picture from an image file's contents, reading its format and dimensions
+pub fn picture_from_image(picture_type: PictureType, data: Vec<u8>) -> Result<Picture> {
+    let format = image::guess_format(&data).wrap_err("Unknown image format")?;
+    let image = image::load_from_memory_with_format(&data, format)?;
+    let mime_type = match format {
+        image::ImageFormat::Png => "image/png",
+        image::ImageFormat::Jpeg => "image/jpeg",
+        image::ImageFormat::Gif => "image/gif",
+        image::ImageFormat::Bmp => "image/bmp",
+        image::ImageFormat::WebP => "image/webp",
+        _ => return Err(eyre!("Unsupported image format: {:?}", format)),
+    };
+
+    let mut picture = Picture::new();
+    picture.picture_type = picture_type;
+    picture.mime_type = mime_type.to_string();
+    (picture.width, picture.height) = image.dimensions();
+    picture.depth = image.color().bits_per_pixel() as u32;
+    picture.data = data;
+    Ok(picture)
+}
+
+/// Fills in the dimensions of pictures read from formats which don't store them
+fn with_dimensions(mut picture: Picture) -> Picture {
+    if picture.width == 0 || picture.height == 0 {
+        let dimensions = image::io::Reader::new(Cursor::new(&picture.data))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok());
+        if let Some((width, height)) = dimensions {
+            picture.width = width;
+            picture.height = height;
+        }
+    }
+    picture
+}
+
+/// Describes a picture in a line, such as "front, image/png, 500x500"
+pub fn picture_summary(picture: &Picture) -> String {
+    let mut summary = format!(
+        "{}, {}, {}x{}",
+        picture_type_name(picture.picture_type),
+        picture.mime_type,
+        picture.width,
+        picture.height
+    );
+    if !picture.description.is_empty() {
+        summary.push_str(&format!(", \"{}\"", picture.description));
+    }
+    summary
+}
+
+/// The extension of image files with the MIME type
+pub fn picture_extension(mime_type: &str) -> &str {
+    match mime_type {
+        "image/jpeg" | "image/jpg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/bmp" => "bmp",
+        "image/webp" => "webp",
+        _ => "bin",
+    }
+}
+
+/// Metadata of a music file
+pub trait AudioTag: Send {
+    /// Every value of a field, or `None` if the field is missing
+    fn get(&self, key: &str) -> Option<Vec<String>>;
+    /// Replaces every value of a field
+    fn set(&mut self, key: &str, values: Vec<String>);
+    fn remove(&mut self, key: &str);
+    fn pictures(&self) -> Vec<Picture>;
+    /// Adds a picture, replacing any picture of the same type
+    fn set_picture(&mut self, picture: Picture) -> Result<()>;
+    /// Removes pictures of a type, or every picture if no type is given
+    fn remove_pictures(&mut self, picture_type: Option<PictureType>);
+    fn write_to_path(&mut self, path: &Path) -> Result<()>;
+    fn format(&self) -> AudioFormat;
+    fn box_clone(&self) -> Box<dyn AudioTag>;
+}
+
+/// A tag of any format
+pub type BoxedTag = Box<dyn AudioTag>;
+
+impl Clone for BoxedTag {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// Reads the tag of a music file, choosing the format from the file contents
+pub fn read_from_path(path: &Path) -> Result<Box<dyn AudioTag>> {
+    read_with_format(path, AudioFormat::detect(path)?)
+}
+
+/// Reads the tag of a music file in the given format
+pub fn read_with_format(path: &Path, format: AudioFormat) -> Result<Box<dyn AudioTag>> {
+    let tag: Box<dyn AudioTag> = match format {
+        AudioFormat::Flac => Box::new(metaflac::Tag::read_from_path(path)?),
+        AudioFormat::Mp3 => Box::new(id3v2::read_from_path(path)?),
+        AudioFormat::Vorbis | AudioFormat::Opus => Box::new(ogg::OggTag::read_from_path(path)?),
+        AudioFormat::M4a => Box::new(mp4ameta::Tag::read_from_path(path)?),
+    };
+    Ok(tag)
+}
+
+/// Reads a TRACKNUMBER value, which may give the number of tracks too, as in `3/12`
+pub fn parse_track_number(value: &str) -> Option<u32> {
+    value.split('/').next()?.trim().parse().ok()
+}
+
+/// Whether the path has the extension of a music file
+pub fn is_music_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            EXTENSIONS
+                .iter()
+                .any(|music_extension| extension.eq_ignore_ascii_case(music_extension))
+        })
+}
+
+/// Writes a file next to the original and renames it over the original
+fn replace_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .ok_or_else(|| eyre!("Invalid path: {}", path.display()))?;
+    let temporary = path.with_file_name(format!(".{}.tmp", file_name));
+    std::fs::write(&temporary, contents)
+        .wrap_err_with(|| format!("Can't write {}", temporary.display()))?;
+    std::fs::rename(&temporary, path)
+        .wrap_err_with(|| format!("Can't replace {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Writes a file which is just enough of the format for tags to be read and written
+    fn write_fixture(name: &str, contents: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join("music-manager-tag-tests");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn empty_mp3() -> Vec<u8> {
+        let mut contents = vec![0xFF, 0xFB, 0x90, 0x64];
+        contents.resize(417, 0);
+        contents
+    }
+
+    fn empty_m4a() -> Vec<u8> {
+        let mut contents = vec![];
+        let mut atom = |name: &[u8], content: &[u8]| {
+            contents.extend(((content.len() + 8) as u32).to_be_bytes());
+            contents.extend(name);
+            contents.extend(content);
+        };
+        atom(b"ftyp", b"M4A \x00\x00\x02\x00isomiso2");
+        atom(b"moov", b"");
+        atom(b"mdat", b"audio");
+        contents
+    }
+
+    fn empty_flac() -> Vec<u8> {
+        let mut contents = b"fLaC".to_vec();
+        // The last metadata block, a STREAMINFO of 44.1kHz stereo 16 bit audio
+        contents.extend([0x80, 0x00, 0x00, 0x22]);
+        contents.extend([0x10, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0]);
+        contents.extend([0x0A, 0xC4, 0x42, 0xF0, 0, 0, 0, 0]);
+        contents.extend([0; 16]);
+        contents
+    }
+
+    #[test]
+    fn detect_formats() {
+        let cases = [
+            ("detect.flac", empty_flac(), Some(AudioFormat::Flac)),
+            ("detect.mp3", empty_mp3(), Some(AudioFormat::Mp3)),
+            ("detect.m4a", empty_m4a(), Some(AudioFormat::M4a)),
+            (
+                "detect.opus",
+                super::ogg::tests::empty_ogg(AudioFormat::Opus),
+                Some(AudioFormat::Opus),
+            ),
+            (
+                "detect.ogg",
+                super::ogg::tests::empty_ogg(AudioFormat::Vorbis),
+                Some(AudioFormat::Vorbis),
+            ),
+            ("detect.txt", b"not music".to_vec(), None),
+        ];
+        for (name, contents, expected) in cases {
+            let path = write_fixture(name, &contents);
+            assert_eq!(AudioFormat::detect(&path).ok(), expected, "{}", name);
+        }
+
+        // Detection ignores the extension
+        let path = write_fixture("actually_mp3.flac", &empty_mp3());
+        assert_eq!(AudioFormat::detect(&path).unwrap(), AudioFormat::Mp3);
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let cases = [
+            ("tags.flac", empty_flac()),
+            ("tags.mp3", empty_mp3()),
+            ("tags.m4a", empty_m4a()),
+            ("tags.opus", super::ogg::tests::empty_ogg(AudioFormat::Opus)),
+            (
+                "tags.ogg",
+                super::ogg::tests::empty_ogg(AudioFormat::Vorbis),
+            ),
+        ];
+        for (name, contents) in cases {
+            let path = write_fixture(name, &contents);
+            let mut tag = read_from_path(&path).unwrap();
+            assert_eq!(tag.get("TITLE"), None, "{}", name);
+
+            tag.set("TITLE", vec!["Yoru ni Kakeru".to_string()]);
+            tag.set("ARTIST", vec!["YOASOBI".to_string(), "Ayase".to_string()]);
+            tag.set("ALBUM", vec!["THE BOOK".to_string()]);
+            tag.set("GENRE", vec!["J-Pop".to_string()]);
+            tag.set("YOUTUBE_ID", vec!["x8VYWazR5mE".to_string()]);
+            tag.write_to_path(&path).unwrap();
+
+            let mut tag = read_from_path(&path).unwrap();
+            let get = |key| tag.get(key).unwrap_or_default().join(", ");
+            assert_eq!(get("TITLE"), "Yoru ni Kakeru", "{}", name);
+            assert_eq!(get("ARTIST"), "YOASOBI, Ayase", "{}", name);
+            assert_eq!(get("ALBUM"), "THE BOOK", "{}", name);
+            assert_eq!(get("GENRE"), "J-Pop", "{}", name);
+            assert_eq!(get("YOUTUBE_ID"), "x8VYWazR5mE", "{}", name);
+
+            tag.remove("ALBUM");
+            tag.set("TITLE", vec!["Racing into the Night".to_string()]);
+            tag.write_to_path(&path).unwrap();
+            let tag = read_from_path(&path).unwrap();
+            assert_eq!(tag.get("ALBUM"), None, "{}", name);
+            assert_eq!(
+                tag.get("TITLE"),
+                Some(vec!["Racing into the Night".to_string()]),
+                "{}",
+                name
+            );
+        }
+    }
+
+    /// Encodes a generated gradient
+    fn generated_image(width: u32, height: u32, format: image::ImageOutputFormat) -> Vec<u8> {
+        let image = image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x * 255 / width) as u8, (y * 255 / height) as u8, 128])
+        });
+        let mut data = vec![];
+        image::DynamicImage::ImageRgb8(image)
+            .write_to(&mut data, format)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn read_images() {
+        let png = generated_image(40, 30, image::ImageOutputFormat::Png);
+        let picture = picture_from_image(PictureType::CoverBack, png.clone()).unwrap();
+        assert_eq!(picture.mime_type, "image/png");
+        assert_eq!((picture.width, picture.height, picture.depth), (40, 30, 24));
+        assert_eq!(picture.data, png);
+        assert_eq!(picture_summary(&picture), "back, image/png, 40x30");
+
+        let jpeg = generated_image(16, 8, image::ImageOutputFormat::Jpeg(90));
+        let picture = picture_from_image(PictureType::CoverFront, jpeg).unwrap();
+        assert_eq!(picture.mime_type, "image/jpeg");
+        assert_eq!(picture_extension(&picture.mime_type), "jpg");
+
+        assert!(picture_from_image(PictureType::Other, b"not an image".to_vec()).is_err());
+        assert_eq!(
+            picture_type_from_name("Front"),
+            Some(PictureType::CoverFront)
+        );
+        assert_eq!(
+            picture_type_from_name("publisher-logo"),
+            Some(PictureType::PublisherLogo)
+        );
+        assert_eq!(picture_type_from_number(200), PictureType::Other);
+    }
+
+    #[test]
+    fn write_and_read_pictures() {
+        let front = picture_from_image(
+            PictureType::CoverFront,
+            generated_image(40, 30, image::ImageOutputFormat::Png),
+        )
+        .unwrap();
+        let back = picture_from_image(
+            PictureType::CoverBack,
+            generated_image(20, 20, image::ImageOutputFormat::Jpeg(90)),
+        )
+        .unwrap();
+        let cases = [
+            ("pictures.flac", empty_flac()),
+            ("pictures.mp3", empty_mp3()),
+            ("pictures.m4a", empty_m4a()),
+            (
+                "pictures.opus",
+                super::ogg::tests::empty_ogg(AudioFormat::Opus),
+            ),
+            (
+                "pictures.ogg",
+                super::ogg::tests::empty_ogg(AudioFormat::Vorbis),
+            ),
+        ];
+        for (name, contents) in cases {
+            let path = write_fixture(name, &contents);
+            let mut tag = read_from_path(&path).unwrap();
+            assert!(tag.pictures().is_empty(), "{}", name);
+
+            tag.set_picture(back.clone()).unwrap();
+            tag.set_picture(front.clone()).unwrap();
+            tag.write_to_path(&path).unwrap();
+
+            let mut tag = read_from_path(&path).unwrap();
+            let summaries = tag
+                .pictures()
+                .iter()
+                .map(picture_summary)
+                .collect::<Vec<_>>();
+            let pictures = tag.pictures();
+            if tag.format() == AudioFormat::M4a {
+                // Artwork has no type, so the front cover replaced the back cover
+                assert_eq!(summaries, ["front, image/png, 40x30"], "{}", name);
+            } else {
+                assert_eq!(
+                    summaries,
+                    ["back, image/jpeg, 20x20", "front, image/png, 40x30"],
+                    "{}",
+                    name
+                );
+                assert_eq!(pictures[0].data, back.data, "{}", name);
+            }
+            assert_eq!(pictures.last().unwrap().data, front.data, "{}", name);
+
+            tag.remove_pictures(Some(PictureType::CoverFront));
+            tag.write_to_path(&path).unwrap();
+            let mut tag = read_from_path(&path).unwrap();
+            let remaining = if tag.format() == AudioFormat::M4a {
+                0
+            } else {
+                1
+            };
+            assert_eq!(tag.pictures().len(), remaining, "{}", name);
+
+            tag.remove_pictures(None);
+            tag.write_to_path(&path).unwrap();
+            assert!(
+                read_from_path(&path).unwrap().pictures().is_empty(),
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! M4A files keep their metadata in iTunes style atoms
+
+use std::path::Path;
+
+use eyre::{eyre, Result};
+use mp4ameta::{ident, Data, Fourcc, FreeformIdent, Img, ImgFmt};
+
+use super::{with_dimensions, AudioFormat, AudioTag, Picture, PictureType};
+
+/// Mean of the freeform atoms storing fields without an atom of their own
+const FREEFORM_MEAN: &str = "com.apple.iTunes";
+
+/// Atoms of the fields with an atom of their own. Genres are handled separately as they can
+/// also be stored as an ID3v1 genre number.
+const ATOMS: &[(&str, Fourcc)] = &[
+    ("TITLE", ident::TITLE),
+    ("ARTIST", ident::ARTIST),
+    ("ALBUM", ident::ALBUM),
+    ("ALBUMARTIST", ident::ALBUM_ARTIST),
+    ("DATE", ident::YEAR),
+    ("COMMENT", ident::COMMENT),
+];
+
+fn atom(key: &str) -> Option<Fourcc> {
+    ATOMS
+        .iter()
+        .find(|(field, _)| field.eq_ignore_ascii_case(key))
+        .map(|(_, atom)| *atom)
+}
+
+fn is_genre(key: &str) -> bool {
+    key.eq_ignore_ascii_case("GENRE")
+}
+
+fn collect<'a>(values: impl Iterator<Item = &'a str>) -> Option<Vec<String>> {
+    let values = values.map(str::to_string).collect::<Vec<_>>();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values)
+    }
+}
+
+impl AudioTag for mp4ameta::Tag {
+    fn get(&self, key: &str) -> Option<Vec<String>> {
+        if is_genre(key) {
+            return collect(self.genres());
+        }
+        match atom(key) {
+            Some(atom) => collect(self.strings_of(&atom)),
+            None => collect(self.strings_of(&FreeformIdent::new(FREEFORM_MEAN, key))),
+        }
+    }
+
+    fn set(&mut self, key: &str, values: Vec<String>) {
+        if is_genre(key) {
+            self.set_genres(values);
+            return;
+        }
+        let data = values.into_iter().map(Data::Utf8);
+        match atom(key) {
+            Some(atom) => self.set_all_data(atom, data),
+            None => self.set_all_data(FreeformIdent::new(FREEFORM_MEAN, key), data),
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if is_genre(key) {
+            self.remove_genres();
+            return;
+        }
+        match atom(key) {
+            Some(atom) => self.remove_data_of(&atom),
+            None => self.remove_data_of(&FreeformIdent::new(FREEFORM_MEAN, key)),
+        }
+    }
+
+    /// Artwork atoms have no picture type, so every picture is read as a front cover
+    fn pictures(&self) -> Vec<Picture> {
+        self.artworks()
+            .map(|artwork| {
+                let mut picture = Picture::new();
+                picture.picture_type = PictureType::CoverFront;
+                picture.mime_type = match artwork.fmt {
+                    ImgFmt::Bmp => "image/bmp",
+                    ImgFmt::Jpeg => "image/jpeg",
+                    ImgFmt::Png => "image/png",
+                }
+                .to_string();
+                picture.data = artwork.data.to_vec();
+                with_dimensions(picture)
+            })
+            .collect()
+    }
+
+    /// Replaces every artwork, as they can't be told apart by type
+    fn set_picture(&mut self, picture: Picture) -> Result<()> {
+        let fmt = match picture.mime_type.as_str() {
+            "image/bmp" => ImgFmt::Bmp,
+            "image/jpeg" | "image/jpg" => ImgFmt::Jpeg,
+            "image/png" => ImgFmt::Png,
+            mime_type => return Err(eyre!("M4A files can't hold {} pictures", mime_type)),
+        };
+        self.set_artwork(Img::new(fmt, picture.data));
+        Ok(())
+    }
+
+    fn remove_pictures(&mut self, _picture_type: Option<PictureType>) {
+        self.remove_artworks();
+    }
+
+    fn write_to_path(&mut self, path: &Path) -> Result<()> {
+        mp4ameta::Tag::write_to_path(self, path)?;
+        Ok(())
+    }
+
+    fn format(&self) -> AudioFormat {
+        AudioFormat::M4a
+    }
+
+    fn box_clone(&self) -> Box<dyn AudioTag> {
+        Box::new(self.clone())
+    }
+}
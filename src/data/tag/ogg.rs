@@ -0,0 +1,315 @@
+//! Ogg Vorbis and Opus files keep their metadata as Vorbis comments in the second packet of
+//! the stream. Writing rewrites the whole stream with a new comment packet.
+
+use std::{
+    fs::File,
+    io::{BufReader, Cursor},
+    path::Path,
+};
+
+use ::ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+use eyre::{eyre, Result};
+
+use super::{replace_file, AudioFormat, AudioTag, Picture, PictureType};
+
+const VORBIS_COMMENT_MAGIC: &[u8] = b"\x03vorbis";
+/// Comments holding base64 encoded FLAC PICTURE blocks
+const PICTURE_KEY: &str = "METADATA_BLOCK_PICTURE";
+const OPUS_TAGS_MAGIC: &[u8] = b"OpusTags";
+
+#[derive(Debug, Clone)]
+pub struct OggTag {
+    format: AudioFormat,
+    vendor: String,
+    comments: Vec<(String, String)>,
+    /// Bytes following the comments, the framing bit for Vorbis and padding for Opus
+    trailing: Vec<u8>,
+}
+
+impl OggTag {
+    pub fn read_from_path(path: &Path) -> Result<OggTag> {
+        let mut reader = PacketReader::new(BufReader::new(File::open(path)?));
+        let first = reader
+            .read_packet()?
+            .ok_or_else(|| eyre!("{} is an empty Ogg file", path.display()))?;
+        let format = if first.data.starts_with(b"\x01vorbis") {
+            AudioFormat::Vorbis
+        } else if first.data.starts_with(b"OpusHead") {
+            AudioFormat::Opus
+        } else {
+            return Err(eyre!("{} is neither Vorbis nor Opus", path.display()));
+        };
+
+        loop {
+            let packet = reader
+                .read_packet()?
+                .ok_or_else(|| eyre!("{} has no comment header", path.display()))?;
+            if packet.stream_serial() == first.stream_serial() {
+                return OggTag::parse(format, &packet.data);
+            }
+        }
+    }
+
+    fn magic(format: AudioFormat) -> &'static [u8] {
+        match format {
+            AudioFormat::Opus => OPUS_TAGS_MAGIC,
+            _ => VORBIS_COMMENT_MAGIC,
+        }
+    }
+
+    fn parse(format: AudioFormat, packet: &[u8]) -> Result<OggTag> {
+        let mut packet = packet
+            .strip_prefix(OggTag::magic(format))
+            .ok_or_else(|| eyre!("Invalid comment header"))?;
+
+        let vendor = String::from_utf8_lossy(read_field(&mut packet)?).into_owned();
+        let count = read_u32(&mut packet)?;
+        let mut comments = vec![];
+        for _ in 0..count {
+            let comment = String::from_utf8_lossy(read_field(&mut packet)?).into_owned();
+            // Comments without a separator are invalid and dropped
+            if let Some((key, value)) = comment.split_once('=') {
+                comments.push((key.to_string(), value.to_string()));
+            }
+        }
+
+        Ok(OggTag {
+            format,
+            vendor,
+            comments,
+            trailing: packet.to_vec(),
+        })
+    }
+
+    fn to_packet(&self) -> Vec<u8> {
+        let mut packet = OggTag::magic(self.format).to_vec();
+        write_field(&mut packet, self.vendor.as_bytes());
+        packet.extend((self.comments.len() as u32).to_le_bytes());
+        for (key, value) in &self.comments {
+            write_field(&mut packet, format!("{}={}", key, value).as_bytes());
+        }
+        packet.extend(&self.trailing);
+        packet
+    }
+}
+
+fn read_u32(data: &mut &[u8]) -> Result<u32> {
+    if data.len() < 4 {
+        return Err(eyre!("Comment header is truncated"));
+    }
+    let (value, rest) = data.split_at(4);
+    *data = rest;
+    Ok(u32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+}
+
+/// Reads a length prefixed string
+fn read_field<'a>(data: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let length = read_u32(data)? as usize;
+    if data.len() < length {
+        return Err(eyre!("Comment header is truncated"));
+    }
+    let (field, rest) = data.split_at(length);
+    *data = rest;
+    Ok(field)
+}
+
+fn write_field(packet: &mut Vec<u8>, field: &[u8]) {
+    packet.extend((field.len() as u32).to_le_bytes());
+    packet.extend(field);
+}
+
+impl AudioTag for OggTag {
+    fn get(&self, key: &str) -> Option<Vec<String>> {
+        let values = self
+            .comments
+            .iter()
+            .filter(|(comment_key, _)| comment_key.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.clone())
+            .collect::<Vec<_>>();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values)
+        }
+    }
+
+    fn set(&mut self, key: &str, values: Vec<String>) {
+        self.remove(key);
+        for value in values {
+            self.comments.push((key.to_string(), value));
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.comments
+            .retain(|(comment_key, _)| !comment_key.eq_ignore_ascii_case(key));
+    }
+
+    fn pictures(&self) -> Vec<Picture> {
+        self.get(PICTURE_KEY)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|value| base64::decode(value).ok())
+            .filter_map(|block| Picture::from_bytes(&block).ok())
+            .collect()
+    }
+
+    fn set_picture(&mut self, picture: Picture) -> Result<()> {
+        self.remove_pictures(Some(picture.picture_type));
+        self.comments
+            .push((PICTURE_KEY.to_string(), base64::encode(picture.to_bytes())));
+        Ok(())
+    }
+
+    fn remove_pictures(&mut self, picture_type: Option<PictureType>) {
+        self.comments.retain(|(key, value)| {
+            if !key.eq_ignore_ascii_case(PICTURE_KEY) {
+                return true;
+            }
+            match picture_type {
+                // Keep pictures of other types, dropping any which can't be read
+                Some(picture_type) => base64::decode(value)
+                    .ok()
+                    .and_then(|block| Picture::from_bytes(&block).ok())
+                    .is_some_and(|picture| picture.picture_type != picture_type),
+                None => false,
+            }
+        });
+    }
+
+    fn write_to_path(&mut self, path: &Path) -> Result<()> {
+        let mut reader = PacketReader::new(Cursor::new(std::fs::read(path)?));
+        let mut output = vec![];
+        let mut writer = PacketWriter::new(&mut output);
+
+        // Only the first logical stream is tagged, any others are copied as they are
+        let mut serial = None;
+        let mut index = 0;
+        while let Some(packet) = reader.read_packet()? {
+            let end_info = if packet.last_in_stream() {
+                PacketWriteEndInfo::EndStream
+            } else if packet.last_in_page() {
+                PacketWriteEndInfo::EndPage
+            } else {
+                PacketWriteEndInfo::NormalPacket
+            };
+            let stream_serial = packet.stream_serial();
+            let absgp = packet.absgp_page();
+
+            let data = if *serial.get_or_insert(stream_serial) == stream_serial {
+                index += 1;
+                if index == 2 {
+                    self.to_packet()
+                } else {
+                    packet.data
+                }
+            } else {
+                packet.data
+            };
+            writer.write_packet(data.into_boxed_slice(), stream_serial, end_info, absgp)?;
+        }
+        if index < 2 {
+            return Err(eyre!("{} has no comment header", path.display()));
+        }
+
+        drop(writer);
+        replace_file(path, &output)
+    }
+
+    fn format(&self) -> AudioFormat {
+        self.format
+    }
+
+    fn box_clone(&self) -> Box<dyn AudioTag> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// An Ogg stream with the headers and two packets of silence
+    pub fn empty_ogg(format: AudioFormat) -> Vec<u8> {
+        let mut output = vec![];
+        let mut writer = PacketWriter::new(&mut output);
+        let mut write = |data: &[u8], end_info, absgp| {
+            writer
+                .write_packet(data.to_vec().into_boxed_slice(), 0x4d4d, end_info, absgp)
+                .unwrap();
+        };
+        match format {
+            AudioFormat::Opus => {
+                write(
+                    b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00",
+                    PacketWriteEndInfo::EndPage,
+                    0,
+                );
+                write(
+                    b"OpusTags\x04\x00\x00\x00test\x00\x00\x00\x00",
+                    PacketWriteEndInfo::EndPage,
+                    0,
+                );
+            }
+            _ => {
+                write(
+                    b"\x01vorbis\x00\x00\x00\x00\x02\x44\xac\x00\x00\x00\x00\x00\x00\
+                    \x00\xee\x02\x00\x00\x00\x00\x00\xb8\x01",
+                    PacketWriteEndInfo::EndPage,
+                    0,
+                );
+                write(
+                    b"\x03vorbis\x04\x00\x00\x00test\x00\x00\x00\x00\x01",
+                    PacketWriteEndInfo::NormalPacket,
+                    0,
+                );
+                write(b"\x05vorbis setup", PacketWriteEndInfo::EndPage, 0);
+            }
+        }
+        write(b"\xfc\xff\xfe", PacketWriteEndInfo::EndPage, 960);
+        write(b"\xfc\xff\xfe", PacketWriteEndInfo::EndStream, 1920);
+        drop(writer);
+        output
+    }
+
+    fn packets(path: &Path) -> Vec<(Vec<u8>, u64, bool)> {
+        let mut reader = PacketReader::new(BufReader::new(File::open(path).unwrap()));
+        let mut packets = vec![];
+        while let Some(packet) = reader.read_packet().unwrap() {
+            let last_in_page = packet.last_in_page();
+            packets.push((packet.data.clone(), packet.absgp_page(), last_in_page));
+        }
+        packets
+    }
+
+    #[test]
+    fn rewrite_keeps_audio() {
+        for format in [AudioFormat::Opus, AudioFormat::Vorbis] {
+            let dir = std::env::temp_dir().join("music-manager-tag-tests");
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join(format!("rewrite.{}", format.extension()));
+            std::fs::write(&path, empty_ogg(format)).unwrap();
+            let before = packets(&path);
+
+            let mut tag = OggTag::read_from_path(&path).unwrap();
+            assert_eq!(tag.vendor, "test");
+            // Large enough to span several pages
+            tag.set("LYRICS", vec!["la".repeat(40_000)]);
+            tag.write_to_path(&path).unwrap();
+
+            let after = packets(&path);
+            assert_eq!(before.len(), after.len());
+            for (index, (before, after)) in before.iter().zip(&after).enumerate() {
+                if index != 1 {
+                    assert_eq!(before, after, "{} packet {}", format, index);
+                }
+            }
+            let tag = OggTag::read_from_path(&path).unwrap();
+            assert_eq!(tag.get("lyrics").unwrap()[0].len(), 80_000);
+            assert_eq!(
+                tag.trailing,
+                OggTag::parse(format, &before[1].0).unwrap().trailing
+            );
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Silence cut off the start and end of downloaded songs. How long the song was before and
+//! how much was cut are kept in the database and in the file's tags, so trimming can be
+//! checked afterwards and `scan` recovers it.
+
+use super::tag::AudioTag;
+
+const ORIGINAL_DURATION: &str = "ORIGINAL_DURATION";
+const TRIMMED_START: &str = "TRIMMED_START";
+const TRIMMED_END: &str = "TRIMMED_END";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Trim {
+    /// Length of the song before trimming, in seconds
+    pub original_duration: Option<f64>,
+    /// Seconds of silence cut off the start
+    pub start: Option<f64>,
+    /// Seconds of silence cut off the end
+    pub end: Option<f64>,
+}
+
+/// Rounds seconds to the milliseconds tags have
+pub fn round_seconds(seconds: f64) -> f64 {
+    (seconds * 1000.0).round() / 1000.0
+}
+
+impl Trim {
+    /// Whether the song was never checked for silence
+    pub fn is_empty(&self) -> bool {
+        *self == Trim::default()
+    }
+
+    pub fn read(tag: &dyn AudioTag) -> Self {
+        let field = |key: &str| {
+            tag.get(key)
+                .and_then(|values| values.into_iter().next())
+                .and_then(|value| value.trim().parse::<f64>().ok())
+        };
+        Self {
+            original_duration: field(ORIGINAL_DURATION),
+            start: field(TRIMMED_START),
+            end: field(TRIMMED_END),
+        }
+    }
+
+    /// Writes the known values to the tag, leaving the others as they are
+    pub fn write(&self, tag: &mut dyn AudioTag) {
+        let fields = [
+            (ORIGINAL_DURATION, self.original_duration),
+            (TRIMMED_START, self.start),
+            (TRIMMED_END, self.end),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                tag.set(key, vec![format!("{:.3}", value)]);
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Trim {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.2} s cut from the start and {:.2} s from the end",
+            self.start.unwrap_or(0.0),
+            self.end.unwrap_or(0.0)
+        )?;
+        if let Some(duration) = self.original_duration {
+            write!(f, " of {:.2} s", duration)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tag_round_trip() {
+        let trim = Trim {
+            original_duration: Some(180.0),
+            start: Some(12.5),
+            end: Some(round_seconds(7.12345)),
+        };
+        let mut tag = metaflac::Tag::new();
+        trim.write(&mut tag);
+        assert_eq!(tag.get_vorbis("TRIMMED_END").unwrap().next(), Some("7.123"));
+        assert_eq!(Trim::read(&tag), trim);
+        assert_eq!(
+            trim.to_string(),
+            "12.50 s cut from the start and 7.12 s from the end of 180.00 s"
+        );
+        assert!(Trim::read(&metaflac::Tag::new()).is_empty());
+    }
+}
@@ -0,0 +1,368 @@
+//! Programs songs are downloaded with. youtube-dl and yt-dlp search and download from
+//! YouTube, while the local backend imports music files from a directory, which needs no
+//! network. The backend is chosen with `download.backend` in the config file.
+
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+};
+
+use eyre::{eyre, Result, WrapErr};
+use tokio::process::Command;
+use youtube_dl::{Playlist, SearchOptions, SingleVideo, YoutubeDl, YoutubeDlOutput};
+
+use super::progress::{self, OnProgress};
+use crate::{
+    config::{Backend, DownloadConfig},
+    data::tag,
+    library::scan,
+};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Finds and downloads videos. Backends which don't get their songs from YouTube describe
+/// them as videos too, with IDs of their own.
+pub trait Downloader: Send + Sync {
+    /// Searches for up to `count` videos
+    fn search<'a>(
+        &'a self,
+        query: &'a str,
+        count: usize,
+    ) -> BoxFuture<'a, Result<Vec<SingleVideo>>>;
+    /// Looks up the video at a URL
+    fn video_info<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<SingleVideo>>;
+    /// Lists the videos of a playlist without downloading them
+    fn playlist<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Playlist>>;
+    /// Where a video comes from, to show to the user
+    fn source_url(&self, video_id: &str) -> String;
+    /// The name and version of the program downloading the audio, recorded with each song
+    fn version(&self) -> BoxFuture<'_, Option<String>>;
+    /// Saves the audio of a video to `path`, in whichever format it comes in, reporting how
+    /// much was downloaded
+    fn download_audio<'a>(
+        &'a self,
+        video_id: &'a str,
+        path: &'a Path,
+        on_progress: &'a OnProgress,
+    ) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Creates the backend chosen in the config
+pub fn from_config(config: &DownloadConfig) -> Result<Arc<dyn Downloader>> {
+    let program = |name: &str| config.binary.clone().unwrap_or_else(|| PathBuf::from(name));
+    Ok(match config.backend {
+        Backend::YoutubeDl => Arc::new(YoutubeDlProgram::new(program("youtube-dl"))),
+        Backend::YtDlp => Arc::new(YoutubeDlProgram::new(program("yt-dlp"))),
+        Backend::Local => {
+            Arc::new(LocalDirectory::new(config.directory.clone().ok_or_else(
+                || eyre!("download.directory must be set to use the local backend"),
+            )?))
+        }
+    })
+}
+
+/// youtube-dl, or a program taking the same options such as its fork yt-dlp
+pub struct YoutubeDlProgram {
+    program: PathBuf,
+}
+
+impl YoutubeDlProgram {
+    pub fn new(program: PathBuf) -> Self {
+        Self { program }
+    }
+
+    /// Runs the program on a blocking thread, as the youtube_dl crate waits for it to exit
+    async fn run(&self, mut youtube_dl: YoutubeDl) -> Result<YoutubeDlOutput> {
+        let program = self.program.clone();
+        tokio::task::spawn_blocking(move || {
+            youtube_dl
+                .youtube_dl_path(&program)
+                .socket_timeout("10")
+                .run()
+                .map_err(|e| eyre!("{} failed: {}", program.display(), e))
+        })
+        .await?
+    }
+}
+
+impl Downloader for YoutubeDlProgram {
+    fn search<'a>(
+        &'a self,
+        query: &'a str,
+        count: usize,
+    ) -> BoxFuture<'a, Result<Vec<SingleVideo>>> {
+        Box::pin(async move {
+            let search_options = SearchOptions::youtube(query).with_count(count);
+            match self.run(YoutubeDl::search_for(&search_options)).await? {
+                YoutubeDlOutput::Playlist(playlist) => Ok(playlist.entries.unwrap_or_default()),
+                YoutubeDlOutput::SingleVideo(video) => Ok(vec![*video]),
+            }
+        })
+    }
+
+    fn video_info<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<SingleVideo>> {
+        Box::pin(async move {
+            match self.run(YoutubeDl::new(url)).await? {
+                YoutubeDlOutput::SingleVideo(video) => Ok(*video),
+                YoutubeDlOutput::Playlist(_) => Err(eyre!(
+                    "{} is a playlist. Use --playlist to download playlists",
+                    url
+                )),
+            }
+        })
+    }
+
+    fn playlist<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Playlist>> {
+        Box::pin(async move {
+            let mut youtube_dl = YoutubeDl::new(url);
+            youtube_dl.flat_playlist(true);
+            match self.run(youtube_dl).await? {
+                YoutubeDlOutput::Playlist(playlist) => Ok(*playlist),
+                YoutubeDlOutput::SingleVideo(_) => {
+                    Err(eyre!("{} is a single video, not a playlist", url))
+                }
+            }
+        })
+    }
+
+    fn source_url(&self, video_id: &str) -> String {
+        format!("https://youtu.be/{}", video_id)
+    }
+
+    fn version(&self) -> BoxFuture<'_, Option<String>> {
+        Box::pin(async move {
+            let output = Command::new(&self.program)
+                .arg("--version")
+                .output()
+                .await
+                .ok()
+                .filter(|output| output.status.success())?;
+            let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            let name = self.program.file_name()?.to_string_lossy();
+            (!version.is_empty()).then(|| format!("{} {}", name, version))
+        })
+    }
+
+    /// Downloads the audio as Opus. youtube-dl names the file itself from a template, which
+    /// gives `path` as long as it has the `opus` extension.
+    fn download_audio<'a>(
+        &'a self,
+        video_id: &'a str,
+        path: &'a Path,
+        on_progress: &'a OnProgress,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let output_template = super::output_template(path)?;
+            let youtube_args = [
+                "--newline",
+                "--audio-format",
+                "opus",
+                "--audio-quality",
+                "0",
+                "-x",
+                "--output",
+                output_template
+                    .to_str()
+                    .ok_or_else(|| eyre!("Can't convert path to str"))?,
+            ];
+            super::run(
+                Command::new(&self.program).args(youtube_args).arg(video_id),
+                &self.program.display().to_string(),
+                |_, line| {
+                    if let Some(fraction) = progress::parse_youtube_dl(line) {
+                        on_progress(fraction);
+                    }
+                },
+            )
+            .await
+        })
+    }
+}
+
+/// Imports music files from a directory. A file's ID is its path relative to the directory,
+/// and its title and channel come from its tags, or else from its file name and directory.
+/// Directories can be downloaded like playlists.
+pub struct LocalDirectory {
+    dir: PathBuf,
+}
+
+impl LocalDirectory {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// The path a URL, a path or an ID refers to
+    fn path(&self, url: &str) -> PathBuf {
+        self.dir.join(url.strip_prefix("file://").unwrap_or(url))
+    }
+
+    fn video(&self, path: &Path) -> Result<SingleVideo> {
+        let id = path
+            .strip_prefix(&self.dir)
+            .map_err(|_| eyre!("{} is not in {}", path.display(), self.dir.display()))?
+            .to_str()
+            .ok_or_else(|| eyre!("Can't convert path to str"))?
+            .to_string();
+        let tag = tag::read_from_path(path).ok();
+        let field = |key: &str| {
+            tag.as_ref()
+                .and_then(|tag| tag.get(key))
+                .and_then(|values| values.into_iter().next())
+        };
+        let directory_name = path
+            .parent()
+            .filter(|parent| *parent != self.dir)
+            .and_then(|parent| parent.file_name())
+            .map(|name| name.to_string_lossy().to_string());
+
+        Ok(SingleVideo {
+            title: field("TITLE").unwrap_or_else(|| {
+                path.file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_else(|| id.clone())
+            }),
+            channel: field("ARTIST").or(directory_name),
+            album: field("ALBUM"),
+            id,
+            ..Default::default()
+        })
+    }
+}
+
+impl Downloader for LocalDirectory {
+    /// Finds the files whose path, title or artist contain every word of the query
+    fn search<'a>(
+        &'a self,
+        query: &'a str,
+        count: usize,
+    ) -> BoxFuture<'a, Result<Vec<SingleVideo>>> {
+        Box::pin(async move {
+            let query = super::words(query);
+            let mut videos = vec![];
+            for path in scan::music_files(&self.dir)? {
+                let video = self.video(&path)?;
+                let text = super::words(&format!(
+                    "{} {} {}",
+                    video.id,
+                    video.title,
+                    video.channel.as_deref().unwrap_or_default()
+                ));
+                if query.iter().all(|word| text.contains(word)) {
+                    videos.push(video);
+                }
+                if videos.len() == count {
+                    break;
+                }
+            }
+            Ok(videos)
+        })
+    }
+
+    fn video_info<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<SingleVideo>> {
+        Box::pin(async move {
+            let path = self.path(url);
+            if path.is_dir() {
+                return Err(eyre!(
+                    "{} is a directory. Use --playlist to download directories",
+                    url
+                ));
+            }
+            if !path.is_file() {
+                return Err(eyre!("{} doesn't exist", path.display()));
+            }
+            self.video(&path)
+        })
+    }
+
+    fn playlist<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Playlist>> {
+        Box::pin(async move {
+            let path = self.path(url);
+            if !path.is_dir() {
+                return Err(eyre!("{} is not a directory", path.display()));
+            }
+            let entries = scan::music_files(&path)?
+                .iter()
+                .map(|file| self.video(file))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Playlist {
+                title: path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string()),
+                entries: Some(entries),
+                ..Default::default()
+            })
+        })
+    }
+
+    fn source_url(&self, video_id: &str) -> String {
+        self.dir.join(video_id).display().to_string()
+    }
+
+    fn version(&self) -> BoxFuture<'_, Option<String>> {
+        Box::pin(async { None })
+    }
+
+    /// Copies the file as it is, leaving conversion to ffmpeg which tells formats apart by
+    /// their contents
+    fn download_audio<'a>(
+        &'a self,
+        video_id: &'a str,
+        path: &'a Path,
+        on_progress: &'a OnProgress,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let source = self.dir.join(video_id);
+            tokio::fs::copy(&source, path)
+                .await
+                .wrap_err_with(|| format!("Can't copy {}", source.display()))?;
+            on_progress(1.0);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn search_local_directory() {
+        let dir = std::env::temp_dir().join("music-manager-backend-tests");
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        std::fs::create_dir_all(dir.join("YOASOBI")).unwrap();
+        std::fs::write(dir.join("YOASOBI/Yoru ni Kakeru.opus"), "OggS").unwrap();
+        std::fs::write(dir.join("YOASOBI/Gunjou.opus"), "OggS").unwrap();
+        std::fs::write(dir.join("Lemon.mp3"), "ID3").unwrap();
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+        let local = LocalDirectory::new(dir.clone());
+
+        let videos = local.search("yoasobi", 5).await.unwrap();
+        let ids = videos
+            .iter()
+            .map(|video| video.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            vec!["YOASOBI/Gunjou.opus", "YOASOBI/Yoru ni Kakeru.opus"]
+        );
+        assert_eq!(videos[1].title, "Yoru ni Kakeru");
+        assert_eq!(videos[1].channel.as_deref(), Some("YOASOBI"));
+        assert_eq!(local.search("yoasobi", 1).await.unwrap().len(), 1);
+        assert!(local.search("yoasobi lemon", 5).await.unwrap().is_empty());
+
+        let video = local.video_info("file://Lemon.mp3").await.unwrap();
+        assert_eq!(video.title, "Lemon");
+        assert_eq!(video.channel, None);
+        assert!(local.video_info("YOASOBI").await.is_err());
+        assert!(local.video_info("Missing.flac").await.is_err());
+
+        let playlist = local.playlist("YOASOBI").await.unwrap();
+        assert_eq!(playlist.title.as_deref(), Some("YOASOBI"));
+        assert_eq!(playlist.entries.unwrap().len(), 2);
+        assert!(local.playlist("Lemon.mp3").await.is_err());
+    }
+}
@@ -0,0 +1,304 @@
+//! Downloads lists of songs kept in a file. Text files hold one search or video URL per line,
+//! with blank lines and lines starting with `#` ignored. CSV files have a header row with
+//! `title`, `artist` and `album` columns, which fill in the tags, and may have a `url` or
+//! `query` column to use instead of searching for the artist and title.
+//!
+//! Every entry is looked up without asking anything, picking the best search result, and the
+//! outcome of each entry is written to a report.
+
+use std::{io::Write, path::Path};
+
+use eyre::{eyre, Result, WrapErr};
+use log::info;
+use serde::Serialize;
+
+use super::{backend::Downloader, profile::Profile, Enqueuer, Tags};
+use crate::data::{database::Database, job::JobState};
+
+/// Number of search results to pick the best match from
+const SEARCH_COUNT: usize = 5;
+
+/// A song to download
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Line number in the file, counting from 1
+    pub line: usize,
+    /// A search or video URL
+    pub query: String,
+    pub tags: Tags,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Downloaded,
+    /// Queued, but not downloaded yet
+    Queued,
+    /// Already in the library or the queue
+    Skipped,
+    Failed,
+}
+
+/// The outcome of one entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReportRecord {
+    pub line: usize,
+    pub query: String,
+    pub status: Status,
+    pub youtube_id: Option<String>,
+    pub file: Option<String>,
+    pub reason: Option<String>,
+    #[serde(skip)]
+    job_id: Option<usize>,
+}
+
+/// Reads the entries of a list, telling CSV files apart by their extension
+pub fn read(path: &Path) -> Result<Vec<Entry>> {
+    let contents =
+        std::fs::read_to_string(path).wrap_err_with(|| format!("Can't read {}", path.display()))?;
+    let is_csv = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+    if is_csv {
+        read_csv(&contents)
+    } else {
+        Ok(read_text(&contents))
+    }
+}
+
+fn read_text(contents: &str) -> Vec<Entry> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line, query)| Entry {
+            line,
+            query: query.to_string(),
+            tags: Tags::default(),
+        })
+        .collect()
+}
+
+fn read_csv(contents: &str) -> Result<Vec<Entry>> {
+    let mut reader = csv::Reader::from_reader(contents.as_bytes());
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|header| header.trim().eq_ignore_ascii_case(name))
+    };
+    let (title, artist, album) = (column("title"), column("artist"), column("album"));
+    let query = column("url").or_else(|| column("query"));
+    if title.is_none() && query.is_none() {
+        return Err(eyre!("The list needs a title, url or query column"));
+    }
+
+    let mut entries = vec![];
+    for record in reader.records() {
+        let record = record?;
+        let line = record
+            .position()
+            .map_or(0, |position| position.line() as usize);
+        let field = |index: Option<usize>| {
+            index
+                .and_then(|index| record.get(index))
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+
+        let tags = Tags {
+            title: field(title),
+            artists: field(artist).map(|artist| vec![artist]),
+            album: field(album),
+            ..Default::default()
+        };
+        let query = match field(query) {
+            Some(query) => query,
+            None => {
+                let words = [field(artist), tags.title.clone()];
+                let words = words.iter().flatten().cloned().collect::<Vec<_>>();
+                if words.is_empty() {
+                    continue;
+                }
+                words.join(" ")
+            }
+        };
+        entries.push(Entry { line, query, tags });
+    }
+    Ok(entries)
+}
+
+/// Looks up every entry and queues the videos which are not in the library or the queue yet
+pub async fn enqueue(
+    downloader: &dyn Downloader,
+    database: &Database,
+    music_dir: &Path,
+    profile: Profile,
+    entries: &[Entry],
+) -> Result<Vec<ReportRecord>> {
+    let mut enqueuer = Enqueuer::new(database, music_dir, profile)?;
+    let mut report = vec![];
+
+    for entry in entries {
+        let mut record = ReportRecord {
+            line: entry.line,
+            query: entry.query.clone(),
+            status: Status::Failed,
+            youtube_id: None,
+            file: None,
+            reason: None,
+            job_id: None,
+        };
+
+        info!("Looking up {}...", entry.query);
+        let video = if super::is_url(&entry.query) {
+            downloader.video_info(&entry.query).await
+        } else {
+            downloader
+                .search(&entry.query, SEARCH_COUNT)
+                .await
+                .and_then(|videos| {
+                    super::best_match(&entry.query, &videos)
+                        .map(|index| videos[index].clone())
+                        .ok_or_else(|| eyre!("Nothing was found"))
+                })
+        };
+
+        match video {
+            Ok(video) => {
+                record.youtube_id = Some(video.id.clone());
+                if enqueuer.is_known(&video.id)? {
+                    record.status = Status::Skipped;
+                    record.reason = Some(format!(
+                        "{} is already in the library or the queue",
+                        video.title
+                    ));
+                } else {
+                    let job = enqueuer.enqueue(&video, entry.tags.clone())?;
+                    record.status = Status::Queued;
+                    record.file = job.file_path.to_str().map(str::to_string);
+                    record.job_id = job.id;
+                }
+            }
+            Err(e) => record.reason = Some(e.to_string()),
+        }
+        report.push(record);
+    }
+
+    Ok(report)
+}
+
+/// Fills in how the queued entries went once the queue has run
+pub fn finish(database: &Database, report: &mut [ReportRecord]) -> Result<()> {
+    let jobs = database.download_jobs()?;
+    for record in report.iter_mut() {
+        let job = match jobs
+            .iter()
+            .find(|job| job.id.is_some() && job.id == record.job_id)
+        {
+            Some(job) => job,
+            None => continue,
+        };
+        match job.state {
+            JobState::Done => record.status = Status::Downloaded,
+            JobState::Failed => {
+                record.status = Status::Failed;
+                record.reason = job.error.clone();
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Writes the report as CSV
+pub fn write_report<W>(writer: W, report: &[ReportRecord]) -> Result<()>
+where
+    W: Write,
+{
+    let mut writer = csv::Writer::from_writer(writer);
+    for record in report {
+        writer.serialize(record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_text_lists() {
+        let entries = read_text(
+            "# Wishlist\n\
+            yoasobi yoru ni kakeru\n\
+            \n   https://youtu.be/Y4nEEZwckuU  \n",
+        );
+        assert_eq!(
+            entries,
+            vec![
+                Entry {
+                    line: 2,
+                    query: "yoasobi yoru ni kakeru".to_string(),
+                    tags: Tags::default(),
+                },
+                Entry {
+                    line: 4,
+                    query: "https://youtu.be/Y4nEEZwckuU".to_string(),
+                    tags: Tags::default(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn read_csv_lists() {
+        let entries = read_csv(
+            "Title,Artist,Album,URL\n\
+            Yoru ni Kakeru,YOASOBI,THE BOOK,\n\
+            Gunjou,,,https://youtu.be/Y4nEEZwckuU\n\
+            ,,Nothing to search for,\n",
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].line, 2);
+        assert_eq!(entries[0].query, "YOASOBI Yoru ni Kakeru");
+        assert_eq!(
+            entries[0].tags,
+            Tags {
+                title: Some("Yoru ni Kakeru".to_string()),
+                artists: Some(vec!["YOASOBI".to_string()]),
+                album: Some("THE BOOK".to_string()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(entries[1].query, "https://youtu.be/Y4nEEZwckuU");
+        assert_eq!(entries[1].tags.artists, None);
+
+        assert!(read_csv("artist,album\nYOASOBI,THE BOOK\n").is_err());
+    }
+
+    #[test]
+    fn write_csv_report() {
+        let report = vec![ReportRecord {
+            line: 2,
+            query: "yoasobi gunjou".to_string(),
+            status: Status::Skipped,
+            youtube_id: Some("Y4nEEZwckuU".to_string()),
+            file: None,
+            reason: Some("Gunjou is already in the library or the queue".to_string()),
+            job_id: None,
+        }];
+        let mut output = vec![];
+        write_report(&mut output, &report).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "line,query,status,youtube_id,file,reason\n\
+            2,yoasobi gunjou,skipped,Y4nEEZwckuU,,Gunjou is already in the library or the queue\n"
+        );
+    }
+}
@@ -0,0 +1,732 @@
+//! Downloads songs from YouTube. Videos are downloaded with one of the backends in `backend`,
+//! converted with ffmpeg as their `profile` says, then tagged and added to the library. See
+//! `queue` for how jobs are run.
+
+use std::{
+    cmp::Reverse,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use eyre::{eyre, Result};
+use strum_macros::{Display, EnumString, EnumVariantNames};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+};
+use youtube_dl::SingleVideo;
+
+use self::{
+    profile::Profile,
+    progress::{FfmpegProgress, OnProgress, Output},
+};
+use crate::data::{
+    database::Database,
+    job::{DownloadJob, JobState},
+    tag,
+};
+
+pub mod backend;
+pub mod batch;
+pub mod playlist;
+pub mod profile;
+pub mod progress;
+pub mod prompt;
+pub mod queue;
+pub mod silence;
+pub mod split;
+pub mod staging;
+pub mod title;
+
+/// Words in titles of versions of a song people rarely want unless they ask for them
+const UNWANTED_VERSIONS: &[&str] = &[
+    "live",
+    "cover",
+    "karaoke",
+    "instrumental",
+    "reaction",
+    "remix",
+    "slowed",
+    "nightcore",
+];
+
+/// What `download` does with a video which is in the library already
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, EnumVariantNames, Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ExistingSong {
+    /// Leave the song as it is
+    Skip,
+    /// Tag the song's file again without downloading it
+    Retag,
+    /// Download the song again over its file
+    Redownload,
+    /// Download the song again as another song
+    Duplicate,
+}
+
+impl ExistingSong {
+    pub fn description(&self) -> &'static str {
+        match self {
+            ExistingSong::Skip => "Skip it",
+            ExistingSong::Retag => "Tag the file again",
+            ExistingSong::Redownload => "Download it again over the file",
+            ExistingSong::Duplicate => "Download it again as a duplicate",
+        }
+    }
+}
+
+/// Genres recognised among the keywords of a video, written as the GENRE tag
+const GENRES: &[&str] = &[
+    "Pop",
+    "J-Pop",
+    "K-Pop",
+    "Rock",
+    "J-Rock",
+    "Metal",
+    "Punk",
+    "Indie",
+    "Hip Hop",
+    "Rap",
+    "R&B",
+    "Soul",
+    "Funk",
+    "Jazz",
+    "Blues",
+    "Classical",
+    "Electronic",
+    "EDM",
+    "House",
+    "Techno",
+    "Trance",
+    "Dubstep",
+    "Drum and Bass",
+    "Ambient",
+    "Folk",
+    "Country",
+    "Reggae",
+    "Latin",
+    "Anime",
+    "Vocaloid",
+    "Soundtrack",
+];
+
+/// Tags to give a downloaded song. Missing tags come from the music metadata youtube-dl found
+/// for the video, or else are guessed from its title and channel.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artists: Option<Vec<String>>,
+    pub album: Option<String>,
+    /// A year or YYYY-MM-DD
+    pub release_date: Option<String>,
+    pub track_number: Option<u32>,
+    pub genre: Option<String>,
+}
+
+impl Tags {
+    /// The tags youtube-dl knows for a video. YouTube Music uploads come with their track,
+    /// artist and album, for other videos the title and artists are parsed from the title.
+    pub fn from_video(video: &SingleVideo) -> Tags {
+        let parsed = title::parse(
+            &video.title,
+            video.channel.as_deref().or(video.uploader.as_deref()),
+        );
+        let artists = match &video.artist {
+            Some(artist) => artist
+                .split(", ")
+                .map(|artist| artist.trim().to_string())
+                .filter(|artist| !artist.is_empty())
+                .collect(),
+            None => parsed.artists,
+        };
+        Tags {
+            title: Some(video.track.clone().unwrap_or(parsed.title)),
+            artists: (!artists.is_empty()).then_some(artists),
+            album: video.album.clone(),
+            release_date: release_date(video),
+            track_number: video
+                .track_number
+                .as_deref()
+                .and_then(tag::parse_track_number),
+            genre: video.genre.clone().or_else(|| {
+                video.tags.iter().flatten().flatten().find_map(|keyword| {
+                    GENRES
+                        .iter()
+                        .find(|genre| genre.eq_ignore_ascii_case(keyword.trim()))
+                        .map(|genre| genre.to_string())
+                })
+            }),
+        }
+    }
+
+    /// Fills in the missing tags from others
+    pub fn or(self, other: Tags) -> Tags {
+        Tags {
+            title: self.title.or(other.title),
+            artists: self.artists.or(other.artists),
+            album: self.album.or(other.album),
+            release_date: self.release_date.or(other.release_date),
+            track_number: self.track_number.or(other.track_number),
+            genre: self.genre.or(other.genre),
+        }
+    }
+}
+
+/// The release date of a video's song, or its year, or else the day it was uploaded
+fn release_date(video: &SingleVideo) -> Option<String> {
+    // youtube-dl writes dates as YYYYMMDD
+    let date = |date: &str| match (date.get(..4), date.get(4..6), date.get(6..8)) {
+        (Some(year), Some(month), Some(day)) if date.len() == 8 => {
+            Some(format!("{}-{}-{}", year, month, day))
+        }
+        _ => None,
+    };
+    video
+        .release_date
+        .as_deref()
+        .and_then(date)
+        .or_else(|| video.release_year.map(|year| year.to_string()))
+        .or_else(|| video.upload_date.as_deref().and_then(date))
+}
+
+/// Jobs which are waiting in the queue or being worked on
+pub fn pending_jobs(database: &Database) -> Result<Vec<DownloadJob>> {
+    Ok(database
+        .download_jobs()?
+        .into_iter()
+        .filter(|job| !matches!(job.state, JobState::Done | JobState::Failed))
+        .collect())
+}
+
+/// Queues videos which are not in the library or the queue yet, giving every new job a file
+/// name no other song or job uses
+pub struct Enqueuer<'a> {
+    database: &'a Database,
+    music_dir: PathBuf,
+    profile: Profile,
+    known_ids: Vec<String>,
+    taken: Vec<PathBuf>,
+}
+
+impl<'a> Enqueuer<'a> {
+    pub fn new(database: &'a Database, music_dir: &Path, profile: Profile) -> Result<Self> {
+        let pending = pending_jobs(database)?;
+        Ok(Self {
+            database,
+            music_dir: music_dir.to_path_buf(),
+            profile,
+            taken: pending.iter().flat_map(DownloadJob::destinations).collect(),
+            known_ids: pending.into_iter().map(|job| job.youtube_id).collect(),
+        })
+    }
+
+    /// Whether the video is in the library or waiting in the queue
+    pub fn is_known(&self, video_id: &str) -> Result<bool> {
+        Ok(self.known_ids.iter().any(|id| id == video_id)
+            || !self
+                .database
+                .query_songs_by_youtube_id(video_id)?
+                .is_empty())
+    }
+
+    /// Adds a video to the queue
+    pub fn enqueue(&mut self, video: &SingleVideo, tags: Tags) -> Result<DownloadJob> {
+        let mut job = DownloadJob::new(
+            &video.id,
+            library_path(
+                &self.music_dir,
+                &video.title,
+                &video.id,
+                self.profile,
+                &self.taken,
+            ),
+        );
+        let tags = tags.or(Tags::from_video(video));
+        job.title = tags.title;
+        job.artists = tags.artists;
+        job.album = tags.album;
+        job.release_date = tags.release_date;
+        job.track_number = tags.track_number;
+        job.genre = tags.genre;
+        job.thumbnail_url = thumbnail_url(video);
+        job.source_bitrate = source_bitrate(video);
+        job.id = Some(self.database.insert_download_job(&job)?);
+
+        self.taken.push(job.file_path.clone());
+        self.known_ids.push(video.id.clone());
+        Ok(job)
+    }
+}
+
+/// Whether a line names a video by its URL rather than searching for it
+pub fn is_url(query: &str) -> bool {
+    ["https://", "http://", "file://"]
+        .iter()
+        .any(|scheme| query.starts_with(scheme))
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Picks the search result most likely to be the song searched for. Results matching more
+/// words of the query rank higher. Other versions of the song, such as covers or live
+/// performances, rank lower unless the query asks for them. Ties go to the result YouTube
+/// ranked first.
+pub fn best_match(query: &str, videos: &[SingleVideo]) -> Option<usize> {
+    let query = words(query);
+    videos
+        .iter()
+        .enumerate()
+        .max_by_key(|(index, video)| {
+            let text = words(&format!(
+                "{} {}",
+                video.title,
+                video.channel.as_deref().unwrap_or_default()
+            ));
+            let matched = query.iter().filter(|word| text.contains(word)).count() as i64;
+            let unwanted = UNWANTED_VERSIONS
+                .iter()
+                .filter(|version| {
+                    text.iter().any(|word| word == *version)
+                        && !query.iter().any(|word| word == *version)
+                })
+                .count() as i64;
+            (matched - 2 * unwanted, Reverse(*index))
+        })
+        .map(|(index, _)| index)
+}
+
+/// Turns a video title into a file name, replacing characters which can't or shouldn't be
+/// in one
+pub fn sanitize_file_name(title: &str) -> String {
+    title.replace('/', "_").replace(':', " -")
+}
+
+/// Where a video is saved in the library. Names already in use, on disk or in the given list,
+/// get the video's ID appended so no song is overwritten by another one with the same title.
+/// Copies of the same video are counted, as in `Title [id] (2)`.
+pub fn library_path(
+    music_dir: &Path,
+    title: &str,
+    video_id: &str,
+    profile: Profile,
+    taken: &[PathBuf],
+) -> PathBuf {
+    let file_name = sanitize_file_name(title);
+    let is_free = |path: &PathBuf| !path.exists() && !taken.contains(path);
+    let path = music_dir.join(format!("{}.{}", file_name, profile.extension()));
+    if is_free(&path) {
+        return path;
+    }
+    let path = music_dir.join(format!(
+        "{} [{}].{}",
+        file_name,
+        video_id,
+        profile.extension()
+    ));
+    if is_free(&path) {
+        return path;
+    }
+    (2..)
+        .map(|copy| {
+            music_dir.join(format!(
+                "{} [{}] ({}).{}",
+                file_name,
+                video_id,
+                copy,
+                profile.extension()
+            ))
+        })
+        .find(is_free)
+        .unwrap()
+}
+
+/// Fills in a file name template such as `{artist} - {title}`. The fields are the song's
+/// `title`, `artist` (the first one), `artists` and `album`, and the video's `id`,
+/// `video_title` and `channel`. Fields without a value become "Unknown".
+pub fn render_file_name(template: &str, job: &DownloadJob, video: &SingleVideo) -> Result<String> {
+    let mut file_name = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '{' {
+            file_name.push(c);
+            continue;
+        }
+
+        let mut field = String::new();
+        loop {
+            match chars.next() {
+                Some('}') => break,
+                Some(c) => field.push(c),
+                None => {
+                    return Err(eyre!(
+                        "Unterminated field in file name template: {}",
+                        template
+                    ))
+                }
+            }
+        }
+        let value = match field.as_str() {
+            "title" => job.title.clone(),
+            "artist" => job
+                .artists
+                .as_ref()
+                .and_then(|artists| artists.first().cloned()),
+            "artists" => job.artists.as_ref().map(|artists| artists.join(", ")),
+            "album" => job.album.clone(),
+            "id" => Some(video.id.clone()),
+            "video_title" => Some(video.title.clone()),
+            "channel" => video.channel.clone(),
+            _ => return Err(eyre!("Unknown field in file name template: {{{}}}", field)),
+        };
+        file_name.push_str(value.as_deref().unwrap_or("Unknown"));
+    }
+
+    let file_name = sanitize_file_name(file_name.trim());
+    if file_name.is_empty() {
+        return Err(eyre!(
+            "The file name template {} gave an empty name",
+            template
+        ));
+    }
+    Ok(file_name)
+}
+
+/// The URL of a video's thumbnail. Entries of flat playlists may only list thumbnails in
+/// several sizes, the largest coming last.
+pub fn thumbnail_url(video: &SingleVideo) -> Option<String> {
+    video.thumbnail.clone().or_else(|| {
+        video
+            .thumbnails
+            .iter()
+            .flatten()
+            .rev()
+            .find_map(|thumbnail| thumbnail.url.clone())
+    })
+}
+
+/// The bitrate of the video's audio in kbit/s, if youtube-dl knows it
+pub fn source_bitrate(video: &SingleVideo) -> Option<u32> {
+    video
+        .abr
+        .filter(|abr| *abr > 0.0)
+        .map(|abr| abr.round() as u32)
+}
+
+/// The youtube-dl output template for downloading next to `path`, keeping its file stem.
+/// youtube-dl fills in the extension, and treats % as the start of a template field.
+pub fn output_template(path: &Path) -> Result<PathBuf> {
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| eyre!("Can't convert path to str"))?
+        .replace('%', "%%");
+    Ok(path.with_file_name(format!("{}.%(ext)s", stem)))
+}
+
+/// Converts a file as the profile says, then deletes it. Files already in the profile's format
+/// are moved instead. An existing output file is overwritten.
+pub async fn convert(
+    input_file: &Path,
+    output_file: &Path,
+    profile: Profile,
+    on_progress: &OnProgress,
+) -> Result<()> {
+    if profile.keeps(input_file) {
+        std::fs::rename(input_file, output_file)?;
+        on_progress(1.0);
+        return Ok(());
+    }
+
+    let input = input_file
+        .to_str()
+        .ok_or_else(|| eyre!("Can't convert path to str"))?;
+    let output = output_file
+        .to_str()
+        .ok_or_else(|| eyre!("Can't convert path to str"))?;
+    let mut progress = FfmpegProgress::default();
+    run(
+        Command::new("ffmpeg")
+            .args(["-y", "-nostats", "-progress", "pipe:1", "-i", input])
+            .args(profile.ffmpeg_args())
+            .arg(output),
+        "ffmpeg",
+        |output, line| {
+            if let Some(fraction) = progress.line(output, line) {
+                on_progress(fraction);
+            }
+        },
+    )
+    .await?;
+    std::fs::remove_file(input_file)?;
+    Ok(())
+}
+
+/// Cuts the part of a file between `start` and `end`, in milliseconds, converting it as the
+/// profile says. Without an end the part lasts until the end of the file. The input is kept,
+/// as the other tracks are cut from it too.
+pub async fn cut(
+    input_file: &Path,
+    output_file: &Path,
+    profile: Profile,
+    start: u64,
+    end: Option<u64>,
+) -> Result<()> {
+    let input = input_file
+        .to_str()
+        .ok_or_else(|| eyre!("Can't convert path to str"))?;
+    let output = output_file
+        .to_str()
+        .ok_or_else(|| eyre!("Can't convert path to str"))?;
+    let seconds = |ms: u64| format!("{}.{:03}", ms / 1000, ms % 1000);
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-y", "-nostats", "-i", input])
+        .args(["-ss", &seconds(start)]);
+    if let Some(end) = end {
+        command.args(["-to", &seconds(end)]);
+    }
+    if profile.keeps(input_file) {
+        command.args(["-vn", "-codec:a", "copy"]);
+    } else {
+        command.args(profile.ffmpeg_args());
+    }
+    run(command.arg(output), "ffmpeg", |_, _| {}).await
+}
+
+/// Runs an external program to completion, handing each line it prints to `on_line` as it
+/// comes. Its output is kept out of the terminal, as several may run at once, but the last
+/// line of its error output is reported if it fails. The program is killed if the returned
+/// future is dropped before it finishes, as when it times out.
+pub async fn run(
+    command: &mut Command,
+    name: &str,
+    mut on_line: impl FnMut(Output, &str),
+) -> Result<()> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| eyre!("Can't run {}: {}", name, e))?;
+    let mut stdout = BufReader::new(child.stdout.take().unwrap()).split(b'\n');
+    let mut stderr = BufReader::new(child.stderr.take().unwrap()).split(b'\n');
+
+    let mut reason = String::new();
+    let (mut stdout_open, mut stderr_open) = (true, true);
+    while stdout_open || stderr_open {
+        tokio::select! {
+            line = stdout.next_segment(), if stdout_open => match line? {
+                Some(line) => on_line(Output::Stdout, &String::from_utf8_lossy(&line)),
+                None => stdout_open = false,
+            },
+            line = stderr.next_segment(), if stderr_open => match line? {
+                Some(line) => {
+                    let line = String::from_utf8_lossy(&line);
+                    if !line.trim().is_empty() {
+                        reason = format!(": {}", line.trim());
+                    }
+                    on_line(Output::Stderr, &line);
+                }
+                None => stderr_open = false,
+            },
+        }
+    }
+
+    let status = child.wait().await?;
+    if status.success() {
+        return Ok(());
+    }
+    match status.code() {
+        Some(code) => Err(eyre!("{} failed with code {}{}", name, code, reason)),
+        None => Err(eyre!("{} was killed{}", name, reason)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub fn video(id: &str, title: &str, channel: &str) -> SingleVideo {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "title": title,
+            "channel": channel,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn pick_best_match() {
+        let results = [
+            video(
+                "live",
+                "YOASOBI - Yoru ni Kakeru (Live at Budokan)",
+                "YOASOBI",
+            ),
+            video("other", "Night Drive Mix", "Lofi Girl"),
+            video(
+                "official",
+                "YOASOBI - Yoru ni Kakeru (Official Music Video)",
+                "Ayase / YOASOBI",
+            ),
+            video("cover", "Yoru ni Kakeru - YOASOBI (Cover)", "Someone"),
+        ];
+        assert_eq!(best_match("yoasobi yoru ni kakeru", &results), Some(2));
+        assert_eq!(best_match("YOASOBI Yoru ni Kakeru live", &results), Some(0));
+        // Ties go to the first result
+        assert_eq!(best_match("something else", &results[1..]), Some(0));
+        assert_eq!(best_match("anything", &[]), None);
+    }
+
+    #[test]
+    fn render_file_names() {
+        let video = video("x8VYWazR5mE", "YOASOBI「夜に駆ける」", "Ayase / YOASOBI");
+        let mut job = DownloadJob::new("x8VYWazR5mE", "/music/a.flac".into());
+        job.title = Some("Yoru ni Kakeru".to_string());
+        job.artists = Some(vec!["YOASOBI".to_string(), "Ayase".to_string()]);
+
+        let render = |template: &str| render_file_name(template, &job, &video);
+        assert_eq!(
+            render("{artist} - {title}").unwrap(),
+            "YOASOBI - Yoru ni Kakeru"
+        );
+        assert_eq!(
+            render("{artists}: {title} [{id}]").unwrap(),
+            "YOASOBI, Ayase - Yoru ni Kakeru [x8VYWazR5mE]"
+        );
+        assert_eq!(
+            render("{album}/{channel}").unwrap(),
+            "Unknown_Ayase _ YOASOBI"
+        );
+        assert!(render("{artist} - {name}").is_err());
+        assert!(render("{artist").is_err());
+        assert!(render(" ").is_err());
+    }
+
+    #[test]
+    fn library_paths() {
+        let dir = std::env::temp_dir().join("music-manager-library-path-tests");
+        std::fs::create_dir_all(&dir).unwrap();
+        let existing = dir.join("Taken.flac");
+        std::fs::write(&existing, "fLaC").unwrap();
+
+        assert_eq!(
+            library_path(&dir, "AC/DC: Live", "id", Profile::Flac, &[]),
+            dir.join("AC_DC - Live.flac")
+        );
+        assert_eq!(
+            library_path(&dir, "Taken", "x8VYWazR5mE", Profile::Flac, &[]),
+            dir.join("Taken [x8VYWazR5mE].flac")
+        );
+        // Only files of the same format are in the way
+        assert_eq!(
+            library_path(&dir, "Taken", "x8VYWazR5mE", Profile::Mp3V0, &[]),
+            dir.join("Taken.mp3")
+        );
+        assert_eq!(
+            library_path(
+                &dir,
+                "Queued",
+                "id",
+                Profile::Flac,
+                &[dir.join("Queued.flac")]
+            ),
+            dir.join("Queued [id].flac")
+        );
+        // Copies of a video which has its ID in the name already are counted
+        let taken = [dir.join("Taken [id].flac"), dir.join("Taken [id] (2).flac")];
+        assert_eq!(
+            library_path(&dir, "Taken", "id", Profile::Flac, &taken),
+            dir.join("Taken [id] (3).flac")
+        );
+    }
+
+    #[test]
+    fn output_templates() {
+        assert_eq!(
+            output_template(Path::new("/music/Yoru ni Kakeru.flac")).unwrap(),
+            Path::new("/music/Yoru ni Kakeru.%(ext)s")
+        );
+        assert_eq!(
+            output_template(Path::new("/music/100% Night.flac")).unwrap(),
+            Path::new("/music/100%% Night.%(ext)s")
+        );
+    }
+
+    #[test]
+    fn tags_from_video() {
+        let music: SingleVideo = serde_json::from_value(serde_json::json!({
+            "id": "x8VYWazR5mE",
+            "title": "Yoru ni Kakeru",
+            "channel": "YOASOBI - Topic",
+            "track": "夜に駆ける",
+            "artist": "YOASOBI, Ayase",
+            "album": "THE BOOK",
+            "release_year": 2021,
+            "upload_date": "20191215",
+            "track_number": "1",
+            "tags": ["YOASOBI", "j-pop"],
+        }))
+        .unwrap();
+        assert_eq!(
+            Tags::from_video(&music),
+            Tags {
+                title: Some("夜に駆ける".to_string()),
+                artists: Some(vec!["YOASOBI".to_string(), "Ayase".to_string()]),
+                album: Some("THE BOOK".to_string()),
+                release_date: Some("2021".to_string()),
+                track_number: Some(1),
+                genre: Some("J-Pop".to_string()),
+            }
+        );
+
+        // Without music metadata, the title is parsed
+        let mut upload = video(
+            "x8VYWazR5mE",
+            "YOASOBI「夜に駆ける」Official Music Video",
+            "Ayase / YOASOBI",
+        );
+        upload.upload_date = Some("20191215".to_string());
+        let tags = Tags::from_video(&upload);
+        assert_eq!(tags.title.as_deref(), Some("夜に駆ける"));
+        assert_eq!(tags.artists, Some(vec!["YOASOBI".to_string()]));
+        assert_eq!(tags.album, None);
+        assert_eq!(tags.release_date.as_deref(), Some("2019-12-15"));
+        assert_eq!(tags.genre, None);
+
+        let given = Tags {
+            title: Some("Yoru ni Kakeru".to_string()),
+            ..Default::default()
+        };
+        let merged = given.or(Tags::from_video(&music));
+        assert_eq!(merged.title.as_deref(), Some("Yoru ni Kakeru"));
+        assert_eq!(merged.album.as_deref(), Some("THE BOOK"));
+    }
+
+    #[tokio::test]
+    async fn run_reports_lines() {
+        let mut lines = vec![];
+        let result = run(
+            Command::new("sh").args([
+                "-c",
+                "echo '[download]  50.0% of 1.00MiB'; echo 'ERROR: gone' >&2; exit 3",
+            ]),
+            "sh",
+            |output, line| lines.push((output, line.to_string())),
+        )
+        .await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "sh failed with code 3: ERROR: gone"
+        );
+        assert!(lines.contains(&(Output::Stdout, "[download]  50.0% of 1.00MiB".to_string())));
+        assert!(lines.contains(&(Output::Stderr, "ERROR: gone".to_string())));
+    }
+}
@@ -0,0 +1,131 @@
+//! Downloads whole YouTube playlists. Videos which are already in the library or the queue are
+//! skipped, and the playlist can be saved to the database and as an M3U file in its original
+//! order.
+
+use std::path::{Path, PathBuf};
+
+use eyre::Result;
+use youtube_dl::SingleVideo;
+
+use super::{profile::Profile, Enqueuer, Tags};
+use crate::data::{database::Database, job::DownloadJob, song::Song};
+
+/// Titles YouTube gives to videos which can't be watched
+const UNAVAILABLE_TITLES: &[&str] = &["[Deleted video]", "[Private video]"];
+
+/// The videos of a playlist, sorted by what happened to them
+#[derive(Debug, Default)]
+pub struct Enqueued {
+    pub queued: Vec<DownloadJob>,
+    /// Videos which are in the library or the queue already
+    pub known: Vec<SingleVideo>,
+    /// Deleted and private videos
+    pub unavailable: Vec<SingleVideo>,
+}
+
+/// Adds the videos of a playlist which are not in the library or the queue to the queue
+pub fn enqueue(
+    database: &Database,
+    music_dir: &Path,
+    profile: Profile,
+    entries: &[SingleVideo],
+) -> Result<Enqueued> {
+    let mut enqueuer = Enqueuer::new(database, music_dir, profile)?;
+    let mut enqueued = Enqueued::default();
+    for video in entries {
+        if UNAVAILABLE_TITLES.contains(&video.title.as_str()) {
+            enqueued.unavailable.push(video.clone());
+        } else if enqueuer.is_known(&video.id)? {
+            enqueued.known.push(video.clone());
+        } else {
+            enqueued
+                .queued
+                .push(enqueuer.enqueue(video, Tags::default())?);
+        }
+    }
+    Ok(enqueued)
+}
+
+/// Saves the playlist to the database and writes it to `<name>.m3u` in the music directory.
+/// Videos which are not in the library, e.g. because their download failed, are left out.
+/// Returns the songs in the playlist and the path of the M3U file.
+pub fn save(
+    database: &Database,
+    music_dir: &Path,
+    name: &str,
+    youtube_id: Option<&str>,
+    entries: &[SingleVideo],
+) -> Result<(Vec<Song>, PathBuf)> {
+    let mut songs = vec![];
+    for video in entries {
+        if let Some(song) = database
+            .query_songs_by_youtube_id(&video.id)?
+            .into_iter()
+            .next()
+        {
+            songs.push(song);
+        }
+    }
+
+    let song_ids = songs.iter().filter_map(|song| song.id).collect::<Vec<_>>();
+    database.save_playlist(name, youtube_id, &song_ids)?;
+
+    let path = music_dir.join(format!("{}.m3u", super::sanitize_file_name(name)));
+    write_m3u(&path, &songs)?;
+    Ok((songs, path))
+}
+
+/// Writes an extended M3U playlist. Paths are relative to the playlist when possible.
+pub fn write_m3u(path: &Path, songs: &[Song]) -> Result<()> {
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut contents = String::from("#EXTM3U\n");
+    for song in songs {
+        let name = match (
+            song.artists.as_ref().and_then(|artists| artists.first()),
+            &song.title,
+        ) {
+            (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+            (None, Some(title)) => title.clone(),
+            _ => song.file_name.clone(),
+        };
+        let file = song.file_path.strip_prefix(base).unwrap_or(&song.file_path);
+        contents.push_str(&format!("#EXTINF:-1,{}\n{}\n", name, file.display()));
+    }
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_extended_m3u() {
+        let dir = std::env::temp_dir().join("music-manager-playlist-tests");
+        std::fs::create_dir_all(&dir).unwrap();
+        let songs = vec![
+            Song {
+                file_path: dir.join("Yoasobi/Yoru ni Kakeru.flac"),
+                title: Some("Yoru ni Kakeru".to_string()),
+                artists: Some(vec!["YOASOBI".to_string(), "Ayase".to_string()]),
+                ..Default::default()
+            },
+            Song {
+                file_path: "/elsewhere/Untitled.flac".into(),
+                file_name: "Untitled.flac".to_string(),
+                ..Default::default()
+            },
+        ];
+
+        let path = dir.join("Mix.m3u");
+        write_m3u(&path, &songs).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "#EXTM3U\n\
+            #EXTINF:-1,YOASOBI - Yoru ni Kakeru\n\
+            Yoasobi/Yoru ni Kakeru.flac\n\
+            #EXTINF:-1,Untitled.flac\n\
+            /elsewhere/Untitled.flac\n"
+        );
+    }
+}
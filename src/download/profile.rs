@@ -0,0 +1,102 @@
+//! Transcoding profiles, which choose the format downloaded songs are saved in. A profile sets
+//! the ffmpeg arguments, the container and the tag format. Downloads which already are in the
+//! profile's format are kept as they are rather than transcoded again.
+
+use std::path::Path;
+
+use eyre::{eyre, Result};
+use serde::Deserialize;
+use strum_macros::{Display, EnumString, EnumVariantNames};
+
+use crate::data::tag::{self, AudioFormat, BoxedTag};
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, EnumString, EnumVariantNames, Display,
+)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum Profile {
+    /// The Opus audio YouTube serves, without transcoding it
+    Opus,
+    /// Lossless FLAC at the highest compression level
+    #[default]
+    Flac,
+    /// MP3 with LAME's highest quality variable bitrate
+    #[strum(serialize = "mp3-v0")]
+    #[serde(rename = "mp3-v0")]
+    Mp3V0,
+    /// Ogg Vorbis at quality 6, around 192 kbit/s
+    #[strum(serialize = "ogg-q6")]
+    #[serde(rename = "ogg-q6")]
+    OggQ6,
+}
+
+impl Profile {
+    /// The format of the files the profile writes, which chooses their tag format
+    pub fn format(&self) -> AudioFormat {
+        match self {
+            Profile::Opus => AudioFormat::Opus,
+            Profile::Flac => AudioFormat::Flac,
+            Profile::Mp3V0 => AudioFormat::Mp3,
+            Profile::OggQ6 => AudioFormat::Vorbis,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        self.format().extension()
+    }
+
+    /// The profile writing files with this path's extension
+    pub fn from_path(path: &Path) -> Option<Profile> {
+        let extension = path.extension()?.to_str()?;
+        [Profile::Opus, Profile::Flac, Profile::Mp3V0, Profile::OggQ6]
+            .into_iter()
+            .find(|profile| profile.extension().eq_ignore_ascii_case(extension))
+    }
+
+    /// The ffmpeg arguments which encode the audio, placed between the input and output files
+    pub fn ffmpeg_args(&self) -> &'static [&'static str] {
+        match self {
+            Profile::Opus => &["-vn", "-codec:a", "libopus", "-b:a", "160k"],
+            Profile::Flac => &["-vn", "-compression_level", "12"],
+            Profile::Mp3V0 => &["-vn", "-codec:a", "libmp3lame", "-q:a", "0"],
+            Profile::OggQ6 => &["-vn", "-codec:a", "libvorbis", "-q:a", "6"],
+        }
+    }
+
+    /// Whether a downloaded file can be kept as it is
+    pub fn keeps(&self, path: &Path) -> bool {
+        AudioFormat::detect(path).is_ok_and(|format| format == self.format())
+    }
+
+    /// Reads the tag of a file written by this profile
+    pub fn read_tag(&self, path: &Path) -> Result<BoxedTag> {
+        tag::read_with_format(path, self.format()).map_err(|e| {
+            eyre!(
+                "Can't read the {} tag of {}: {}",
+                self.format(),
+                path.display(),
+                e
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn profile_names() {
+        assert_eq!(Profile::from_str("mp3-v0").unwrap(), Profile::Mp3V0);
+        assert_eq!(Profile::OggQ6.to_string(), "ogg-q6");
+        assert_eq!(Profile::Mp3V0.extension(), "mp3");
+        assert_eq!(
+            Profile::from_path(Path::new("/music/Gunjou.OGG")),
+            Some(Profile::OggQ6)
+        );
+        assert_eq!(Profile::from_path(Path::new("/music/Gunjou.m4a")), None);
+    }
+}
@@ -0,0 +1,218 @@
+//! Progress of the download queue. Workers send events as jobs go through their stages, and
+//! the progress external programs print is parsed into how far a stage is. Frontends receive
+//! the events over a channel: `render` shows them in the terminal, with a progress bar per
+//! job when standard output is a terminal and as plain lines otherwise.
+
+use std::{collections::HashMap, io::IsTerminal};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::data::job::JobState;
+
+/// Called with how much of a stage is done, from 0 to 1
+pub type OnProgress = dyn Fn(f64) + Send + Sync;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// ID of the job in the queue
+    pub job: usize,
+    /// The job as shown to the user
+    pub label: String,
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    /// The job entered a stage, doing what the text says
+    Stage(JobState, String),
+    /// How much of the current stage is done, from 0 to 1
+    Progress(f64),
+    /// Something the job did which the user may want to know, such as the silence it cut
+    Info(String),
+    /// Something went wrong without failing the job
+    Warning(String),
+    /// The job failed for this reason
+    Failed(String),
+}
+
+/// Which output of a program a line was printed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Stdout,
+    Stderr,
+}
+
+/// Reads a youtube-dl progress line such as
+/// `[download]  45.3% of 3.52MiB at  1.21MiB/s ETA 00:02`, printed on lines of their own with
+/// `--newline`
+pub fn parse_youtube_dl(line: &str) -> Option<f64> {
+    let rest = line.trim().strip_prefix("[download]")?;
+    let percent = rest.split_whitespace().next()?.strip_suffix('%')?;
+    percent
+        .parse::<f64>()
+        .ok()
+        .map(|percent| (percent / 100.0).clamp(0.0, 1.0))
+}
+
+/// Follows ffmpeg run with `-progress pipe:1`. The length of the input comes from the
+/// `Duration:` line on standard error, how much was converted from `out_time_us` lines on
+/// standard output.
+#[derive(Debug, Default)]
+pub struct FfmpegProgress {
+    /// Length of the input in seconds
+    duration: Option<f64>,
+}
+
+impl FfmpegProgress {
+    pub fn line(&mut self, output: Output, line: &str) -> Option<f64> {
+        let line = line.trim();
+        match output {
+            Output::Stderr => {
+                if self.duration.is_none() {
+                    self.duration = line
+                        .strip_prefix("Duration:")
+                        .and_then(|rest| rest.split(',').next())
+                        .and_then(|time| parse_time(time.trim()));
+                }
+                None
+            }
+            Output::Stdout => {
+                let (key, value) = line.split_once('=')?;
+                match key {
+                    "progress" if value == "end" => Some(1.0),
+                    // Both are in microseconds, out_time_ms despite its name
+                    "out_time_us" | "out_time_ms" => {
+                        let converted = value.parse::<f64>().ok()? / 1_000_000.0;
+                        let duration = self.duration.filter(|duration| *duration > 0.0)?;
+                        Some((converted / duration).clamp(0.0, 1.0))
+                    }
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+/// Reads a time like `00:03:45.12` as seconds
+pub fn parse_time(time: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+    for part in time.split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(seconds)
+}
+
+/// Shows the events of a queue run until every sender is gone
+pub async fn render(mut events: UnboundedReceiver<Event>) {
+    let interactive = std::io::stdout().is_terminal();
+    let multi = MultiProgress::with_draw_target(match interactive {
+        true => ProgressDrawTarget::stdout(),
+        false => ProgressDrawTarget::hidden(),
+    });
+    let style = ProgressStyle::with_template("{prefix} [{bar:30}] {percent:>3}% {msg}")
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+        .progress_chars("=> ");
+    let mut bars: HashMap<usize, ProgressBar> = HashMap::new();
+    // Printed above the bars, which would otherwise draw over the text
+    let print = |line: String| match interactive {
+        true => {
+            let _ = multi.println(line);
+        }
+        false => println!("{}", line),
+    };
+
+    while let Some(event) = events.recv().await {
+        match event.kind {
+            EventKind::Stage(state, text) => {
+                print(format!("{}: {}", event.label, text));
+                match state {
+                    JobState::Downloading | JobState::Converting | JobState::Trimming => {
+                        let bar = bars.entry(event.job).or_insert_with(|| {
+                            multi.add(
+                                ProgressBar::new(1000)
+                                    .with_style(style.clone())
+                                    .with_prefix(event.label.clone()),
+                            )
+                        });
+                        bar.set_position(0);
+                        bar.set_message(state.to_string());
+                    }
+                    JobState::Done => {
+                        if let Some(bar) = bars.remove(&event.job) {
+                            bar.finish_and_clear();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            EventKind::Progress(fraction) => {
+                if let Some(bar) = bars.get(&event.job) {
+                    bar.set_position((fraction * 1000.0).round() as u64);
+                }
+            }
+            EventKind::Info(text) | EventKind::Warning(text) => {
+                print(format!("{}: {}", event.label, text))
+            }
+            EventKind::Failed(reason) => {
+                if let Some(bar) = bars.remove(&event.job) {
+                    bar.finish_and_clear();
+                }
+                eprintln!("{}: failed: {}", event.label, reason);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_youtube_dl_progress() {
+        let cases = [
+            (
+                "[download]  45.3% of 3.52MiB at  1.21MiB/s ETA 00:02",
+                Some(0.453),
+            ),
+            ("[download] 100% of 3.52MiB in 00:03", Some(1.0)),
+            ("[download] Destination: /music/a.webm", None),
+            ("[youtube] x8VYWazR5mE: Downloading webpage", None),
+            ("[ExtractAudio] Destination: /music/a.opus", None),
+        ];
+        for (line, expected) in cases {
+            let parsed = parse_youtube_dl(line);
+            match expected {
+                Some(expected) => assert!(
+                    parsed.is_some_and(|parsed| (parsed - expected).abs() < 1e-9),
+                    "{}: {:?}",
+                    line,
+                    parsed
+                ),
+                None => assert_eq!(parsed, None, "{}", line),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_ffmpeg_progress() {
+        let mut progress = FfmpegProgress::default();
+        // Nothing is known before the duration
+        assert_eq!(progress.line(Output::Stdout, "out_time_us=1000000"), None);
+        assert_eq!(
+            progress.line(
+                Output::Stderr,
+                "  Duration: 00:04:18.00, start: 0.007000, bitrate: 135 kb/s"
+            ),
+            None
+        );
+        assert_eq!(
+            progress.line(Output::Stdout, "out_time_us=129000000"),
+            Some(0.5)
+        );
+        assert_eq!(progress.line(Output::Stdout, "bitrate= 896.1kbits/s"), None);
+        assert_eq!(progress.line(Output::Stdout, "progress=continue"), None);
+        assert_eq!(progress.line(Output::Stdout, "progress=end"), Some(1.0));
+        assert_eq!(parse_time("01:02:03.5"), Some(3723.5));
+    }
+}
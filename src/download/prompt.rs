@@ -0,0 +1,119 @@
+//! Asks the questions of `download`. With `--yes` every question takes its default answer.
+//! Otherwise questions are asked in the terminal, and when standard input is not a terminal,
+//! a question is an error naming the option which answers it, so scripts fail instead of
+//! hanging.
+
+use std::io::IsTerminal;
+
+use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
+use eyre::{eyre, Report, Result};
+
+pub struct Prompter {
+    interactive: bool,
+    assume_yes: bool,
+}
+
+impl Prompter {
+    pub fn new(assume_yes: bool) -> Self {
+        Self {
+            interactive: std::io::stdin().is_terminal(),
+            assume_yes,
+        }
+    }
+
+    fn unanswered(question: &str, options: &str) -> Report {
+        eyre!(
+            "Can't ask \"{}\" as standard input is not a terminal. \
+            Answer it with {}, or pass --yes to accept the default",
+            question,
+            options
+        )
+    }
+
+    /// Asks to pick one of the items. Returns None if the user cancels.
+    pub fn select(
+        &self,
+        question: &str,
+        items: &[String],
+        default: usize,
+        options: &str,
+    ) -> Result<Option<usize>> {
+        if self.assume_yes {
+            return Ok(Some(default));
+        }
+        if !self.interactive {
+            return Err(Prompter::unanswered(question, options));
+        }
+        println!("{}", question);
+        Ok(Select::with_theme(&ColorfulTheme::default())
+            .items(items)
+            .default(default)
+            .interact_opt()?)
+    }
+
+    pub fn confirm(&self, question: &str, default: bool, options: &str) -> Result<bool> {
+        if self.assume_yes {
+            return Ok(default);
+        }
+        if !self.interactive {
+            return Err(Prompter::unanswered(question, options));
+        }
+        Ok(Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(question)
+            .default(default)
+            .interact()?)
+    }
+
+    pub fn input(&self, question: &str, default: String, options: &str) -> Result<String> {
+        if self.assume_yes {
+            return Ok(default);
+        }
+        if !self.interactive {
+            return Err(Prompter::unanswered(question, options));
+        }
+        Ok(Input::with_theme(&ColorfulTheme::default())
+            .with_prompt(question)
+            .default(default)
+            .interact()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answer_without_terminal() {
+        let prompter = Prompter {
+            interactive: false,
+            assume_yes: true,
+        };
+        assert_eq!(
+            prompter
+                .select("Pick one", &["a".to_string(), "b".to_string()], 1, "--pick")
+                .unwrap(),
+            Some(1)
+        );
+        assert!(!prompter.confirm("Rename?", false, "--rename").unwrap());
+        assert_eq!(
+            prompter
+                .input("Title", "Gunjou".to_string(), "--title")
+                .unwrap(),
+            "Gunjou"
+        );
+
+        let prompter = Prompter {
+            interactive: false,
+            assume_yes: false,
+        };
+        let error = prompter
+            .confirm("Rename?", true, "--rename")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("\"Rename?\""), "{}", error);
+        assert!(error.contains("--rename"), "{}", error);
+        assert!(prompter
+            .input("Title", "Gunjou".to_string(), "--title")
+            .is_err());
+    }
+}
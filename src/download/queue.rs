@@ -0,0 +1,699 @@
+//! Works through the download queue with a pool of concurrent workers.
+//!
+//! Every stage a job enters is recorded in the database. When a run is interrupted, the next
+//! run picks its jobs up again from the last stage whose input is still on disk. Downloads and
+//! conversions that fail or time out are tried again after a growing delay. Converted files
+//! can have silence cut off their start and end, see `silence`. Jobs work in a
+//! staging directory, see `staging`, and only reach the library once they are tagged. Only
+//! one run should work on a queue at a time.
+
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use eyre::{eyre, Result};
+use log::debug;
+use time::OffsetDateTime;
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::{
+    backend::Downloader,
+    progress::{Event, EventKind},
+    silence, staging,
+};
+use crate::{
+    config::Config,
+    cover,
+    data::{
+        database::Database,
+        job::{DownloadJob, JobState},
+        provenance::Provenance,
+        replaygain::ReplayGain,
+        song::Song,
+        tag::AudioFormat,
+        trim::{round_seconds, Trim},
+    },
+    library::loudness,
+};
+
+/// How many jobs a run finished
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub done: usize,
+    pub failed: usize,
+}
+
+struct Queue {
+    database: Mutex<Database>,
+    /// Jobs taken by a worker during this run
+    claimed: Mutex<Vec<usize>>,
+    config: Config,
+    downloader: Arc<dyn Downloader>,
+    /// Recorded with every song, as the downloader is asked only once per run
+    downloader_version: Option<String>,
+    events: UnboundedSender<Event>,
+    /// Where jobs keep their files until they are done
+    staging: PathBuf,
+}
+
+/// Runs every pending job, including jobs left over from an interrupted run, until the queue
+/// is empty. Jobs that fail are marked as failed and don't stop the others. How the jobs go
+/// is sent to `events`. The staging directory is found from the library the database is in.
+pub async fn run(
+    database: Database,
+    config: &Config,
+    downloader: Arc<dyn Downloader>,
+    events: UnboundedSender<Event>,
+) -> Result<Summary> {
+    let database_path = database.database_path();
+    let music_dir = database_path.parent().unwrap_or_else(|| Path::new("."));
+    let staging = config.download.staging_dir(music_dir);
+    let queue = Arc::new(Queue {
+        database: Mutex::new(database),
+        claimed: Mutex::new(vec![]),
+        config: config.clone(),
+        downloader_version: downloader.version().await,
+        downloader,
+        events,
+        staging,
+    });
+
+    let workers = (0..config.download.workers)
+        .map(|_| {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.work().await })
+        })
+        .collect::<Vec<_>>();
+
+    let mut summary = Summary::default();
+    for worker in workers {
+        let worker_summary = worker.await??;
+        summary.done += worker_summary.done;
+        summary.failed += worker_summary.failed;
+    }
+    Ok(summary)
+}
+
+/// Picks the stage to start a job from. Interrupted jobs continue from the last stage whose
+/// output is in their staging directory, anything else is downloaded again. Jobs tagging a
+/// file already in the library tag it where it is. Split videos are converted until every
+/// track is cut. Trimming is safe to start over, as it skips files it trimmed already.
+fn start_stage(job: &DownloadJob, staging: &Path) -> JobState {
+    let outputs = job.outputs(staging);
+    let downloaded = job.download_path(staging).exists();
+    let converted = outputs.iter().all(|(staged, _)| staged.exists());
+    let in_place = outputs
+        .iter()
+        .all(|(staged, path)| staged.exists() || path.exists());
+    match job.state {
+        JobState::Converting | JobState::Trimming if converted => JobState::Trimming,
+        JobState::Tagging if converted => JobState::Tagging,
+        JobState::Converting | JobState::Trimming | JobState::Tagging if downloaded => {
+            JobState::Converting
+        }
+        JobState::Tagging if in_place => JobState::Tagging,
+        _ => JobState::Downloading,
+    }
+}
+
+impl Queue {
+    fn database(&self) -> Result<MutexGuard<'_, Database>> {
+        self.database
+            .lock()
+            .map_err(|_| eyre!("A download worker panicked while using the database"))
+    }
+
+    /// Takes the oldest pending job no other worker has taken
+    fn claim(&self) -> Result<Option<DownloadJob>> {
+        let mut claimed = self
+            .claimed
+            .lock()
+            .map_err(|_| eyre!("A download worker panicked while taking a job"))?;
+        let job = self.database()?.next_pending_download_job(&claimed)?;
+        if let Some(id) = job.as_ref().and_then(|job| job.id) {
+            claimed.push(id);
+        }
+        Ok(job)
+    }
+
+    fn set_state(&self, job: &DownloadJob, state: JobState, error: Option<&str>) -> Result<()> {
+        let id = job
+            .id
+            .ok_or_else(|| eyre!("Download job {} is not in the database", job))?;
+        debug!("Download job {} is {}", job, state);
+        self.database()?.set_download_job_state(id, state, error)
+    }
+
+    /// Tells the frontend about a job. Events nobody listens to any more are dropped.
+    fn send(&self, job: &DownloadJob, kind: EventKind) {
+        let _ = self.events.send(Event {
+            job: job.id.unwrap_or_default(),
+            label: job.to_string(),
+            kind,
+        });
+    }
+
+    /// Moves a job to a stage, telling the frontend what it does there
+    fn enter(&self, job: &DownloadJob, state: JobState, text: String) -> Result<()> {
+        self.set_state(job, state, None)?;
+        self.send(job, EventKind::Stage(state, text));
+        Ok(())
+    }
+
+    /// Reports the progress of the job's current stage
+    fn progress_of(&self, job: &DownloadJob) -> impl Fn(f64) + Send + Sync {
+        let events = self.events.clone();
+        let id = job.id.unwrap_or_default();
+        let label = job.to_string();
+        move |fraction| {
+            let _ = events.send(Event {
+                job: id,
+                label: label.clone(),
+                kind: EventKind::Progress(fraction),
+            });
+        }
+    }
+
+    async fn work(&self) -> Result<Summary> {
+        let mut summary = Summary::default();
+        while let Some(job) = self.claim()? {
+            match self.process(&job).await {
+                Ok(_) => {
+                    staging::remove(&job.staging_dir(&self.staging))?;
+                    self.enter(&job, JobState::Done, "done".to_string())?;
+                    summary.done += 1;
+                }
+                Err(e) => {
+                    // Finished stages are kept for `queue retry` to resume from
+                    self.clean(&job)?;
+                    self.set_state(&job, JobState::Failed, Some(&e.to_string()))?;
+                    self.send(&job, EventKind::Failed(e.to_string()));
+                    summary.failed += 1;
+                }
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Removes what unfinished stages left in the job's staging directory
+    fn clean(&self, job: &DownloadJob) -> Result<()> {
+        let mut keep = vec![job.download_path(&self.staging)];
+        keep.extend(
+            job.outputs(&self.staging)
+                .into_iter()
+                .map(|(staged, _)| staged),
+        );
+        staging::clean(&job.staging_dir(&self.staging), &keep)
+    }
+
+    /// Runs a stage of a job, trying again up to `download.retries` times if it fails. The
+    /// delay before a retry starts at `download.retry_delay` and doubles with each one.
+    /// Attempts running longer than `timeout` seconds are stopped, which kills the program
+    /// they run.
+    async fn attempt<F, Fut>(&self, job: &DownloadJob, timeout: u64, mut stage: F) -> Result<()>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let retries = self.config.download.retries;
+        let mut delay = self.config.download.retry_delay;
+        for attempt in 0..=retries {
+            let error = match tokio::time::timeout(Duration::from_secs(timeout), stage()).await {
+                Ok(Ok(_)) => return Ok(()),
+                Ok(Err(e)) => e,
+                Err(_) => eyre!("Timed out after {} seconds", timeout),
+            };
+            self.clean(job)?;
+            if attempt == retries {
+                return Err(error);
+            }
+            self.send(
+                job,
+                EventKind::Warning(format!(
+                    "attempt {} of {} failed: {}. Trying again in {} seconds",
+                    attempt + 1,
+                    retries + 1,
+                    error,
+                    delay
+                )),
+            );
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+            delay = delay.saturating_mul(2);
+        }
+        unreachable!("the last attempt returns")
+    }
+
+    async fn process(&self, job: &DownloadJob) -> Result<()> {
+        let mut job = job.clone();
+        let mut stage = start_stage(&job, &self.staging);
+        std::fs::create_dir_all(job.staging_dir(&self.staging))?;
+        let download_path = job.download_path(&self.staging);
+        let converted_path = job.converted_path(&self.staging);
+
+        if stage == JobState::Downloading {
+            let source_url = self.downloader.source_url(&job.youtube_id);
+            self.enter(&job, stage, format!("downloading {}", source_url))?;
+            let partial = staging::partial_path(&download_path);
+            let on_progress = self.progress_of(&job);
+            self.attempt(&job, self.config.download.download_timeout, || async {
+                self.downloader
+                    .download_audio(&job.youtube_id, &partial, &on_progress)
+                    .await?;
+                std::fs::rename(&partial, &download_path)?;
+                Ok(())
+            })
+            .await?;
+            stage = JobState::Converting;
+        }
+
+        if stage == JobState::Converting {
+            let text = match job.tracks.len() {
+                0 => format!("converting to {}", job.profile.format()),
+                tracks => format!("cutting {} tracks as {}", tracks, job.profile.format()),
+            };
+            self.enter(&job, stage, text)?;
+            job.source_codec = AudioFormat::detect(&download_path)
+                .ok()
+                .map(|format| format.to_string());
+            let on_progress = self.progress_of(&job);
+            let profile = job.profile;
+            if job.tracks.is_empty() {
+                let partial = staging::partial_path(&converted_path);
+                self.attempt(&job, self.config.download.convert_timeout, || async {
+                    // A failed attempt leaves the download where it was, as ffmpeg deletes its
+                    // input only once it succeeded and moving a file doesn't fail halfway
+                    super::convert(&download_path, &partial, profile, &on_progress).await?;
+                    std::fs::rename(&partial, &converted_path)?;
+                    Ok(())
+                })
+                .await?;
+            } else {
+                self.cut_tracks(&job, &on_progress).await?;
+            }
+            let converted_at = OffsetDateTime::now_utc();
+            job.converted_at = Some(converted_at);
+            if let Some(id) = job.id {
+                self.database()?.set_download_job_source(
+                    id,
+                    job.source_codec.as_deref(),
+                    converted_at,
+                )?;
+            }
+            stage = JobState::Trimming;
+        }
+
+        if stage == JobState::Trimming && self.config.download.trim_silence {
+            self.enter(&job, stage, "trimming silence".to_string())?;
+            self.trim(&job).await?;
+        }
+
+        self.enter(&job, JobState::Tagging, "tagging".to_string())?;
+        self.tag(&job).await
+    }
+
+    /// Cuts each track of a split video out of the download, leaving tracks a previous run
+    /// cut as they are. The download is removed once every track is cut.
+    async fn cut_tracks(&self, job: &DownloadJob, on_progress: &impl Fn(f64)) -> Result<()> {
+        let download_path = job.download_path(&self.staging);
+        for (index, track) in job.tracks.iter().enumerate() {
+            let track_path = job.track_path(&self.staging, track);
+            if !track_path.exists() {
+                let partial = staging::partial_path(&track_path);
+                self.attempt(job, self.config.download.convert_timeout, || async {
+                    super::cut(
+                        &download_path,
+                        &partial,
+                        job.profile,
+                        track.start,
+                        track.end,
+                    )
+                    .await?;
+                    std::fs::rename(&partial, &track_path)?;
+                    Ok(())
+                })
+                .await?;
+            }
+            on_progress((index + 1) as f64 / job.tracks.len() as f64);
+        }
+        std::fs::remove_file(&download_path)?;
+        Ok(())
+    }
+
+    /// Cuts silence off the start and end of each file the job made. How long the file was
+    /// and what was cut are written to its tag, where `tag` finds them. Files with these
+    /// values were trimmed by an earlier run and are left as they are.
+    async fn trim(&self, job: &DownloadJob) -> Result<()> {
+        let config = &self.config.download;
+        let on_progress = self.progress_of(job);
+        let outputs = job.outputs(&self.staging);
+        for (index, (path, destination)) in outputs.iter().enumerate() {
+            if !Trim::read(job.profile.read_tag(path)?.as_ref()).is_empty() {
+                continue;
+            }
+            let partial = staging::partial_path(path);
+            self.attempt(job, config.convert_timeout, || async {
+                let silence =
+                    silence::detect(path, config.silence_threshold, config.min_silence).await?;
+                let trim = Trim {
+                    original_duration: Some(round_seconds(silence.duration)),
+                    start: Some(round_seconds(silence.start)),
+                    end: Some(round_seconds(silence.end)),
+                };
+                let file = match silence.is_empty() {
+                    true => path,
+                    false => {
+                        let end = silence.duration - silence.end;
+                        let (start, end) = (silence.start * 1000.0, end * 1000.0);
+                        super::cut(
+                            path,
+                            &partial,
+                            job.profile,
+                            start.round() as u64,
+                            Some(end.round() as u64),
+                        )
+                        .await?;
+                        &partial
+                    }
+                };
+                let mut tag = job.profile.read_tag(file)?;
+                trim.write(tag.as_mut());
+                tag.write_to_path(file)?;
+                if file != path {
+                    std::fs::rename(file, path)?;
+                }
+
+                let text = match silence.is_empty() {
+                    true => "no silence to trim".to_string(),
+                    false => format!("trimmed silence, {}", trim),
+                };
+                debug!("{}: {}", destination.display(), text);
+                if !silence.is_empty() {
+                    let text = match destination.file_name() {
+                        Some(name) if !job.tracks.is_empty() => {
+                            format!("{}: {}", name.to_string_lossy(), text)
+                        }
+                        _ => text,
+                    };
+                    self.send(job, EventKind::Info(text));
+                }
+                Ok(())
+            })
+            .await?;
+            on_progress((index + 1) as f64 / outputs.len() as f64);
+        }
+        Ok(())
+    }
+
+    /// Measures the files of a job for their ReplayGain values. The tracks of a split video
+    /// make a whole album, so they get an album gain too. A file that can't be measured is
+    /// left without values.
+    async fn measure(&self, job: &DownloadJob, paths: &[PathBuf]) -> Vec<ReplayGain> {
+        let mut measured = vec![];
+        for path in paths {
+            match loudness::measure(path).await {
+                Ok(loudness) => measured.push(Some(loudness)),
+                Err(e) => {
+                    self.send(
+                        job,
+                        EventKind::Warning(format!("can't measure the loudness: {}", e)),
+                    );
+                    measured.push(None);
+                }
+            }
+        }
+        let album = match job.tracks.is_empty() {
+            true => None,
+            false => measured
+                .iter()
+                .copied()
+                .collect::<Option<Vec<_>>>()
+                .and_then(|songs| loudness::album_loudness(&songs)),
+        };
+        measured
+            .into_iter()
+            .map(|loudness| match loudness {
+                Some(loudness) => loudness::replaygain(&loudness, album.as_ref()),
+                None => ReplayGain::default(),
+            })
+            .collect()
+    }
+
+    /// Writes the job's metadata and cover to the converted file, or to each track of a split
+    /// video, moves the files into the library and adds them to the database, or updates the
+    /// database's record of them. Files already in the library are tagged where they are.
+    async fn tag(&self, job: &DownloadJob) -> Result<()> {
+        let files = job
+            .outputs(&self.staging)
+            .into_iter()
+            .map(|(staged, path)| match staged.exists() {
+                true => (staged, path),
+                false => (path.clone(), path),
+            })
+            .collect::<Vec<_>>();
+        let picture = match &job.thumbnail_url {
+            Some(thumbnail_url) => {
+                match cover::fetch_cover(thumbnail_url, &self.config.cover).await {
+                    Ok(picture) => Some(picture),
+                    Err(e) => {
+                        self.send(
+                            job,
+                            EventKind::Warning(format!("can't fetch the cover: {}", e)),
+                        );
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        let source_url = self.downloader.source_url(&job.youtube_id);
+        let provenance = Provenance {
+            source_codec: job.source_codec.clone(),
+            source_bitrate: job.source_bitrate,
+            source_url: Some(source_url.clone()),
+            downloader_version: self.downloader_version.clone(),
+            converted_at: job.converted_at,
+        };
+        // Album gains of single songs need the whole album, which `replaygain` measures
+        let replaygains = match self.config.download.replaygain {
+            true => {
+                let paths = files
+                    .iter()
+                    .map(|(path, _)| path.clone())
+                    .collect::<Vec<_>>();
+                self.measure(job, &paths).await
+            }
+            false => vec![ReplayGain::default(); files.len()],
+        };
+
+        let tracks = job.tracks.iter().map(Some).chain(std::iter::repeat(None));
+        for (((path, destination), replaygain), track) in
+            files.into_iter().zip(replaygains).zip(tracks)
+        {
+            let mut tag = job.profile.read_tag(&path)?;
+            let artists = track
+                .and_then(|track| track.artists.clone())
+                .or_else(|| job.artists.clone());
+            // Tracks with artists of their own stay on the album of the video's artist
+            let album_artist = track
+                .and(job.artists.as_ref())
+                .and_then(|job_artists| job_artists.first())
+                .filter(|&artist| {
+                    artists.as_ref().and_then(|artists| artists.first()) != Some(artist)
+                })
+                .cloned();
+            let song = Song {
+                file_path: destination.clone(),
+                file_name: destination
+                    .file_name()
+                    .and_then(|file_name| file_name.to_str())
+                    .ok_or_else(|| eyre!("Can't convert path to str"))?
+                    .to_string(),
+                title: track
+                    .map(|track| track.title.clone())
+                    .or_else(|| job.title.clone()),
+                artists,
+                album: job.album.clone(),
+                album_artist,
+                genre: job.genre.clone(),
+                release_date: job.release_date.clone(),
+                track_number: track.map(|track| track.number).or(job.track_number),
+                youtube_id: Some(job.youtube_id.clone()),
+                thumbnail_url: job.thumbnail_url.clone(),
+                provenance: provenance.clone(),
+                replaygain,
+                trim: Trim::read(tag.as_ref()),
+                ..Default::default()
+            };
+
+            if let Some(title) = &song.title {
+                tag.set("TITLE", vec![title.clone()]);
+            }
+            if let Some(artists) = &song.artists {
+                tag.set("ARTIST", artists.clone());
+            }
+            if let Some(album) = &song.album {
+                tag.set("ALBUM", vec![album.clone()]);
+            }
+            if let Some(album_artist) = &song.album_artist {
+                tag.set("ALBUMARTIST", vec![album_artist.clone()]);
+            }
+            if let Some(release_date) = &song.release_date {
+                tag.set("DATE", vec![release_date.clone()]);
+            }
+            if let Some(track_number) = song.track_number {
+                tag.set("TRACKNUMBER", vec![track_number.to_string()]);
+            }
+            if let Some(genre) = &song.genre {
+                tag.set("GENRE", vec![genre.clone()]);
+            }
+            tag.set("COMMENT", vec![source_url.clone()]);
+            if let Some(picture) = &picture {
+                tag.set_picture(picture.clone())?;
+            }
+            provenance.write(tag.as_mut());
+            if !replaygain.is_empty() {
+                replaygain.write(tag.as_mut());
+            }
+            tag.write_to_path(&path)?;
+            if path != destination {
+                staging::move_into_library(&path, &destination)?;
+            }
+
+            let song_id = self.add_song(song)?;
+            if let Some(id) = job.id {
+                match track {
+                    Some(track) => {
+                        self.database()?
+                            .set_download_job_track_song(id, track.number, song_id)?
+                    }
+                    None => self.database()?.set_download_job_song(id, song_id)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a downloaded song to the database, or updates the song already at its path,
+    /// keeping what the download doesn't know. Returns the song's ID.
+    fn add_song(&self, song: Song) -> Result<usize> {
+        let database = self.database()?;
+        let song_id = match database.query_song_by_path(&song.file_path)? {
+            Some(existing) => {
+                let song = Song {
+                    id: existing.id,
+                    title: song.title.or(existing.title),
+                    artists: song.artists.or(existing.artists),
+                    album: song.album.or(existing.album),
+                    album_artist: song.album_artist.or(existing.album_artist),
+                    genre: song.genre.or(existing.genre),
+                    release_date: song.release_date.or(existing.release_date),
+                    track_number: song.track_number.or(existing.track_number),
+                    replaygain: match song.replaygain.is_empty() {
+                        true => existing.replaygain,
+                        false => song.replaygain,
+                    },
+                    trim: match song.trim.is_empty() {
+                        true => existing.trim,
+                        false => song.trim,
+                    },
+                    ..song
+                };
+                database.update_song(&song)?;
+                existing
+                    .id
+                    .ok_or_else(|| eyre!("Song {} has no ID", song))?
+            }
+            None => database.insert_song(&song)?,
+        };
+        Ok(song_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::job::Track;
+
+    #[test]
+    fn resume_from_files_on_disk() {
+        let dir = std::env::temp_dir().join("music-manager-queue-tests");
+        let staging = dir.join(".staging");
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut job = DownloadJob::new("x8VYWazR5mE", dir.join("resume.flac"));
+        job.id = Some(1);
+        std::fs::create_dir_all(job.staging_dir(&staging)).unwrap();
+        assert_eq!(job.staging_dir(&staging), staging.join("job-1"));
+
+        let stage = |job: &DownloadJob, state| {
+            start_stage(
+                &DownloadJob {
+                    state,
+                    ..job.clone()
+                },
+                &staging,
+            )
+        };
+
+        // Nothing on disk, or only what a failed download left
+        std::fs::write(staging::partial_path(&job.download_path(&staging)), "Og").unwrap();
+        for state in [
+            JobState::Queued,
+            JobState::Downloading,
+            JobState::Converting,
+            JobState::Tagging,
+        ] {
+            assert_eq!(stage(&job, state), JobState::Downloading);
+        }
+
+        // Downloaded
+        std::fs::write(job.download_path(&staging), "OggS").unwrap();
+        assert_eq!(stage(&job, JobState::Downloading), JobState::Downloading);
+        assert_eq!(stage(&job, JobState::Converting), JobState::Converting);
+        assert_eq!(stage(&job, JobState::Tagging), JobState::Converting);
+
+        // Converted
+        std::fs::remove_file(job.download_path(&staging)).unwrap();
+        std::fs::write(job.converted_path(&staging), "fLaC").unwrap();
+        assert_eq!(stage(&job, JobState::Converting), JobState::Trimming);
+        assert_eq!(stage(&job, JobState::Trimming), JobState::Trimming);
+        assert_eq!(stage(&job, JobState::Tagging), JobState::Tagging);
+        job.state = JobState::Queued;
+        assert_eq!(start_stage(&job, &staging), JobState::Downloading);
+
+        // Moved into the library, or a song in the library being tagged again
+        std::fs::remove_file(job.converted_path(&staging)).unwrap();
+        std::fs::write(&job.file_path, "fLaC").unwrap();
+        assert_eq!(stage(&job, JobState::Tagging), JobState::Tagging);
+        assert_eq!(stage(&job, JobState::Converting), JobState::Downloading);
+
+        // Jobs outside the queue are named after their video
+        let job = DownloadJob::new("YOASOBI/Gunjou.opus", dir.join("Gunjou.opus"));
+        assert_eq!(
+            job.converted_path(&staging),
+            staging.join("YOASOBI_Gunjou.opus/converted.opus")
+        );
+
+        // Split videos are converted until every track is cut
+        let mut job = DownloadJob::new("n6nXBOr6mOk", dir.join("THE BOOK"));
+        job.id = Some(2);
+        job.tracks = (1..=2)
+            .map(|number| Track {
+                number,
+                title: format!("Track {}", number),
+                artists: None,
+                start: 0,
+                end: None,
+                file_path: dir.join(format!("THE BOOK/0{}.flac", number)),
+            })
+            .collect();
+        std::fs::create_dir_all(job.staging_dir(&staging)).unwrap();
+        std::fs::write(job.download_path(&staging), "OggS").unwrap();
+        std::fs::write(job.track_path(&staging, &job.tracks[0]), "fLaC").unwrap();
+        assert_eq!(stage(&job, JobState::Tagging), JobState::Converting);
+        std::fs::write(job.track_path(&staging, &job.tracks[1]), "fLaC").unwrap();
+        assert_eq!(stage(&job, JobState::Converting), JobState::Trimming);
+    }
+}
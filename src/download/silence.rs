@@ -0,0 +1,152 @@
+//! Finds silence at the start and end of songs with ffmpeg's `silencedetect` filter, so it
+//! can be cut off. Music videos often open or close on a silent intro or skit. Silence in the
+//! middle of a song, such as a pause before a hidden track, is left alone.
+
+use std::path::Path;
+
+use eyre::{eyre, Result};
+use tokio::process::Command;
+
+use super::progress::{parse_time, Output};
+
+/// Silence starting or ending this close to the edge of a song, in seconds, counts as
+/// reaching it. Decoders can report a start slightly before 0 or an end short of the length.
+const EDGE: f64 = 0.05;
+
+/// Silence found at the edges of a song, in seconds
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Silence {
+    /// Length of the song
+    pub duration: f64,
+    /// Silence before the song starts
+    pub start: f64,
+    /// Silence after the song ends
+    pub end: f64,
+}
+
+impl Silence {
+    /// Whether there is nothing to cut
+    pub fn is_empty(&self) -> bool {
+        self.start == 0.0 && self.end == 0.0
+    }
+}
+
+/// Looks for silence at the edges of a music file. Audio below `threshold` dB counts as
+/// silent, and only silence lasting at least `min_silence` seconds is reported.
+pub async fn detect(path: &Path, threshold: f64, min_silence: f64) -> Result<Silence> {
+    let input = path
+        .to_str()
+        .ok_or_else(|| eyre!("Can't convert path to str"))?;
+    let filter = format!("silencedetect=noise={}dB:d={}", threshold, min_silence);
+    let mut lines = vec![];
+    super::run(
+        Command::new("ffmpeg")
+            .args(["-hide_banner", "-nostats", "-i", input])
+            .args(["-af", &filter, "-f", "null", "-"]),
+        "ffmpeg",
+        |output, line| {
+            if output == Output::Stderr {
+                lines.push(line.to_string());
+            }
+        },
+    )
+    .await?;
+    parse_silencedetect(&lines)
+        .ok_or_else(|| eyre!("ffmpeg didn't tell the length of {}", path.display()))
+}
+
+/// Reads the silent periods silencedetect prints as `silence_start: 0` and
+/// `silence_end: 12.5 | silence_duration: 12.5`, and the length of the input from ffmpeg's
+/// `Duration:` line. Silence running to the end of the input may have no `silence_end`.
+/// A song which is silent throughout is left whole.
+fn parse_silencedetect(lines: &[String]) -> Option<Silence> {
+    let value = |line: &str, key: &str| {
+        let (_, rest) = line.split_once(key)?;
+        rest.split_whitespace().next()?.parse::<f64>().ok()
+    };
+    let duration = lines
+        .iter()
+        .find_map(|line| line.trim().strip_prefix("Duration:"))
+        .and_then(|rest| rest.split(',').next())
+        .and_then(|time| parse_time(time.trim()))?;
+
+    let mut periods: Vec<(f64, Option<f64>)> = vec![];
+    for line in lines {
+        if let Some(start) = value(line, "silence_start:") {
+            periods.push((start.max(0.0), None));
+        } else if let Some(end) = value(line, "silence_end:") {
+            if let Some(period) = periods.last_mut() {
+                period.1 = Some(end.min(duration));
+            }
+        }
+    }
+
+    let reaches_end = |end: Option<f64>| end.is_none_or(|end| end >= duration - EDGE);
+    let mut silence = Silence {
+        duration,
+        ..Default::default()
+    };
+    let leading = periods.first().filter(|(start, _)| *start <= EDGE);
+    if let Some((_, end)) = leading {
+        if reaches_end(*end) {
+            return Some(silence);
+        }
+        silence.start = end.unwrap_or(duration);
+    }
+    let trailing = periods
+        .iter()
+        .skip(leading.is_some() as usize)
+        .last()
+        .filter(|(_, end)| reaches_end(*end));
+    if let Some((start, _)) = trailing {
+        silence.end = duration - start;
+    }
+    Some(silence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(output: &str) -> Vec<String> {
+        output.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn parse_silencedetect_output() {
+        let output = "
+Input #0, flac, from 'converted.flac':
+  Duration: 00:04:00.00, start: 0.000000, bitrate: 896 kb/s
+[silencedetect @ 0x5581] silence_start: -0.00133
+[silencedetect @ 0x5581] silence_end: 12.5 | silence_duration: 12.50133
+[silencedetect @ 0x5581] silence_start: 120
+[silencedetect @ 0x5581] silence_end: 123 | silence_duration: 3
+[silencedetect @ 0x5581] silence_start: 230.25
+size=N/A time=00:04:00.00 bitrate=N/A speed= 512x";
+        assert_eq!(
+            parse_silencedetect(&lines(output)),
+            Some(Silence {
+                duration: 240.0,
+                start: 12.5,
+                end: 9.75
+            })
+        );
+
+        // Newer versions end silence reaching the end of the input
+        let output = "  Duration: 00:03:00.00, start: 0.000000
+[silencedetect @ 0x1] silence_start: 175
+[silencedetect @ 0x1] silence_end: 180 | silence_duration: 5";
+        let silence = parse_silencedetect(&lines(output)).unwrap();
+        assert_eq!((silence.start, silence.end), (0.0, 5.0));
+
+        // Pauses in the middle stay, and so does a song that is all silence
+        let output = "  Duration: 00:03:00.00, start: 0.000000
+[silencedetect @ 0x1] silence_start: 60
+[silencedetect @ 0x1] silence_end: 65 | silence_duration: 5";
+        assert!(parse_silencedetect(&lines(output)).unwrap().is_empty());
+        let output = "  Duration: 00:03:00.00, start: 0.000000
+[silencedetect @ 0x1] silence_start: 0";
+        assert!(parse_silencedetect(&lines(output)).unwrap().is_empty());
+        assert_eq!(parse_silencedetect(&lines(output)[1..]), None);
+    }
+}
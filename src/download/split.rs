@@ -0,0 +1,305 @@
+//! Splits videos holding several songs, such as full album uploads, into tracks. Tracks come
+//! from the video's chapters as youtube-dl reports them, or from a cue sheet the user gives.
+//! Each track is cut out of the download and becomes a song of its own.
+
+use std::path::{Path, PathBuf};
+
+use eyre::{eyre, Result};
+use youtube_dl::model::Chapter;
+
+use super::{library_path, profile::Profile, sanitize_file_name, title};
+use crate::data::job::Track;
+
+/// Cue sheet times count frames, 75 to a second
+const FRAMES_PER_SECOND: u64 = 75;
+
+/// What a cue sheet says about an album
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CueSheet {
+    /// The album
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub tracks: Vec<Track>,
+}
+
+/// Makes a track of every chapter. Chapter titles lose numbering like `01.` and are taken
+/// apart like video titles, so `Artist - Title` chapters name their artist.
+pub fn from_chapters(chapters: &[Chapter]) -> Vec<Track> {
+    chapters
+        .iter()
+        .filter_map(|chapter| Some((chapter, chapter.start_time?)))
+        .enumerate()
+        .map(|(index, (chapter, start))| {
+            let number = index as u32 + 1;
+            let parsed = chapter
+                .title
+                .as_deref()
+                .map(strip_numbering)
+                .filter(|title| !title.is_empty())
+                .map(|title| title::parse(title, None));
+            Track {
+                number,
+                title: match &parsed {
+                    Some(parsed) => parsed.title.clone(),
+                    None => format!("Track {}", number),
+                },
+                artists: parsed
+                    .map(|parsed| parsed.artists)
+                    .filter(|artists| !artists.is_empty()),
+                start: (start * 1000.0).round() as u64,
+                end: chapter.end_time.map(|end| (end * 1000.0).round() as u64),
+                file_path: Default::default(),
+            }
+        })
+        .collect()
+}
+
+/// Removes a track number from the start of a chapter title, as in `01. Title`, `1) Title`
+/// or `03 - Title`. Titles starting with a number which isn't followed by punctuation, such
+/// as `99 Luftballons`, are left alone.
+fn strip_numbering(title: &str) -> &str {
+    let title = title.trim();
+    let rest = title.trim_start_matches(|c: char| c.is_ascii_digit());
+    if rest.len() == title.len() {
+        return title;
+    }
+    let rest = rest.trim_start();
+    match rest.strip_prefix(['.', ')', '-', ':']) {
+        Some(rest) => rest.trim_start(),
+        None => title,
+    }
+}
+
+/// Reads a cue sheet. Only sheets for a single file are supported, as they describe one
+/// video. Each track starts at its `INDEX 01` and ends where the next one starts.
+pub fn parse_cue(contents: &str) -> Result<CueSheet> {
+    let mut sheet = CueSheet {
+        title: None,
+        performer: None,
+        tracks: vec![],
+    };
+    let mut files = 0;
+    // Whether the track's start was found
+    let mut started = true;
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim().trim_start_matches('\u{feff}');
+        let (command, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let value = unquote(value);
+        let error = |message: &str| eyre!("Line {} of the cue sheet: {}", index + 1, message);
+        match command.to_ascii_uppercase().as_str() {
+            "FILE" => {
+                files += 1;
+                if files > 1 {
+                    return Err(error("cue sheets for more than one file are not supported"));
+                }
+            }
+            "TRACK" => {
+                if !started {
+                    return Err(error("the track before has no INDEX 01"));
+                }
+                let number = value
+                    .split_whitespace()
+                    .next()
+                    .and_then(|number| number.parse::<u32>().ok())
+                    .ok_or_else(|| error("TRACK needs a number"))?;
+                if sheet
+                    .tracks
+                    .last()
+                    .is_some_and(|last| number <= last.number)
+                {
+                    return Err(error("TRACK numbers must increase"));
+                }
+                sheet.tracks.push(Track {
+                    number,
+                    title: format!("Track {}", number),
+                    artists: None,
+                    start: 0,
+                    end: None,
+                    file_path: Default::default(),
+                });
+                started = false;
+            }
+            "TITLE" => match sheet.tracks.last_mut() {
+                Some(track) => track.title = value.to_string(),
+                None => sheet.title = Some(value.to_string()),
+            },
+            "PERFORMER" => match sheet.tracks.last_mut() {
+                Some(track) => track.artists = Some(vec![value.to_string()]),
+                None => sheet.performer = Some(value.to_string()),
+            },
+            "INDEX" => {
+                let (number, time) = value
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| error("INDEX needs a number and a time"))?;
+                let track = sheet
+                    .tracks
+                    .last_mut()
+                    .ok_or_else(|| error("INDEX comes before any TRACK"))?;
+                if number.trim() == "01" {
+                    track.start = parse_cue_time(time.trim())
+                        .ok_or_else(|| error("times are written as mm:ss:ff"))?;
+                    started = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    if !started {
+        return Err(eyre!("The last track of the cue sheet has no INDEX 01"));
+    }
+    if sheet.tracks.is_empty() {
+        return Err(eyre!("The cue sheet has no tracks"));
+    }
+
+    let starts = sheet
+        .tracks
+        .iter()
+        .map(|track| track.start)
+        .collect::<Vec<_>>();
+    if starts.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(eyre!("The tracks of the cue sheet are out of order"));
+    }
+    for (track, next) in sheet.tracks.iter_mut().zip(starts.into_iter().skip(1)) {
+        track.end = Some(next);
+    }
+    Ok(sheet)
+}
+
+fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// Reads a time like `03:12:37`, minutes, seconds and frames, as milliseconds
+fn parse_cue_time(time: &str) -> Option<u64> {
+    let parts = time
+        .split(':')
+        .map(|part| part.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    match parts[..] {
+        [minutes, seconds, frames] if seconds < 60 && frames < FRAMES_PER_SECOND => {
+            Some((minutes * 60 + seconds) * 1000 + frames * 1000 / FRAMES_PER_SECOND)
+        }
+        _ => None,
+    }
+}
+
+/// Places the tracks in a directory of their own, named like `01 - Title.flac`. As with
+/// `library_path`, names used on disk or in `taken` get the video's ID appended. Files in
+/// `replaced` belong to the songs the tracks are downloaded again for, so they are reused.
+pub fn set_paths(
+    tracks: &mut [Track],
+    dir: &Path,
+    video_id: &str,
+    profile: Profile,
+    taken: &[PathBuf],
+    replaced: &[PathBuf],
+) {
+    for track in tracks {
+        let name = format!("{:02} - {}", track.number, sanitize_file_name(&track.title));
+        let path = dir.join(format!("{}.{}", name, profile.extension()));
+        track.file_path = match replaced.contains(&path) {
+            true => path,
+            false => library_path(dir, &name, video_id, profile, taken),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_from_chapters() {
+        let chapter = |start: f64, end: f64, title: &str| Chapter {
+            start_time: Some(start),
+            end_time: Some(end),
+            title: Some(title.to_string()),
+        };
+        let mut tracks = from_chapters(&[
+            chapter(0.0, 252.5, "01. Encore"),
+            chapter(252.5, 513.0, "2) YOASOBI - Yoru ni Kakeru (Official Audio)"),
+            chapter(513.0, 780.0, "99 Luftballons"),
+            chapter(780.0, 900.0, ""),
+        ]);
+        let dir = Path::new("/music/THE BOOK");
+        let taken = [dir.join("03 - 99 Luftballons.flac")];
+        let replaced = [dir.join("04 - Track 4.flac")];
+        set_paths(&mut tracks, dir, "abc", Profile::Flac, &taken, &replaced);
+
+        let titles = tracks
+            .iter()
+            .map(|track| track.title.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            titles,
+            vec!["Encore", "Yoru ni Kakeru", "99 Luftballons", "Track 4"]
+        );
+        assert_eq!(tracks[0].artists, None);
+        assert_eq!(tracks[1].artists, Some(vec!["YOASOBI".to_string()]));
+        assert_eq!((tracks[1].start, tracks[1].end), (252_500, Some(513_000)));
+        assert_eq!(
+            tracks[1].file_path,
+            PathBuf::from("/music/THE BOOK/02 - Yoru ni Kakeru.flac")
+        );
+        assert_eq!(
+            tracks[2].file_path,
+            PathBuf::from("/music/THE BOOK/03 - 99 Luftballons [abc].flac")
+        );
+        assert_eq!(tracks[3].file_path, replaced[0]);
+    }
+
+    #[test]
+    fn read_cue_sheet() {
+        let sheet = parse_cue(
+            r#"REM GENRE J-Pop
+PERFORMER "YOASOBI"
+TITLE "THE BOOK"
+FILE "THE BOOK.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "Encore"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Yoru ni Kakeru"
+    PERFORMER "YOASOBI & Ikura"
+    INDEX 00 04:10:00
+    INDEX 01 04:12:37
+"#,
+        )
+        .unwrap();
+        assert_eq!(sheet.title.as_deref(), Some("THE BOOK"));
+        assert_eq!(sheet.performer.as_deref(), Some("YOASOBI"));
+        assert_eq!(sheet.tracks.len(), 2);
+        assert_eq!(sheet.tracks[0].title, "Encore");
+        assert_eq!(sheet.tracks[0].end, Some(252_493));
+        assert_eq!(
+            sheet.tracks[1].artists,
+            Some(vec!["YOASOBI & Ikura".to_string()])
+        );
+        assert_eq!(
+            (sheet.tracks[1].start, sheet.tracks[1].end),
+            (252_493, None)
+        );
+
+        assert!(parse_cue("").is_err());
+        assert!(parse_cue("TRACK 01 AUDIO\nTITLE \"A\"\n").is_err());
+        assert!(parse_cue(
+            "TRACK 01 AUDIO\nINDEX 01 01:00:00\nTRACK 02 AUDIO\nINDEX 01 00:30:00\n"
+        )
+        .is_err());
+        assert!(parse_cue("FILE \"a.flac\" WAVE\nFILE \"b.flac\" WAVE\n").is_err());
+        assert!(parse_cue("TRACK 01 AUDIO\nINDEX 01 1:99:00\n").is_err());
+        assert!(parse_cue(
+            "TRACK 02 AUDIO\nINDEX 01 00:00:00\nTRACK 02 AUDIO\nINDEX 01 01:00:00\n"
+        )
+        .is_err());
+        assert!(parse_cue(
+            "TRACK 02 AUDIO\nINDEX 01 00:00:00\nTRACK 01 AUDIO\nINDEX 01 01:00:00\n"
+        )
+        .is_err());
+    }
+}
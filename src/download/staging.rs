@@ -0,0 +1,122 @@
+//! Download jobs keep their files in a staging directory of their own until they are done, so
+//! nothing half-finished ends up in the library. Each stage writes its file under a partial
+//! name and renames it once it succeeds, so the files a job finds there on resuming are
+//! complete. The tagged file is moved into the library in one step.
+
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use eyre::{eyre, Result, WrapErr};
+
+/// Where a stage writes the file it makes at `path` until the stage succeeds
+pub fn partial_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!("partial-{}", name))
+}
+
+/// Removes everything in `dir` but the files in `keep`, such as the partial files and
+/// `.part` files a failed attempt left behind
+pub fn clean(dir: &Path, keep: &[PathBuf]) -> Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if keep.contains(&path) {
+            continue;
+        }
+        if path.is_dir() {
+            std::fs::remove_dir_all(&path)?;
+        } else {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes a job's staging directory with whatever is left in it
+pub fn remove(dir: &Path) -> Result<()> {
+    match std::fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).wrap_err_with(|| format!("Can't remove {}", dir.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Moves a finished file to its place in the library, replacing any file there. The file
+/// appears there whole or not at all: a staging directory on another file system is copied
+/// next to the destination first, then renamed.
+pub fn move_into_library(file: &Path, destination: &Path) -> Result<()> {
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match std::fs::rename(file, destination) {
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {}
+        Err(e) => {
+            return Err(eyre!(
+                "Can't move {} to {}: {}",
+                file.display(),
+                destination.display(),
+                e
+            ))
+        }
+    }
+
+    let copy = partial_path(destination);
+    let moved = std::fs::copy(file, &copy).and_then(|_| std::fs::rename(&copy, destination));
+    if let Err(e) = moved {
+        let _ = std::fs::remove_file(&copy);
+        return Err(eyre!(
+            "Can't move {} to {}: {}",
+            file.display(),
+            destination.display(),
+            e
+        ));
+    }
+    std::fs::remove_file(file)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean_and_move() {
+        let dir = std::env::temp_dir().join("music-manager-staging-tests");
+        let _ = std::fs::remove_dir_all(&dir);
+        let staging = dir.join(".staging/job-1");
+        std::fs::create_dir_all(staging.join("leftovers")).unwrap();
+        let download = staging.join("download.opus");
+        assert_eq!(
+            partial_path(&download),
+            staging.join("partial-download.opus")
+        );
+        std::fs::write(&download, "OggS").unwrap();
+        std::fs::write(staging.join("partial-converted.flac"), "fL").unwrap();
+        std::fs::write(staging.join("partial-download.webm.part"), "").unwrap();
+
+        clean(&staging, std::slice::from_ref(&download)).unwrap();
+        let left = std::fs::read_dir(&staging)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(left, vec![download.clone()]);
+
+        let destination = dir.join("Artist/song.opus");
+        move_into_library(&download, &destination).unwrap();
+        assert!(!download.exists());
+        assert_eq!(std::fs::read(&destination).unwrap(), b"OggS");
+
+        remove(&staging).unwrap();
+        assert!(!staging.exists());
+        remove(&staging).unwrap();
+    }
+}
@@ -0,0 +1,386 @@
+//! Guesses the artist and title of a song from the title of its video. Music uploads are
+//! usually titled like `Artist - Title (Official Video)` or `Artist「Title」MV`, and their
+//! channel may be an auto-generated `Artist - Topic` one.
+//!
+//! The rules only take apart what they recognise: brackets holding nothing but words like
+//! "official", "video" or "lyrics" are dropped, `feat.` annotations become extra artists, and
+//! anything else, such as `(Live)` or `[Remix]`, stays in the title.
+
+/// Brackets whose contents are annotations rather than part of the title
+const BRACKETS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('【', '】'), ('（', '）')];
+
+/// Quotes Japanese uploads put around the title, as in `Artist「Title」`
+const QUOTES: &[(char, char)] = &[('「', '」'), ('『', '』'), ('"', '"'), ('“', '”')];
+
+/// Separators between the artist and the title
+const SEPARATORS: &[&str] = &[" - ", " – ", " — ", " ｰ ", " ー "];
+
+/// Words which only describe the upload. Bracketed text made of them alone is dropped.
+const NOISE_WORDS: &[&str] = &[
+    "official",
+    "music",
+    "video",
+    "mv",
+    "pv",
+    "audio",
+    "lyric",
+    "lyrics",
+    "visualizer",
+    "visualiser",
+    "hd",
+    "hq",
+    "4k",
+    "clip",
+    "with",
+    "eng",
+    "english",
+    "sub",
+    "subs",
+    "subtitles",
+    "color",
+    "coded",
+    "romaji",
+];
+
+/// Words which mark text as noise even outside brackets, as in `Title Official Video`
+const NOISE_MARKERS: &[&str] = &["official", "mv", "pv", "lyric", "lyrics"];
+
+/// Words introducing featured artists
+const FEATURING: &[&str] = &["feat.", "feat", "ft.", "ft", "featuring"];
+
+/// Separators between featured artists
+const ARTIST_SEPARATORS: &[&str] = &[", ", " & ", " and ", " x ", " × "];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedTitle {
+    pub title: String,
+    /// The main artist first, followed by featured artists. Empty when neither the title nor
+    /// the channel name one.
+    pub artists: Vec<String>,
+}
+
+/// Splits a video title into the song's title and artists. The channel is the artist when the
+/// title doesn't name one.
+pub fn parse(video_title: &str, channel: Option<&str>) -> ParsedTitle {
+    let mut featured = vec![];
+    let text = strip_brackets(video_title, &mut featured);
+    let text = text
+        .split(" | ")
+        .filter(|segment| !is_noise(segment))
+        .collect::<Vec<_>>()
+        .join(" | ");
+
+    let (artist, title) = match split_artist(&text) {
+        Some((artist, title)) => (Some(artist), title),
+        None => (None, text),
+    };
+
+    let (title, title_featured) = split_featuring(&strip_trailing_noise(&title));
+    let title = unquote(&title);
+    let mut artists = match artist {
+        Some(artist) => {
+            let (artist, artist_featured) = split_featuring(&strip_trailing_noise(&artist));
+            let mut artists = split_artists(&artist, &[", ", " x ", " × "]);
+            artists.extend(artist_featured);
+            artists
+        }
+        None => channel
+            .map(clean_channel)
+            .filter(|channel| !channel.is_empty())
+            .into_iter()
+            .collect(),
+    };
+    artists.extend(featured);
+    artists.extend(title_featured);
+
+    let mut unique: Vec<String> = vec![];
+    for artist in artists {
+        if !unique
+            .iter()
+            .any(|known| known.to_lowercase() == artist.to_lowercase())
+        {
+            unique.push(artist);
+        }
+    }
+
+    ParsedTitle {
+        title: match title.is_empty() {
+            true => video_title.trim().to_string(),
+            false => title,
+        },
+        artists: unique,
+    }
+}
+
+/// Removes bracketed noise and featured artists, keeping any other brackets
+fn strip_brackets(text: &str, featured: &mut Vec<String>) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some((start, open)) = rest
+        .char_indices()
+        .find(|(_, c)| BRACKETS.iter().any(|(open, _)| open == c))
+    {
+        let close = BRACKETS.iter().find(|(o, _)| *o == open).unwrap().1;
+        let after_open = start + open.len_utf8();
+        let end = match rest[after_open..].find(close) {
+            Some(end) => after_open + end,
+            None => break,
+        };
+        let inner = &rest[after_open..end];
+        result.push_str(&rest[..start]);
+        if let Some(names) = strip_featuring_prefix(inner) {
+            featured.extend(split_artists(names, ARTIST_SEPARATORS));
+        } else if !is_noise(inner) {
+            result.push_str(&rest[start..end + close.len_utf8()]);
+        }
+        rest = &rest[end + close.len_utf8()..];
+    }
+    result.push_str(rest);
+    collapse_whitespace(&result)
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .replace("m/v", "mv")
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn is_noise(text: &str) -> bool {
+    words(text)
+        .iter()
+        .all(|word| NOISE_WORDS.contains(&word.as_str()))
+}
+
+/// Drops trailing words like `Official Music Video` which aren't in brackets
+fn strip_trailing_noise(text: &str) -> String {
+    let tokens = text.split_whitespace().collect::<Vec<_>>();
+    let mut keep = tokens.len();
+    while keep > 0 && is_noise(tokens[keep - 1]) {
+        keep -= 1;
+    }
+    let trailing = words(&tokens[keep..].join(" "));
+    if keep == 0
+        || !trailing
+            .iter()
+            .any(|word| NOISE_MARKERS.contains(&word.as_str()))
+    {
+        return text.to_string();
+    }
+    tokens[..keep]
+        .join(" ")
+        .trim_end_matches(|c: char| c == '-' || c == '|' || c == '/' || c.is_whitespace())
+        .to_string()
+}
+
+/// Splits `Artist - Title` or `Artist「Title」`
+fn split_artist(text: &str) -> Option<(String, String)> {
+    if let Some((artist, title)) = SEPARATORS
+        .iter()
+        .filter_map(|separator| text.split_once(separator))
+        .min_by_key(|(artist, _)| artist.len())
+    {
+        if !artist.trim().is_empty() && !title.trim().is_empty() {
+            return Some((artist.trim().to_string(), title.trim().to_string()));
+        }
+    }
+
+    for (open, close) in QUOTES {
+        if let Some((artist, rest)) = text.split_once(*open) {
+            if let Some((title, after)) = rest.split_once(*close) {
+                if !artist.trim().is_empty() && !title.trim().is_empty() {
+                    let after = strip_trailing_noise(after.trim());
+                    let title = match is_noise(&after) {
+                        true => title.trim().to_string(),
+                        false => format!("{} {}", title.trim(), after),
+                    };
+                    return Some((artist.trim().to_string(), title));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// The artists after `feat.` at the start of the text, as in `(feat. Someone)`
+fn strip_featuring_prefix(text: &str) -> Option<&str> {
+    let text = text.trim();
+    let (word, rest) = text.split_once(' ')?;
+    FEATURING
+        .iter()
+        .any(|featuring| word.eq_ignore_ascii_case(featuring))
+        .then(|| rest.trim())
+}
+
+/// Splits `Name feat. Someone & Other` into the name and its featured artists
+fn split_featuring(text: &str) -> (String, Vec<String>) {
+    let tokens = text.split(' ').collect::<Vec<_>>();
+    match tokens.iter().position(|token| {
+        FEATURING
+            .iter()
+            .any(|featuring| token.eq_ignore_ascii_case(featuring))
+    }) {
+        Some(position) if position > 0 => (
+            tokens[..position].join(" ").trim().to_string(),
+            split_artists(&tokens[position + 1..].join(" "), ARTIST_SEPARATORS),
+        ),
+        _ => (text.trim().to_string(), vec![]),
+    }
+}
+
+fn split_artists(text: &str, separators: &[&str]) -> Vec<String> {
+    let mut artists = vec![text.to_string()];
+    for separator in separators {
+        artists = artists
+            .iter()
+            .flat_map(|artist| artist.split(separator))
+            .map(|artist| artist.trim().to_string())
+            .collect();
+    }
+    artists.retain(|artist| !artist.is_empty());
+    artists
+}
+
+/// Removes surrounding quotes, as in `Artist - "Title"`
+fn unquote(text: &str) -> String {
+    let text = text.trim();
+    for (open, close) in QUOTES {
+        if let Some(inner) = text
+            .strip_prefix(*open)
+            .and_then(|text| text.strip_suffix(*close))
+        {
+            if !inner.contains(*open) {
+                return inner.trim().to_string();
+            }
+        }
+    }
+    text.to_string()
+}
+
+/// The artist an auto-generated or label channel belongs to, as in `Artist - Topic` or
+/// `ArtistVEVO`
+fn clean_channel(channel: &str) -> String {
+    let channel = channel.trim();
+    let channel = channel
+        .strip_suffix(" - Topic")
+        .or_else(|| channel.strip_suffix("VEVO"))
+        .unwrap_or(channel);
+    let tokens = channel.split_whitespace().collect::<Vec<_>>();
+    let mut keep = tokens.len();
+    while keep > 1
+        && ["official", "channel", "youtube"].contains(&tokens[keep - 1].to_lowercase().as_str())
+    {
+        keep -= 1;
+    }
+    tokens[..keep].join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_titles() {
+        let cases: &[(&str, Option<&str>, &str, &[&str])] = &[
+            (
+                "YOASOBI - Yoru ni Kakeru",
+                Some("Ayase / YOASOBI"),
+                "Yoru ni Kakeru",
+                &["YOASOBI"],
+            ),
+            (
+                "YOASOBI「夜に駆ける」Official Music Video",
+                Some("Ayase / YOASOBI"),
+                "夜に駆ける",
+                &["YOASOBI"],
+            ),
+            (
+                "Yoru ni Kakeru",
+                Some("YOASOBI - Topic"),
+                "Yoru ni Kakeru",
+                &["YOASOBI"],
+            ),
+            (
+                "Rick Astley - Never Gonna Give You Up (Official Music Video)",
+                Some("Rick Astley"),
+                "Never Gonna Give You Up",
+                &["Rick Astley"],
+            ),
+            (
+                "米津玄師 MV「Lemon」",
+                Some("Kenshi Yonezu 米津玄師"),
+                "Lemon",
+                &["米津玄師"],
+            ),
+            (
+                "Mark Ronson - Uptown Funk (Official Video) ft. Bruno Mars",
+                Some("Mark Ronson"),
+                "Uptown Funk",
+                &["Mark Ronson", "Bruno Mars"],
+            ),
+            (
+                "Calvin Harris - This Is What You Came For (Official Video) [feat. Rihanna]",
+                Some("CalvinHarrisVEVO"),
+                "This Is What You Came For",
+                &["Calvin Harris", "Rihanna"],
+            ),
+            (
+                "DJ Snake feat. Lil Jon - Turn Down for What",
+                None,
+                "Turn Down for What",
+                &["DJ Snake", "Lil Jon"],
+            ),
+            (
+                "Daft Punk - Get Lucky (feat. Pharrell Williams & Nile Rodgers) [Lyrics]",
+                None,
+                "Get Lucky",
+                &["Daft Punk", "Pharrell Williams", "Nile Rodgers"],
+            ),
+            ("Ado - 踊 (Odo) 【MV】", Some("Ado"), "踊 (Odo)", &["Ado"]),
+            (
+                "YOASOBI - Yoru ni Kakeru (Live)",
+                Some("YOASOBI"),
+                "Yoru ni Kakeru (Live)",
+                &["YOASOBI"],
+            ),
+            (
+                "Gunjou | Official Lyric Video",
+                Some("YOASOBI Official Channel"),
+                "Gunjou",
+                &["YOASOBI"],
+            ),
+            ("Eve - 廻廻奇譚 [M/V]", None, "廻廻奇譚", &["Eve"]),
+            (
+                "Queen – \"Bohemian Rhapsody\" (Official Video)",
+                None,
+                "Bohemian Rhapsody",
+                &["Queen"],
+            ),
+            (
+                "Queen - Don't Stop Me Now (Remastered 2011)",
+                Some("Queen Official"),
+                "Don't Stop Me Now (Remastered 2011)",
+                &["Queen"],
+            ),
+            (
+                "Official Video",
+                Some("Someone"),
+                "Official Video",
+                &["Someone"],
+            ),
+            ("Untitled", None, "Untitled", &[]),
+        ];
+
+        for (video_title, channel, title, artists) in cases {
+            let parsed = parse(video_title, *channel);
+            assert_eq!(parsed.title, *title, "title of {:?}", video_title);
+            assert_eq!(parsed.artists, *artists, "artists of {:?}", video_title);
+        }
+    }
+}
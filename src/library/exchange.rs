@@ -0,0 +1,706 @@
+//! Exports the database to JSON Lines or CSV, and imports it back
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    path::PathBuf,
+};
+
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString, EnumVariantNames};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::data::{
+    database::Database, provenance::Provenance, replaygain::ReplayGain, song::Song, trim::Trim,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, EnumVariantNames, Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Format {
+    /// One JSON object per line
+    Jsonl,
+    /// Comma separated values with a header row. Artists are separated by newlines.
+    Csv,
+}
+
+impl Format {
+    /// Guesses the format from a file name, defaulting to JSON Lines
+    pub fn from_path(path: &std::path::Path) -> Format {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("csv") => Format::Csv,
+            _ => Format::Jsonl,
+        }
+    }
+}
+
+/// How imported records are matched with existing ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, EnumVariantNames, Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ConflictKey {
+    Path,
+    /// Records without a YouTube ID never conflict
+    YoutubeId,
+}
+
+/// What to do when an imported record matches an existing one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, EnumVariantNames, Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// Keep the existing record
+    Skip,
+    /// Replace the existing record with the imported one
+    Overwrite,
+    /// Fill in fields the existing record is missing from the imported one
+    Merge,
+}
+
+/// A song as written to an export. IDs are exported for reference, but never imported.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SongRecord {
+    #[serde(default)]
+    pub id: Option<usize>,
+    pub path: String,
+    #[serde(default)]
+    pub file_name: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub artists: Vec<String>,
+    #[serde(default)]
+    pub album: Option<String>,
+    #[serde(default)]
+    pub genre: Option<String>,
+    #[serde(default)]
+    pub youtube_id: Option<String>,
+    #[serde(default)]
+    pub thumbnail_url: Option<String>,
+    #[serde(default)]
+    pub release_date: Option<String>,
+    #[serde(default)]
+    pub track_number: Option<u32>,
+    /// RFC 3339 timestamp
+    #[serde(default)]
+    pub date_added: Option<String>,
+    #[serde(default)]
+    pub source_codec: Option<String>,
+    #[serde(default)]
+    pub source_bitrate: Option<u32>,
+    #[serde(default)]
+    pub source_url: Option<String>,
+    #[serde(default)]
+    pub downloader_version: Option<String>,
+    /// RFC 3339 timestamp
+    #[serde(default)]
+    pub converted_at: Option<String>,
+    #[serde(default)]
+    pub replaygain_track_gain: Option<f64>,
+    #[serde(default)]
+    pub replaygain_track_peak: Option<f64>,
+    #[serde(default)]
+    pub replaygain_album_gain: Option<f64>,
+    #[serde(default)]
+    pub replaygain_album_peak: Option<f64>,
+    /// Length before silence was trimmed, in seconds
+    #[serde(default)]
+    pub original_duration: Option<f64>,
+    #[serde(default)]
+    pub trimmed_start: Option<f64>,
+    #[serde(default)]
+    pub trimmed_end: Option<f64>,
+}
+
+/// CSV cells can't hold lists, so artists are joined by newlines
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvRecord {
+    id: Option<usize>,
+    path: String,
+    file_name: Option<String>,
+    title: Option<String>,
+    artists: Option<String>,
+    album: Option<String>,
+    genre: Option<String>,
+    youtube_id: Option<String>,
+    thumbnail_url: Option<String>,
+    release_date: Option<String>,
+    track_number: Option<u32>,
+    date_added: Option<String>,
+    source_codec: Option<String>,
+    source_bitrate: Option<u32>,
+    source_url: Option<String>,
+    downloader_version: Option<String>,
+    converted_at: Option<String>,
+    replaygain_track_gain: Option<f64>,
+    replaygain_track_peak: Option<f64>,
+    replaygain_album_gain: Option<f64>,
+    replaygain_album_peak: Option<f64>,
+    original_duration: Option<f64>,
+    trimmed_start: Option<f64>,
+    trimmed_end: Option<f64>,
+}
+
+impl From<SongRecord> for CsvRecord {
+    fn from(record: SongRecord) -> Self {
+        Self {
+            id: record.id,
+            path: record.path,
+            file_name: record.file_name,
+            title: record.title,
+            artists: Some(record.artists.join("\n")).filter(|artists| !artists.is_empty()),
+            album: record.album,
+            genre: record.genre,
+            youtube_id: record.youtube_id,
+            thumbnail_url: record.thumbnail_url,
+            release_date: record.release_date,
+            track_number: record.track_number,
+            date_added: record.date_added,
+            source_codec: record.source_codec,
+            source_bitrate: record.source_bitrate,
+            source_url: record.source_url,
+            downloader_version: record.downloader_version,
+            converted_at: record.converted_at,
+            replaygain_track_gain: record.replaygain_track_gain,
+            replaygain_track_peak: record.replaygain_track_peak,
+            replaygain_album_gain: record.replaygain_album_gain,
+            replaygain_album_peak: record.replaygain_album_peak,
+            original_duration: record.original_duration,
+            trimmed_start: record.trimmed_start,
+            trimmed_end: record.trimmed_end,
+        }
+    }
+}
+
+impl From<CsvRecord> for SongRecord {
+    fn from(record: CsvRecord) -> Self {
+        Self {
+            id: record.id,
+            path: record.path,
+            file_name: record.file_name,
+            title: record.title,
+            artists: record
+                .artists
+                .unwrap_or_default()
+                .lines()
+                .map(str::trim)
+                .filter(|artist| !artist.is_empty())
+                .map(str::to_string)
+                .collect(),
+            album: record.album,
+            genre: record.genre,
+            youtube_id: record.youtube_id,
+            thumbnail_url: record.thumbnail_url,
+            release_date: record.release_date,
+            track_number: record.track_number,
+            date_added: record.date_added,
+            source_codec: record.source_codec,
+            source_bitrate: record.source_bitrate,
+            source_url: record.source_url,
+            downloader_version: record.downloader_version,
+            converted_at: record.converted_at,
+            replaygain_track_gain: record.replaygain_track_gain,
+            replaygain_track_peak: record.replaygain_track_peak,
+            replaygain_album_gain: record.replaygain_album_gain,
+            replaygain_album_peak: record.replaygain_album_peak,
+            original_duration: record.original_duration,
+            trimmed_start: record.trimmed_start,
+            trimmed_end: record.trimmed_end,
+        }
+    }
+}
+
+impl SongRecord {
+    pub fn from_song(song: &Song) -> Result<Self> {
+        Ok(Self {
+            id: song.id,
+            path: song
+                .file_path
+                .to_str()
+                .ok_or_else(|| eyre!("Can't convert path to str"))?
+                .to_string(),
+            file_name: Some(song.file_name.clone()),
+            title: song.title.clone(),
+            artists: song.artists.clone().unwrap_or_default(),
+            album: song.album.clone(),
+            genre: song.genre.clone(),
+            youtube_id: song.youtube_id.clone(),
+            thumbnail_url: song.thumbnail_url.clone(),
+            release_date: song.release_date.clone(),
+            track_number: song.track_number,
+            date_added: song
+                .date_added
+                .map(|date_added| date_added.format(&Rfc3339))
+                .transpose()?,
+            source_codec: song.provenance.source_codec.clone(),
+            source_bitrate: song.provenance.source_bitrate,
+            source_url: song.provenance.source_url.clone(),
+            downloader_version: song.provenance.downloader_version.clone(),
+            converted_at: song
+                .provenance
+                .converted_at
+                .map(|converted_at| converted_at.format(&Rfc3339))
+                .transpose()?,
+            replaygain_track_gain: song.replaygain.track_gain,
+            replaygain_track_peak: song.replaygain.track_peak,
+            replaygain_album_gain: song.replaygain.album_gain,
+            replaygain_album_peak: song.replaygain.album_peak,
+            original_duration: song.trim.original_duration,
+            trimmed_start: song.trim.start,
+            trimmed_end: song.trim.end,
+        })
+    }
+
+    pub fn into_song(self) -> Result<Song> {
+        let file_path = PathBuf::from(&self.path);
+        let file_name = match self.file_name {
+            Some(file_name) => file_name,
+            None => file_path
+                .file_name()
+                .and_then(|file_name| file_name.to_str())
+                .ok_or_else(|| eyre!("Path {} has no file name", self.path))?
+                .to_string(),
+        };
+        let date_added = self
+            .date_added
+            .map(|date_added| OffsetDateTime::parse(&date_added, &Rfc3339))
+            .transpose()
+            .map_err(|e| eyre!("Invalid date_added: {}", e))?;
+        let converted_at = self
+            .converted_at
+            .map(|converted_at| OffsetDateTime::parse(&converted_at, &Rfc3339))
+            .transpose()
+            .map_err(|e| eyre!("Invalid converted_at: {}", e))?;
+
+        Ok(Song {
+            file_path,
+            file_name,
+            title: self.title,
+            artists: Some(self.artists).filter(|artists| !artists.is_empty()),
+            album: self.album,
+            genre: self.genre,
+            youtube_id: self.youtube_id,
+            thumbnail_url: self.thumbnail_url,
+            release_date: self.release_date,
+            track_number: self.track_number,
+            date_added,
+            provenance: Provenance {
+                source_codec: self.source_codec,
+                source_bitrate: self.source_bitrate,
+                source_url: self.source_url,
+                downloader_version: self.downloader_version,
+                converted_at,
+            },
+            replaygain: ReplayGain {
+                track_gain: self.replaygain_track_gain,
+                track_peak: self.replaygain_track_peak,
+                album_gain: self.replaygain_album_gain,
+                album_peak: self.replaygain_album_peak,
+            },
+            trim: Trim {
+                original_duration: self.original_duration,
+                start: self.trimmed_start,
+                end: self.trimmed_end,
+            },
+            ..Default::default()
+        })
+    }
+}
+
+/// Writes every song in the database. Returns the number of songs written.
+pub fn export<W: Write>(database: &Database, writer: W, format: Format) -> Result<usize> {
+    let songs = database.all_songs()?;
+    let records = songs
+        .iter()
+        .map(SongRecord::from_song)
+        .collect::<Result<Vec<_>>>()?;
+
+    match format {
+        Format::Jsonl => {
+            let mut writer = writer;
+            for record in &records {
+                serde_json::to_writer(&mut writer, record)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for record in &records {
+                writer.serialize(CsvRecord::from(record.clone()))?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(records.len())
+}
+
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub inserted: usize,
+    pub overwritten: usize,
+    pub merged: usize,
+    pub skipped: usize,
+    /// Line or record number and the error
+    pub failed: Vec<(usize, String)>,
+}
+
+/// Reads songs and adds them to the database, resolving conflicts with existing records
+pub fn import<R: Read>(
+    database: &Database,
+    reader: R,
+    format: Format,
+    key: ConflictKey,
+    policy: ConflictPolicy,
+) -> Result<ImportSummary> {
+    let mut summary = ImportSummary::default();
+
+    let records: Vec<(usize, Result<SongRecord>)> = match format {
+        Format::Jsonl => BufReader::new(reader)
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line))
+            .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+            .map(|(number, line)| {
+                let record = line
+                    .map_err(|e| eyre!(e))
+                    .and_then(|line| serde_json::from_str(&line).map_err(|e| eyre!(e)));
+                (number, record)
+            })
+            .collect(),
+        Format::Csv => csv::Reader::from_reader(reader)
+            .deserialize::<CsvRecord>()
+            .enumerate()
+            // The header is line 1
+            .map(|(index, record)| {
+                (
+                    index + 2,
+                    record.map(SongRecord::from).map_err(|e| eyre!(e)),
+                )
+            })
+            .collect(),
+    };
+
+    for (number, record) in records {
+        match record
+            .and_then(SongRecord::into_song)
+            .and_then(|song| import_song(database, song, key, policy))
+        {
+            Ok(ImportResult::Inserted) => summary.inserted += 1,
+            Ok(ImportResult::Overwritten) => summary.overwritten += 1,
+            Ok(ImportResult::Merged) => summary.merged += 1,
+            Ok(ImportResult::Skipped) => summary.skipped += 1,
+            Err(e) => summary.failed.push((number, e.to_string())),
+        }
+    }
+
+    Ok(summary)
+}
+
+enum ImportResult {
+    Inserted,
+    Overwritten,
+    Merged,
+    Skipped,
+}
+
+fn import_song(
+    database: &Database,
+    song: Song,
+    key: ConflictKey,
+    policy: ConflictPolicy,
+) -> Result<ImportResult> {
+    let existing = match key {
+        ConflictKey::Path => database.query_song_by_path(&song.file_path)?,
+        ConflictKey::YoutubeId => match &song.youtube_id {
+            Some(youtube_id) => database
+                .query_songs_by_youtube_id(youtube_id)?
+                .into_iter()
+                .next(),
+            None => None,
+        },
+    };
+
+    let existing = match existing {
+        Some(existing) => existing,
+        None => {
+            database.insert_song(&song)?;
+            return Ok(ImportResult::Inserted);
+        }
+    };
+
+    match policy {
+        ConflictPolicy::Skip => Ok(ImportResult::Skipped),
+        ConflictPolicy::Overwrite => {
+            let song = Song {
+                id: existing.id,
+                ..song
+            };
+            database.update_song(&song)?;
+            Ok(ImportResult::Overwritten)
+        }
+        ConflictPolicy::Merge => {
+            let merged = Song {
+                title: existing.title.clone().or(song.title),
+                artists: existing.artists.clone().or(song.artists),
+                album: existing.album.clone().or(song.album),
+                genre: existing.genre.clone().or(song.genre),
+                youtube_id: existing.youtube_id.clone().or(song.youtube_id),
+                thumbnail_url: existing.thumbnail_url.clone().or(song.thumbnail_url),
+                release_date: existing.release_date.clone().or(song.release_date),
+                track_number: existing.track_number.or(song.track_number),
+                provenance: Provenance {
+                    source_codec: existing
+                        .provenance
+                        .source_codec
+                        .clone()
+                        .or(song.provenance.source_codec),
+                    source_bitrate: existing
+                        .provenance
+                        .source_bitrate
+                        .or(song.provenance.source_bitrate),
+                    source_url: existing
+                        .provenance
+                        .source_url
+                        .clone()
+                        .or(song.provenance.source_url),
+                    downloader_version: existing
+                        .provenance
+                        .downloader_version
+                        .clone()
+                        .or(song.provenance.downloader_version),
+                    converted_at: existing
+                        .provenance
+                        .converted_at
+                        .or(song.provenance.converted_at),
+                },
+                replaygain: ReplayGain {
+                    track_gain: existing
+                        .replaygain
+                        .track_gain
+                        .or(song.replaygain.track_gain),
+                    track_peak: existing
+                        .replaygain
+                        .track_peak
+                        .or(song.replaygain.track_peak),
+                    album_gain: existing
+                        .replaygain
+                        .album_gain
+                        .or(song.replaygain.album_gain),
+                    album_peak: existing
+                        .replaygain
+                        .album_peak
+                        .or(song.replaygain.album_peak),
+                },
+                trim: Trim {
+                    original_duration: existing
+                        .trim
+                        .original_duration
+                        .or(song.trim.original_duration),
+                    start: existing.trim.start.or(song.trim.start),
+                    end: existing.trim.end.or(song.trim.end),
+                },
+                ..existing.clone()
+            };
+            if Song::equate(&merged, &existing) {
+                return Ok(ImportResult::Skipped);
+            }
+            database.update_song(&merged)?;
+            Ok(ImportResult::Merged)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn fresh_database(name: &str) -> Database {
+        let path = Path::new("/tmp").join(name);
+        if path.exists() {
+            std::fs::remove_file(&path).unwrap();
+        }
+        Database::open_from_path(path).unwrap()
+    }
+
+    fn fill(database: &Database) {
+        database
+            .insert_song(&Song {
+                file_path: "/music/Yoru ni Kakeru.flac".into(),
+                file_name: "Yoru ni Kakeru.flac".into(),
+                title: Some("Yoru ni Kakeru".to_string()),
+                artists: Some(vec!["YOASOBI".to_string(), "Ayase, \"ikura\"".to_string()]),
+                album: Some("THE BOOK".to_string()),
+                genre: Some("J-Pop".to_string()),
+                youtube_id: Some("x8VYWazR5mE".to_string()),
+                thumbnail_url: Some("https://i.ytimg.com/vi/x8VYWazR5mE/hq.jpg".to_string()),
+                release_date: Some("2019-12-15".to_string()),
+                track_number: Some(2),
+                provenance: Provenance {
+                    source_codec: Some("Opus".to_string()),
+                    source_bitrate: Some(135),
+                    source_url: Some("https://youtu.be/x8VYWazR5mE".to_string()),
+                    downloader_version: Some("youtube-dl 2021.12.17".to_string()),
+                    converted_at: Some(OffsetDateTime::from_unix_timestamp(1638352800).unwrap()),
+                },
+                replaygain: ReplayGain {
+                    track_gain: Some(-8.42),
+                    track_peak: Some(0.988525),
+                    album_gain: Some(-8.1),
+                    album_peak: Some(1.0),
+                },
+                trim: Trim {
+                    original_duration: Some(275.25),
+                    start: Some(1.5),
+                    end: Some(3.125),
+                },
+                ..Default::default()
+            })
+            .unwrap();
+        database
+            .insert_song(&Song {
+                file_path: "/music/Untagged.flac".into(),
+                file_name: "Untagged.flac".into(),
+                ..Default::default()
+            })
+            .unwrap();
+    }
+
+    fn records(database: &Database) -> Vec<SongRecord> {
+        database
+            .all_songs()
+            .unwrap()
+            .iter()
+            .map(|song| SongRecord {
+                id: None,
+                ..SongRecord::from_song(song).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for format in [Format::Jsonl, Format::Csv] {
+            let source = fresh_database(&format!("exchange_source_{}.sqlite", format));
+            fill(&source);
+            let mut exported = vec![];
+            assert_eq!(export(&source, &mut exported, format).unwrap(), 2);
+
+            let target = fresh_database(&format!("exchange_target_{}.sqlite", format));
+            let summary = import(
+                &target,
+                exported.as_slice(),
+                format,
+                ConflictKey::Path,
+                ConflictPolicy::Skip,
+            )
+            .unwrap();
+            assert_eq!(summary.inserted, 2, "{}: {:?}", format, summary.failed);
+            assert_eq!(records(&source), records(&target));
+
+            // Importing again only finds conflicts
+            let summary = import(
+                &target,
+                exported.as_slice(),
+                format,
+                ConflictKey::Path,
+                ConflictPolicy::Skip,
+            )
+            .unwrap();
+            assert_eq!(summary.skipped, 2);
+            assert_eq!(target.all_songs().unwrap().len(), 2);
+
+            // Overwriting with the same records changes nothing
+            let summary = import(
+                &target,
+                exported.as_slice(),
+                format,
+                ConflictKey::Path,
+                ConflictPolicy::Overwrite,
+            )
+            .unwrap();
+            assert_eq!(summary.overwritten, 2);
+            assert_eq!(records(&source), records(&target));
+            let song = target
+                .query_song_by_path(Path::new("/music/Yoru ni Kakeru.flac"))
+                .unwrap()
+                .unwrap();
+            assert_eq!(song.provenance.source_codec.as_deref(), Some("Opus"));
+            assert!(song.provenance.converted_at.is_some());
+            assert_eq!(song.release_date.as_deref(), Some("2019-12-15"));
+            assert_eq!(song.track_number, Some(2));
+            assert_eq!(song.replaygain.track_gain, Some(-8.42));
+            assert_eq!(song.replaygain.album_peak, Some(1.0));
+            assert_eq!(song.trim.original_duration, Some(275.25));
+            assert_eq!(song.trim.end, Some(3.125));
+        }
+    }
+
+    #[test]
+    fn resolve_conflicts() {
+        let database = fresh_database("exchange_conflicts.sqlite");
+        fill(&database);
+
+        let import_line = |line: &str, key, policy| {
+            import(&database, line.as_bytes(), Format::Jsonl, key, policy).unwrap()
+        };
+
+        // Merging only fills in missing fields
+        let summary = import_line(
+            r#"{"path": "/music/Untagged.flac", "title": "Tagged", "album": "Album"}"#,
+            ConflictKey::Path,
+            ConflictPolicy::Merge,
+        );
+        assert_eq!(summary.merged, 1);
+        let summary = import_line(
+            r#"{"path": "/music/Untagged.flac", "title": "Ignored"}"#,
+            ConflictKey::Path,
+            ConflictPolicy::Merge,
+        );
+        assert_eq!(summary.skipped, 1);
+        let song = database
+            .query_song_by_path(Path::new("/music/Untagged.flac"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(song.title.as_deref(), Some("Tagged"));
+        assert_eq!(song.album.as_deref(), Some("Album"));
+
+        // Merging keeps the provenance a song has
+        let summary = import_line(
+            r#"{"path": "/music/Yoru ni Kakeru.flac", "source_codec": "FLAC",
+                "source_url": "https://example.com"}"#
+                .replace('\n', " ")
+                .as_str(),
+            ConflictKey::Path,
+            ConflictPolicy::Merge,
+        );
+        assert_eq!(summary.skipped, 1);
+        let song = database
+            .query_song_by_path(Path::new("/music/Yoru ni Kakeru.flac"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(song.provenance.source_codec.as_deref(), Some("Opus"));
+
+        // Overwriting by YouTube ID replaces everything, including the path
+        let summary = import_line(
+            r#"{"path": "/music/Moved.flac", "title": "Moved", "youtube_id": "x8VYWazR5mE"}"#,
+            ConflictKey::YoutubeId,
+            ConflictPolicy::Overwrite,
+        );
+        assert_eq!(summary.overwritten, 1);
+        let songs = database.query_songs_by_youtube_id("x8VYWazR5mE").unwrap();
+        assert_eq!(songs.len(), 1);
+        assert_eq!(songs[0].file_path, Path::new("/music/Moved.flac"));
+        assert_eq!(songs[0].artists, None);
+
+        // Broken lines are reported without stopping the import
+        let summary = import_line(
+            "not json\n\n{\"path\": \"/music/New.flac\"}\n",
+            ConflictKey::Path,
+            ConflictPolicy::Skip,
+        );
+        assert_eq!(summary.inserted, 1);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, 1);
+    }
+}
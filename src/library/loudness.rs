@@ -0,0 +1,269 @@
+//! Measures the loudness of music files with ffmpeg's `ebur128` filter and tags them with
+//! ReplayGain values. The loudness of an album is the mean of its songs' loudness weighted by
+//! their length, which comes close to measuring the album as one stream.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use eyre::{eyre, Result};
+use tokio::process::Command;
+
+use crate::{
+    data::{
+        database::Database,
+        replaygain::{round_gain, round_peak, ReplayGain, REFERENCE_LOUDNESS},
+        song::Song,
+    },
+    download::{self, progress::Output},
+};
+
+/// Loudness of a song or an album as EBU R128 measures it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS
+    pub integrated: f64,
+    /// True peak, where 1 is full scale
+    pub peak: f64,
+    /// Length in seconds
+    pub duration: f64,
+}
+
+impl Loudness {
+    /// Gain bringing this loudness to the reference
+    pub fn gain(&self) -> f64 {
+        round_gain(REFERENCE_LOUDNESS - self.integrated)
+    }
+}
+
+/// What `apply` did to each song
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub tagged: Vec<(PathBuf, ReplayGain)>,
+    /// Songs which have their values already
+    pub skipped: usize,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// Measures a music file
+pub async fn measure(path: &Path) -> Result<Loudness> {
+    let input = path
+        .to_str()
+        .ok_or_else(|| eyre!("Can't convert path to str"))?;
+    let mut lines = vec![];
+    download::run(
+        Command::new("ffmpeg")
+            .args(["-hide_banner", "-nostats", "-i", input])
+            .args(["-filter_complex", "ebur128=peak=true", "-f", "null", "-"]),
+        "ffmpeg",
+        |output, line| {
+            if output == Output::Stderr {
+                lines.push(line.to_string());
+            }
+        },
+    )
+    .await?;
+    parse_ebur128(&lines)
+        .ok_or_else(|| eyre!("ffmpeg didn't tell the loudness of {}", path.display()))
+}
+
+/// Reads the summary the ebur128 filter prints once it is done, and the length of the input
+/// from ffmpeg's `Duration:` line. A song without audio has a peak of `-inf` dBFS.
+fn parse_ebur128(lines: &[String]) -> Option<Loudness> {
+    let value = |line: &str, key: &str| {
+        line.trim()
+            .strip_prefix(key)?
+            .split_whitespace()
+            .next()?
+            .parse::<f64>()
+            .ok()
+    };
+    let duration = lines
+        .iter()
+        .find_map(|line| line.trim().strip_prefix("Duration:"))
+        .and_then(|rest| rest.split(',').next())
+        .and_then(|time| download::progress::parse_time(time.trim()))
+        .unwrap_or_default();
+    let summary = lines.iter().position(|line| line.contains("Summary:"))?;
+    let summary = &lines[summary..];
+    let integrated = summary.iter().find_map(|line| value(line, "I:"))?;
+    let peak = summary.iter().find_map(|line| value(line, "Peak:"))?;
+    Some(Loudness {
+        integrated,
+        peak: 10f64.powf(peak / 20.0),
+        duration,
+    })
+}
+
+/// The loudness of songs played one after the other
+pub fn album_loudness(songs: &[Loudness]) -> Option<Loudness> {
+    let duration = songs.iter().map(|song| song.duration).sum::<f64>();
+    // Songs of unknown length count the same
+    let weight = |song: &Loudness| match duration > 0.0 {
+        true => song.duration / duration,
+        false => 1.0 / songs.len() as f64,
+    };
+    let energy = songs
+        .iter()
+        .map(|song| weight(song) * 10f64.powf(song.integrated / 10.0))
+        .sum::<f64>();
+    (!songs.is_empty()).then(|| Loudness {
+        integrated: 10.0 * energy.log10(),
+        peak: songs.iter().map(|song| song.peak).fold(0.0, f64::max),
+        duration,
+    })
+}
+
+/// ReplayGain values of a song, and of its album if it has one
+pub fn replaygain(song: &Loudness, album: Option<&Loudness>) -> ReplayGain {
+    ReplayGain {
+        track_gain: Some(song.gain()),
+        track_peak: Some(round_peak(song.peak)),
+        album_gain: album.map(Loudness::gain),
+        album_peak: album.map(|album| round_peak(album.peak)),
+    }
+}
+
+/// Whether a song is missing values it can have
+fn needs_replaygain(song: &Song) -> bool {
+    song.replaygain.track_gain.is_none()
+        || (song.album.is_some() && song.replaygain.album_gain.is_none())
+}
+
+/// Measures the songs missing ReplayGain values, or every song when `force` is given, and
+/// writes the values to their tags and the database. The rest of a song's album in the
+/// library is measured with it, so the album gain covers all of it. Songs whose file is
+/// missing fail.
+pub async fn apply(database: &Database, songs: &[Song], force: bool) -> Result<Summary> {
+    let mut summary = Summary::default();
+    let mut chosen = vec![];
+    for song in songs {
+        if !force && !needs_replaygain(song) {
+            summary.skipped += 1;
+        } else if !song.file_path.exists() {
+            let error = "File is missing".to_string();
+            summary.failed.push((song.file_path.clone(), error));
+        } else {
+            chosen.push(song);
+        }
+    }
+
+    // Songs without an album are measured on their own
+    let mut groups: BTreeMap<String, Vec<Song>> = BTreeMap::new();
+    let mut singles = vec![];
+    for song in &chosen {
+        match &song.album {
+            Some(album) => {
+                groups.entry(album.clone()).or_default();
+            }
+            None => singles.push(vec![(*song).clone()]),
+        }
+    }
+    for song in database.query_all_song_data()? {
+        let group = song.album.as_ref().and_then(|album| groups.get_mut(album));
+        if let Some(group) = group {
+            if song.file_path.exists() {
+                group.push(song);
+            }
+        }
+    }
+
+    for group in groups.into_values().chain(singles) {
+        let mut measured = vec![];
+        for song in group {
+            match measure(&song.file_path).await {
+                Ok(loudness) => measured.push((song, loudness)),
+                Err(e) => summary.failed.push((song.file_path, e.to_string())),
+            }
+        }
+        let loudness = measured
+            .iter()
+            .map(|(_, loudness)| *loudness)
+            .collect::<Vec<_>>();
+        let album = match measured.first() {
+            Some((song, _)) if song.album.is_some() => album_loudness(&loudness),
+            _ => None,
+        };
+
+        for (mut song, loudness) in measured {
+            let replaygain = replaygain(&loudness, album.as_ref());
+            let written = Song::read_music_file(&song.file_path).and_then(|mut file| {
+                file.set_replaygain(replaygain);
+                file.write_tag_changes()
+            });
+            if let Err(e) = written {
+                summary.failed.push((song.file_path, e.to_string()));
+                continue;
+            }
+            song.replaygain = replaygain;
+            database.update_song(&song)?;
+            summary.tagged.push((song.file_path, replaygain));
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ebur128_summary() {
+        let output = "
+Input #0, flac, from 'song.flac':
+  Duration: 00:04:18.00, start: 0.000000, bitrate: 896 kb/s
+[Parsed_ebur128_0 @ 0x5581] t: 0.1  TARGET:-23 LUFS    M:-120.7 S:-120.7     I: -70.0 LUFS
+[Parsed_ebur128_0 @ 0x5581] Summary:
+
+  Integrated loudness:
+    I:         -12.0 LUFS
+    Threshold: -22.3 LUFS
+
+  Loudness range:
+    LRA:         5.2 LU
+    Threshold: -32.4 LUFS
+    LRA low:   -16.1 LUFS
+    LRA high:  -10.9 LUFS
+
+  True peak:
+    Peak:        0.0 dBFS";
+        let lines = output.lines().map(str::to_string).collect::<Vec<_>>();
+        let loudness = parse_ebur128(&lines).unwrap();
+        assert_eq!(
+            loudness,
+            Loudness {
+                integrated: -12.0,
+                peak: 1.0,
+                duration: 258.0
+            }
+        );
+        assert_eq!(loudness.gain(), -6.0);
+        assert_eq!(parse_ebur128(&lines[..3]), None);
+    }
+
+    #[test]
+    fn album_gain() {
+        let quiet = Loudness {
+            integrated: -20.0,
+            peak: 0.5,
+            duration: 100.0,
+        };
+        let loud = Loudness {
+            integrated: -10.0,
+            peak: 0.9,
+            duration: 300.0,
+        };
+        let album = album_loudness(&[quiet, loud]).unwrap();
+        // The loud song makes up most of the album
+        assert!((album.integrated - -11.11).abs() < 0.01, "{:?}", album);
+        assert_eq!(album.peak, 0.9);
+        assert_eq!(album.duration, 400.0);
+        assert_eq!(album_loudness(&[]), None);
+
+        let replaygain = replaygain(&quiet, Some(&album));
+        assert_eq!(replaygain.track_gain, Some(2.0));
+        assert_eq!(replaygain.album_gain, Some(-6.89));
+        assert_eq!(replaygain.album_peak, Some(0.9));
+    }
+}
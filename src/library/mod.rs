@@ -0,0 +1,5 @@
+//! This module reconciles the database with the music files in the library
+pub mod exchange;
+pub mod loudness;
+pub mod scan;
+pub mod verify;
@@ -0,0 +1,155 @@
+//! Registers music files found in the library in the database
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use eyre::{eyre, Result};
+use log::debug;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::data::{
+    database::{Database, FileState},
+    song::Song,
+    tag,
+};
+
+/// What happened to each file during a scan
+#[derive(Debug, Default)]
+pub struct ScanSummary {
+    pub added: Vec<PathBuf>,
+    pub updated: Vec<PathBuf>,
+    pub unchanged: usize,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// Walks the directory recursively, adding music files missing from the database and
+/// updating records of files which changed since they were last scanned
+pub fn scan(database: &Database, dir: &Path) -> Result<ScanSummary> {
+    let mut summary = ScanSummary::default();
+
+    for path in music_files(dir)? {
+        match scan_file(database, &path) {
+            Ok(ScanResult::Added) => summary.added.push(path),
+            Ok(ScanResult::Updated) => summary.updated.push(path),
+            Ok(ScanResult::Unchanged) => summary.unchanged += 1,
+            Err(e) => summary.failed.push((path, e.to_string())),
+        }
+    }
+
+    Ok(summary)
+}
+
+pub enum ScanResult {
+    Added,
+    Updated,
+    Unchanged,
+}
+
+/// Adds or updates the record of a single file
+pub fn scan_file(database: &Database, path: &Path) -> Result<ScanResult> {
+    let existing = database.query_song_by_path(path)?;
+    let metadata = std::fs::metadata(path)?;
+    let size = metadata.len();
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as i64)
+        .unwrap_or_default();
+
+    // Size and modification time are cheap to check, so only hash when they differ
+    let recorded = match &existing {
+        Some(song) => database.file_state(song.id.unwrap())?,
+        None => None,
+    };
+    if let Some(recorded) = &recorded {
+        if recorded.size == size && recorded.modified == modified {
+            return Ok(ScanResult::Unchanged);
+        }
+    }
+
+    let state = FileState {
+        size,
+        modified,
+        hash: hash_file(path)?,
+    };
+    if let (Some(song), Some(recorded)) = (&existing, &recorded) {
+        if recorded.hash == state.hash {
+            debug!("{} was touched, but not changed", path.display());
+            database.set_file_state(song.id.unwrap(), &state)?;
+            return Ok(ScanResult::Unchanged);
+        }
+    }
+
+    let mut song = Song::read_music_file(path)?;
+    match existing {
+        Some(existing) => {
+            // Keep what can only come from the database
+            song.id = existing.id;
+            song.youtube_id = existing.youtube_id.clone();
+            song.thumbnail_url = existing.thumbnail_url.clone();
+            // Files tagged before provenance was written to tags don't carry it
+            if song.provenance.is_empty() {
+                song.provenance = existing.provenance.clone();
+            }
+
+            // Without a recorded state, the file counts as changed if its tags differ
+            let changed = recorded.is_some() || !Song::equate(&song, &existing);
+            if changed {
+                database.update_song(&song)?;
+            }
+            database.set_file_state(existing.id.unwrap(), &state)?;
+            Ok(match changed {
+                true => ScanResult::Updated,
+                false => ScanResult::Unchanged,
+            })
+        }
+        None => {
+            let id = database.insert_song(&song)?;
+            database.set_file_state(id, &state)?;
+            Ok(ScanResult::Added)
+        }
+    }
+}
+
+/// Returns the music files in a directory and its subdirectories, sorted by path.
+/// Hidden files and directories are skipped.
+pub fn music_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let walker = WalkDir::new(dir)
+        .follow_links(true)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0 || !entry.file_name().to_string_lossy().starts_with('.')
+        });
+    for entry in walker {
+        let entry = entry.map_err(|e| eyre!("Can't read {}: {}", dir.display(), e))?;
+        if entry.file_type().is_file() && tag::is_music_file(entry.path()) {
+            files.push(entry.into_path());
+        }
+    }
+    Ok(files)
+}
+
+/// Hex encoded SHA-256 of the file contents
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
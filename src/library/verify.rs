@@ -0,0 +1,267 @@
+//! Finds inconsistencies between the database and the files in the library
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use eyre::Result;
+use strum_macros::{Display, EnumString, EnumVariantNames};
+
+use crate::data::{database::Database, song::Song};
+
+use super::scan::{music_files, scan_file};
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Records whose file does not exist
+    pub missing: Vec<Song>,
+    /// Music files without a record
+    pub orphans: Vec<PathBuf>,
+    /// Records whose tags differ from the tags in the file
+    pub mismatches: Vec<TagMismatch>,
+    /// Files with more than one record
+    pub duplicates: Vec<Duplicate>,
+    /// Files with a record which could not be read
+    pub unreadable: Vec<(Song, String)>,
+}
+
+#[derive(Debug)]
+pub struct TagMismatch {
+    pub song: Song,
+    pub file: Song,
+    pub differences: Vec<Difference>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Difference {
+    pub field: &'static str,
+    pub database: String,
+    pub file: String,
+}
+
+#[derive(Debug)]
+pub struct Duplicate {
+    pub path: PathBuf,
+    pub songs: Vec<Song>,
+}
+
+impl Report {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty()
+            && self.orphans.is_empty()
+            && self.mismatches.is_empty()
+            && self.duplicates.is_empty()
+            && self.unreadable.is_empty()
+    }
+}
+
+/// Checks every record against its file, and every music file in the directory against
+/// the records
+pub fn verify(database: &Database, dir: &Path) -> Result<Report> {
+    let mut report = Report::default();
+    let songs = database.all_songs()?;
+
+    let mut by_path: BTreeMap<PathBuf, Vec<Song>> = BTreeMap::new();
+    for song in songs {
+        by_path
+            .entry(song.file_path.clone())
+            .or_default()
+            .push(song);
+    }
+    let recorded = by_path.keys().cloned().collect::<HashSet<PathBuf>>();
+
+    for (path, mut songs) in by_path {
+        if !path.exists() {
+            report.missing.append(&mut songs);
+            continue;
+        }
+
+        match Song::read_music_file(&path) {
+            Ok(file) => {
+                for song in &songs {
+                    let differences = compare_tags(song, &file);
+                    if !differences.is_empty() {
+                        report.mismatches.push(TagMismatch {
+                            song: song.clone(),
+                            file: file.clone(),
+                            differences,
+                        });
+                    }
+                }
+            }
+            Err(e) => {
+                for song in &songs {
+                    report.unreadable.push((song.clone(), e.to_string()));
+                }
+            }
+        }
+
+        if songs.len() > 1 {
+            report.duplicates.push(Duplicate { path, songs });
+        }
+    }
+
+    report.orphans = music_files(dir)?
+        .into_iter()
+        .filter(|path| !recorded.contains(path))
+        .collect();
+
+    Ok(report)
+}
+
+/// Lists the fields whose values differ between the record and the file
+pub fn compare_tags(song: &Song, file: &Song) -> Vec<Difference> {
+    let join = |values: &Option<Vec<String>>| values.clone().unwrap_or_default().join(", ");
+    let single = |value: &Option<String>| value.clone().unwrap_or_default();
+
+    [
+        ("title", single(&song.title), single(&file.title)),
+        ("artists", join(&song.artists), join(&file.artists)),
+        ("album", single(&song.album), single(&file.album)),
+        ("genre", single(&song.genre), single(&file.genre)),
+    ]
+    .into_iter()
+    .filter(|(_, database, file)| database != file)
+    .map(|(field, database, file)| Difference {
+        field,
+        database,
+        file,
+    })
+    .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, EnumVariantNames, Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum MissingPolicy {
+    /// Keep the record, so the file can be restored
+    Keep,
+    /// Remove the record
+    PruneRow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, EnumVariantNames, Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum OrphanPolicy {
+    Ignore,
+    /// Add a record for the file
+    Import,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, EnumVariantNames, Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum MismatchPolicy {
+    Ignore,
+    /// Update the record from the tags in the file
+    TrustFile,
+    /// Write the tags in the record to the file
+    TrustDatabase,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, EnumVariantNames, Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum DuplicatePolicy {
+    Ignore,
+    /// Keep the oldest record and remove the others
+    PruneRow,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Policies {
+    pub missing: MissingPolicy,
+    pub orphan: OrphanPolicy,
+    pub mismatch: MismatchPolicy,
+    pub duplicate: DuplicatePolicy,
+}
+
+/// Applies the policies to the problems in the report. Returns a description of every change.
+pub fn fix(database: &Database, report: &Report, policies: &Policies) -> Result<Vec<String>> {
+    let mut changes = vec![];
+    let mut removed = HashSet::new();
+
+    if policies.duplicate == DuplicatePolicy::PruneRow {
+        for duplicate in &report.duplicates {
+            for song in duplicate.songs.iter().skip(1) {
+                let id = song.id.unwrap();
+                database.remove_song(id)?;
+                removed.insert(id);
+                changes.push(format!(
+                    "Removed duplicate record [ID: {}] of {}",
+                    id,
+                    duplicate.path.display()
+                ));
+            }
+        }
+    }
+
+    if policies.missing == MissingPolicy::PruneRow {
+        for song in &report.missing {
+            let id = song.id.unwrap();
+            if removed.insert(id) {
+                database.remove_song(id)?;
+                changes.push(format!(
+                    "Removed record [ID: {}] of missing file {}",
+                    id,
+                    song.file_path.display()
+                ));
+            }
+        }
+    }
+
+    for mismatch in &report.mismatches {
+        let id = mismatch.song.id.unwrap();
+        if removed.contains(&id) {
+            continue;
+        }
+        let fields = mismatch
+            .differences
+            .iter()
+            .map(|difference| difference.field)
+            .collect::<Vec<_>>()
+            .join(", ");
+        match policies.mismatch {
+            MismatchPolicy::Ignore => {}
+            MismatchPolicy::TrustFile => {
+                // Only the compared tags come from the file, the rest of the record stays
+                let mut song = mismatch.song.clone();
+                song.title = mismatch.file.title.clone();
+                song.artists = mismatch.file.artists.clone();
+                song.album = mismatch.file.album.clone();
+                song.genre = mismatch.file.genre.clone();
+                database.update_song(&song)?;
+                changes.push(format!(
+                    "Updated {} of record [ID: {}] from {}",
+                    fields,
+                    id,
+                    mismatch.song.file_path.display()
+                ));
+            }
+            MismatchPolicy::TrustDatabase => {
+                let mut file = Song::read_music_file(&mismatch.song.file_path)?;
+                file.title = mismatch.song.title.clone();
+                file.artists = mismatch.song.artists.clone();
+                file.album = mismatch.song.album.clone();
+                file.genre = mismatch.song.genre.clone();
+                file.sync_tag();
+                file.write_tag_changes()?;
+                changes.push(format!(
+                    "Wrote {} of record [ID: {}] to {}",
+                    fields,
+                    id,
+                    mismatch.song.file_path.display()
+                ));
+            }
+        }
+    }
+
+    if policies.orphan == OrphanPolicy::Import {
+        for path in &report.orphans {
+            match scan_file(database, path) {
+                Ok(_) => changes.push(format!("Imported {}", path.display())),
+                Err(e) => changes.push(format!("Failed to import {}: {}", path.display(), e)),
+            }
+        }
+    }
+
+    Ok(changes)
+}
@@ -1,26 +1,44 @@
-use std::{path::Path, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use clap::crate_authors;
 use clap::crate_version;
 use clap::{App as CApp, AppSettings, Arg, ArgMatches};
-use dialoguer::{theme::ColorfulTheme, Confirm, Input, MultiSelect, Select};
+use dialoguer::{theme::ColorfulTheme, MultiSelect};
+use eyre::{eyre, Context, Result};
+use log::{debug, info};
+use strum::VariantNames;
 use tui::{
     app::App,
     io::{handler::IoAsyncHandler, IoEvent},
     start_ui,
 };
-use eyre::{eyre, Context, Result};
-use image::ImageFormat;
-use log::{debug, info};
-use metaflac::Tag;
-use youtube_dl::{
-    SearchOptions, SingleVideo as Video, YoutubeDl,
-    YoutubeDlOutput::{Playlist, SingleVideo},
-};
 
-use crate::data::{database::Database, song::Song};
+use crate::{
+    config::Config,
+    data::{
+        database::Database,
+        job::{DownloadJob, JobState},
+        query::Query,
+        song::Song,
+        tag,
+    },
+    download::{
+        backend::Downloader, profile::Profile, prompt::Prompter, split, ExistingSong, Tags,
+    },
+    library::{
+        exchange::{ConflictKey, ConflictPolicy, Format},
+        verify::{DuplicatePolicy, MismatchPolicy, MissingPolicy, OrphanPolicy, Policies},
+    },
+};
 
+mod config;
+mod cover;
 mod data;
+mod download;
+mod library;
 mod tui;
 
 /// Main function
@@ -28,8 +46,8 @@ mod tui;
 /// Made async to support async
 #[tokio::main]
 async fn main() -> Result<()> {
-    // This program manages music in FLAC format
-    // Additional formats are to be added later
+    // This program downloads music in FLAC format
+    // MP3, Ogg Vorbis, Opus and M4A files in the library can be tagged as well
 
     // Setup clap
     let matches = setup_cli();
@@ -42,6 +60,14 @@ async fn main() -> Result<()> {
             )
             .await?;
         }
+        Some("queue") => {
+            queue(
+                matches
+                    .subcommand_matches("queue")
+                    .ok_or_else(|| eyre!("No arguments gave to subcommand queue"))?,
+            )
+            .await?;
+        }
         Some("edit") => {
             edit(
                 matches
@@ -71,6 +97,57 @@ async fn main() -> Result<()> {
                     .ok_or_else(|| eyre!("No arguments gave to subcommand search"))?,
             )?;
         }
+        Some("restore") => {
+            restore(
+                matches
+                    .subcommand_matches("restore")
+                    .ok_or_else(|| eyre!("No arguments gave to subcommand restore"))?,
+            )
+            .await?;
+        }
+        Some("scan") => {
+            scan(
+                matches
+                    .subcommand_matches("scan")
+                    .ok_or_else(|| eyre!("No arguments gave to subcommand scan"))?,
+            )?;
+        }
+        Some("replaygain") => {
+            replaygain(
+                matches
+                    .subcommand_matches("replaygain")
+                    .ok_or_else(|| eyre!("No arguments gave to subcommand replaygain"))?,
+            )
+            .await?;
+        }
+        Some("verify") => {
+            verify(
+                matches
+                    .subcommand_matches("verify")
+                    .ok_or_else(|| eyre!("No arguments gave to subcommand verify"))?,
+            )?;
+        }
+        Some("cover") => {
+            cover(
+                matches
+                    .subcommand_matches("cover")
+                    .ok_or_else(|| eyre!("No arguments gave to subcommand cover"))?,
+            )?;
+        }
+        Some("export") => {
+            export(
+                matches
+                    .subcommand_matches("export")
+                    .ok_or_else(|| eyre!("No arguments gave to subcommand export"))?,
+            )?;
+        }
+        Some("import") => {
+            import(
+                matches
+                    .subcommand_matches("import")
+                    .ok_or_else(|| eyre!("No arguments gave to subcommand import"))?,
+            )?;
+        }
         Some(_) => {
             // TODO: handle the error instead of panicking
             panic!("CLAP IS NOT WORKING");
@@ -96,17 +173,217 @@ fn setup_cli() -> ArgMatches {
                         .long("search-only")
                         .takes_value(false),
                 )
+                .arg(
+                    Arg::new("pick")
+                        .help("Which search result to download: its number, or best")
+                        .long("pick")
+                        .takes_value(true)
+                        .conflicts_with("search-only"),
+                )
+                .arg(
+                    Arg::new("song-title")
+                        .help("Title to tag the song with")
+                        .long("title")
+                        .takes_value(true)
+                        .conflicts_with_all(&["no-edit", "split", "cue"]),
+                )
+                .arg(
+                    Arg::new("song-artist")
+                        .help("Artist to tag the song with")
+                        .long("artist")
+                        .takes_value(true)
+                        .conflicts_with("no-edit"),
+                )
+                .arg(
+                    Arg::new("song-album")
+                        .help("Album to tag the song with")
+                        .long("album")
+                        .takes_value(true)
+                        .conflicts_with("no-edit"),
+                )
+                .arg(
+                    Arg::new("no-edit")
+                        .help("Don't tag the song with a title, artist and album")
+                        .long("no-edit")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::new("rename")
+                        .help(
+                            "Name the file after a template such as \"{artist} - {title}\". \
+                            Fields are title, artist, artists, album, id, video_title and channel",
+                        )
+                        .long("rename")
+                        .takes_value(true)
+                        .conflicts_with_all(&["split", "cue"]),
+                )
+                .arg(
+                    Arg::new("split")
+                        .help(
+                            "Split the video into a song per chapter, tagged as tracks of \
+                            one album",
+                        )
+                        .long("split")
+                        .takes_value(false)
+                        .conflicts_with_all(&["search-only", "cue"]),
+                )
+                .arg(
+                    Arg::new("cue")
+                        .help("Split the video into the tracks of this cue sheet")
+                        .long("cue")
+                        .takes_value(true)
+                        .conflicts_with("search-only"),
+                )
+                .arg(
+                    Arg::new("profile")
+                        .help(
+                            "Transcoding profile choosing the format songs are saved in \
+                            [default: download.profile in the config, or flac]",
+                        )
+                        .long("profile")
+                        .takes_value(true)
+                        .possible_values(Profile::VARIANTS),
+                )
+                .arg(
+                    Arg::new("existing")
+                        .help(
+                            "What to do when the video is in the library already \
+                            [default: ask, or skip with --yes]",
+                        )
+                        .long("existing")
+                        .takes_value(true)
+                        .possible_values(ExistingSong::VARIANTS),
+                )
+                .arg(
+                    Arg::new("yes")
+                        .help("Accept the default answer to every question")
+                        .long("yes")
+                        .short('y')
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::new("queue-only")
+                        .help("Add the song to the download queue without running it")
+                        .long("queue-only")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::new("playlist")
+                        .help("Download every video of the playlist at this URL")
+                        .long("playlist")
+                        .short('p')
+                        .takes_value(true)
+                        .conflicts_with_all(&["title", "search-only", "split", "cue"]),
+                )
+                .arg(
+                    Arg::new("save-playlist")
+                        .help(
+                            "Save the playlist to the database and as an M3U file, \
+                            named after the YouTube playlist unless a name is given",
+                        )
+                        .long("save-playlist")
+                        .takes_value(true)
+                        .min_values(0)
+                        .requires("playlist")
+                        .conflicts_with("queue-only"),
+                )
+                .arg(
+                    Arg::new("from-file")
+                        .help(
+                            "Download every song listed in this file without asking anything. \
+                            Text files list a search or URL per line, CSV files have title, \
+                            artist and album columns",
+                        )
+                        .long("from-file")
+                        .short('f')
+                        .takes_value(true)
+                        .conflicts_with_all(&["title", "search-only", "playlist", "split", "cue"]),
+                )
+                .arg(
+                    Arg::new("report")
+                        .help(
+                            "Where to write the report of a download from a file. \
+                            Defaults to the file's name followed by .report.csv",
+                        )
+                        .long("report")
+                        .takes_value(true)
+                        .requires("from-file"),
+                )
                 .arg(
                     Arg::new("title")
                         .takes_value(true)
-                        .required(true)
+                        .required_unless_present_any(["playlist", "from-file"])
                         .multiple_values(true)
                         .use_delimiter(false)
                         .index(1),
                 ),
         )
+        .subcommand(
+            CApp::new("queue")
+                .about("Manage the download queue")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(CApp::new("list").about("List the jobs in the queue"))
+                .subcommand(
+                    CApp::new("run")
+                        .about("Download every queued song, resuming interrupted downloads"),
+                )
+                .subcommand(CApp::new("retry").about("Put failed jobs back in the queue"))
+                .subcommand(
+                    CApp::new("clear")
+                        .about("Remove finished jobs from the queue")
+                        .arg(
+                            Arg::new("failed")
+                                .help("Remove failed jobs as well")
+                                .long("failed")
+                                .takes_value(false),
+                        ),
+                ),
+        )
         .subcommand(CApp::new("edit").about("Edit song library"))
-        .subcommand(CApp::new("list").about("List songs registered in the database"))
+        .subcommand(
+            CApp::new("list")
+                .about("List songs registered in the database")
+                .arg(
+                    Arg::new("artist")
+                        .help("Only list songs by this artist")
+                        .takes_value(true)
+                        .long("artist")
+                        .short('a')
+                        .forbid_empty_values(true),
+                )
+                .arg(
+                    Arg::new("albums")
+                        .help("List albums by the artist given with --artist instead")
+                        .long("albums")
+                        .requires("artist"),
+                )
+                .arg(
+                    Arg::new("artists")
+                        .help("List artists instead of songs")
+                        .long("artists")
+                        .conflicts_with("artist"),
+                )
+                .arg(
+                    Arg::new("playlist")
+                        .help("Only list songs in this playlist, in its order")
+                        .takes_value(true)
+                        .long("playlist")
+                        .short('p')
+                        .conflicts_with_all(&["artist", "artists"]),
+                )
+                .arg(
+                    Arg::new("playlists")
+                        .help("List playlists instead of songs")
+                        .long("playlists")
+                        .conflicts_with_all(&["artist", "artists", "playlist"]),
+                )
+                .arg(
+                    Arg::new("lossy-origin")
+                        .help("Only list songs made from lossy audio, whatever their format")
+                        .long("lossy-origin")
+                        .conflicts_with_all(&["albums", "artists", "playlists"]),
+                ),
+        )
         .subcommand(
             CApp::new("remove")
                 .about("Remove a song registered in the database")
@@ -123,241 +400,1081 @@ fn setup_cli() -> ArgMatches {
         .subcommand(
             CApp::new("search")
                 .about("Search for songs in database")
+                .after_help(
+                    "QUERY is a list of terms which all have to match, for example:\n    \
+                    artist:\"Yoasobi\" album:unknown added:>2021-12-01 -genre:pop night\n\n\
+                    Plain words match the start of words in any field and quoted text matches a \
+                    phrase. Fields are title, artist, album, genre, path, youtube, added and \
+                    origin. Dates are written as YYYY-MM-DD and can be prefixed by >, >=, < or \
+                    <=. origin is lossy or lossless, the kind of audio a song was made from. \
+                    Prefix a term with - to exclude songs matching it.",
+                )
                 .arg(
-                    Arg::new("title")
+                    Arg::new("query")
                         .takes_value(true)
                         .required(true)
+                        .multiple_values(true)
+                        .allow_hyphen_values(true)
+                        .forbid_empty_values(true),
+                ),
+        )
+        .subcommand(
+            CApp::new("restore")
+                .about("Re-download songs in the database whose files are missing")
+                .arg(
+                    Arg::new("dry-run")
+                        .help("Only show which songs would be restored")
+                        .long("dry-run")
+                        .short('n'),
+                ),
+        )
+        .subcommand(
+            CApp::new("scan")
+                .about("Register music files in the music directory in the database")
+                .arg(
+                    Arg::new("verbose")
+                        .help("List every added and updated file")
+                        .long("verbose")
+                        .short('v'),
+                ),
+        )
+        .subcommand(
+            CApp::new("replaygain")
+                .about("Measure the loudness of songs and tag them with ReplayGain values")
+                .after_help(
+                    "Songs missing a track gain, or an album gain while they have an album, \
+                    are measured with the rest of their album. QUERY chooses the songs as \
+                    for search.",
+                )
+                .arg(
+                    Arg::new("query")
+                        .takes_value(true)
+                        .multiple_values(true)
+                        .allow_hyphen_values(true)
                         .forbid_empty_values(true),
+                )
+                .arg(
+                    Arg::new("force")
+                        .help("Measure songs which have their values already")
+                        .long("force")
+                        .short('f'),
+                ),
+        )
+        .subcommand(
+            CApp::new("verify")
+                .about("Check the database against the files in the music directory")
+                .arg(
+                    Arg::new("fix")
+                        .help("Fix the problems found using the policies below")
+                        .long("fix"),
+                )
+                .arg(
+                    Arg::new("missing")
+                        .help("What to do with records whose file is missing [default: keep]")
+                        .long("missing")
+                        .takes_value(true)
+                        .possible_values(MissingPolicy::VARIANTS)
+                        .requires("fix"),
+                )
+                .arg(
+                    Arg::new("orphan")
+                        .help("What to do with music files without a record [default: import]")
+                        .long("orphan")
+                        .takes_value(true)
+                        .possible_values(OrphanPolicy::VARIANTS)
+                        .requires("fix"),
+                )
+                .arg(
+                    Arg::new("mismatch")
+                        .help(
+                            "What to do with records whose tags differ from the file \
+                            [default: trust-file]",
+                        )
+                        .long("mismatch")
+                        .takes_value(true)
+                        .possible_values(MismatchPolicy::VARIANTS)
+                        .requires("fix"),
+                )
+                .arg(
+                    Arg::new("duplicate")
+                        .help(
+                            "What to do with files that have more than one record \
+                            [default: prune-row]",
+                        )
+                        .long("duplicate")
+                        .takes_value(true)
+                        .possible_values(DuplicatePolicy::VARIANTS)
+                        .requires("fix"),
+                ),
+        )
+        .subcommand(
+            CApp::new("cover")
+                .about("View, replace, extract and remove pictures embedded in a music file")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    CApp::new("show")
+                        .about("List the pictures of a music file")
+                        .arg(Arg::new("file").required(true)),
+                )
+                .subcommand(
+                    CApp::new("set")
+                        .about("Add a picture, replacing any picture of the same type")
+                        .arg(Arg::new("file").required(true))
+                        .arg(
+                            Arg::new("image")
+                                .help("PNG, JPEG, GIF, BMP or WebP image")
+                                .required(true),
+                        )
+                        .arg(picture_type_arg().default_value("front"))
+                        .arg(
+                            Arg::new("description")
+                                .help("Description of the picture")
+                                .long("description")
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    CApp::new("remove")
+                        .about("Remove pictures from a music file")
+                        .arg(Arg::new("file").required(true))
+                        .arg(picture_type_arg().help("Only remove pictures of this type")),
+                )
+                .subcommand(
+                    CApp::new("extract")
+                        .about("Write the pictures of a music file to image files")
+                        .arg(Arg::new("file").required(true))
+                        .arg(Arg::new("dir").help(
+                            "Directory to write the images to [default: the directory of FILE]",
+                        ))
+                        .arg(picture_type_arg().help("Only extract pictures of this type")),
+                ),
+        )
+        .subcommand(
+            CApp::new("export")
+                .about("Export the database to JSON Lines or CSV")
+                .arg(
+                    Arg::new("file")
+                        .help("File to write to, standard output if not given or -")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("format")
+                        .help("Format of the export [default: guessed from the file extension]")
+                        .long("format")
+                        .short('f')
+                        .takes_value(true)
+                        .possible_values(Format::VARIANTS),
+                ),
+        )
+        .subcommand(
+            CApp::new("import")
+                .about("Import songs exported as JSON Lines or CSV into the database")
+                .arg(
+                    Arg::new("file")
+                        .help("File to read from, standard input if -")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("format")
+                        .help("Format of the import [default: guessed from the file extension]")
+                        .long("format")
+                        .short('f')
+                        .takes_value(true)
+                        .possible_values(Format::VARIANTS),
+                )
+                .arg(
+                    Arg::new("key")
+                        .help("Field used to find existing songs")
+                        .long("key")
+                        .takes_value(true)
+                        .possible_values(ConflictKey::VARIANTS)
+                        .default_value("path"),
+                )
+                .arg(
+                    Arg::new("on-conflict")
+                        .help("What to do with songs that are already in the database")
+                        .long("on-conflict")
+                        .takes_value(true)
+                        .possible_values(ConflictPolicy::VARIANTS)
+                        .default_value("skip"),
                 ),
         )
         .get_matches()
 }
 
-async fn download(args: &ArgMatches) -> Result<()> {
-    let music_dir = directories_next::UserDirs::new()
+fn picture_type_arg() -> Arg<'static> {
+    Arg::new("type")
+        .help("Type of the picture")
+        .long("type")
+        .short('t')
+        .takes_value(true)
+        .possible_values(
+            tag::PICTURE_TYPES
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>(),
+        )
+}
+
+/// Returns the user's music directory, where the library and its database live
+fn music_dir() -> Result<PathBuf> {
+    let user_dirs = directories_next::UserDirs::new()
         .ok_or_else(|| eyre!("directories_next failed to initialize"))?;
-    let music_dir = music_dir
+    Ok(user_dirs
         .audio_dir()
-        .ok_or_else(|| eyre!("directories_next failed to retrieve music dir"))?
-        .to_path_buf();
+        .ok_or_else(|| eyre!("Couldn't get user music dir."))?
+        .to_path_buf())
+}
+
+async fn download(args: &ArgMatches) -> Result<()> {
+    let config = Config::load()?;
+    let music_dir = music_dir()?;
+    let downloader = download::backend::from_config(&config.download)?;
+    let profile = match args.value_of("profile") {
+        Some(profile) => profile.parse()?,
+        None => config.download.profile,
+    };
+    if let Some(url) = args.value_of("playlist") {
+        return download_playlist(url, args, &config, downloader, profile, &music_dir).await;
+    }
+    if let Some(file) = args.value_of("from-file") {
+        let file = Path::new(file);
+        return download_from_file(file, args, &config, downloader, profile, &music_dir).await;
+    }
+
+    let prompter = Prompter::new(args.is_present("yes"));
+    let cue = match args.value_of("cue") {
+        Some(file) => {
+            let contents = std::fs::read_to_string(file)
+                .wrap_err_with(|| format!("Can't read the cue sheet {}", file))?;
+            Some(split::parse_cue(&contents)?)
+        }
+        None => None,
+    };
     let title = args
         .values_of("title")
         .ok_or_else(|| eyre!("Song title is not given"))?
         .collect::<Vec<&str>>()
         .join(" ");
-    let search_options = SearchOptions::youtube(title).with_count(5);
-    let ytsearch = YoutubeDl::search_for(&search_options)
-        .socket_timeout("10")
-        .run()?;
-
-    match ytsearch {
-        Playlist(playlist) => {
-            let entries = playlist
-                .entries
-                .ok_or_else(|| eyre!("Can't get video entries"))?;
-
-            let mut count = 1;
-            let mut entries_vec = vec![];
-            for video in &entries {
-                entries_vec.push(format!(
-                    "{}. Title: {}, Channel:{}",
-                    count,
-                    video.title,
-                    video.channel.as_ref().unwrap()
+    let videos = downloader.search(&title, 5).await?;
+    if videos.is_empty() {
+        return Err(eyre!("Nothing was found for {}", title));
+    }
+
+    let entries_vec = videos
+        .iter()
+        .enumerate()
+        .map(|(count, video)| {
+            format!(
+                "{}. Title: {}, Channel:{}",
+                count + 1,
+                video.title,
+                video.channel.as_deref().unwrap_or("Unknown")
+            )
+        })
+        .collect::<Vec<_>>();
+
+    if args.is_present("search-only") {
+        // Shows search results
+        println!("Search results: ");
+        for entries in entries_vec {
+            println!("{}", entries);
+        }
+        return Ok(());
+    }
+
+    let selection = match args.value_of("pick") {
+        Some("best") => download::best_match(&title, &videos).unwrap_or(0),
+        Some(number) => match number.parse::<usize>() {
+            Ok(number) if (1..=videos.len()).contains(&number) => number - 1,
+            _ => {
+                return Err(eyre!(
+                    "--pick takes best or a number from 1 to {}, not {}",
+                    videos.len(),
+                    number
+                ))
+            }
+        },
+        None => prompter
+            .select(
+                "[Enter] or [Space] to select: ",
+                &entries_vec,
+                0,
+                "--pick N or --pick best",
+            )?
+            .ok_or_else(|| eyre!("User canceled"))?,
+    };
+    let video = &videos[selection];
+    let tags = Tags::from_video(video);
+    let mut tracks = match &cue {
+        Some(cue) => cue.tracks.clone(),
+        None if args.is_present("split") => {
+            let tracks = split::from_chapters(video.chapters.as_deref().unwrap_or_default());
+            if tracks.is_empty() {
+                return Err(eyre!(
+                    "{} has no chapters to split it by, give its tracks with --cue",
+                    video.id
                 ));
-                count += 1;
             }
+            tracks
+        }
+        None => vec![],
+    };
+    let splitting = !tracks.is_empty();
 
-            if !args.is_present("search-only") {
-                println!("[Enter] or [Space] to select: ");
+    // Songs are found by their video rather than their file, which may have been renamed
+    let database = Database::open_from_path(music_dir.join("database.sqlite"))?;
+    let existing = database
+        .query_songs_by_youtube_id(&video.id)?
+        .into_iter()
+        .next();
+    // Files pending jobs will be saved as
+    let taken = download::pending_jobs(&database)?
+        .into_iter()
+        .flat_map(|job| job.destinations())
+        .collect::<Vec<_>>();
+    let action = match &existing {
+        Some(song) => {
+            println!("{} is in the library already as {}", video.id, song);
+            println!("\tPath: {}", song.file_path.display());
+            match args.value_of("existing") {
+                Some(action) => action.parse()?,
+                None => {
+                    let actions = ExistingSong::VARIANTS
+                        .iter()
+                        .map(|action| action.parse::<ExistingSong>())
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let descriptions = actions
+                        .iter()
+                        .map(|action| action.description().to_string())
+                        .collect::<Vec<_>>();
+                    let selection = prompter
+                        .select("What do you want to do?", &descriptions, 0, "--existing")?
+                        .ok_or_else(|| eyre!("User canceled"))?;
+                    actions[selection]
+                }
+            }
+        }
+        None => ExistingSong::Duplicate,
+    };
 
-                if let Some(selection) = Select::with_theme(&ColorfulTheme::default())
-                    .items(&entries_vec)
-                    .default(0)
-                    .interact_opt()?
-                {
-                    let output_format = music_dir.join("%(title)s.%(ext)s");
-                    let video = &entries
-                        .get(selection)
-                        .ok_or_else(|| eyre!("Can't get entry number: {}", selection))?;
+    let mut job = match (&existing, action) {
+        (Some(song), ExistingSong::Skip) => {
+            println!("Skipped {}", song.file_path.display());
+            return Ok(());
+        }
+        (Some(song), ExistingSong::Retag | ExistingSong::Redownload) => {
+            if Profile::from_path(&song.file_path).is_none() {
+                return Err(eyre!(
+                    "Can't tag {} as downloads are never saved in its format",
+                    song.file_path.display()
+                ));
+            }
+            let mut job = DownloadJob::new(&video.id, song.file_path.clone());
+            if action == ExistingSong::Retag {
+                job.state = JobState::Tagging;
+            }
+            job
+        }
+        _ => DownloadJob::new(
+            &video.id,
+            download::library_path(&music_dir, &video.title, &video.id, profile, &taken),
+        ),
+    };
+    // Songs replacing a library entry keep its file
+    let keeps_file = matches!(action, ExistingSong::Retag | ExistingSong::Redownload);
+    job.thumbnail_url = download::thumbnail_url(video);
+    job.source_bitrate = download::source_bitrate(video);
+    job.release_date = tags.release_date.clone();
+    job.track_number = tags.track_number;
+    job.genre = tags.genre.clone();
+    // Split videos are albums, named after the cue sheet or else the video
+    let default_artists = match cue.as_ref().and_then(|cue| cue.performer.clone()) {
+        Some(performer) => Some(vec![performer]),
+        None => tags.artists.clone(),
+    };
+    let default_album = match splitting {
+        true => cue
+            .as_ref()
+            .and_then(|cue| cue.title.clone())
+            .or_else(|| tags.album.clone())
+            .or_else(|| tags.title.clone())
+            .or_else(|| Some(video.title.clone())),
+        false => tags.album.clone(),
+    };
+    if splitting {
+        job.artists = default_artists.clone();
+        job.album = default_album.clone();
+    }
 
-                    let mut video_title = video.title.replace("/", "_").replace(":", " -");
-                    video_title.push_str(".opus");
+    let edit_metadata = if args.is_present("no-edit") {
+        false
+    } else if ["song-title", "song-artist", "song-album"]
+        .iter()
+        .any(|arg| args.is_present(arg))
+    {
+        true
+    } else {
+        prompter.confirm(
+            "Do you want to edit metadata now?",
+            true,
+            "--no-edit, or --title, --artist and --album",
+        )?
+    };
+    if edit_metadata {
+        let answer =
+            |arg: &str, question: &str, default: String, option: &str| match args.value_of(arg) {
+                Some(value) => Ok(value.to_string()),
+                None => prompter.input(question, default, option),
+            };
+        // Tracks have titles of their own
+        if !splitting {
+            let song_title = answer(
+                "song-title",
+                "Song title",
+                tags.title.clone().unwrap_or_else(|| video.title.clone()),
+                "--title",
+            )?;
+            job.title = Some(song_title);
+        }
+        let default_artists = default_artists.unwrap_or_default();
+        let default_artist = default_artists.join(", ");
+        let song_artist = answer(
+            "song-artist",
+            "Song artist: ",
+            default_artist.clone(),
+            "--artist",
+        )?;
+        let song_album = answer(
+            "song-album",
+            "Song album: ",
+            default_album.unwrap_or_else(|| "Unknown".to_string()),
+            "--album",
+        )?;
 
-                    let mut filename_opus = music_dir.join(&video_title);
-                    filename_opus.set_extension("opus");
-                    let mut filename_flac = filename_opus.with_extension("flac");
+        // Keep the artists apart unless the guess was changed
+        job.artists = match song_artist == default_artist && !default_artists.is_empty() {
+            true => Some(default_artists),
+            false => Some(vec![song_artist]),
+        };
+        job.album = Some(song_album);
+    }
 
-                    if !filename_opus.exists() && !filename_flac.exists() {
-                        // Download if opus does not exist
-                        println!(
-                            "Downloading: {} from channel: {} using youtube-dl...",
-                            video.title,
-                            video.channel.as_ref().unwrap()
-                        );
-                        youtube_dl_download_audio(video, &output_format)?;
-
-                        ffmpeg_convert_to_flac(&filename_opus, &filename_flac)?;
-                    } else if !filename_flac.exists() && filename_opus.exists() {
-                        // File is downloaded, but not yet converted
-                        ffmpeg_convert_to_flac(&filename_opus, &filename_flac)?;
-                    } else {
-                        // If opus file does not exist
-                        println!("Song is already downloaded");
-                    }
+    if splitting {
+        // Tracks go in a directory named after the album, or next to the song they replace
+        let dir = match &existing {
+            Some(song) if keeps_file => song.file_path.parent().unwrap_or(&music_dir).to_path_buf(),
+            _ => music_dir.join(download::sanitize_file_name(
+                job.album.as_deref().unwrap_or(&video.title),
+            )),
+        };
+        job.profile = Profile::from_path(&job.file_path).unwrap_or(profile);
+        // Tracks downloaded again take the place of the ones before
+        let replaced = match keeps_file {
+            true => database
+                .query_songs_by_youtube_id(&video.id)?
+                .into_iter()
+                .map(|song| song.file_path)
+                .collect(),
+            false => vec![],
+        };
+        split::set_paths(&mut tracks, &dir, &video.id, job.profile, &taken, &replaced);
+        job.file_path = dir;
+        job.tracks = tracks;
+    }
 
-                    let rename_file = Confirm::with_theme(&ColorfulTheme::default())
-                        .with_prompt("Do you want to rename the file?")
-                        .default(true)
-                        .interact()?;
+    let file_name = match args.value_of("rename") {
+        _ if keeps_file || splitting => None,
+        Some(template) => Some(download::render_file_name(template, &job, video)?),
+        None if prompter.confirm("Do you want to rename the file?", true, "--rename")? => {
+            let default = job
+                .file_path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or_default()
+                .to_string();
+            let file_name = prompter.input("File name: ", default.clone(), "--rename")?;
+            Some(file_name).filter(|file_name| *file_name != default)
+        }
+        None => None,
+    };
+    // Chosen names are checked for other songs like the default one
+    if let Some(file_name) = file_name {
+        job.file_path = download::library_path(&music_dir, &file_name, &video.id, profile, &taken);
+    }
 
-                    if rename_file {
-                        let mut filename_new_input = Input::with_theme(&ColorfulTheme::default())
-                            .with_prompt("File name: ")
-                            .default(
-                                filename_flac
-                                    .file_name()
-                                    .unwrap()
-                                    .to_str()
-                                    .unwrap()
-                                    .to_string(),
-                            )
-                            .interact()?;
-                        filename_new_input.push_str(".flac");
-
-                        let mut filename_new = filename_flac.clone();
-                        filename_new.set_file_name(&filename_new_input);
-
-                        std::fs::rename(&filename_flac, filename_new)?;
-                        filename_flac.set_file_name(filename_new_input);
-                        println!("File rename successful");
-                    }
+    job.id = Some(database.insert_download_job(&job)?);
+    match job.tracks.len() {
+        0 => println!("Queued {}", job),
+        tracks => println!("Queued {} as {} tracks", job, tracks),
+    }
 
-                    // Add the song to the database
-                    let edit_metadata = Confirm::with_theme(&ColorfulTheme::default())
-                        .with_prompt("Do you want to edit metadata now?")
-                        .default(true)
-                        .interact()?;
-                    if edit_metadata {
-                        let song_title: String = Input::with_theme(&ColorfulTheme::default())
-                            .with_prompt("Song title")
-                            .default(video_title)
-                            .interact()?;
-                        let song_artist: String = Input::with_theme(&ColorfulTheme::default())
-                            .with_prompt("Song artist: ")
-                            .default(video.channel.clone().unwrap())
-                            .interact()?;
-                        let song_album: String = Input::with_theme(&ColorfulTheme::default())
-                            .with_prompt("Song album: ")
-                            .default("Unknown".to_string())
-                            .interact()?;
-
-                        let mut tag = Tag::read_from_path(&filename_flac)?;
-                        tag.set_vorbis("TITLE", vec![song_title.clone()]);
-                        tag.set_vorbis("ARTIST", vec![song_artist.clone()]);
-                        tag.set_vorbis("ALBUM", vec![song_album.clone()]);
-
-                        let request = reqwest::get(video.thumbnail.clone().unwrap()).await;
-                        match request {
-                            Ok(request) => {
-                                let picture =
-                                    image::load_from_memory(&request.bytes().await?.to_vec())?;
-                                let mut vect = vec![];
-                                // BUG: Figure out why the picture is black and white
-                                picture.write_to(&mut vect, ImageFormat::Jpeg)?;
-                                tag.add_picture(
-                                    "image/jpeg",
-                                    metaflac::block::PictureType::CoverFront,
-                                    vect,
-                                );
-                            }
-                            Err(e) => {
-                                println!("Error: {}", e);
-                            }
-                        };
-                        tag.save()?;
-
-                        let database = Database::open_from_path(music_dir.join("database.sqlite"))?;
-                        database.insert_song(&Song {
-                            file_path: filename_flac.clone(),
-                            file_name: filename_flac
-                                .file_name()
-                                .unwrap()
-                                .to_str()
-                                .unwrap()
-                                .to_string(),
-                            title: Some(song_title),
-                            artists: Some(vec![song_artist]),
-                            album: Some(song_album),
-                            youtube_id: Some(video.id.clone()),
-                            thumbnail_url: Some(video.thumbnail.clone().unwrap()),
-                            ..Default::default()
-                        })?;
-                        println!("Inserted into database");
-                    }
-                } else {
-                    return Err(eyre!("User canceled"));
-                }
-            } else {
-                // Shows search results
-                println!("Search results: ");
-                for entries in entries_vec {
-                    println!("{}", entries);
+    if !args.is_present("queue-only") {
+        run_queue(database, &config, downloader).await?;
+    }
+
+    Ok(())
+}
+
+/// Queues every video of a playlist which is not in the library yet, then downloads them
+async fn download_playlist(
+    url: &str,
+    args: &ArgMatches,
+    config: &Config,
+    downloader: Arc<dyn Downloader>,
+    profile: Profile,
+    music_dir: &Path,
+) -> Result<()> {
+    println!("Fetching playlist {}...", url);
+    let playlist = downloader.playlist(url).await?;
+    let entries = playlist.entries.unwrap_or_default();
+    let playlist_title = playlist.title.unwrap_or_else(|| url.to_string());
+    println!("{} has {} videos", playlist_title, entries.len());
+
+    let database = Database::open_from_path(music_dir.join("database.sqlite"))?;
+    let enqueued = download::playlist::enqueue(&database, music_dir, profile, &entries)?;
+    for video in &enqueued.known {
+        println!(
+            "Skipping {}: already in the library or the queue",
+            video.title
+        );
+    }
+    for video in &enqueued.unavailable {
+        println!("Skipping {}: unavailable", video.id);
+    }
+    println!(
+        "Queued {} videos, skipped {}",
+        enqueued.queued.len(),
+        enqueued.known.len() + enqueued.unavailable.len()
+    );
+
+    if args.is_present("queue-only") {
+        return Ok(());
+    }
+    run_queue(database, config, downloader).await?;
+
+    if args.is_present("save-playlist") {
+        let name = args.value_of("save-playlist").unwrap_or(&playlist_title);
+        let database = Database::open_from_path(music_dir.join("database.sqlite"))?;
+        let (songs, path) =
+            download::playlist::save(&database, music_dir, name, playlist.id.as_deref(), &entries)?;
+        println!(
+            "Saved playlist {} with {} songs to {}",
+            name,
+            songs.len(),
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Queues every song listed in a file, downloads them and writes a report of how it went
+async fn download_from_file(
+    file: &Path,
+    args: &ArgMatches,
+    config: &Config,
+    downloader: Arc<dyn Downloader>,
+    profile: Profile,
+    music_dir: &Path,
+) -> Result<()> {
+    let entries = download::batch::read(file)?;
+    let report_path = match args.value_of("report") {
+        Some(report) => PathBuf::from(report),
+        None => {
+            let mut report = file.as_os_str().to_os_string();
+            report.push(".report.csv");
+            PathBuf::from(report)
+        }
+    };
+
+    let database = Database::open_from_path(music_dir.join("database.sqlite"))?;
+    let mut report =
+        download::batch::enqueue(downloader.as_ref(), &database, music_dir, profile, &entries)
+            .await?;
+    if !args.is_present("queue-only") {
+        run_queue(database, config, downloader).await?;
+        let database = Database::open_from_path(music_dir.join("database.sqlite"))?;
+        download::batch::finish(&database, &mut report)?;
+    }
+
+    let writer = std::fs::File::create(&report_path)
+        .wrap_err_with(|| format!("Can't create {}", report_path.display()))?;
+    download::batch::write_report(std::io::BufWriter::new(writer), &report)?;
+
+    let count = |status| {
+        report
+            .iter()
+            .filter(|record| record.status == status)
+            .count()
+    };
+    println!(
+        "{} downloaded, {} queued, {} skipped and {} failed. See {} for details",
+        count(download::batch::Status::Downloaded),
+        count(download::batch::Status::Queued),
+        count(download::batch::Status::Skipped),
+        count(download::batch::Status::Failed),
+        report_path.display()
+    );
+    Ok(())
+}
+
+/// Runs the download queue and reports how it went
+async fn run_queue(
+    database: Database,
+    config: &Config,
+    downloader: Arc<dyn Downloader>,
+) -> Result<()> {
+    let (events, received) = tokio::sync::mpsc::unbounded_channel();
+    let renderer = tokio::spawn(download::progress::render(received));
+    let summary = download::queue::run(database, config, downloader, events).await;
+    // The queue is gone once it has run, so the renderer shows what is left and stops
+    renderer.await?;
+    let summary = summary?;
+    println!("{} downloads done, {} failed", summary.done, summary.failed);
+    if summary.failed > 0 {
+        println!("Run `music-manager queue retry` to try the failed downloads again");
+    }
+    Ok(())
+}
+
+/// Executed by the queue command
+async fn queue(args: &ArgMatches) -> Result<()> {
+    let (subcommand, args) = args
+        .subcommand()
+        .ok_or_else(|| eyre!("No subcommand gave to subcommand queue"))?;
+    let music_dir = music_dir()?;
+    let database = Database::open_from_path(music_dir.join("database.sqlite"))?;
+
+    match subcommand {
+        "list" => {
+            let jobs = database.download_jobs()?;
+            if jobs.is_empty() {
+                println!("The download queue is empty");
+            }
+            for job in jobs {
+                print!("{} [{}] {}", job, job.state, job.file_path.display());
+                match &job.error {
+                    Some(error) => println!(": {}", error),
+                    None => println!(),
                 }
             }
         }
-        SingleVideo(video) => {
-            println!("Title: {}, Channel:{}", video.title, video.channel.unwrap())
-            // TODO: handle the case of only 1 video coming up on the search (as impossible that is)
+        "run" => {
+            let config = Config::load()?;
+            let downloader = download::backend::from_config(&config.download)?;
+            run_queue(database, &config, downloader).await?
         }
+        "retry" => {
+            let count = database.retry_failed_download_jobs()?;
+            println!("Queued {} failed jobs again", count);
+        }
+        "clear" => {
+            let mut states = vec![JobState::Done];
+            if args.is_present("failed") {
+                states.push(JobState::Failed);
+            }
+            // What failed jobs kept for a retry goes with them
+            let staging = Config::load()?.download.staging_dir(&music_dir);
+            for job in database.download_jobs()? {
+                if states.contains(&job.state) {
+                    download::staging::remove(&job.staging_dir(&staging))?;
+                }
+            }
+            let count = database.remove_download_jobs(&states)?;
+            println!("Removed {} jobs", count);
+        }
+        _ => return Err(eyre!("Unknown subcommand: queue {}", subcommand)),
     }
 
     Ok(())
 }
 
-fn youtube_dl_download_audio(video: &Video, output_format: &Path) -> Result<()> {
-    let youtube_args = [
-        "--audio-format",
-        "opus",
-        "--audio-quality",
-        "0",
-        "-x",
-        "--output",
-        output_format
-            .to_str()
-            .ok_or_else(|| eyre!("Can't convert path to str"))?,
-    ];
-    let youtube_dl = std::process::Command::new("youtube-dl")
-        .args(youtube_args)
-        .arg(&video.id)
-        .status()?;
-    if youtube_dl.success() {
-        Ok(())
+/// Executed by the restore command.
+///
+/// Re-downloads songs whose files are missing from the library, using the YouTube IDs
+/// recorded in the database, and tags them with the metadata from the database.
+async fn restore(args: &ArgMatches) -> Result<()> {
+    let dry_run = args.is_present("dry-run");
+    let config = Config::load()?;
+    let downloader = download::backend::from_config(&config.download)?;
+    let music_dir = music_dir()?;
+    let database = Database::open_from_path(music_dir.join("database.sqlite"))?;
+    let songs = database.query_all_song_data()?;
+
+    let (mut restored, mut skipped, mut failed) = (0, 0, 0);
+    for song in songs.iter().filter(|song| !song.file_path.exists()) {
+        let youtube_id = match &song.youtube_id {
+            Some(youtube_id) => youtube_id,
+            None => {
+                println!("Skipping {}: no YouTube ID was recorded", song);
+                skipped += 1;
+                continue;
+            }
+        };
+
+        if dry_run {
+            println!(
+                "Would restore {} from {} to {}",
+                song,
+                downloader.source_url(youtube_id),
+                song.file_path.display()
+            );
+            restored += 1;
+            continue;
+        }
+
+        println!("Restoring {} to {}", song, song.file_path.display());
+        match restore_song(song, youtube_id, &config, downloader.as_ref(), &music_dir).await {
+            Ok(_) => restored += 1,
+            Err(e) => {
+                eprintln!("Error restoring {}: {}", song, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if dry_run {
+        println!("{} songs would be restored, {} skipped", restored, skipped);
     } else {
-        Err(eyre!("youtube-dl failed to download"))
+        println!(
+            "{} songs restored, {} skipped, {} failed",
+            restored, skipped, failed
+        );
+    }
+    Ok(())
+}
+
+/// Downloads a song to the path recorded in the database and applies its metadata. The
+/// transcoding profile is chosen by the file's extension. The song is made in the staging
+/// directory, which is removed afterwards whether it worked or not.
+async fn restore_song(
+    song: &Song,
+    youtube_id: &str,
+    config: &Config,
+    downloader: &dyn Downloader,
+    music_dir: &Path,
+) -> Result<()> {
+    let job = DownloadJob::new(youtube_id, song.file_path.clone());
+    if Profile::from_path(&job.file_path) != Some(job.profile) {
+        return Err(eyre!(
+            "No transcoding profile makes files like {}",
+            job.file_path.display()
+        ));
     }
+    let staging = config.download.staging_dir(music_dir);
+    std::fs::create_dir_all(job.staging_dir(&staging))?;
+    let restored = restore_staged(song, &job, &staging, config, downloader).await;
+    download::staging::remove(&job.staging_dir(&staging))?;
+    restored
 }
 
-fn ffmpeg_convert_to_flac(input_file: &Path, output_file: &Path) -> Result<()> {
-    let ffmpeg_args = [
-        "-i",
-        input_file.to_str().unwrap(),
-        "-compression_level",
-        "12",
-        output_file.to_str().unwrap(),
-    ];
-    println!("Converting to FLAC format using ffmpeg...");
-    let ffmpeg = std::process::Command::new("ffmpeg")
-        .args(ffmpeg_args)
-        .status()?;
-    if ffmpeg.success() {
-        println!("Conversion to FLAC successful");
-        println!("Deleting old opus file...");
-        std::fs::remove_file(input_file)?;
-        Ok(())
+/// Downloads, converts and tags a song in the staging directory, then moves it into place
+async fn restore_staged(
+    song: &Song,
+    job: &DownloadJob,
+    staging: &Path,
+    config: &Config,
+    downloader: &dyn Downloader,
+) -> Result<()> {
+    let download_path = job.download_path(staging);
+    let converted_path = job.converted_path(staging);
+    downloader
+        .download_audio(&job.youtube_id, &download_path, &|_| {})
+        .await?;
+    download::convert(&download_path, &converted_path, job.profile, &|_| {}).await?;
+
+    let mut tag = job.profile.read_tag(&converted_path)?;
+    if let Some(title) = &song.title {
+        tag.set("TITLE", vec![title.clone()]);
+    }
+    if let Some(artists) = &song.artists {
+        tag.set("ARTIST", artists.clone());
+    }
+    if let Some(album) = &song.album {
+        tag.set("ALBUM", vec![album.clone()]);
+    }
+    if let Some(genre) = &song.genre {
+        tag.set("GENRE", vec![genre.clone()]);
+    }
+    if let Some(thumbnail_url) = &song.thumbnail_url {
+        match cover::fetch_cover(thumbnail_url, &config.cover).await {
+            Ok(picture) => tag.set_picture(picture)?,
+            Err(e) => {
+                println!("Error fetching cover: {}", e);
+            }
+        }
+    }
+    tag.write_to_path(&converted_path)?;
+    download::staging::move_into_library(&converted_path, &job.file_path)
+}
+
+/// Executed by the scan command.
+///
+/// Adds music files which are not in the database yet and updates the records of files
+/// which changed since the last scan
+fn scan(args: &ArgMatches) -> Result<()> {
+    let music_dir = music_dir()?;
+    let database = Database::open_from_path(music_dir.join("database.sqlite"))?;
+
+    println!("Scanning {}...", music_dir.display());
+    let summary = library::scan::scan(&database, &music_dir)?;
+
+    if args.is_present("verbose") {
+        for path in &summary.added {
+            println!("Added: {}", path.display());
+        }
+        for path in &summary.updated {
+            println!("Updated: {}", path.display());
+        }
+    }
+    for (path, error) in &summary.failed {
+        eprintln!("Failed: {}: {}", path.display(), error);
+    }
+    println!(
+        "{} added, {} updated, {} unchanged, {} failed",
+        summary.added.len(),
+        summary.updated.len(),
+        summary.unchanged,
+        summary.failed.len()
+    );
+    Ok(())
+}
+
+/// Executed by the replaygain command.
+///
+/// Measures the loudness of the songs the query matches, or of every song, and writes their
+/// track and album gains to their tags and the database.
+async fn replaygain(args: &ArgMatches) -> Result<()> {
+    let music_dir = music_dir()?;
+    let database = Database::open_from_path(music_dir.join("database.sqlite"))?;
+    let songs = match query_argument(args)? {
+        Some(query) => database.query(&query)?,
+        None => database.query_all_song_data()?,
+    };
+
+    let summary = library::loudness::apply(&database, &songs, args.is_present("force")).await?;
+    for (path, replaygain) in &summary.tagged {
+        println!("{}: {}", path.display(), replaygain);
+    }
+    for (path, error) in &summary.failed {
+        eprintln!("Failed: {}: {}", path.display(), error);
+    }
+    println!(
+        "{} tagged, {} skipped, {} failed",
+        summary.tagged.len(),
+        summary.skipped,
+        summary.failed.len()
+    );
+    Ok(())
+}
+
+/// Executed by the verify command.
+///
+/// Reports records whose file is missing, files without a record, records whose tags differ
+/// from the file and files with more than one record. Fixes them when `--fix` is given.
+fn verify(args: &ArgMatches) -> Result<()> {
+    let music_dir = music_dir()?;
+    let database = Database::open_from_path(music_dir.join("database.sqlite"))?;
+    let report = library::verify::verify(&database, &music_dir)?;
+
+    if report.is_empty() {
+        println!("The database and the music directory are consistent");
+        return Ok(());
+    }
+
+    if !report.missing.is_empty() {
+        println!("Missing files ({}):", report.missing.len());
+        for song in &report.missing {
+            println!("\t{}: {}", song, song.file_path.display());
+        }
+    }
+    if !report.orphans.is_empty() {
+        println!("Files without a record ({}):", report.orphans.len());
+        for path in &report.orphans {
+            println!("\t{}", path.display());
+        }
+    }
+    if !report.mismatches.is_empty() {
+        println!("Tag mismatches ({}):", report.mismatches.len());
+        for mismatch in &report.mismatches {
+            println!("\t{}: {}", mismatch.song, mismatch.song.file_path.display());
+            for difference in &mismatch.differences {
+                println!(
+                    "\t\t{}: \"{}\" in database, \"{}\" in file",
+                    difference.field, difference.database, difference.file
+                );
+            }
+        }
+    }
+    if !report.duplicates.is_empty() {
+        println!("Duplicate paths ({}):", report.duplicates.len());
+        for duplicate in &report.duplicates {
+            let ids = duplicate
+                .songs
+                .iter()
+                .map(|song| song.id.unwrap().to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            println!("\t{} [IDs: {}]", duplicate.path.display(), ids);
+        }
+    }
+    if !report.unreadable.is_empty() {
+        println!("Unreadable files ({}):", report.unreadable.len());
+        for (song, error) in &report.unreadable {
+            println!("\t{}: {}", song.file_path.display(), error);
+        }
+    }
+
+    if args.is_present("fix") {
+        let policies = Policies {
+            missing: args.value_of("missing").unwrap_or("keep").parse()?,
+            orphan: args.value_of("orphan").unwrap_or("import").parse()?,
+            mismatch: args.value_of("mismatch").unwrap_or("trust-file").parse()?,
+            duplicate: args.value_of("duplicate").unwrap_or("prune-row").parse()?,
+        };
+        let changes = library::verify::fix(&database, &report, &policies)?;
+        println!("Fixed {} problems:", changes.len());
+        for change in changes {
+            println!("\t{}", change);
+        }
+    }
+
+    Ok(())
+}
+
+fn cover(args: &ArgMatches) -> Result<()> {
+    let (subcommand, args) = args
+        .subcommand()
+        .ok_or_else(|| eyre!("No subcommand gave to subcommand cover"))?;
+    let file = Path::new(
+        args.value_of("file")
+            .ok_or_else(|| eyre!("No file given"))?,
+    );
+    let mut song = Song::read_music_file(file)?;
+
+    match subcommand {
+        "show" => {
+            if song.pictures.is_empty() {
+                println!("{} has no pictures", file.display());
+            }
+            for (count, picture) in song.pictures.iter().enumerate() {
+                println!(
+                    "{}. {}, {} bytes",
+                    count + 1,
+                    tag::picture_summary(picture),
+                    picture.data.len()
+                );
+            }
+        }
+        "set" => {
+            let image = args
+                .value_of("image")
+                .ok_or_else(|| eyre!("No image given"))?;
+            let data = std::fs::read(image).wrap_err_with(|| format!("Can't read {}", image))?;
+            let mut picture = tag::picture_from_image(
+                picture_type(args)?.unwrap_or(tag::PictureType::CoverFront),
+                data,
+            )?;
+            picture.description = args.value_of("description").unwrap_or_default().to_string();
+            println!("Setting picture: {}", tag::picture_summary(&picture));
+            song.set_picture(picture)?;
+            song.write_tag_changes()?;
+        }
+        "remove" => {
+            let count = song.pictures.len();
+            song.remove_pictures(picture_type(args)?);
+            song.write_tag_changes()?;
+            println!("Removed {} pictures", count - song.pictures.len());
+        }
+        "extract" => {
+            let dir = match args.value_of("dir") {
+                Some(dir) => PathBuf::from(dir),
+                None => file.parent().map(Path::to_path_buf).unwrap_or_default(),
+            };
+            std::fs::create_dir_all(&dir)?;
+            let paths = song.extract_pictures(&dir, picture_type(args)?)?;
+            if paths.is_empty() {
+                println!("No pictures to extract");
+            }
+            for path in paths {
+                println!("Extracted {}", path.display());
+            }
+        }
+        _ => return Err(eyre!("Unknown subcommand: cover {}", subcommand)),
+    }
+
+    Ok(())
+}
+
+/// Reads the picture type given with --type
+fn picture_type(args: &ArgMatches) -> Result<Option<tag::PictureType>> {
+    args.value_of("type")
+        .map(|name| {
+            tag::picture_type_from_name(name).ok_or_else(|| eyre!("Unknown picture type: {}", name))
+        })
+        .transpose()
+}
+
+fn export(args: &ArgMatches) -> Result<()> {
+    let music_dir = music_dir()?;
+    let database = Database::open_from_path(music_dir.join("database.sqlite"))?;
+    let file = args.value_of("file").filter(|file| *file != "-");
+    let format = match args.value_of("format") {
+        Some(format) => format.parse()?,
+        None => file.map_or(Format::Jsonl, |file| Format::from_path(Path::new(file))),
+    };
+
+    match file {
+        Some(file) => {
+            let writer = std::io::BufWriter::new(
+                std::fs::File::create(file).wrap_err_with(|| format!("Can't create {}", file))?,
+            );
+            let count = library::exchange::export(&database, writer, format)?;
+            println!("Exported {} songs to {}", count, file);
+        }
+        None => {
+            library::exchange::export(&database, std::io::stdout().lock(), format)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn import(args: &ArgMatches) -> Result<()> {
+    let music_dir = music_dir()?;
+    let database = Database::open_from_path(music_dir.join("database.sqlite"))?;
+    let file = args
+        .value_of("file")
+        .ok_or_else(|| eyre!("No file given"))?;
+    let format = match args.value_of("format") {
+        Some(format) => format.parse()?,
+        None => Format::from_path(Path::new(file)),
+    };
+    let key = args.value_of("key").unwrap_or("path").parse()?;
+    let policy = args.value_of("on-conflict").unwrap_or("skip").parse()?;
+
+    let summary = if file == "-" {
+        library::exchange::import(&database, std::io::stdin().lock(), format, key, policy)?
     } else {
-        return Err(eyre!("ffmpeg failed with code: {}", ffmpeg.code().unwrap()));
+        let reader = std::fs::File::open(file).wrap_err_with(|| format!("Can't open {}", file))?;
+        library::exchange::import(&database, reader, format, key, policy)?
+    };
+
+    for (number, error) in &summary.failed {
+        println!("Failed to import record {}: {}", number, error);
     }
+    println!(
+        "Imported {} new, {} overwritten, {} merged, {} skipped and {} failed",
+        summary.inserted,
+        summary.overwritten,
+        summary.merged,
+        summary.skipped,
+        summary.failed.len()
+    );
+
+    Ok(())
 }
 
 /// Executed by the edit command.
@@ -392,34 +1509,73 @@ async fn edit(_args: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
-fn list(_args: &ArgMatches) -> Result<()> {
-    let music_dir = directories_next::UserDirs::new().unwrap();
-    let music_dir = music_dir.audio_dir().unwrap();
+fn list(args: &ArgMatches) -> Result<()> {
+    let music_dir = music_dir()?;
     let database = Database::open_from_path(music_dir.join("database.sqlite"))?;
 
-    let songs = database.query_all_song_data()?;
+    if args.is_present("artists") {
+        println!("List of artists in database:");
+        for (count, artist) in database.list_artists()?.into_iter().enumerate() {
+            println!("{}. {}", count + 1, artist);
+        }
+        return Ok(());
+    }
+    if args.is_present("playlists") {
+        println!("List of playlists in database:");
+        for (count, playlist) in database.list_playlists()?.into_iter().enumerate() {
+            println!("{}. {}", count + 1, playlist);
+        }
+        return Ok(());
+    }
+
+    let songs = match args.value_of("artist") {
+        Some(artist) if args.is_present("albums") => {
+            println!("List of albums by {}:", artist);
+            for (count, album) in database.albums_by_artist(artist)?.into_iter().enumerate() {
+                println!("{}. {}", count + 1, album);
+            }
+            return Ok(());
+        }
+        Some(artist) => {
+            println!("List of songs by {}:", artist);
+            database.songs_by_artist(artist)?
+        }
+        None => match args.value_of("playlist") {
+            Some(playlist) => {
+                println!("List of songs in {}:", playlist);
+                database.playlist_songs(playlist)?
+            }
+            None => {
+                println!("List of songs in database:");
+                database.query_all_song_data()?
+            }
+        },
+    };
 
-    println!("List of songs in database:");
-    let mut count = 1;
-    for song in songs {
+    let lossy_origin = args.is_present("lossy-origin");
+    let songs = songs
+        .into_iter()
+        .filter(|song| !lossy_origin || song.provenance.is_lossy());
+    for (count, song) in songs.enumerate() {
         let song_title = song.title.clone().unwrap_or_else(|| "None".to_string());
         let song_id = song.id.unwrap();
-        let song_artist = song.artists.unwrap();
-        let song_artist = song_artist.first().unwrap();
+        let song_artist = song
+            .artists
+            .and_then(|artists| artists.first().cloned())
+            .unwrap_or_else(|| "None".to_string());
         println!(
             "{}. {} - {} [ID: {}]",
-            count, song_title, song_artist, song_id,
+            count + 1,
+            song_title,
+            song_artist,
+            song_id,
         );
-        count += 1;
     }
     Ok(())
 }
 
 fn remove(args: &ArgMatches) -> Result<()> {
-    let music_dir = directories_next::UserDirs::new().unwrap();
-    let music_dir = music_dir
-        .audio_dir()
-        .ok_or_else(|| eyre!("Couldn't get user music dir."))?;
+    let music_dir = music_dir()?;
     let database = Database::open_from_path(music_dir.join("database.sqlite"))?;
 
     match args.value_of("title") {
@@ -437,19 +1593,13 @@ fn remove(args: &ArgMatches) -> Result<()> {
                         let id = song.id.unwrap();
                         println!(
                             "Removing: {} - {} [ID: {}]",
-                            song.title.as_ref().unwrap(),
-                            song.artists.as_ref().unwrap().join(":"),
+                            song.title.as_deref().unwrap_or("None"),
+                            song.artists.clone().unwrap_or_default().join(", "),
                             id
                         );
-                        match database.remove_song(id) {
-                            Ok(_) => {
-                                println!("Song removed from database. Removing old file...");
-                                std::fs::remove_file(music_dir.join(&song.file_name))?;
-                                println!("File removed.");
-                            }
-                            Err(e) => {
-                                eprintln!("Error: {}", e);
-                            }
+                        match remove_song(&database, song) {
+                            Ok(_) => println!("Removed the song and its file."),
+                            Err(e) => eprintln!("Error: {}", e),
                         }
                     }
                 }
@@ -465,49 +1615,80 @@ fn remove(args: &ArgMatches) -> Result<()> {
 
             println!(
                 "Removing: {} - {} [ID: {}]",
-                song.title.as_ref().unwrap(),
-                song.artists.as_ref().unwrap().join(":"),
+                song.title.as_deref().unwrap_or("None"),
+                song.artists.clone().unwrap_or_default().join(", "),
                 song.id.unwrap()
             );
-            match database.remove_song(song_id) {
-                Ok(_) => {
-                    println!("Song removed from database. Removing old file...");
-                    std::fs::remove_file(music_dir.join(&song.file_name))?;
-                    println!("File removed.");
-                }
-                Err(e) => {
-                    eprintln!("Error: {}", e)
-                }
-            }
+            remove_song(&database, song)?;
+            println!("Removed the song and its file.");
         }
     }
     Ok(())
 }
 
+/// Deletes a song's file, then its record, so a record is never removed while its file
+/// stays. Records whose file is gone already are removed all the same.
+fn remove_song(database: &Database, song: &Song) -> Result<()> {
+    match std::fs::remove_file(&song.file_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).wrap_err_with(|| format!("Can't remove {}", song.file_path.display()))
+        }
+        _ => {}
+    }
+    database.remove_song(song.id.ok_or_else(|| eyre!("Song has no ID"))?)
+}
+
+/// Parses the words of the query argument, pointing at the mistake if they don't parse.
+/// Returns None if no query was given.
+fn query_argument(args: &ArgMatches) -> Result<Option<Query>> {
+    let query_string = match args.values_of("query") {
+        Some(words) => words
+            .map(requote_argument)
+            .collect::<Vec<String>>()
+            .join(" "),
+        None => return Ok(None),
+    };
+    match Query::parse(&query_string) {
+        Ok(query) => Ok(Some(query)),
+        Err(e) => {
+            eprintln!("{}", query_string);
+            eprintln!("{}^", " ".repeat(e.position));
+            Err(eyre!("Invalid search query: {}", e))
+        }
+    }
+}
+
 fn search(args: &ArgMatches) -> Result<()> {
-    let song_title = args.value_of("title").unwrap();
-    let music_dir = directories_next::UserDirs::new().unwrap();
-    let music_dir = music_dir
-        .audio_dir()
-        .ok_or_else(|| eyre!("Couldn't get user music dir."))?;
+    let query = query_argument(args)?.ok_or_else(|| eyre!("Search query is not given"))?;
+
+    let music_dir = music_dir()?;
     let database = Database::open_from_path(music_dir.join("database.sqlite"))?;
 
-    match database.search_song(song_title) {
+    match database.query(&query) {
         Ok(songs) => {
             println!("Results found: ");
-            let mut count = 1;
-
-            for song in songs {
-                let song_title = song.title.clone().unwrap();
-                let song_artist = song.artists.clone().unwrap().join(":");
+            for (count, song) in songs.into_iter().enumerate() {
+                let song_title = song.title.clone().unwrap_or_else(|| "None".to_string());
+                let song_artist = song.artists.clone().unwrap_or_default().join(", ");
                 let song_id = song.id.unwrap();
                 let path = song.file_path;
                 println!(
                     "{}. {} - {} [ID: {}]",
-                    count, song_title, song_artist, song_id
+                    count + 1,
+                    song_title,
+                    song_artist,
+                    song_id
                 );
                 println!("\tPath: {}", path.display());
-                count += 1;
+                if song.provenance.source_codec.is_some() {
+                    println!("\tSource: {}", song.provenance);
+                }
+                if !song.replaygain.is_empty() {
+                    println!("\tReplayGain: {}", song.replaygain);
+                }
+                if !song.trim.is_empty() {
+                    println!("\tTrimmed: {}", song.trim);
+                }
             }
         }
         Err(e) => {
@@ -517,3 +1698,21 @@ fn search(args: &ArgMatches) -> Result<()> {
 
     Ok(())
 }
+
+/// The shell strips the quotes in `artist:"Some One"`, leaving `artist:Some One` as a single
+/// argument. Puts the quotes back so the argument is parsed as one term.
+fn requote_argument(argument: &str) -> String {
+    if !argument.contains(char::is_whitespace) || argument.contains('"') {
+        return argument.to_string();
+    }
+    let (negation, rest) = match argument.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", argument),
+    };
+    match rest.split_once(':') {
+        Some((field, value)) if !field.contains(char::is_whitespace) => {
+            format!("{}{}:\"{}\"", negation, field, value)
+        }
+        _ => format!("{}\"{}\"", negation, rest),
+    }
+}
@@ -35,6 +35,7 @@ pub enum Action {
 
     // MetadataWidgetActions
     SaveTagsToFile,
+    ExtractPictures,
 }
 
 impl Action {
@@ -67,6 +68,7 @@ impl Action {
             Action::SelectUp => &[Key::Char('k')],
             Action::Enter => &[Key::Enter],
             Action::SaveTagsToFile => &[Key::Char('s')],
+            Action::ExtractPictures => &[Key::Char('x')],
             Action::SwitchToDirListWidget => &[Key::Char('d')],
         }
     }
@@ -94,6 +96,7 @@ impl Display for Action {
             Action::SelectUp => "SelectUp",
             Action::Enter => "EnterKey",
             Action::SaveTagsToFile => "SaveTagsToFile",
+            Action::ExtractPictures => "ExtractPictures",
             Action::SwitchToDirListWidget => "SwitchToDirListWidget",
         };
 
@@ -88,7 +88,7 @@ impl DirListState {
 
 pub fn get_files_in_dir(path: &Path) -> Result<(Vec<String>, Vec<PathBuf>)> {
     let mut paths = std::fs::read_dir(path)?
-        .map(|res| res.map(|e| (e.path())))
+        .map(|res| res.map(|e| e.path()))
         .collect::<Result<Vec<_>, _>>()?;
     let mut file_name = paths
         .iter()
@@ -192,6 +192,24 @@ impl App {
                             }
                             AppReturn::Continue
                         }
+                        Action::ExtractPictures => {
+                            let song = &self.current_selected_song;
+                            let dir = song.file_path.parent().unwrap_or(&song.file_path);
+                            match song.extract_pictures(dir, None) {
+                                Ok(paths) if paths.is_empty() => {
+                                    warn!("The song has no pictures to extract")
+                                }
+                                Ok(paths) => {
+                                    for path in paths {
+                                        info!("Extracted picture to: {}", path.display());
+                                    }
+                                }
+                                Err(e) => {
+                                    error!("Error extracting pictures: {}", e);
+                                }
+                            }
+                            AppReturn::Continue
+                        }
                         Action::SwitchToDirListWidget => {
                             self.enter_dirlisting_widget();
                             AppReturn::Continue
@@ -293,6 +311,23 @@ impl App {
 
     /// Execute upon entering MetadataEditorWidget
     fn enter_metadata_editor_widget(&mut self) {
+        if self.current_app_widget == AppActiveWidgetState::DirListing
+            || !self.current_selected_song.is_initialized()
+        {
+            let path = self
+                .dirlist
+                .current_dir_file_paths
+                .get(self.dirlist.state.selected().unwrap_or(0))
+                .unwrap();
+            match Song::read_music_file(path) {
+                Ok(song) => self.current_selected_song = song,
+                Err(e) => {
+                    error!("Can't read tags of {}: {}", path.display(), e);
+                    return;
+                }
+            }
+        }
+
         if self.current_app_widget == AppActiveWidgetState::InputBar {
             self.previous_app_widget = AppActiveWidgetState::MetadataEditor
         } else {
@@ -308,21 +343,11 @@ impl App {
                 Action::SwitchToLogWidget,
                 Action::SwitchToPreviousWidget,
                 Action::SaveTagsToFile,
+                Action::ExtractPictures,
                 Action::SwitchToDirListWidget,
             ]
             .into(),
         );
-        if self.previous_app_widget == AppActiveWidgetState::DirListing {
-            self.current_selected_song.initialized = false;
-        }
-        if !self.current_selected_song.is_initialized() {
-            let path = self
-                .dirlist
-                .current_dir_file_paths
-                .get(self.dirlist.state.selected().unwrap_or(0))
-                .unwrap();
-            self.current_selected_song = Song::read_music_file(path).unwrap();
-        }
     }
 
     /// Execute upon entering LogViewerWidget
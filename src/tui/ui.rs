@@ -160,7 +160,7 @@ where
     Ok(())
 }
 
-fn draw_help(actions: &Actions) -> Table {
+fn draw_help(actions: &Actions) -> Table<'_> {
     let key_style = Style::default().fg(Color::LightCyan);
     let help_style = Style::default().fg(Color::Gray);
 
@@ -0,0 +1,335 @@
+//! Helpers for running music-manager against a throwaway home directory, with stub
+//! executables standing in for external tools such as youtube-dl and ffmpeg
+#![allow(dead_code)]
+
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+pub struct TestEnv {
+    pub home: PathBuf,
+    pub music_dir: PathBuf,
+    pub bin_dir: PathBuf,
+}
+
+impl TestEnv {
+    /// Creates an empty home directory whose XDG music directory is `~/Music`
+    pub fn new(name: &str) -> Self {
+        let home = std::env::temp_dir().join("music-manager-tests").join(name);
+        if home.exists() {
+            std::fs::remove_dir_all(&home).unwrap();
+        }
+        let music_dir = home.join("Music");
+        let bin_dir = home.join("bin");
+        std::fs::create_dir_all(&music_dir).unwrap();
+        std::fs::create_dir_all(&bin_dir).unwrap();
+        std::fs::create_dir_all(home.join(".config")).unwrap();
+        std::fs::write(
+            home.join(".config/user-dirs.dirs"),
+            "XDG_MUSIC_DIR=\"$HOME/Music\"\n",
+        )
+        .unwrap();
+
+        Self {
+            home,
+            music_dir,
+            bin_dir,
+        }
+    }
+
+    /// Installs a shell script as an executable which shadows the real one
+    pub fn stub(&self, name: &str, script: &str) {
+        let path = self.bin_dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    /// Stubs youtube-dl, writing an empty Opus file to the output template and recording the
+    /// requested video IDs in `youtube-dl.log`. Requests for information with `-J` are
+    /// answered with what was given to `youtube_dl_info`, or else the contents of
+    /// `youtube-dl.json`.
+    pub fn stub_youtube_dl(&self) {
+        let opus = self.home.join("fixture.opus");
+        std::fs::write(&opus, minimal_opus()).unwrap();
+        self.stub(
+            "youtube-dl",
+            &format!(
+                r#"
+while [ $# -gt 0 ]; do
+    case "$1" in
+        --output) output="$2"; shift ;;
+        --version) echo 2021.12.17; exit ;;
+        -J) info=1 ;;
+        -*) ;;
+        *) id="$1" ;;
+    esac
+    shift
+done
+if [ -n "$info" ]; then
+    key=$(printf '%s' "$id" | tr -c 'A-Za-z0-9' '_')
+    if [ -f "{info}/$key.json" ]; then
+        cat "{info}/$key.json"
+    elif [ -f "{json}" ]; then
+        cat "{json}"
+    else
+        echo "ERROR: Unable to look up $id" >&2
+        exit 1
+    fi
+    exit
+fi
+echo "$id" >> "{log}"
+cp "{opus}" "$(echo "$output" | sed 's/%(ext)s/opus/')"
+"#,
+                opus = opus.display(),
+                info = self.home.join("youtube-dl").display(),
+                json = self.home.join("youtube-dl.json").display(),
+                log = self.home.join("youtube-dl.log").display()
+            ),
+        );
+    }
+
+    /// Stubs ffmpeg, copying a minimal Ogg file to the last argument if it ends in `.ogg` and
+    /// a minimal FLAC file otherwise, and recording its arguments in `ffmpeg.log`. Loudness
+    /// measurements report three minutes at the loudness given to `loudness`, or -18 LUFS,
+    /// with a peak of -1 dBFS. Silence detection reports three minutes with the silence given
+    /// to `silence`, or none.
+    pub fn stub_ffmpeg(&self) {
+        let flac = self.home.join("fixture.flac");
+        std::fs::write(&flac, minimal_flac()).unwrap();
+        let ogg = self.home.join("fixture.ogg");
+        std::fs::write(&ogg, minimal_opus()).unwrap();
+        self.stub(
+            "ffmpeg",
+            &format!(
+                r#"
+echo "$@" >> "{log}"
+for arg; do
+    case "$arg" in
+        ebur128*) measure=1 ;;
+        silencedetect*) detect=1 ;;
+    esac
+    [ "$previous" = -i ] && input="$arg"
+    previous="$arg"
+done
+if [ -n "$measure" ]; then
+    loudness=-18.0
+    name=$(basename "$input")
+    [ -f "{loudness}/$name" ] && loudness=$(cat "{loudness}/$name")
+    echo "  Duration: 00:03:00.00, start: 0.000000, bitrate: 896 kb/s" >&2
+    echo "[Parsed_ebur128_0 @ 0x1] Summary:" >&2
+    echo "  Integrated loudness:" >&2
+    echo "    I:         $loudness LUFS" >&2
+    echo "  True peak:" >&2
+    echo "    Peak:        -1.0 dBFS" >&2
+    exit
+fi
+if [ -n "$detect" ]; then
+    name=$(basename "$input")
+    echo "  Duration: 00:03:00.00, start: 0.000000, bitrate: 896 kb/s" >&2
+    [ -f "{silence}/$name" ] && cat "{silence}/$name" >&2
+    exit
+fi
+case "$previous" in
+    *.ogg) cp "{ogg}" "$previous" ;;
+    *) cp "{flac}" "$previous" ;;
+esac
+"#,
+                log = self.home.join("ffmpeg.log").display(),
+                loudness = self.home.join("loudness").display(),
+                silence = self.home.join("silence").display(),
+                ogg = ogg.display(),
+                flac = flac.display()
+            ),
+        );
+    }
+
+    /// Sets the loudness in LUFS the ffmpeg stub measures for files with this name
+    pub fn loudness(&self, file_name: &str, lufs: f64) {
+        let dir = self.home.join("loudness");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(file_name), lufs.to_string()).unwrap();
+    }
+
+    /// Sets the silent periods the ffmpeg stub finds in files with this name, as pairs of
+    /// start and end in seconds. Periods without an end run to the end of the file.
+    pub fn silence(&self, file_name: &str, periods: &[(f64, Option<f64>)]) {
+        let dir = self.home.join("silence");
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = periods
+            .iter()
+            .map(|(start, end)| {
+                let mut lines = format!("[silencedetect @ 0x1] silence_start: {}\n", start);
+                if let Some(end) = end {
+                    lines += &format!(
+                        "[silencedetect @ 0x1] silence_end: {} | silence_duration: {}\n",
+                        end,
+                        end - start
+                    );
+                }
+                lines
+            })
+            .collect::<String>();
+        std::fs::write(dir.join(file_name), output).unwrap();
+    }
+
+    /// Sets what the youtube-dl stub answers when asked about a URL or search, such as
+    /// `ytsearch5:yoasobi gunjou`
+    pub fn youtube_dl_info(&self, query: &str, json: &str) {
+        let key = query
+            .bytes()
+            .map(|byte| {
+                if byte.is_ascii_alphanumeric() {
+                    byte as char
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        let dir = self.home.join("youtube-dl");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(format!("{}.json", key)), json).unwrap();
+    }
+
+    /// Writes music-manager's config file
+    pub fn write_config(&self, contents: &str) {
+        let dir = self.home.join(".config/music-manager");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("config.toml"), contents).unwrap();
+    }
+
+    pub fn run(&self, args: &[&str]) -> Output {
+        let path = format!(
+            "{}:{}",
+            self.bin_dir.display(),
+            std::env::var("PATH").unwrap_or_default()
+        );
+        Command::new(env!("CARGO_BIN_EXE_music-manager"))
+            .args(args)
+            .env("HOME", &self.home)
+            .env("XDG_CONFIG_HOME", self.home.join(".config"))
+            .env("PATH", path)
+            .output()
+            .unwrap()
+    }
+
+    /// Runs music-manager and returns its standard output, failing the test if it fails
+    pub fn run_ok(&self, args: &[&str]) -> String {
+        let output = self.run(args);
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        assert!(
+            output.status.success(),
+            "music-manager {:?} failed\nstdout:\n{}\nstderr:\n{}",
+            args,
+            stdout,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        stdout
+    }
+
+    /// Opens the library database, letting music-manager create it first
+    pub fn database(&self) -> rusqlite::Connection {
+        let path = self.music_dir.join("database.sqlite");
+        if !path.exists() {
+            // Creates the database, then fails as there are no songs
+            self.run(&["list"]);
+        }
+        rusqlite::Connection::open(path).unwrap()
+    }
+
+    /// Registers a song directly in the database and returns its ID
+    pub fn insert_song(
+        &self,
+        path: &Path,
+        title: &str,
+        artist: &str,
+        album: &str,
+        youtube_id: Option<&str>,
+    ) -> i64 {
+        let conn = self.database();
+        conn.execute("INSERT OR IGNORE INTO artists (name) VALUES (?1)", [artist])
+            .unwrap();
+        conn.execute(
+            "INSERT OR IGNORE INTO albums (title, artist_id)
+            VALUES (?1, (SELECT id FROM artists WHERE name = ?2))",
+            [album, artist],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO songs (song_path, song_filename, song_title, album_id, song_youtube_id)
+            VALUES (?1, ?2, ?3, (SELECT albums.id FROM albums
+                JOIN artists ON artists.id = albums.artist_id
+                WHERE albums.title = ?4 AND artists.name = ?6), ?5)",
+            rusqlite::params![
+                path.to_str().unwrap(),
+                path.file_name().unwrap().to_str().unwrap(),
+                title,
+                album,
+                youtube_id,
+                artist
+            ],
+        )
+        .unwrap();
+        let id = conn.last_insert_rowid();
+        conn.execute(
+            "INSERT INTO song_artists (song_id, artist_id, position)
+            SELECT ?1, id, 0 FROM artists WHERE name = ?2",
+            rusqlite::params![id, artist],
+        )
+        .unwrap();
+        id
+    }
+}
+
+/// Writes a FLAC file with the given tags, creating its directory
+pub fn write_flac(path: &Path, title: &str, artist: &str, album: &str) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, minimal_flac()).unwrap();
+    let mut tag = metaflac::Tag::read_from_path(path).unwrap();
+    tag.set_vorbis("TITLE", vec![title]);
+    tag.set_vorbis("ARTIST", vec![artist]);
+    tag.set_vorbis("ALBUM", vec![album]);
+    tag.save().unwrap();
+}
+
+/// An Ogg Opus stream with the headers and one packet of silence
+pub fn minimal_opus() -> Vec<u8> {
+    let mut opus = vec![];
+    let mut writer = ogg::PacketWriter::new(&mut opus);
+    let packets: [(&[u8], _, _); 3] = [
+        (
+            b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00",
+            ogg::PacketWriteEndInfo::EndPage,
+            0,
+        ),
+        (
+            b"OpusTags\x04\x00\x00\x00test\x00\x00\x00\x00",
+            ogg::PacketWriteEndInfo::EndPage,
+            0,
+        ),
+        (b"\xfc\xff\xfe", ogg::PacketWriteEndInfo::EndStream, 960),
+    ];
+    for (data, end_info, absgp) in packets {
+        writer
+            .write_packet(data.to_vec().into_boxed_slice(), 1, end_info, absgp)
+            .unwrap();
+    }
+    drop(writer);
+    opus
+}
+
+/// The smallest file metaflac accepts: the FLAC marker and a STREAMINFO block describing
+/// an empty 44.1kHz 16 bit stereo stream
+pub fn minimal_flac() -> Vec<u8> {
+    let mut flac = b"fLaC".to_vec();
+    // Last metadata block, type STREAMINFO, 34 bytes long
+    flac.extend_from_slice(&[0x80, 0x00, 0x00, 0x22]);
+    // Block sizes and frame sizes
+    flac.extend_from_slice(&[0x10, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0]);
+    // Sample rate, channels, bits per sample and total samples
+    flac.extend_from_slice(&[0x0A, 0xC4, 0x42, 0xF0, 0, 0, 0, 0]);
+    // MD5 of the audio
+    flac.extend_from_slice(&[0; 16]);
+    flac
+}
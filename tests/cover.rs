@@ -0,0 +1,84 @@
+mod common;
+
+use common::{write_flac, TestEnv};
+
+fn write_png(path: &std::path::Path, width: u32, height: u32) {
+    let image = image::RgbImage::from_fn(width, height, |x, _| image::Rgb([x as u8, 0, 255]));
+    image
+        .save_with_format(path, image::ImageFormat::Png)
+        .unwrap();
+}
+
+#[test]
+fn cover_set_show_extract_remove() {
+    let env = TestEnv::new("cover");
+    let song = env.music_dir.join("Yoru ni Kakeru.flac");
+    write_flac(&song, "Yoru ni Kakeru", "YOASOBI", "THE BOOK");
+    let front = env.home.join("front.png");
+    let back = env.home.join("back.png");
+    write_png(&front, 64, 48);
+    write_png(&back, 32, 32);
+    let song_arg = song.to_str().unwrap();
+
+    let stdout = env.run_ok(&["cover", "show", song_arg]);
+    assert!(stdout.contains("has no pictures"));
+
+    env.run_ok(&["cover", "set", song_arg, front.to_str().unwrap()]);
+    env.run_ok(&[
+        "cover",
+        "set",
+        song_arg,
+        back.to_str().unwrap(),
+        "--type",
+        "back",
+    ]);
+    // Setting a picture of the same type replaces it
+    env.run_ok(&[
+        "cover",
+        "set",
+        song_arg,
+        front.to_str().unwrap(),
+        "--description",
+        "Cover",
+    ]);
+    let stdout = env.run_ok(&["cover", "show", song_arg]);
+    assert!(stdout.contains("1. back, image/png, 32x32"), "{}", stdout);
+    assert!(
+        stdout.contains("2. front, image/png, 64x48, \"Cover\""),
+        "{}",
+        stdout
+    );
+    assert!(!stdout.contains("3."));
+
+    // The tags are untouched
+    let tag = metaflac::Tag::read_from_path(&song).unwrap();
+    assert_eq!(
+        tag.get_vorbis("TITLE").unwrap().next(),
+        Some("Yoru ni Kakeru")
+    );
+
+    let extracted = env.home.join("extracted");
+    env.run_ok(&[
+        "cover",
+        "extract",
+        song_arg,
+        extracted.to_str().unwrap(),
+        "--type",
+        "front",
+    ]);
+    assert_eq!(
+        std::fs::read(extracted.join("Yoru ni Kakeru - front.png")).unwrap(),
+        std::fs::read(&front).unwrap()
+    );
+    assert!(!extracted.join("Yoru ni Kakeru - back.png").exists());
+
+    let stdout = env.run_ok(&["cover", "remove", song_arg, "--type", "front"]);
+    assert!(stdout.contains("Removed 1 pictures"));
+    let stdout = env.run_ok(&["cover", "remove", song_arg]);
+    assert!(stdout.contains("Removed 1 pictures"));
+    let stdout = env.run_ok(&["cover", "show", song_arg]);
+    assert!(stdout.contains("has no pictures"));
+
+    let output = env.run(&["cover", "set", song_arg, song_arg]);
+    assert!(!output.status.success());
+}
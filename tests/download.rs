@@ -0,0 +1,997 @@
+mod common;
+
+use std::path::Path;
+
+use common::{write_flac, TestEnv};
+
+/// Adds a job to the download queue directly and returns its ID
+fn queue_job(env: &TestEnv, youtube_id: &str, path: &Path, title: &str, state: &str) -> i64 {
+    let conn = env.database();
+    conn.execute(
+        "INSERT INTO download_jobs (youtube_id, file_path, title, artists, album, state,
+            created_at, updated_at)
+        VALUES (?1, ?2, ?3, 'YOASOBI', 'THE BOOK', ?4, '2021-12-01 10:00:00.0Z',
+            '2021-12-01 10:00:00.0Z')",
+        rusqlite::params![youtube_id, path.to_str().unwrap(), title, state],
+    )
+    .unwrap();
+    conn.last_insert_rowid()
+}
+
+fn job_states(env: &TestEnv) -> Vec<(String, String)> {
+    let conn = env.database();
+    let mut stmt = conn
+        .prepare("SELECT youtube_id, state FROM download_jobs ORDER BY id")
+        .unwrap();
+    let states = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .unwrap();
+    states
+}
+
+#[test]
+fn run_and_resume_queue() {
+    let env = TestEnv::new("download-queue");
+    env.stub_youtube_dl();
+    env.stub_ffmpeg();
+
+    let queued = env.music_dir.join("Yoru ni Kakeru.flac");
+    let interrupted = env.music_dir.join("Gunjou.flac");
+    queue_job(&env, "x8VYWazR5mE", &queued, "Yoru ni Kakeru", "queued");
+    // Interrupted while converting, with the download in its staging directory
+    let id = queue_job(&env, "Y4nEEZwckuU", &interrupted, "Gunjou", "converting");
+    let staging = env.music_dir.join(format!(".staging/job-{}", id));
+    std::fs::create_dir_all(&staging).unwrap();
+    std::fs::write(staging.join("download.opus"), "OggS").unwrap();
+
+    let stdout = env.run_ok(&["queue", "run"]);
+    assert!(stdout.contains("2 downloads done, 0 failed"), "{}", stdout);
+    // Only the queued job was downloaded
+    assert_eq!(
+        std::fs::read_to_string(env.home.join("youtube-dl.log")).unwrap(),
+        "x8VYWazR5mE\n"
+    );
+    // Finished jobs leave nothing behind
+    assert!(!staging.exists());
+    assert_eq!(
+        std::fs::read_dir(env.music_dir.join(".staging"))
+            .unwrap()
+            .count(),
+        0
+    );
+
+    let tag = metaflac::Tag::read_from_path(&interrupted).unwrap();
+    let get = |key: &str| tag.get_vorbis(key).unwrap().collect::<Vec<_>>();
+    assert_eq!(get("TITLE"), vec!["Gunjou"]);
+    assert_eq!(get("ARTIST"), vec!["YOASOBI"]);
+
+    assert_eq!(
+        job_states(&env),
+        vec![
+            ("x8VYWazR5mE".to_string(), "done".to_string()),
+            ("Y4nEEZwckuU".to_string(), "done".to_string())
+        ]
+    );
+    let stdout = env.run_ok(&["list"]);
+    assert!(stdout.contains("Yoru ni Kakeru"));
+    assert!(stdout.contains("Gunjou"));
+
+    let stdout = env.run_ok(&["queue", "clear"]);
+    assert!(stdout.contains("Removed 2 jobs"));
+    assert!(env
+        .run_ok(&["queue", "list"])
+        .contains("The download queue is empty"));
+}
+
+#[test]
+fn retry_failed_jobs() {
+    let env = TestEnv::new("download-queue-retry");
+    env.stub(
+        "youtube-dl",
+        "echo 'ERROR: Video unavailable' >&2\nexit 1\n",
+    );
+    env.stub_ffmpeg();
+    env.write_config("[download]\nworkers = 3\nretry_delay = 0\n");
+
+    let path = env.music_dir.join("Yoru ni Kakeru.flac");
+    queue_job(&env, "x8VYWazR5mE", &path, "Yoru ni Kakeru", "queued");
+
+    let stdout = env.run_ok(&["queue", "run"]);
+    assert!(stdout.contains("0 downloads done, 1 failed"), "{}", stdout);
+    let stdout = env.run_ok(&["queue", "list"]);
+    assert!(stdout.contains("[failed]"), "{}", stdout);
+    assert!(stdout.contains("ERROR: Video unavailable"), "{}", stdout);
+
+    env.stub_youtube_dl();
+    assert!(env
+        .run_ok(&["queue", "retry"])
+        .contains("Queued 1 failed jobs again"));
+    let stdout = env.run_ok(&["queue", "run"]);
+    assert!(stdout.contains("1 downloads done, 0 failed"), "{}", stdout);
+    assert!(path.exists());
+}
+
+#[test]
+fn retry_time_out_and_clean_up_stages() {
+    let env = TestEnv::new("download-queue-stages");
+    env.stub_youtube_dl();
+    env.stub_ffmpeg();
+    // The first download breaks off, leaving a partial file
+    std::fs::rename(
+        env.bin_dir.join("youtube-dl"),
+        env.bin_dir.join("youtube-dl-stub"),
+    )
+    .unwrap();
+    env.stub(
+        "youtube-dl",
+        &format!(
+            r#"
+for arg; do
+    [ "$previous" = --output ] && output="$arg"
+    previous="$arg"
+done
+if [ -n "$output" ] && [ ! -f "{flag}" ]; then
+    touch "{flag}"
+    echo partial > "$(echo "$output" | sed 's/%(ext)s/webm.part/')"
+    echo "ERROR: Connection reset by peer" >&2
+    exit 1
+fi
+exec "{stub}" "$@"
+"#,
+            flag = env.home.join("broke-off").display(),
+            stub = env.bin_dir.join("youtube-dl-stub").display()
+        ),
+    );
+    env.write_config("[download]\nretries = 1\nretry_delay = 0\n");
+
+    let path = env.music_dir.join("Yoru ni Kakeru.flac");
+    let id = queue_job(&env, "x8VYWazR5mE", &path, "Yoru ni Kakeru", "queued");
+    let stdout = env.run_ok(&["queue", "run"]);
+    assert!(stdout.contains("1 downloads done, 0 failed"), "{}", stdout);
+    assert!(
+        stdout.contains(
+            "attempt 1 of 2 failed: youtube-dl failed with code 1: ERROR: Connection reset by peer"
+        ),
+        "{}",
+        stdout
+    );
+    assert!(path.exists());
+    assert!(!env.music_dir.join(format!(".staging/job-{}", id)).exists());
+
+    // Conversions taking too long are stopped, and nothing reaches the library
+    env.stub("ffmpeg", "sleep 10\n");
+    env.write_config("[download]\nretries = 0\nconvert_timeout = 1\n");
+    let path = env.music_dir.join("Gunjou.flac");
+    let id = queue_job(&env, "Y4nEEZwckuU", &path, "Gunjou", "queued");
+    let stdout = env.run_ok(&["queue", "run"]);
+    assert!(stdout.contains("0 downloads done, 1 failed"), "{}", stdout);
+    let stdout = env.run_ok(&["queue", "list"]);
+    assert!(stdout.contains("Timed out after 1 seconds"), "{}", stdout);
+    assert!(!path.exists());
+    // The finished download is kept for a retry, the partial conversion is not
+    let staging = env.music_dir.join(format!(".staging/job-{}", id));
+    let left = std::fs::read_dir(&staging)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    assert_eq!(left, vec!["download.opus"]);
+
+    env.run_ok(&["queue", "clear", "--failed"]);
+    assert!(!staging.exists());
+}
+
+#[test]
+fn download_playlist() {
+    let env = TestEnv::new("download-playlist");
+    env.stub_youtube_dl();
+    env.stub_ffmpeg();
+    std::fs::write(
+        env.home.join("youtube-dl.json"),
+        r#"{
+            "_type": "playlist",
+            "id": "PLtest",
+            "title": "Night Drive",
+            "entries": [
+                {"_type": "url", "id": "Y4nEEZwckuU", "title": "Gunjou", "channel": "Ayase / YOASOBI"},
+                {"_type": "url", "id": "x8VYWazR5mE", "title": "Yoru ni Kakeru", "channel": "Ayase / YOASOBI"},
+                {"_type": "url", "id": "deleted", "title": "[Deleted video]"},
+                {"_type": "url", "id": "Y4nEEZwckuU", "title": "Gunjou", "channel": "Ayase / YOASOBI"},
+                {"_type": "url", "id": "sAuEeM_6zpk", "title": "Idol", "channel": "Ayase / YOASOBI"}
+            ]
+        }"#,
+    )
+    .unwrap();
+
+    // Already in the library, under another name
+    let existing = env.music_dir.join("Yoru ni Kakeru (renamed).flac");
+    common::write_flac(&existing, "Yoru ni Kakeru", "YOASOBI", "THE BOOK");
+    env.insert_song(
+        &existing,
+        "Yoru ni Kakeru",
+        "YOASOBI",
+        "THE BOOK",
+        Some("x8VYWazR5mE"),
+    );
+
+    let stdout = env.run_ok(&[
+        "download",
+        "--playlist",
+        "https://www.youtube.com/playlist?list=PLtest",
+        "--save-playlist",
+    ]);
+    assert!(stdout.contains("Queued 2 videos, skipped 3"), "{}", stdout);
+    assert!(stdout.contains("2 downloads done, 0 failed"), "{}", stdout);
+    let log = std::fs::read_to_string(env.home.join("youtube-dl.log")).unwrap();
+    let mut downloaded = log.lines().collect::<Vec<_>>();
+    downloaded.sort_unstable();
+    assert_eq!(downloaded, vec!["Y4nEEZwckuU", "sAuEeM_6zpk"]);
+
+    assert_eq!(
+        std::fs::read_to_string(env.music_dir.join("Night Drive.m3u")).unwrap(),
+        "#EXTM3U\n\
+        #EXTINF:-1,Ayase / YOASOBI - Gunjou\n\
+        Gunjou.flac\n\
+        #EXTINF:-1,YOASOBI - Yoru ni Kakeru\n\
+        Yoru ni Kakeru (renamed).flac\n\
+        #EXTINF:-1,Ayase / YOASOBI - Gunjou\n\
+        Gunjou.flac\n\
+        #EXTINF:-1,Ayase / YOASOBI - Idol\n\
+        Idol.flac\n"
+    );
+    let stdout = env.run_ok(&["list", "--playlist", "Night Drive"]);
+    let titles = stdout
+        .lines()
+        .skip(1)
+        .map(|line| {
+            line.split(". ")
+                .nth(1)
+                .unwrap()
+                .split(" - ")
+                .next()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(titles, vec!["Gunjou", "Yoru ni Kakeru", "Gunjou", "Idol"]);
+
+    // Nothing is left to download the second time
+    let stdout = env.run_ok(&[
+        "download",
+        "--playlist",
+        "https://www.youtube.com/playlist?list=PLtest",
+    ]);
+    assert!(stdout.contains("Queued 0 videos, skipped 5"), "{}", stdout);
+}
+
+#[test]
+fn download_from_file() {
+    let env = TestEnv::new("download-from-file");
+    env.stub_youtube_dl();
+    env.stub_ffmpeg();
+    env.youtube_dl_info(
+        "ytsearch5:YOASOBI Yoru ni Kakeru",
+        r#"{"_type": "playlist", "entries": [
+            {"id": "live", "title": "YOASOBI - Yoru ni Kakeru (Live)", "channel": "YOASOBI"},
+            {"id": "x8VYWazR5mE", "title": "YOASOBI - Yoru ni Kakeru", "channel": "Ayase / YOASOBI"}
+        ]}"#,
+    );
+    env.youtube_dl_info(
+        "ytsearch5:YOASOBI Gunjou",
+        r#"{"_type": "playlist", "entries": [
+            {"id": "Y4nEEZwckuU", "title": "YOASOBI - Gunjou", "channel": "Ayase / YOASOBI"}
+        ]}"#,
+    );
+    env.youtube_dl_info(
+        "https://youtu.be/sAuEeM_6zpk",
+        r#"{"id": "sAuEeM_6zpk", "title": "YOASOBI - Idol", "channel": "Ayase / YOASOBI"}"#,
+    );
+
+    let gunjou = env.music_dir.join("Gunjou.flac");
+    common::write_flac(&gunjou, "Gunjou", "YOASOBI", "THE BOOK");
+    env.insert_song(
+        &gunjou,
+        "Gunjou",
+        "YOASOBI",
+        "THE BOOK",
+        Some("Y4nEEZwckuU"),
+    );
+
+    let list = env.home.join("wishlist.csv");
+    std::fs::write(
+        &list,
+        "title,artist,album,url\n\
+        Yoru ni Kakeru,YOASOBI,THE BOOK,\n\
+        Gunjou,YOASOBI,,\n\
+        Idol,YOASOBI,Idol,https://youtu.be/sAuEeM_6zpk\n\
+        Missing,Nobody,,\n",
+    )
+    .unwrap();
+
+    let stdout = env.run_ok(&["download", "--from-file", list.to_str().unwrap()]);
+    assert!(
+        stdout.contains("2 downloaded, 0 queued, 1 skipped and 1 failed"),
+        "{}",
+        stdout
+    );
+    let log = std::fs::read_to_string(env.home.join("youtube-dl.log")).unwrap();
+    let mut downloaded = log.lines().collect::<Vec<_>>();
+    downloaded.sort_unstable();
+    assert_eq!(downloaded, vec!["sAuEeM_6zpk", "x8VYWazR5mE"]);
+
+    // Tags come from the list
+    let tag =
+        metaflac::Tag::read_from_path(env.music_dir.join("YOASOBI - Yoru ni Kakeru.flac")).unwrap();
+    let get = |key: &str| tag.get_vorbis(key).unwrap().collect::<Vec<_>>();
+    assert_eq!(get("TITLE"), vec!["Yoru ni Kakeru"]);
+    assert_eq!(get("ARTIST"), vec!["YOASOBI"]);
+    assert_eq!(get("ALBUM"), vec!["THE BOOK"]);
+
+    let report = std::fs::read_to_string(env.home.join("wishlist.csv.report.csv")).unwrap();
+    let lines = report.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "line,query,status,youtube_id,file,reason");
+    assert!(lines[1].starts_with("2,YOASOBI Yoru ni Kakeru,downloaded,x8VYWazR5mE,"));
+    assert!(lines[2].starts_with("3,YOASOBI Gunjou,skipped,Y4nEEZwckuU,,"));
+    assert!(lines[3].starts_with("4,https://youtu.be/sAuEeM_6zpk,downloaded,sAuEeM_6zpk,"));
+    assert!(lines[4].starts_with("5,Nobody Missing,failed,,,"));
+    assert!(lines[4].contains("Unable to look up"), "{}", lines[4]);
+}
+
+const SEARCH_RESULTS: &str = r#"{"_type": "playlist", "entries": [
+    {"id": "live", "title": "YOASOBI - Yoru ni Kakeru (Live)", "channel": "YOASOBI"},
+    {"id": "x8VYWazR5mE", "title": "YOASOBI - Yoru ni Kakeru", "channel": "Ayase / YOASOBI"}
+]}"#;
+
+#[test]
+fn download_without_terminal() {
+    let env = TestEnv::new("download-scripted");
+    env.stub_youtube_dl();
+    env.stub_ffmpeg();
+    env.youtube_dl_info("ytsearch5:yoasobi yoru ni kakeru", SEARCH_RESULTS);
+
+    // Questions without an answer are errors
+    let output = env.run(&["download", "yoasobi", "yoru", "ni", "kakeru"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("standard input is not a terminal"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("--pick"), "{}", stderr);
+
+    let output = env.run(&[
+        "download", "yoasobi", "yoru", "ni", "kakeru", "--pick", "best",
+    ]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--no-edit"));
+    assert!(!env.home.join("youtube-dl.log").exists());
+
+    let output = env.run(&["download", "yoasobi", "--pick", "3", "--yes"]);
+    assert!(!output.status.success());
+
+    env.run_ok(&[
+        "download",
+        "yoasobi",
+        "yoru",
+        "ni",
+        "kakeru",
+        "--pick",
+        "best",
+        "--title",
+        "Yoru ni Kakeru",
+        "--artist",
+        "YOASOBI",
+        "--album",
+        "THE BOOK",
+        "--rename",
+        "{artist} - {title}",
+    ]);
+    assert_eq!(
+        std::fs::read_to_string(env.home.join("youtube-dl.log")).unwrap(),
+        "x8VYWazR5mE\n"
+    );
+    let path = env.music_dir.join("YOASOBI - Yoru ni Kakeru.flac");
+    let tag = metaflac::Tag::read_from_path(&path).unwrap();
+    let get = |key: &str| tag.get_vorbis(key).unwrap().collect::<Vec<_>>();
+    assert_eq!(get("TITLE"), vec!["Yoru ni Kakeru"]);
+    assert_eq!(get("ALBUM"), vec!["THE BOOK"]);
+}
+
+#[test]
+fn download_with_defaults() {
+    let env = TestEnv::new("download-defaults");
+    env.stub_youtube_dl();
+    env.stub_ffmpeg();
+    env.youtube_dl_info("ytsearch5:yoasobi yoru ni kakeru", SEARCH_RESULTS);
+
+    env.run_ok(&["download", "yoasobi", "yoru", "ni", "kakeru", "--yes"]);
+    // The first result is the default, and the artist is taken out of its title
+    let path = env.music_dir.join("YOASOBI - Yoru ni Kakeru (Live).flac");
+    let tag = metaflac::Tag::read_from_path(&path).unwrap();
+    let get = |key: &str| tag.get_vorbis(key).unwrap().collect::<Vec<_>>();
+    assert_eq!(get("TITLE"), vec!["Yoru ni Kakeru (Live)"]);
+    assert_eq!(get("ARTIST"), vec!["YOASOBI"]);
+    assert_eq!(get("ALBUM"), vec!["Unknown"]);
+
+    env.run_ok(&[
+        "download",
+        "yoasobi",
+        "yoru",
+        "ni",
+        "kakeru",
+        "--pick",
+        "2",
+        "--no-edit",
+        "--yes",
+    ]);
+    let tag =
+        metaflac::Tag::read_from_path(env.music_dir.join("YOASOBI - Yoru ni Kakeru.flac")).unwrap();
+    assert!(tag.get_vorbis("TITLE").is_none());
+}
+
+#[test]
+fn download_videos_with_the_same_title() {
+    let env = TestEnv::new("download-same-title");
+    env.stub_youtube_dl();
+    env.stub_ffmpeg();
+    env.youtube_dl_info(
+        "ytsearch5:yoru ni kakeru",
+        r#"{"_type": "playlist", "entries": [
+            {"id": "first", "title": "Yoru ni Kakeru", "channel": "YOASOBI"},
+            {"id": "second", "title": "Yoru ni Kakeru", "channel": "Cover"}
+        ]}"#,
+    );
+
+    // The second video's name is taken by the first one's queued job
+    env.run_ok(&[
+        "download",
+        "yoru",
+        "ni",
+        "kakeru",
+        "--pick",
+        "1",
+        "--yes",
+        "--queue-only",
+    ]);
+    env.run_ok(&["download", "yoru", "ni", "kakeru", "--pick", "2", "--yes"]);
+
+    let connection = env.database();
+    let mut statement = connection
+        .prepare("SELECT song_youtube_id, song_path FROM songs ORDER BY song_youtube_id")
+        .unwrap();
+    let songs = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<Result<Vec<(String, String)>, _>>()
+        .unwrap();
+    let path = |name: &str| env.music_dir.join(name).display().to_string();
+    assert_eq!(
+        songs,
+        vec![
+            ("first".to_string(), path("Yoru ni Kakeru.flac")),
+            ("second".to_string(), path("Yoru ni Kakeru [second].flac")),
+        ]
+    );
+}
+
+#[test]
+fn download_with_yt_dlp() {
+    let env = TestEnv::new("download-yt-dlp");
+    env.stub_youtube_dl();
+    env.stub_ffmpeg();
+    let binary = env.bin_dir.join("yt-dlp-nightly");
+    std::fs::rename(env.bin_dir.join("youtube-dl"), &binary).unwrap();
+    env.write_config(&format!(
+        "[download]\nbackend = \"yt-dlp\"\nbinary = \"{}\"\n",
+        binary.display()
+    ));
+    env.youtube_dl_info("ytsearch5:yoasobi yoru ni kakeru", SEARCH_RESULTS);
+
+    env.run_ok(&[
+        "download",
+        "yoasobi",
+        "yoru",
+        "ni",
+        "kakeru",
+        "--pick",
+        "2",
+        "--no-edit",
+        "--yes",
+    ]);
+    assert_eq!(
+        std::fs::read_to_string(env.home.join("youtube-dl.log")).unwrap(),
+        "x8VYWazR5mE\n"
+    );
+    assert!(env.music_dir.join("YOASOBI - Yoru ni Kakeru.flac").exists());
+}
+
+#[test]
+fn download_from_local_directory() {
+    let env = TestEnv::new("download-local");
+    env.stub_ffmpeg();
+    let imports = env.home.join("Imports");
+    write_flac(
+        &imports.join("YOASOBI/Gunjou.flac"),
+        "Gunjou",
+        "YOASOBI",
+        "THE BOOK",
+    );
+    write_flac(
+        &imports.join("YOASOBI/Yoru ni Kakeru.flac"),
+        "Yoru ni Kakeru",
+        "YOASOBI",
+        "THE BOOK",
+    );
+    write_flac(
+        &imports.join("Lemon.flac"),
+        "Lemon",
+        "Kenshi Yonezu",
+        "STRAY SHEEP",
+    );
+    env.write_config(&format!(
+        "[download]\nbackend = \"local\"\ndirectory = \"{}\"\n",
+        imports.display()
+    ));
+
+    let stdout = env.run_ok(&["download", "yoasobi", "--search-only"]);
+    assert!(
+        stdout.contains("1. Title: Gunjou, Channel:YOASOBI"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("2. Title: Yoru ni Kakeru"), "{}", stdout);
+    assert!(!stdout.contains("Lemon"), "{}", stdout);
+
+    let stdout = env.run_ok(&[
+        "download",
+        "lemon",
+        "--yes",
+        "--album",
+        "STRAY SHEEP",
+        "--rename",
+        "{artist} - {title}",
+    ]);
+    assert!(stdout.contains(&format!(
+        "downloading {}",
+        imports.join("Lemon.flac").display()
+    )));
+    let path = env.music_dir.join("Kenshi Yonezu - Lemon.flac");
+    let tag = metaflac::Tag::read_from_path(&path).unwrap();
+    let get = |key: &str| tag.get_vorbis(key).unwrap().collect::<Vec<_>>();
+    assert_eq!(get("TITLE"), vec!["Lemon"]);
+    assert_eq!(get("ARTIST"), vec!["Kenshi Yonezu"]);
+    assert_eq!(get("ALBUM"), vec!["STRAY SHEEP"]);
+
+    // Directories download like playlists
+    let stdout = env.run_ok(&["download", "--playlist", "YOASOBI"]);
+    assert!(stdout.contains("2 downloads done, 0 failed"), "{}", stdout);
+    assert!(env.music_dir.join("Gunjou.flac").exists());
+    assert!(env.music_dir.join("Yoru ni Kakeru.flac").exists());
+    assert!(imports.join("Lemon.flac").exists());
+}
+
+#[test]
+fn download_with_profiles() {
+    let env = TestEnv::new("download-profiles");
+    env.stub_youtube_dl();
+    env.stub_ffmpeg();
+    env.youtube_dl_info("ytsearch5:yoasobi yoru ni kakeru", SEARCH_RESULTS);
+    let download = |profile: &str| {
+        env.run_ok(&[
+            "download",
+            "yoasobi",
+            "yoru",
+            "ni",
+            "kakeru",
+            "--pick",
+            "2",
+            "--title",
+            "Yoru ni Kakeru",
+            "--artist",
+            "YOASOBI",
+            "--album",
+            "THE BOOK",
+            "--rename",
+            "{title}",
+            "--profile",
+            profile,
+            "--existing",
+            "duplicate",
+        ])
+    };
+
+    // Opus is kept as it was downloaded
+    let stdout = download("opus");
+    assert!(stdout.contains("converting to Opus"), "{}", stdout);
+    assert!(!env.home.join("ffmpeg.log").exists());
+    let opus = std::fs::read(env.music_dir.join("Yoru ni Kakeru.opus")).unwrap();
+    assert!(opus.starts_with(b"OggS"));
+    assert!(opus
+        .windows(20)
+        .any(|window| window == b"TITLE=Yoru ni Kakeru"));
+    assert!(!env.music_dir.join(".staging/job-1").exists());
+
+    download("mp3-v0");
+    let ffmpeg = std::fs::read_to_string(env.home.join("ffmpeg.log")).unwrap();
+    assert!(ffmpeg.contains("-codec:a libmp3lame -q:a 0"), "{}", ffmpeg);
+    let mp3 = std::fs::read(env.music_dir.join("Yoru ni Kakeru.mp3")).unwrap();
+    assert!(mp3.starts_with(b"ID3"));
+    // The Opus song with the same name is left alone
+    assert_eq!(
+        std::fs::read(env.music_dir.join("Yoru ni Kakeru.opus")).unwrap(),
+        opus
+    );
+
+    // The config sets the default profile
+    env.write_config("[download]\nprofile = \"ogg-q6\"\n");
+    env.run_ok(&[
+        "download",
+        "yoasobi",
+        "yoru",
+        "ni",
+        "kakeru",
+        "--pick",
+        "2",
+        "--no-edit",
+        "--rename",
+        "{id}",
+        "--existing",
+        "duplicate",
+    ]);
+    let ffmpeg = std::fs::read_to_string(env.home.join("ffmpeg.log")).unwrap();
+    assert!(ffmpeg.contains("-codec:a libvorbis -q:a 6"), "{}", ffmpeg);
+    assert!(env.music_dir.join("x8VYWazR5mE.ogg").exists());
+}
+
+#[test]
+fn download_records_provenance() {
+    let env = TestEnv::new("download-provenance");
+    env.stub_youtube_dl();
+    env.stub_ffmpeg();
+    env.youtube_dl_info(
+        "ytsearch5:yoasobi yoru ni kakeru",
+        r#"{"_type": "playlist", "entries": [
+            {"id": "x8VYWazR5mE", "title": "YOASOBI - Yoru ni Kakeru", "channel": "YOASOBI",
+             "abr": 129.6}
+        ]}"#,
+    );
+    env.insert_song(
+        &env.music_dir.join("Gunjou.flac"),
+        "Gunjou",
+        "YOASOBI",
+        "THE BOOK",
+        None,
+    );
+    env.run_ok(&[
+        "download",
+        "yoasobi",
+        "yoru",
+        "ni",
+        "kakeru",
+        "--pick",
+        "best",
+        "--title",
+        "Yoru ni Kakeru",
+        "--artist",
+        "YOASOBI",
+        "--album",
+        "THE BOOK",
+        "--rename",
+        "{title}",
+    ]);
+
+    // The FLAC file remembers it was transcoded from Opus
+    let tag = metaflac::Tag::read_from_path(env.music_dir.join("Yoru ni Kakeru.flac")).unwrap();
+    let field = |key: &str| tag.get_vorbis(key).unwrap().next().unwrap().to_string();
+    assert_eq!(field("SOURCE_CODEC"), "Opus");
+    assert_eq!(field("SOURCE_BITRATE"), "130");
+    assert_eq!(field("SOURCE_URL"), "https://youtu.be/x8VYWazR5mE");
+    assert_eq!(field("DOWNLOADER_VERSION"), "youtube-dl 2021.12.17");
+    assert!(field("CONVERSION_TIME").starts_with("20"));
+
+    // Songs of unknown origin aren't counted as lossy
+    let stdout = env.run_ok(&["list", "--lossy-origin"]);
+    assert!(stdout.contains("Yoru ni Kakeru"), "{}", stdout);
+    assert!(!stdout.contains("Gunjou"), "{}", stdout);
+
+    let stdout = env.run_ok(&["search", "origin:lossy"]);
+    assert!(
+        stdout.contains("Source: Opus 130 kbit/s from https://youtu.be/x8VYWazR5mE"),
+        "{}",
+        stdout
+    );
+    let stdout = env.run_ok(&["search", "-origin:lossy"]);
+    assert!(stdout.contains("Gunjou"), "{}", stdout);
+    assert!(!stdout.contains("Yoru ni Kakeru"), "{}", stdout);
+}
+
+#[test]
+fn download_music_metadata_and_existing_songs() {
+    let env = TestEnv::new("download-existing");
+    env.stub_youtube_dl();
+    env.stub_ffmpeg();
+    env.youtube_dl_info(
+        "ytsearch5:yoasobi yoru ni kakeru",
+        r#"{"_type": "playlist", "entries": [
+            {"id": "x8VYWazR5mE", "title": "Yoru ni Kakeru", "channel": "YOASOBI - Topic",
+             "track": "Yoru ni Kakeru", "artist": "YOASOBI", "album": "THE BOOK",
+             "release_year": 2021, "track_number": "1", "tags": ["YOASOBI", "J-Pop"]}
+        ]}"#,
+    );
+    let download = |extra: &[&str]| {
+        let mut args = vec![
+            "download", "yoasobi", "yoru", "ni", "kakeru", "--pick", "1", "--yes",
+        ];
+        args.extend(extra);
+        env.run_ok(&args)
+    };
+    let downloads = || {
+        std::fs::read_to_string(env.home.join("youtube-dl.log"))
+            .unwrap()
+            .lines()
+            .count()
+    };
+
+    download(&["--rename", "{artist} - {title}"]);
+    let renamed = env.music_dir.join("YOASOBI - Yoru ni Kakeru.flac");
+    let tag = metaflac::Tag::read_from_path(&renamed).unwrap();
+    let get = |key: &str| tag.get_vorbis(key).unwrap().collect::<Vec<_>>();
+    assert_eq!(get("TITLE"), vec!["Yoru ni Kakeru"]);
+    assert_eq!(get("ARTIST"), vec!["YOASOBI"]);
+    assert_eq!(get("ALBUM"), vec!["THE BOOK"]);
+    assert_eq!(get("DATE"), vec!["2021"]);
+    assert_eq!(get("TRACKNUMBER"), vec!["1"]);
+    assert_eq!(get("GENRE"), vec!["J-Pop"]);
+    assert_eq!(get("COMMENT"), vec!["https://youtu.be/x8VYWazR5mE"]);
+    assert_eq!(downloads(), 1);
+
+    // The song is found by its video although its file was renamed, and skipped by default
+    let path = env.music_dir.join("Yoru ni Kakeru.flac");
+    let stdout = download(&[]);
+    assert!(stdout.contains("is in the library already"), "{}", stdout);
+    assert!(stdout.contains("Skipped"), "{}", stdout);
+    assert_eq!(downloads(), 1);
+
+    download(&["--existing", "retag", "--title", "Racing into the Night"]);
+    assert_eq!(downloads(), 1);
+    let tag = metaflac::Tag::read_from_path(&renamed).unwrap();
+    assert_eq!(
+        tag.get_vorbis("TITLE").unwrap().collect::<Vec<_>>(),
+        vec!["Racing into the Night"]
+    );
+
+    download(&["--existing", "redownload"]);
+    assert_eq!(downloads(), 2);
+    assert!(renamed.exists());
+    assert!(!path.exists());
+
+    download(&["--existing", "duplicate"]);
+    assert_eq!(downloads(), 3);
+    assert!(path.exists());
+
+    // Each duplicate gets a file of its own
+    download(&["--existing", "duplicate"]);
+    download(&["--existing", "duplicate"]);
+    assert_eq!(downloads(), 5);
+    for name in [
+        "Yoru ni Kakeru.flac",
+        "Yoru ni Kakeru [x8VYWazR5mE].flac",
+        "Yoru ni Kakeru [x8VYWazR5mE] (2).flac",
+    ] {
+        assert!(env.music_dir.join(name).exists(), "{}", name);
+    }
+    let songs: i64 = env
+        .database()
+        .query_row(
+            "SELECT COUNT(*) FROM songs WHERE song_youtube_id = 'x8VYWazR5mE'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(songs, 4);
+}
+
+#[test]
+fn download_split_into_tracks() {
+    let env = TestEnv::new("download-split");
+    env.stub_youtube_dl();
+    env.stub_ffmpeg();
+    env.write_config("[download]\nreplaygain = true\n");
+    env.loudness("track-01.flac", -10.0);
+    env.loudness("track-02.flac", -20.0);
+    env.youtube_dl_info(
+        "ytsearch5:yoasobi the book",
+        r#"{"_type": "playlist", "entries": [
+            {"id": "n6nXBOr6mOk", "title": "YOASOBI - THE BOOK (Full Album)",
+             "channel": "YOASOBI", "album": "THE BOOK", "release_year": 2021,
+             "chapters": [
+                {"start_time": 0.0, "end_time": 252.5, "title": "01. Encore"},
+                {"start_time": 252.5, "end_time": 513.0, "title": "02. Ikura - Yoru ni Kakeru"}
+             ]}
+        ]}"#,
+    );
+    env.youtube_dl_info(
+        "ytsearch5:yoasobi live",
+        r#"{"_type": "playlist", "entries": [
+            {"id": "Y4nEEZwckuU", "title": "YOASOBI Live", "channel": "YOASOBI"}
+        ]}"#,
+    );
+
+    let stdout = env.run_ok(&[
+        "download", "yoasobi", "the", "book", "--pick", "1", "--yes", "--split",
+    ]);
+    assert!(stdout.contains("as 2 tracks"), "{}", stdout);
+    let album = env.music_dir.join("THE BOOK");
+    let first = metaflac::Tag::read_from_path(album.join("01 - Encore.flac")).unwrap();
+    let second = metaflac::Tag::read_from_path(album.join("02 - Yoru ni Kakeru.flac")).unwrap();
+    let get = |tag: &metaflac::Tag, key: &str| {
+        tag.get_vorbis(key)
+            .map(|values| values.collect::<Vec<_>>().join(", "))
+            .unwrap_or_default()
+    };
+    assert_eq!(get(&first, "TITLE"), "Encore");
+    assert_eq!(get(&first, "ARTIST"), "YOASOBI");
+    assert_eq!(get(&first, "TRACKNUMBER"), "1");
+    assert_eq!(get(&second, "TITLE"), "Yoru ni Kakeru");
+    assert_eq!(get(&second, "ARTIST"), "Ikura");
+    assert_eq!(get(&second, "TRACKNUMBER"), "2");
+    for tag in [&first, &second] {
+        assert_eq!(get(tag, "ALBUM"), "THE BOOK");
+        assert_eq!(get(tag, "DATE"), "2021");
+        assert_eq!(get(tag, "COMMENT"), "https://youtu.be/n6nXBOr6mOk");
+    }
+    // The tracks are measured as one album
+    assert_eq!(get(&first, "REPLAYGAIN_TRACK_GAIN"), "-8.00 dB");
+    assert_eq!(get(&first, "REPLAYGAIN_ALBUM_GAIN"), "-5.40 dB");
+    assert_eq!(get(&second, "REPLAYGAIN_ALBUM_GAIN"), "-5.40 dB");
+
+    let ffmpeg = std::fs::read_to_string(env.home.join("ffmpeg.log")).unwrap();
+    assert!(ffmpeg.contains("-ss 252.500 -to 513.000"), "{}", ffmpeg);
+    assert!(!env.music_dir.join(".staging/job-1").exists());
+
+    // Each track is a song of its own, recorded with the job
+    let songs = env
+        .database()
+        .prepare(
+            "SELECT song_title, album_id, track_number FROM songs
+            WHERE song_youtube_id = 'n6nXBOr6mOk' ORDER BY track_number",
+        )
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .unwrap()
+        .collect::<rusqlite::Result<Vec<(String, i64, u32)>>>()
+        .unwrap();
+    assert_eq!(
+        songs,
+        vec![
+            ("Encore".to_string(), 1, 1),
+            ("Yoru ni Kakeru".to_string(), 1, 2)
+        ]
+    );
+    let recorded: i64 = env
+        .database()
+        .query_row(
+            "SELECT COUNT(*) FROM download_job_tracks WHERE song_id IS NOT NULL",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(recorded, 2);
+
+    // Videos without chapters are split by a cue sheet
+    let output = env.run(&[
+        "download", "yoasobi", "live", "--pick", "1", "--yes", "--split",
+    ]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--cue"));
+    let cue = env.home.join("live.cue");
+    std::fs::write(
+        &cue,
+        "PERFORMER \"YOASOBI\"\nTITLE \"Live at Budokan\"\nFILE \"live.flac\" WAVE\n\
+        TRACK 01 AUDIO\n  TITLE \"Gunjou\"\n  INDEX 01 00:00:00\n\
+        TRACK 02 AUDIO\n  TITLE \"Tabun\"\n  INDEX 01 04:05:30\n",
+    )
+    .unwrap();
+    // Files in the way are kept
+    let live = env.music_dir.join("Live at Budokan");
+    std::fs::create_dir_all(&live).unwrap();
+    std::fs::write(live.join("01 - Gunjou.flac"), "another song").unwrap();
+    env.run_ok(&[
+        "download",
+        "yoasobi",
+        "live",
+        "--pick",
+        "1",
+        "--yes",
+        "--cue",
+        cue.to_str().unwrap(),
+    ]);
+    let tag = metaflac::Tag::read_from_path(live.join("02 - Tabun.flac")).unwrap();
+    assert_eq!(get(&tag, "ALBUM"), "Live at Budokan");
+    assert_eq!(get(&tag, "ARTIST"), "YOASOBI");
+    assert!(live.join("01 - Gunjou [Y4nEEZwckuU].flac").exists());
+    assert_eq!(
+        std::fs::read_to_string(live.join("01 - Gunjou.flac")).unwrap(),
+        "another song"
+    );
+    let ffmpeg = std::fs::read_to_string(env.home.join("ffmpeg.log")).unwrap();
+    assert!(ffmpeg.contains("-ss 245.400 -vn"), "{}", ffmpeg);
+
+    // Removing a track deletes its own file, not one of the same name elsewhere
+    let decoy = env.music_dir.join("02 - Tabun.flac");
+    std::fs::write(&decoy, "another song").unwrap();
+    let id: i64 = env
+        .database()
+        .query_row(
+            "SELECT id FROM songs WHERE song_title = 'Tabun'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    env.run_ok(&["remove", "--id", &id.to_string()]);
+    assert!(!live.join("02 - Tabun.flac").exists());
+    assert!(decoy.exists());
+    let left: i64 = env
+        .database()
+        .query_row("SELECT COUNT(*) FROM songs WHERE id = ?1", [id], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(left, 0);
+}
+
+#[test]
+fn download_trims_silence() {
+    let env = TestEnv::new("download-trim");
+    env.stub_youtube_dl();
+    env.stub_ffmpeg();
+    env.write_config("[download]\ntrim_silence = true\nretry_delay = 0\n");
+    // A silent intro, a pause which stays, and a silent outro
+    env.silence(
+        "converted.flac",
+        &[(0.0, Some(12.5)), (60.0, Some(63.0)), (170.0, None)],
+    );
+    let path = env.music_dir.join("Yoru ni Kakeru.flac");
+    queue_job(&env, "x8VYWazR5mE", &path, "Yoru ni Kakeru", "queued");
+
+    let stdout = env.run_ok(&["queue", "run"]);
+    assert!(stdout.contains("1 downloads done, 0 failed"), "{}", stdout);
+    assert!(
+        stdout.contains(
+            "trimmed silence, 12.50 s cut from the start and 10.00 s from the end of 180.00 s"
+        ),
+        "{}",
+        stdout
+    );
+    let ffmpeg = std::fs::read_to_string(env.home.join("ffmpeg.log")).unwrap();
+    assert!(
+        ffmpeg.contains("silencedetect=noise=-50dB:d=2"),
+        "{}",
+        ffmpeg
+    );
+    assert!(ffmpeg.contains("-ss 12.500 -to 170.000"), "{}", ffmpeg);
+
+    let tag = metaflac::Tag::read_from_path(&path).unwrap();
+    let get = |key: &str| tag.get_vorbis(key).unwrap().collect::<Vec<_>>();
+    assert_eq!(get("ORIGINAL_DURATION"), vec!["180.000"]);
+    assert_eq!(get("TRIMMED_START"), vec!["12.500"]);
+    assert_eq!(get("TRIMMED_END"), vec!["10.000"]);
+    assert_eq!(get("TITLE"), vec!["Yoru ni Kakeru"]);
+
+    let trim: (f64, f64, f64) = env
+        .database()
+        .query_row(
+            "SELECT original_duration, trimmed_start, trimmed_end FROM songs",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .unwrap();
+    assert_eq!(trim, (180.0, 12.5, 10.0));
+    let stdout = env.run_ok(&["search", "yoru"]);
+    assert!(
+        stdout.contains("Trimmed: 12.50 s cut from the start"),
+        "{}",
+        stdout
+    );
+}
@@ -0,0 +1,131 @@
+mod common;
+
+use common::{write_flac, TestEnv};
+
+#[test]
+fn measure_and_tag_replaygain() {
+    let env = TestEnv::new("replaygain");
+    env.stub_ffmpeg();
+    let loud = env.music_dir.join("YOASOBI/Yoru ni Kakeru.flac");
+    let quiet = env.music_dir.join("YOASOBI/Gunjou.flac");
+    let single = env.music_dir.join("Night Dancer.flac");
+    write_flac(&loud, "Yoru ni Kakeru", "YOASOBI", "THE BOOK");
+    write_flac(&quiet, "Gunjou", "YOASOBI", "THE BOOK");
+    write_flac(&single, "Night Dancer", "imase", "Night Dancer");
+    env.loudness("Yoru ni Kakeru.flac", -10.0);
+    env.loudness("Gunjou.flac", -20.0);
+    env.loudness("Night Dancer.flac", -14.0);
+    env.run_ok(&["scan"]);
+
+    let stdout = env.run_ok(&["replaygain", "night"]);
+    assert!(
+        stdout.contains("1 tagged, 0 skipped, 0 failed"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("track -4.00 dB, peak 0.891251, album -4.00 dB, album peak 0.891251"),
+        "{}",
+        stdout
+    );
+
+    // The rest of the library is measured album by album
+    let stdout = env.run_ok(&["replaygain"]);
+    assert!(
+        stdout.contains("2 tagged, 1 skipped, 0 failed"),
+        "{}",
+        stdout
+    );
+    let tag = metaflac::Tag::read_from_path(&loud).unwrap();
+    let get = |key: &str| tag.get_vorbis(key).unwrap().collect::<Vec<_>>();
+    assert_eq!(get("REPLAYGAIN_TRACK_GAIN"), vec!["-8.00 dB"]);
+    assert_eq!(get("REPLAYGAIN_TRACK_PEAK"), vec!["0.891251"]);
+    assert_eq!(get("REPLAYGAIN_ALBUM_GAIN"), vec!["-5.40 dB"]);
+    let tag = metaflac::Tag::read_from_path(&quiet).unwrap();
+    assert_eq!(
+        tag.get_vorbis("REPLAYGAIN_TRACK_GAIN")
+            .unwrap()
+            .collect::<Vec<_>>(),
+        vec!["2.00 dB"]
+    );
+
+    let gains = env
+        .database()
+        .prepare(
+            "SELECT replaygain_track_gain, replaygain_album_gain FROM songs ORDER BY song_path",
+        )
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<rusqlite::Result<Vec<(f64, f64)>>>()
+        .unwrap();
+    assert_eq!(gains, vec![(-4.0, -4.0), (2.0, -5.4), (-8.0, -5.4)]);
+    let stdout = env.run_ok(&["search", "gunjou"]);
+    assert!(stdout.contains("ReplayGain: track +2.00 dB"), "{}", stdout);
+
+    let stdout = env.run_ok(&["replaygain"]);
+    assert!(
+        stdout.contains("0 tagged, 3 skipped, 0 failed"),
+        "{}",
+        stdout
+    );
+    let stdout = env.run_ok(&["replaygain", "--force"]);
+    assert!(
+        stdout.contains("3 tagged, 0 skipped, 0 failed"),
+        "{}",
+        stdout
+    );
+
+    // Missing files fail rather than being skipped
+    std::fs::remove_file(&single).unwrap();
+    let output = env.run(&["replaygain", "--force"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("2 tagged, 0 skipped, 1 failed"),
+        "{}",
+        stdout
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("File is missing"), "{}", stderr);
+}
+
+#[test]
+fn download_with_replaygain() {
+    let env = TestEnv::new("replaygain-download");
+    env.stub_youtube_dl();
+    env.stub_ffmpeg();
+    env.write_config("[download]\nreplaygain = true\n");
+    env.loudness("converted.flac", -12.5);
+
+    let path = env.music_dir.join("Yoru ni Kakeru.flac");
+    env.database()
+        .execute(
+            "INSERT INTO download_jobs (youtube_id, file_path, title, album, state, created_at,
+                updated_at)
+            VALUES ('x8VYWazR5mE', ?1, 'Yoru ni Kakeru', 'THE BOOK', 'queued',
+                '2021-12-01 10:00:00.0Z', '2021-12-01 10:00:00.0Z')",
+            [path.to_str().unwrap()],
+        )
+        .unwrap();
+    let stdout = env.run_ok(&["queue", "run"]);
+    assert!(stdout.contains("1 downloads done, 0 failed"), "{}", stdout);
+
+    // Only the track is measured, as the album may not be complete
+    let tag = metaflac::Tag::read_from_path(&path).unwrap();
+    assert_eq!(
+        tag.get_vorbis("REPLAYGAIN_TRACK_GAIN")
+            .unwrap()
+            .collect::<Vec<_>>(),
+        vec!["-5.50 dB"]
+    );
+    assert!(tag.get_vorbis("REPLAYGAIN_ALBUM_GAIN").is_none());
+    let stdout = env.run_ok(&["search", "yoru"]);
+    assert!(stdout.contains("ReplayGain: track -5.50 dB"), "{}", stdout);
+
+    let stdout = env.run_ok(&["replaygain"]);
+    assert!(
+        stdout.contains("1 tagged, 0 skipped, 0 failed"),
+        "{}",
+        stdout
+    );
+}
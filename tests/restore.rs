@@ -0,0 +1,48 @@
+mod common;
+
+use common::TestEnv;
+
+#[test]
+fn restore_missing_songs() {
+    let env = TestEnv::new("restore");
+    env.stub_youtube_dl();
+    env.stub_ffmpeg();
+
+    let missing = env.music_dir.join("Yoasobi/Yoru ni Kakeru.flac");
+    let no_id = env.music_dir.join("No ID.flac");
+    let present = env.music_dir.join("Present.flac");
+    std::fs::write(&present, common::minimal_flac()).unwrap();
+    env.insert_song(
+        &missing,
+        "Yoru ni Kakeru",
+        "YOASOBI",
+        "THE BOOK",
+        Some("x8VYWazR5mE"),
+    );
+    env.insert_song(&no_id, "No ID", "Someone", "Unknown", None);
+    env.insert_song(&present, "Present", "Someone", "Unknown", Some("present"));
+
+    let stdout = env.run_ok(&["restore", "--dry-run"]);
+    assert!(stdout.contains("Would restore Yoru ni Kakeru"));
+    assert!(stdout.contains("1 songs would be restored, 1 skipped"));
+    assert!(!missing.exists());
+    assert!(!env.home.join("youtube-dl.log").exists());
+
+    let stdout = env.run_ok(&["restore"]);
+    assert!(stdout.contains("1 songs restored, 1 skipped, 0 failed"));
+    assert_eq!(
+        std::fs::read_to_string(env.home.join("youtube-dl.log")).unwrap(),
+        "x8VYWazR5mE\n"
+    );
+    assert!(!env.music_dir.join(".staging/x8VYWazR5mE").exists());
+
+    let tag = metaflac::Tag::read_from_path(&missing).unwrap();
+    let get = |key: &str| tag.get_vorbis(key).unwrap().collect::<Vec<_>>();
+    assert_eq!(get("TITLE"), vec!["Yoru ni Kakeru"]);
+    assert_eq!(get("ARTIST"), vec!["YOASOBI"]);
+    assert_eq!(get("ALBUM"), vec!["THE BOOK"]);
+
+    // Nothing is left to restore
+    let stdout = env.run_ok(&["restore"]);
+    assert!(stdout.contains("0 songs restored, 1 skipped, 0 failed"));
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::{write_flac, TestEnv};
+
+#[test]
+fn scan_registers_and_updates_files() {
+    let env = TestEnv::new("scan");
+    let first = env.music_dir.join("YOASOBI/THE BOOK/Yoru ni Kakeru.flac");
+    let second = env.music_dir.join("Night Dancer.flac");
+    write_flac(&first, "Yoru ni Kakeru", "YOASOBI", "THE BOOK");
+    write_flac(&second, "Night Dancer", "imase", "Night Dancer");
+    write_flac(
+        &env.music_dir.join(".hidden/Hidden.flac"),
+        "Hidden",
+        "Nobody",
+        "Nothing",
+    );
+    std::fs::write(env.music_dir.join("Broken.flac"), b"not a flac file").unwrap();
+    std::fs::write(env.music_dir.join("notes.txt"), b"not music").unwrap();
+
+    let stdout = env.run_ok(&["scan"]);
+    assert!(stdout.contains("2 added, 0 updated, 0 unchanged, 1 failed"));
+    let stdout = env.run_ok(&["list", "--artist", "YOASOBI"]);
+    assert!(stdout.contains("Yoru ni Kakeru - YOASOBI"));
+
+    let stdout = env.run_ok(&["scan"]);
+    assert!(stdout.contains("0 added, 0 updated, 2 unchanged, 1 failed"));
+
+    // Retagging the file changes it
+    let mut tag = metaflac::Tag::read_from_path(&second).unwrap();
+    tag.set_vorbis("ARTIST", vec!["imase", "Someone"]);
+    tag.save().unwrap();
+    let stdout = env.run_ok(&["scan", "--verbose"]);
+    assert!(stdout.contains(&format!("Updated: {}", second.display())));
+    assert!(stdout.contains("0 added, 1 updated, 1 unchanged, 1 failed"));
+    let stdout = env.run_ok(&["list", "--artist", "Someone"]);
+    assert!(stdout.contains("Night Dancer"));
+
+    // Rewriting the same contents only touches the file
+    let contents = std::fs::read(&first).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    std::fs::write(&first, contents).unwrap();
+    let stdout = env.run_ok(&["scan"]);
+    assert!(stdout.contains("0 added, 0 updated, 2 unchanged, 1 failed"));
+
+    // Songs in subdirectories are removed by their path
+    let id: i64 = env
+        .database()
+        .query_row(
+            "SELECT id FROM songs WHERE song_path = ?1",
+            [first.to_str().unwrap()],
+            |row| row.get(0),
+        )
+        .unwrap();
+    env.run_ok(&["remove", "--id", &id.to_string()]);
+    assert!(!first.exists());
+    let stdout = env.run_ok(&["scan"]);
+    assert!(stdout.contains("0 added, 0 updated, 1 unchanged, 1 failed"));
+}
+
+#[test]
+fn scan_picks_up_downloaded_songs() {
+    let env = TestEnv::new("scan_downloaded");
+    let path = env.music_dir.join("Downloaded.flac");
+    write_flac(&path, "Downloaded", "Someone", "Unknown");
+    env.insert_song(&path, "Downloaded", "Someone", "Unknown", Some("abc"));
+
+    // Songs registered without a recorded state are compared by their tags
+    let stdout = env.run_ok(&["scan"]);
+    assert!(stdout.contains("0 added, 0 updated, 1 unchanged, 0 failed"));
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use common::{write_flac, TestEnv};
+
+#[test]
+fn verify_reports_and_fixes_problems() {
+    let env = TestEnv::new("verify");
+    let consistent = env.music_dir.join("Consistent.flac");
+    let mismatched = env.music_dir.join("Mismatched.flac");
+    let missing = env.music_dir.join("Missing.flac");
+    let orphan = env.music_dir.join("Sub/Orphan.flac");
+    let duplicated = env.music_dir.join("Duplicated.flac");
+
+    write_flac(&consistent, "Consistent", "Artist", "Album");
+    env.insert_song(&consistent, "Consistent", "Artist", "Album", None);
+    write_flac(&mismatched, "New Title", "Artist", "Album");
+    env.insert_song(&mismatched, "Old Title", "Artist", "Album", None);
+    env.insert_song(&missing, "Missing", "Artist", "Album", Some("abc"));
+    write_flac(&orphan, "Orphan", "Artist", "Album");
+    write_flac(&duplicated, "Duplicated", "Artist", "Album");
+    env.insert_song(&duplicated, "Duplicated", "Artist", "Album", None);
+    env.insert_song(&duplicated, "Duplicated", "Artist", "Album", None);
+
+    let stdout = env.run_ok(&["verify"]);
+    assert!(stdout.contains("Missing files (1):"));
+    assert!(stdout.contains(&missing.display().to_string()));
+    assert!(stdout.contains("Files without a record (1):"));
+    assert!(stdout.contains(&orphan.display().to_string()));
+    assert!(stdout.contains("Tag mismatches (1):"));
+    assert!(stdout.contains("title: \"Old Title\" in database, \"New Title\" in file"));
+    assert!(stdout.contains("Duplicate paths (1):"));
+    assert!(!stdout.contains("Fixed"));
+
+    // Policies only apply when fixing
+    assert!(!env
+        .run(&["verify", "--missing", "prune-row"])
+        .status
+        .success());
+
+    let stdout = env.run_ok(&[
+        "verify",
+        "--fix",
+        "--missing",
+        "prune-row",
+        "--mismatch",
+        "trust-database",
+    ]);
+    assert!(stdout.contains("Fixed 4 problems:"));
+    let tag = metaflac::Tag::read_from_path(&mismatched).unwrap();
+    assert_eq!(
+        tag.get_vorbis("TITLE").unwrap().collect::<Vec<_>>(),
+        vec!["Old Title"]
+    );
+
+    let stdout = env.run_ok(&["verify"]);
+    assert!(stdout.contains("The database and the music directory are consistent"));
+    let stdout = env.run_ok(&["list"]);
+    assert!(stdout.contains("Orphan - Artist"));
+    assert!(!stdout.contains("Missing - Artist"));
+}
+
+#[test]
+fn verify_trusts_file_tags_by_default() {
+    let env = TestEnv::new("verify_trust_file");
+    let path = env.music_dir.join("Song.flac");
+    write_flac(&path, "File Title", "File Artist", "Album");
+    let id = env.insert_song(&path, "Database Title", "Database Artist", "Album", None);
+    env.database()
+        .execute(
+            "UPDATE songs SET source_codec = 'Opus', replaygain_track_gain = -6.5,
+            trimmed_start = 1.5 WHERE id = ?1",
+            [id],
+        )
+        .unwrap();
+
+    let stdout = env.run_ok(&["verify", "--fix"]);
+    assert!(stdout.contains("Updated title, artists of record"));
+    let stdout = env.run_ok(&["list"]);
+    assert!(stdout.contains("File Title - File Artist"));
+    // What only the database knows is kept
+    let kept: (String, f64, f64) = env
+        .database()
+        .query_row(
+            "SELECT source_codec, replaygain_track_gain, trimmed_start FROM songs WHERE id = ?1",
+            [id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .unwrap();
+    assert_eq!(kept, ("Opus".to_string(), -6.5, 1.5));
+}